@@ -1,16 +1,20 @@
 //! Contains  the data store for local zones
 
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::Path;
-use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{LockResult, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
 
+use dashmap::DashMap;
 use derive_more::{Display, From, Error};
 
 
-use crate::buffer::buffer::{PacketBuffer, StreamPacketBuffer, VectorPacketBuffer};
-use crate::protocols::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
+use crate::authorities::zone_file::parse_zone;
+use crate::buffer::buffer::{PacketBuffer, VectorPacketBuffer};
+use crate::protocols::protocol::{is_subdomain, DnsPacket, DnsRecord, QueryType, ResultCode, TransientTtl};
 
 #[derive(Debug, Display, From, Error)]
 pub enum AuthorityError {
@@ -18,10 +22,15 @@ pub enum AuthorityError {
     Protocol(crate::protocols::protocol::ProtocolError),
     Io(std::io::Error),
     PoisonedLock,
+    #[display(fmt = "Invalid zone file, line {}: {}", line, reason)]
+    InvalidZoneFile { line: usize, reason: String },
 }
 
 type Result<T> = std::result::Result<T, AuthorityError>;
 
+/// Opens every zone file written by `Zones::save`, so they can't be mistaken for master files.
+const SAVED_ZONE_MAGIC: &[u8] = b"\0DNSZONE1";
+
 #[derive(Clone, Debug, Default)]
 pub struct Zone {
     pub domain: String,
@@ -35,6 +44,30 @@ pub struct Zone {
     pub records: BTreeSet<DnsRecord>,
 }
 
+impl Zone {
+    pub fn new(domain: String, m_name: String, r_name: String) -> Zone {
+        Zone {
+            domain,
+            m_name,
+            r_name,
+            serial: 0,
+            refresh: 0,
+            retry: 0,
+            expire: 0,
+            minimum: 0,
+            records: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_record(&mut self, rec: &DnsRecord) -> bool {
+        self.records.insert(rec.clone())
+    }
+
+    pub fn delete_record(&mut self, rec: &DnsRecord) -> bool {
+        self.records.remove(rec)
+    }
+}
+
 #[derive(Default)]
 pub struct Zones {
     zones: BTreeMap<String, Zone>,
@@ -47,49 +80,68 @@ impl<'a> Zones {
         }
     }
 
-    pub fn load(&mut self) -> Result<()> {
-        let zones_dir = Path::new("zones").read_dir()?;
+    /// Loads every zone file in `zones_dir`, parsing them on one thread per CPU.
+    ///
+    /// The directory may mix zones written by `save` with RFC 1035 master files.
+    pub fn load(&mut self, zones_dir: &Path) -> Result<()> {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
 
-        for wrapped_filename in zones_dir {
-            let filename = match wrapped_filename {
-                Ok(x) => x,
-                Err(_) => continue,
-            };
+        self.load_with_threads(zones_dir, threads)
+    }
 
-            let mut zone_file = match File::open(filename.path()) {
-                Ok(x) => x,
-                Err(_) => continue,
-            };
+    /// Loads every zone file in `zones_dir` using up to `threads` parsing threads.
+    ///
+    /// A file that can't be read or parsed is reported and skipped without
+    /// affecting the others.
+    pub fn load_with_threads(&mut self, zones_dir: &Path, threads: usize) -> Result<()> {
+        let paths = zones_dir
+            .read_dir()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect::<Vec<PathBuf>>();
 
-            let mut buffer = StreamPacketBuffer::new(&mut zone_file);
+        if paths.is_empty() {
+            return Ok(());
+        }
 
-            let mut zone = Zone::new(String::new(), String::new(), String::new());
-            buffer.read_qname(&mut zone.domain)?;
-            buffer.read_qname(&mut zone.m_name)?;
-            buffer.read_qname(&mut zone.r_name)?;
-            zone.serial = buffer.read_u32()?;
-            zone.refresh = buffer.read_u32()?;
-            zone.retry = buffer.read_u32()?;
-            zone.expire = buffer.read_u32()?;
-            zone.minimum = buffer.read_u32()?;
+        let chunk_size = paths.len().div_ceil(threads.max(1));
 
-            let record_count = buffer.read_u32()?;
+        let loaded = thread::scope(|scope| {
+            let workers = paths
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|path| match read_zone_file(path) {
+                                Ok(zone) => Some(zone),
+                                Err(err) => {
+                                    println!("Failed to load zone file {:?}: {}", path, err);
+                                    None
+                                }
+                            })
+                            .collect::<Vec<Zone>>()
+                    })
+                })
+                .collect::<Vec<_>>();
 
-            for _ in 0..record_count {
-                let rr = DnsRecord::read(&mut buffer)?;
-                zone.add_record(&rr);
-            }
-
-            println!("Loaded zone {} with {} records", zone.domain, record_count);
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap_or_default())
+                .collect::<Vec<Zone>>()
+        });
 
+        for zone in loaded {
+            println!("Loaded zone {} with {} records", zone.domain, zone.records.len());
             self.zones.insert(zone.domain.clone(), zone);
         }
 
         Ok(())
     }
 
-    pub fn save(&mut self) -> Result<()> {
-        let zones_dir = Path::new("zones");
+    pub fn save(&mut self, zones_dir: &Path) -> Result<()> {
         for zone in self.zones.values() {
             let filename = zones_dir.join(Path::new(&zone.domain));
             let mut zone_file = match File::create(&filename) {
@@ -115,6 +167,7 @@ impl<'a> Zones {
                 let _ = rec.write(&mut buffer);
             }
 
+            let _ = zone_file.write(SAVED_ZONE_MAGIC);
             let _ = zone_file.write(&buffer.buffer[0..buffer.pos]);
         }
 
@@ -138,7 +191,971 @@ impl<'a> Zones {
     }
 }
 
+/// Reads a zone file of either kind, telling them apart by the header `Zones::save` writes.
+///
+/// Zones saved before the header was introduced are read as saved zones once
+/// they fail to parse as master files, the error of the latter being reported.
+fn read_zone_file(path: &Path) -> Result<Zone> {
+    let data = fs::read(path)?;
+
+    if let Some(saved) = data.strip_prefix(SAVED_ZONE_MAGIC) {
+        return read_saved_zone(saved.to_vec());
+    }
+
+    match parse_master_file(path, &String::from_utf8_lossy(&data)) {
+        Ok(zone) => Ok(zone),
+        Err(err) => read_saved_zone(data).map_err(|_| err),
+    }
+}
+
+/// Parses an RFC 1035 master file, with relative names taken relative to the file name.
+fn parse_master_file(path: &Path, input: &str) -> Result<Zone> {
+    let origin = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    parse_zone(input, origin)
+}
+
+/// Parses the body of a zone file written by `Zones::save`, following its header.
+fn read_saved_zone(data: Vec<u8>) -> Result<Zone> {
+    let mut buffer = VectorPacketBuffer::new();
+    buffer.buffer = data;
+
+    let mut zone = Zone::new(String::new(), String::new(), String::new());
+    buffer.read_qname(&mut zone.domain)?;
+    buffer.read_qname(&mut zone.m_name)?;
+    buffer.read_qname(&mut zone.r_name)?;
+    zone.serial = buffer.read_u32()?;
+    zone.refresh = buffer.read_u32()?;
+    zone.retry = buffer.read_u32()?;
+    zone.expire = buffer.read_u32()?;
+    zone.minimum = buffer.read_u32()?;
+
+    let record_count = buffer.read_u32()?;
+
+    for _ in 0..record_count {
+        let rr = DnsRecord::read(&mut buffer)?;
+        zone.add_record(&rr);
+    }
+
+    Ok(zone)
+}
+
+/// TTLs served for records of a given type, in place of the ones in the zone.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TtlPolicy {
+    overrides: HashMap<QueryType, u32>,
+}
+
+impl TtlPolicy {
+    pub fn new() -> TtlPolicy {
+        TtlPolicy::default()
+    }
+
+    /// Serves records of `qtype` with `ttl`, whatever the zone says.
+    pub fn set_override(&mut self, qtype: QueryType, ttl: u32) {
+        self.overrides.insert(qtype, ttl);
+    }
+
+    pub fn get_override(&self, qtype: QueryType) -> Option<u32> {
+        self.overrides.get(&qtype).copied()
+    }
+
+    /// Rewrites the TTLs of the records in `packet` that have an override.
+    pub fn apply(&self, packet: &mut DnsPacket) {
+        if self.overrides.is_empty() {
+            return;
+        }
+
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            if let Some(ttl) = self.get_override(rec.get_querytype()) {
+                if let Some(rec_ttl) = rec.ttl_mut() {
+                    *rec_ttl = TransientTtl(ttl);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Authority {
     zones: RwLock<Zones>,
+    memoize: AtomicBool,
+    answers: DashMap<(String, QueryType), DnsPacket>,
+    ttl_policy: RwLock<TtlPolicy>,
+}
+
+impl Authority {
+    pub fn new() -> Authority {
+        Authority {
+            zones: RwLock::new(Zones::new()),
+            memoize: AtomicBool::new(false),
+            answers: DashMap::new(),
+            ttl_policy: RwLock::new(TtlPolicy::new()),
+        }
+    }
+
+    pub fn load(&self, zones_dir: &Path) -> Result<()> {
+        let mut zones = self.write().map_err(|_| AuthorityError::PoisonedLock)?;
+        zones.load(zones_dir)
+    }
+
+    /// Parses an RFC 1035 master file and adds the zone it describes.
+    ///
+    /// Relative names before any `$ORIGIN` directive are taken relative to the
+    /// file name, so `zones/example.com` may start with plain `@` records.
+    pub fn load_zone_file(&self, path: &Path) -> Result<()> {
+        let input = fs::read_to_string(path)?;
+        let zone = parse_master_file(path, &input)?;
+        let mut zones = self.write().map_err(|_| AuthorityError::PoisonedLock)?;
+        zones.add_zone(zone);
+
+        Ok(())
+    }
+
+    /// Replaces the loaded zones with the contents of `zones_dir`, dropping memoized answers.
+    pub fn reload(&self, zones_dir: &Path) -> Result<()> {
+        let mut fresh = Zones::new();
+        fresh.load(zones_dir)?;
+
+        let mut zones = self.write().map_err(|_| AuthorityError::PoisonedLock)?;
+        *zones = fresh;
+
+        Ok(())
+    }
+
+    /// Enables or disables memoizing computed answers per query name and type.
+    pub fn set_answer_cache(&self, enabled: bool) {
+        self.memoize.store(enabled, Ordering::Release);
+        if !enabled {
+            self.answers.clear();
+        }
+    }
+
+    /// Replaces the TTL overrides applied to authoritative answers, dropping memoized answers.
+    pub fn set_ttl_policy(&self, policy: TtlPolicy) -> Result<()> {
+        // Taking the zones lock clears the memo, and keeps a query that applied the
+        // old policy from memoizing its answer until the new one is in place
+        let _zones = self.write().map_err(|_| AuthorityError::PoisonedLock)?;
+        let mut ttl_policy = self.ttl_policy.write().map_err(|_| AuthorityError::PoisonedLock)?;
+        *ttl_policy = policy;
+
+        Ok(())
+    }
+
+    /// Number of answers currently memoized.
+    pub fn answer_cache_len(&self) -> usize {
+        self.answers.len()
+    }
+
+    /// Answers a query from the zone with the longest suffix match for `qname`.
+    pub fn query(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let zones = self.zones.read().ok()?;
+
+        let memoize = self.memoize.load(Ordering::Acquire);
+        if memoize {
+            if let Some(packet) = self.answers.get(&(qname.to_string(), qtype)) {
+                return Some(packet.clone());
+            }
+        }
+
+        // The read lock is held until the answer is memoized, so a concurrent
+        // write can't clear the memo in between and leave a stale entry behind
+        let mut packet = Self::compute_answer(&zones, qname, qtype)?;
+        self.ttl_policy.read().ok()?.apply(&mut packet);
+        if memoize {
+            self.answers.insert((qname.to_string(), qtype), packet.clone());
+        }
+
+        Some(packet)
+    }
+
+    fn compute_answer(zones: &Zones, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let zone = zones
+            .zones()
+            .into_iter()
+            .filter(|zone| is_subdomain(qname, &zone.domain))
+            .max_by_key(|zone| zone.domain.len())?;
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = true;
+
+        let owner = Self::answering_name(zone, qname);
+
+        for rec in &zone.records {
+            let domain = match rec.get_domain() {
+                Some(x) => x,
+                None => continue,
+            };
+
+            if domain != owner {
+                continue;
+            }
+
+            let rtype = rec.get_querytype();
+            if qtype == rtype
+                || qtype == QueryType::ANY
+                || (qtype == QueryType::A && rtype == QueryType::CNAME)
+            {
+                let mut rec = rec.clone();
+                // Records synthesized from a wildcard are owned by the queried name
+                if let Some(domain) = rec.domain_mut() {
+                    *domain = qname.to_string();
+                }
+                packet.answers.push(rec);
+            }
+        }
+
+        if !packet.answers.is_empty() && qtype != QueryType::NS && qtype != QueryType::ANY {
+            for rec in &zone.records {
+                if let DnsRecord::NS { ref domain, .. } = *rec {
+                    if *domain == zone.domain {
+                        packet.authorities.push(rec.clone());
+                    }
+                }
+            }
+            Self::add_glue(zone, &mut packet);
+        }
+
+        if packet.answers.is_empty() {
+            // A name which exists without records of the type asked for is NODATA, RFC 2308
+            packet.header.rescode = if Self::name_exists(zone, &owner) {
+                ResultCode::NOERROR
+            } else {
+                ResultCode::NXDOMAIN
+            };
+
+            packet.authorities.push(DnsRecord::SOA {
+                domain: zone.domain.clone(),
+                m_name: zone.m_name.clone(),
+                r_name: zone.r_name.clone(),
+                serial: zone.serial,
+                refresh: zone.refresh,
+                retry: zone.retry,
+                expire: zone.expire,
+                minimum: zone.minimum,
+                ttl: TransientTtl(zone.minimum),
+            });
+        }
+
+        Some(packet)
+    }
+
+    /// The owner of the records answering `qname`, following RFC 4592.
+    ///
+    /// That's `qname` itself whenever it exists in the zone, even with no records of
+    /// its own. Otherwise it's the wildcard below the closest existing ancestor, if
+    /// that ancestor has one.
+    fn answering_name(zone: &Zone, qname: &str) -> String {
+        if Self::name_exists(zone, qname) {
+            return qname.to_string();
+        }
+
+        let mut encloser = qname;
+        while let Some((_, parent)) = encloser.split_once('.') {
+            encloser = parent;
+            if encloser.len() <= zone.domain.len() || Self::name_exists(zone, encloser) {
+                break;
+            }
+        }
+
+        let wildcard = format!("*.{}", encloser);
+        if Self::name_exists(zone, &wildcard) {
+            wildcard
+        } else {
+            qname.to_string()
+        }
+    }
+
+    /// Whether `name` owns records in the zone or has descendants that do, ignoring case.
+    fn name_exists(zone: &Zone, name: &str) -> bool {
+        zone.records.iter().any(|rec| match rec.get_domain() {
+            Some(domain) => is_subdomain(&domain, name),
+            None => false,
+        })
+    }
+
+    /// Builds a referral to the delegated zone `child_zone`.
+    ///
+    /// The child's NS records go in the authority section, with any A and AAAA
+    /// records for the name servers held in the parent zone as glue. Returns `None`
+    /// when no loaded zone delegates `child_zone`.
+    pub fn referral(&self, child_zone: &str) -> Option<DnsPacket> {
+        let zones = self.zones.read().ok()?;
+
+        let parent = zones
+            .zones()
+            .into_iter()
+            .filter(|zone| zone.domain != child_zone && is_subdomain(child_zone, &zone.domain))
+            .max_by_key(|zone| zone.domain.len())?;
+
+        let mut packet = DnsPacket::new();
+        packet.header.authoritative_answer = false;
+        packet.header.rescode = ResultCode::NOERROR;
+
+        for rec in &parent.records {
+            if let DnsRecord::NS { ref domain, .. } = *rec {
+                if domain == child_zone {
+                    packet.authorities.push(rec.clone());
+                }
+            }
+        }
+
+        if packet.authorities.is_empty() {
+            return None;
+        }
+
+        Self::add_glue(parent, &mut packet);
+
+        Some(packet)
+    }
+
+    /// Adds the A and AAAA records `zone` holds for the name servers in the authority section.
+    fn add_glue(zone: &Zone, packet: &mut DnsPacket) {
+        let hosts = packet
+            .authorities
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::NS { ref host, .. } => Some(host.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        for rec in &zone.records {
+            match *rec {
+                DnsRecord::A { ref domain, .. } | DnsRecord::AAAA { ref domain, .. }
+                    if hosts.contains(domain) =>
+                {
+                    packet.resources.push(rec.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, Zones>> {
+        self.zones.read()
+    }
+
+    /// Locks the zones for modification, invalidating memoized answers.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, Zones>> {
+        let zones = self.zones.write();
+        self.answers.clear();
+        zones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_any_query_returns_all_records() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::MX {
+            domain: "www.example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("www.example.com", QueryType::ANY).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(2, packet.answers.len());
+    }
+
+    #[test]
+    fn test_answer_carries_zone_ns_and_glue() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 53),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(1, packet.authorities.len());
+        assert_eq!(Some("10.0.0.53".to_string()), packet.get_resolved_ns("www.example.com"));
+
+        // Nothing to add to an answer without one
+        let packet = authority.query("missing.example.com", QueryType::A).unwrap();
+        assert!(packet.resources.is_empty());
+    }
+
+    #[test]
+    fn test_ttl_policy_overrides_served_ttl() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let mut policy = TtlPolicy::new();
+        policy.set_override(QueryType::A, 60);
+        authority.set_ttl_policy(policy).unwrap();
+
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(60, packet.answers[0].get_ttl());
+        // NS records have no override, so keep the zone's TTL
+        assert_eq!(3600, packet.authorities[0].get_ttl());
+
+        // The zone itself is left untouched
+        let zones = authority.read().unwrap();
+        assert!(zones.get_zone("example.com").unwrap().records.iter().all(|rec| rec.get_ttl() == 3600));
+    }
+
+    #[test]
+    fn test_wildcard_answers_names_without_records() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "*.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "host.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("anything.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: "anything.example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            }],
+            packet.answers
+        );
+
+        // An exact match takes precedence over the wildcard
+        let packet = authority.query("host.example.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(Some("10.0.0.2".parse().unwrap()), packet.answers[0].get_address());
+    }
+
+    #[test]
+    fn test_existing_name_blocks_wildcard_below_it() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "*.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "host.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("sub.host.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn test_existing_name_blocks_wildcard_regardless_of_case() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "*.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "Host.Example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("host.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+
+        let packet = authority.query("sub.HOST.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn test_zones_only_answer_names_below_them() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        assert!(authority.query("badexample.com", QueryType::A).is_none());
+        assert!(authority.query("www.badexample.com", QueryType::A).is_none());
+        assert!(authority.query("www.example.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_existing_name_without_the_type_is_nodata() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.query("www.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(packet.answers.is_empty());
+        assert!(matches!(packet.get_soa(), Some(DnsRecord::SOA { domain, .. }) if domain == "example.com"));
+
+        // Names with records below them exist too
+        let packet = authority.query("example.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+
+        let packet = authority.query("missing.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert!(packet.get_soa().is_some());
+    }
+
+    #[test]
+    fn test_referral_to_delegated_child() {
+        let authority = Authority::new();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::NS {
+            domain: "child.example.com".to_string(),
+            host: "ns1.child.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::NS {
+            domain: "child.example.com".to_string(),
+            host: "ns.elsewhere.net".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "ns1.child.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 53),
+            ttl: TransientTtl(3600),
+        });
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(zone);
+
+        let packet = authority.referral("child.example.com").unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+        assert_eq!(2, packet.authorities.len());
+        assert_eq!(
+            vec![DnsRecord::A {
+                domain: "ns1.child.example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 53),
+                ttl: TransientTtl(3600),
+            }],
+            packet.resources
+        );
+
+        assert!(authority.referral("other.example.com").is_none());
+        assert!(authority.referral("example.com").is_none());
+    }
+
+    #[test]
+    fn test_referral_comes_from_the_parent_by_whole_labels() {
+        let authority = Authority::new();
+
+        let mut parent = Zone::new("com".to_string(), "ns1.com".to_string(), "admin.com".to_string());
+        parent.add_record(&DnsRecord::NS {
+            domain: "badexample.com".to_string(),
+            host: "ns1.badexample.net".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        authority.write().unwrap().add_zone(parent);
+        authority.write().unwrap().add_zone(Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        ));
+
+        // example.com is a longer suffix of the name, but not a parent of it
+        let packet = authority.referral("badexample.com").unwrap();
+        assert_eq!(1, packet.authorities.len());
+    }
+
+    #[test]
+    fn test_parallel_load_of_many_zone_files() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-parallel-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let mut zones = Zones::new();
+        for i in 0..200 {
+            let mut zone = Zone::new(
+                format!("zone{}.example", i),
+                format!("ns1.zone{}.example", i),
+                format!("admin.zone{}.example", i),
+            );
+            zone.add_record(&DnsRecord::A {
+                domain: format!("www.zone{}.example", i),
+                addr: Ipv4Addr::new(10, 0, (i / 256) as u8, (i % 256) as u8),
+                ttl: TransientTtl(3600),
+            });
+            zones.add_zone(zone);
+        }
+        zones.save(&zones_dir).unwrap();
+
+        // A truncated file must not keep the others from loading
+        fs::write(zones_dir.join("broken.example"), [SAVED_ZONE_MAGIC, &[4, b'z', b'o']].concat()).unwrap();
+
+        let started = std::time::Instant::now();
+        let mut sequential = Zones::new();
+        sequential.load_with_threads(&zones_dir, 1).unwrap();
+        let sequential_elapsed = started.elapsed();
+
+        let started = std::time::Instant::now();
+        let mut parallel = Zones::new();
+        parallel.load_with_threads(&zones_dir, 4).unwrap();
+        let parallel_elapsed = started.elapsed();
+
+        fs::remove_dir_all(&zones_dir).unwrap();
+
+        assert_eq!(200, parallel.zones().len());
+        for i in 0..200 {
+            let zone = parallel.get_zone(&format!("zone{}.example", i)).unwrap();
+            assert_eq!(1, zone.records.len());
+        }
+        assert_eq!(200, sequential.zones().len());
+
+        // Loose bound, thread start up can dominate for files this small
+        assert!(parallel_elapsed <= sequential_elapsed * 4 + std::time::Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_saved_wildcards_survive_a_reload() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-wildcard-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "*.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 9),
+            ttl: TransientTtl(3600),
+        });
+
+        let mut zones = Zones::new();
+        zones.add_zone(zone);
+        zones.save(&zones_dir).unwrap();
+
+        let authority = Authority::new();
+        authority.reload(&zones_dir).unwrap();
+        fs::remove_dir_all(&zones_dir).unwrap();
+
+        let packet = authority.query("anything.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NOERROR, packet.header.rescode);
+        assert!(matches!(packet.answers.as_slice(), [DnsRecord::A { domain, addr, .. }]
+            if domain == "anything.example.com" && *addr == Ipv4Addr::new(10, 0, 0, 9)));
+    }
+
+    #[test]
+    fn test_saved_zones_with_printable_label_lengths_survive_a_reload() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-label-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        // Labels of 9 and 10 bytes have lengths that read as a tab and a newline
+        let mut zones = Zones::new();
+        for domain in ["localhost", "cloudflare.com"] {
+            let mut zone = Zone::new(domain.to_string(), format!("ns1.{}", domain), format!("admin.{}", domain));
+            zone.add_record(&DnsRecord::A {
+                domain: domain.to_string(),
+                addr: Ipv4Addr::new(127, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            });
+            zones.add_zone(zone);
+        }
+        zones.save(&zones_dir).unwrap();
+
+        let mut loaded = Zones::new();
+        loaded.load(&zones_dir).unwrap();
+        fs::remove_dir_all(&zones_dir).unwrap();
+
+        assert_eq!(2, loaded.zones().len());
+        let zone = loaded.get_zone("localhost").unwrap();
+        assert_eq!("ns1.localhost", zone.m_name);
+        assert_eq!(1, zone.records.len());
+        assert_eq!(1, loaded.get_zone("cloudflare.com").unwrap().records.len());
+    }
+
+    #[test]
+    fn test_zones_saved_without_header_still_load() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-legacy-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let mut zone = Zone::new(
+            "localhost".to_string(),
+            "ns1.localhost".to_string(),
+            "admin.localhost".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "localhost".to_string(),
+            addr: Ipv4Addr::new(127, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        let mut zones = Zones::new();
+        zones.add_zone(zone);
+        zones.save(&zones_dir).unwrap();
+
+        // Strip the header, leaving the file as older versions wrote it
+        let path = zones_dir.join("localhost");
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[SAVED_ZONE_MAGIC.len()..]).unwrap();
+
+        let mut loaded = Zones::new();
+        loaded.load(&zones_dir).unwrap();
+        fs::remove_dir_all(&zones_dir).unwrap();
+
+        let zone = loaded.get_zone("localhost").unwrap();
+        assert_eq!("ns1.localhost", zone.m_name);
+        assert_eq!(1, zone.records.len());
+    }
+
+    #[test]
+    fn test_save_skips_zones_that_cant_be_written() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-partial-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let mut zones = Zones::new();
+        // Named after a directory that doesn't exist, so its file can't be created
+        zones.add_zone(Zone::new(
+            "missing/example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        ));
+        zones.add_zone(Zone::new(
+            "example.org".to_string(),
+            "ns1.example.org".to_string(),
+            "admin.example.org".to_string(),
+        ));
+        zones.save(&zones_dir).unwrap();
+
+        let saved = zones_dir.join("example.org").exists();
+        fs::remove_dir_all(&zones_dir).unwrap();
+        assert!(saved);
+    }
+
+    #[test]
+    fn test_answer_cache_is_cleared_by_reload() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-memo-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let save_zone = |addr: Ipv4Addr| {
+            let mut zone = Zone::new(
+                "example.com".to_string(),
+                "ns1.example.com".to_string(),
+                "admin.example.com".to_string(),
+            );
+            zone.add_record(&DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr,
+                ttl: TransientTtl(3600),
+            });
+
+            let mut zones = Zones::new();
+            zones.add_zone(zone);
+            zones.save(&zones_dir).unwrap();
+        };
+
+        save_zone(Ipv4Addr::new(10, 0, 0, 1));
+
+        let authority = Authority::new();
+        authority.load(&zones_dir).unwrap();
+        authority.set_answer_cache(true);
+
+        let first = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(1, authority.answer_cache_len());
+
+        let second = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(1, authority.answer_cache_len());
+        assert_eq!(first.answers, second.answers);
+
+        save_zone(Ipv4Addr::new(10, 0, 0, 2));
+        authority.reload(&zones_dir).unwrap();
+        assert_eq!(0, authority.answer_cache_len());
+
+        let reloaded = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 2)], reloaded.get_a_records());
+
+        fs::remove_dir_all(&zones_dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_master_zone_file() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-master-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        let path = zones_dir.join("example.com");
+        fs::write(
+            &path,
+            "$TTL 3600\n\
+             @ IN SOA ns1 admin 2024010101 7200 900 1209600 300\n\
+             @ IN NS ns1\n\
+             @ IN NS ns2\n\
+             ns1 IN A 10.0.0.1\n\
+             ns2 IN A 10.0.0.2\n\
+             www IN A 10.0.0.80\n",
+        )
+        .unwrap();
+
+        let authority = Authority::new();
+        authority.load_zone_file(&path).unwrap();
+
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 80)], packet.get_a_records());
+
+        let packet = authority.query("example.com", QueryType::NS).unwrap();
+        assert_eq!(2, packet.answers.len());
+
+        fs::remove_dir_all(&zones_dir).unwrap();
+    }
+
+    #[test]
+    fn test_reload_reads_master_and_saved_zones() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-mixed-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+        fs::create_dir_all(&zones_dir).unwrap();
+
+        fs::write(
+            zones_dir.join("example.org"),
+            "; Written by hand\n\
+             $TTL 3600\n\
+             @ IN SOA ns1 admin 2024010101 7200 900 1209600 300\n\
+             www IN A 10.0.1.80\n",
+        )
+        .unwrap();
+
+        let mut zone = Zone::new(
+            "example.com".to_string(),
+            "ns1.example.com".to_string(),
+            "admin.example.com".to_string(),
+        );
+        zone.add_record(&DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 80),
+            ttl: TransientTtl(3600),
+        });
+        let mut zones = Zones::new();
+        zones.add_zone(zone);
+        zones.save(&zones_dir).unwrap();
+
+        let authority = Authority::new();
+        authority.reload(&zones_dir).unwrap();
+        fs::remove_dir_all(&zones_dir).unwrap();
+
+        let packet = authority.query("www.example.org", QueryType::A).unwrap();
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 1, 80)], packet.get_a_records());
+
+        let packet = authority.query("www.example.com", QueryType::A).unwrap();
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 80)], packet.get_a_records());
+    }
 }
@@ -0,0 +1,467 @@
+//! Parser for RFC 1035 master files
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::authorities::authority::{AuthorityError, Zone};
+use crate::protocols::protocol::{DnsRecord, TransientTtl};
+
+type Result<T> = std::result::Result<T, AuthorityError>;
+
+/// A logical line of a master file, with parenthesized continuations joined.
+struct Entry {
+    line: usize,
+    /// Lines starting with whitespace reuse the owner of the previous record.
+    inherit_owner: bool,
+    tokens: Vec<String>,
+}
+
+fn invalid(line: usize, reason: &str) -> AuthorityError {
+    AuthorityError::InvalidZoneFile {
+        line,
+        reason: reason.to_string(),
+    }
+}
+
+/// Splits `input` into entries, handling comments, quoted strings and parentheses.
+fn tokenize(input: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+
+    let mut line = 1;
+    let mut start_line = 1;
+    let mut depth = 0;
+    let mut inherit_owner = false;
+    let mut at_line_start = true;
+
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 && tokens.is_empty() {
+            inherit_owner = c == ' ' || c == '\t';
+            start_line = line;
+        }
+        at_line_start = false;
+
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        if let Some(escaped) = chars.next() {
+                            token.push(escaped);
+                        }
+                    }
+                    Some('\n') | None => return Err(invalid(line, "unterminated quoted string")),
+                    Some(other) => token.push(other),
+                }
+            }
+            // Pushed even when empty, since "" is a valid character-string
+            tokens.push(std::mem::take(&mut token));
+            continue;
+        }
+
+        if !matches!(c, ';' | '(' | ')') && !c.is_whitespace() {
+            token.push(c);
+            continue;
+        }
+
+        if !token.is_empty() {
+            tokens.push(std::mem::take(&mut token));
+        }
+
+        match c {
+            ';' => {
+                while chars.peek().is_some_and(|next| *next != '\n') {
+                    chars.next();
+                }
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(invalid(line, "unbalanced parentheses"));
+                }
+                depth -= 1;
+            }
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+                if depth == 0 && !tokens.is_empty() {
+                    entries.push(Entry {
+                        line: start_line,
+                        inherit_owner,
+                        tokens: std::mem::take(&mut tokens),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    if depth != 0 {
+        return Err(invalid(line, "unbalanced parentheses"));
+    }
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            line: start_line,
+            inherit_owner,
+            tokens,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Makes `name` absolute, without the trailing dot used in the rest of the server.
+fn absolute_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if let Some(name) = name.strip_suffix('.') {
+        name.to_string()
+    } else if origin.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// Parses a TTL given in seconds or with BIND style unit suffixes, such as `1h30m`.
+fn parse_ttl(value: &str) -> Option<u32> {
+    if value.is_empty() {
+        return None;
+    }
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(seconds);
+    }
+
+    let mut total: u32 = 0;
+    let mut digits = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        let amount = digits.parse::<u32>().ok()?;
+        total = total.checked_add(amount.checked_mul(unit)?)?;
+        digits.clear();
+    }
+
+    if !digits.is_empty() {
+        return None;
+    }
+
+    Some(total)
+}
+
+fn is_class(token: &str) -> bool {
+    matches!(token.to_ascii_uppercase().as_str(), "IN" | "CH" | "HS" | "CS")
+}
+
+/// Parses a master file into a zone.
+///
+/// `origin` is the initial origin for relative names, until a `$ORIGIN`
+/// directive replaces it. The SOA record fills in the zone's own fields
+/// rather than going into its records, so the file must contain one.
+pub fn parse_zone(input: &str, origin: &str) -> Result<Zone> {
+    let mut origin = origin.trim_end_matches('.').to_string();
+    let mut default_ttl: Option<u32> = None;
+    let mut last_ttl: Option<u32> = None;
+    let mut last_owner: Option<String> = None;
+    let mut soa: Option<Zone> = None;
+    let mut records = Vec::new();
+
+    for entry in tokenize(input)? {
+        let line = entry.line;
+        let tokens = &entry.tokens;
+
+        if tokens[0].starts_with('$') {
+            let value = tokens.get(1).ok_or_else(|| invalid(line, "directive without a value"))?;
+            match tokens[0].to_ascii_uppercase().as_str() {
+                "$ORIGIN" => origin = absolute_name(value, &origin),
+                "$TTL" => default_ttl = Some(parse_ttl(value).ok_or_else(|| invalid(line, "invalid TTL"))?),
+                other => return Err(invalid(line, &format!("unsupported directive {}", other))),
+            }
+            continue;
+        }
+
+        let mut idx = 0;
+        let owner = if entry.inherit_owner {
+            last_owner.clone().ok_or_else(|| invalid(line, "record without an owner"))?
+        } else {
+            idx += 1;
+            absolute_name(&tokens[0], &origin)
+        };
+        last_owner = Some(owner.clone());
+
+        // The TTL and class are both optional and may come in either order
+        let mut ttl = None;
+        for _ in 0..2 {
+            match tokens.get(idx) {
+                Some(token) if is_class(token) => idx += 1,
+                Some(token) if ttl.is_none() && parse_ttl(token).is_some() => {
+                    ttl = parse_ttl(token);
+                    idx += 1;
+                }
+                _ => break,
+            }
+        }
+
+        let rtype = tokens
+            .get(idx)
+            .ok_or_else(|| invalid(line, "missing record type"))?
+            .to_ascii_uppercase();
+        let rdata = &tokens[idx + 1..];
+
+        // Without $TTL, records without a TTL inherit the last one given
+        let ttl = match ttl.or(default_ttl).or(last_ttl) {
+            Some(ttl) => ttl,
+            None => return Err(invalid(line, "no TTL given and no default set")),
+        };
+        last_ttl = Some(ttl);
+
+        let field = |i: usize| -> Result<&str> {
+            rdata
+                .get(i)
+                .map(String::as_str)
+                .ok_or_else(|| invalid(line, &format!("{} record is missing fields", rtype)))
+        };
+        let number = |i: usize| -> Result<u32> {
+            let value = field(i)?;
+            parse_ttl(value).ok_or_else(|| invalid(line, &format!("invalid number {}", value)))
+        };
+        let short = |i: usize| -> Result<u16> {
+            let value = field(i)?;
+            value
+                .parse::<u16>()
+                .map_err(|_| invalid(line, &format!("invalid number {}", value)))
+        };
+
+        let record = match rtype.as_str() {
+            "A" => DnsRecord::A {
+                domain: owner,
+                addr: field(0)?
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| invalid(line, "invalid IPv4 address"))?,
+                ttl: TransientTtl(ttl),
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain: owner,
+                addr: field(0)?
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| invalid(line, "invalid IPv6 address"))?,
+                ttl: TransientTtl(ttl),
+            },
+            "NS" => DnsRecord::NS {
+                domain: owner,
+                host: absolute_name(field(0)?, &origin),
+                ttl: TransientTtl(ttl),
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain: owner,
+                host: absolute_name(field(0)?, &origin),
+                ttl: TransientTtl(ttl),
+            },
+            "MX" => DnsRecord::MX {
+                domain: owner,
+                priority: short(0)?,
+                host: absolute_name(field(1)?, &origin),
+                ttl: TransientTtl(ttl),
+            },
+            "TXT" => {
+                if rdata.is_empty() {
+                    return Err(invalid(line, "TXT record is missing fields"));
+                }
+                DnsRecord::TXT {
+                    domain: owner,
+                    data: rdata.concat(),
+                    ttl: TransientTtl(ttl),
+                }
+            }
+            "SRV" => DnsRecord::SRV {
+                domain: owner,
+                priority: short(0)?,
+                weight: short(1)?,
+                port: short(2)?,
+                host: absolute_name(field(3)?, &origin),
+                ttl: TransientTtl(ttl),
+            },
+            "SOA" => {
+                if soa.is_some() {
+                    return Err(invalid(line, "more than one SOA record"));
+                }
+
+                let mut zone = Zone::new(
+                    owner,
+                    absolute_name(field(0)?, &origin),
+                    absolute_name(field(1)?, &origin),
+                );
+                zone.serial = number(2)?;
+                zone.refresh = number(3)?;
+                zone.retry = number(4)?;
+                zone.expire = number(5)?;
+                zone.minimum = number(6)?;
+                soa = Some(zone);
+                continue;
+            }
+            other => return Err(invalid(line, &format!("unsupported record type {}", other))),
+        };
+
+        records.push(record);
+    }
+
+    let mut zone = soa.ok_or_else(|| invalid(input.lines().count(), "no SOA record"))?;
+    for record in &records {
+        zone.add_record(record);
+    }
+
+    Ok(zone)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_zone() {
+        let input = "\
+$ORIGIN example.com.
+$TTL 1h
+@       IN  SOA ns1 hostmaster.example.com. (
+                2024010101 ; serial
+                7200       ; refresh
+                900        ; retry
+                1209600    ; expire
+                300 )      ; minimum
+        IN  NS  ns1
+        IN  NS  ns2.example.net.
+ns1         A   192.0.2.1
+www     600 IN A 192.0.2.10
+            A   192.0.2.11
+mail.example.com.  IN 120 A 192.0.2.25
+@           MX  10 mail
+";
+
+        let zone = parse_zone(input, "").unwrap();
+        assert_eq!("example.com", zone.domain);
+        assert_eq!("ns1.example.com", zone.m_name);
+        assert_eq!("hostmaster.example.com", zone.r_name);
+        assert_eq!(2024010101, zone.serial);
+        assert_eq!(7200, zone.refresh);
+        assert_eq!(900, zone.retry);
+        assert_eq!(1209600, zone.expire);
+        assert_eq!(300, zone.minimum);
+
+        let expected = vec![
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns1.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns2.example.net".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "ns1.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 10),
+                ttl: TransientTtl(600),
+            },
+            DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 11),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "mail.example.com".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 25),
+                ttl: TransientTtl(120),
+            },
+            DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            },
+        ];
+
+        assert_eq!(expected.len(), zone.records.len());
+        for record in &expected {
+            let parsed = zone.records.get(record).unwrap();
+            assert_eq!(record.get_ttl(), parsed.get_ttl());
+        }
+    }
+
+    #[test]
+    fn test_parse_txt_srv_and_aaaa_records() {
+        let input = "\
+$TTL 300
+@ SOA ns1 admin 1 2 3 4 5
+@ TXT \"v=spf1 \" \"-all\" ; two character-strings
+_sip._tcp SRV 10 60 5060 sip
+sip AAAA 2001:db8::5
+";
+
+        let zone = parse_zone(input, "example.org.").unwrap();
+        assert_eq!("example.org", zone.domain);
+        assert_eq!("admin.example.org", zone.r_name);
+
+        assert!(zone.records.contains(&DnsRecord::TXT {
+            domain: "example.org".to_string(),
+            data: "v=spf1 -all".to_string(),
+            ttl: TransientTtl(300),
+        }));
+        assert!(zone.records.contains(&DnsRecord::SRV {
+            domain: "_sip._tcp.example.org".to_string(),
+            priority: 10,
+            weight: 60,
+            port: 5060,
+            host: "sip.example.org".to_string(),
+            ttl: TransientTtl(300),
+        }));
+        assert!(zone.records.contains(&DnsRecord::AAAA {
+            domain: "sip.example.org".to_string(),
+            addr: "2001:db8::5".parse().unwrap(),
+            ttl: TransientTtl(300),
+        }));
+    }
+
+    #[test]
+    fn test_parse_errors_report_the_line() {
+        let input = "$TTL 300\n@ SOA ns1 admin 1 2 3 4 5\nwww A 999.0.0.1\n";
+
+        match parse_zone(input, "example.com") {
+            Err(AuthorityError::InvalidZoneFile { line, .. }) => assert_eq!(3, line),
+            other => panic!("unexpected result {:?}", other.map(|zone| zone.records.len())),
+        }
+
+        assert!(parse_zone("$TTL 300\nwww A 192.0.2.1\n", "example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_ttl_units() {
+        assert_eq!(Some(3600), parse_ttl("3600"));
+        assert_eq!(Some(5400), parse_ttl("1h30m"));
+        assert_eq!(Some(604800), parse_ttl("1W"));
+        assert_eq!(None, parse_ttl("IN"));
+        assert_eq!(None, parse_ttl("10x"));
+        assert_eq!(None, parse_ttl(""));
+    }
+}
@@ -1,8 +1,6 @@
 //! buffers for use when writing and reading dns packets
 
 use std::collections::BTreeMap;
-use tokio::io::AsyncRead;
-use tokio::io::AsyncReadExt;
 use std::io::Read;
 use std::fmt;
 
@@ -53,8 +51,17 @@ pub enum BufferError {
     InvalidCharacterInLabel,
     InvalidCompressionPointer,
     InvalidUtf8,
+    LabelTooLong,
+    NameTooLong,
+    EmptyLabel,
 }
 
+/// Longest label allowed, as the length byte reserves its top two bits for pointers.
+const MAX_LABEL_LENGTH: usize = 63;
+
+/// Longest name allowed on the wire, counting length bytes and the root label.
+const MAX_NAME_LENGTH: usize = 255;
+
 type Result<T> = std::result::Result<T, BufferError>;
 
 /// A trait for managing operations on the packet buffer.
@@ -80,7 +87,7 @@ pub trait PacketBuffer {
         let res = ((self.read()? as u32) << 24)
             | ((self.read()? as u32) << 16)
             | ((self.read()? as u32) << 8)
-            | ((self.read()? as u32) << 0);
+            | (self.read()? as u32);
 
         Ok(res)
     }
@@ -95,6 +102,14 @@ pub trait PacketBuffer {
     fn find_label(&self, label: &str) -> Option<usize>;
     fn save_label(&mut self, label: &str, pos: usize);
 
+    /// Discards everything written from `len` on, leaving the position there.
+    ///
+    /// Buffers that remember labels for compression must also forget the ones
+    /// saved past `len`, so later names don't point into discarded data.
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.seek(len)
+    }
+
     fn write_u8(&mut self, val: u8) -> Result<()> {
         self.write(val)?;
 
@@ -126,81 +141,32 @@ pub trait PacketBuffer {
         self.write(((val >> 24) & 0xFF) as u8)?;
         self.write(((val >> 16) & 0xFF) as u8)?;
         self.write(((val >> 8) & 0xFF) as u8)?;
-        self.write(((val >> 0) & 0xFF) as u8)?;
+        self.write((val & 0xFF) as u8)?;
 
         Ok(())
     }
 
     fn write_qname(&mut self, qname: &str) -> Result<()> {
-        // Handle empty QName case.
-        if qname.is_empty() {
-           self.write_u8(0)?;
-           return Ok(())
-        }
-
-        let labels = qname.split('.').collect::<Vec<&str>>();
-        let mut jumped = false;
-
-        for (i, label) in labels.iter().enumerate() {
-            // Validate the label charactres
-            for c in label.chars() {
-               if !c.is_alphanumeric() && c != '-' {
-                  return Err(BufferError::InvalidCharacterInLabel);
-               }
-            }
-            let remaining_qname = labels[i..].join(".");
-            if let Some(pos) = self.find_label(&remaining_qname) {
-                self.write_u16((pos as u16) | 0xC000)?;
-                jumped = true;
-                break;
-            }
-
-            self.save_label(&remaining_qname, self.pos());
-            self.write_u8(label.len() as u8)?;
-            self.write_all(label.as_bytes())?;
-        }
-
-        if !jumped {
-            self.write_u8(0)?;
-        }
+        write_name(self, qname, true)
+    }
 
-        Ok(())
+    /// Writes `qname` in full, without pointing back at earlier occurrences of its labels.
+    ///
+    /// For names in rdata where the spec forbids compression, such as SRV and SVCB targets,
+    /// since some resolvers reject pointers there.
+    fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        write_name(self, qname, false)
     }
 
     fn read_qname(&mut self, outstr: &mut String) -> Result<()> {
-        let mut pos = self.pos();
-        let mut jumped = false;
-        let mut delim = "";
-
-        loop {
-            let len = self.get(pos)?;
-
-            if self.is_compression_pointer(len) {
-                if !jumped {
-                    self.seek(pos + 2)?;
-                }
-                let offset = self.calculate_offset(pos, len);
-                pos = offset;
-                jumped = true;
-                continue;
-            }
-            pos += 1;
-
-            if len == 0 {
-                break;
-            }
-            outstr.push_str(delim);
-            let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+        read_name(self, outstr, true)
+    }
 
-            delim = ".";
-            pos += len as usize;
-        }
-        if !jumped {
-            self.seek(pos)?;
-        }
-        
-        Ok(())
+    /// Reads a name like `read_qname`, but keeps the case of its labels as sent.
+    ///
+    /// Needed to check that a response echoes a 0x20 encoded question exactly.
+    fn read_qname_preserving_case(&mut self, outstr: &mut String) -> Result<()> {
+        read_name(self, outstr, false)
     }
 
     fn is_compression_pointer(&mut self, len: u8) -> bool {
@@ -217,6 +183,136 @@ pub trait PacketBuffer {
     }
 }
 
+/// Checks that `name` is a valid DNS name, with or without its trailing dot.
+///
+/// Labels must be non-empty and at most 63 bytes, and the whole name at most 255 bytes
+/// encoded. The root may be given as `""` or `"."`, and a wildcard owner as `*` in
+/// place of the leftmost label.
+pub fn validate_qname(name: &str) -> Result<()> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    if name.is_empty() {
+        return Ok(());
+    }
+
+    let mut encoded_len = 1;
+    for (i, label) in name.split('.').enumerate() {
+        if label.is_empty() {
+            return Err(BufferError::EmptyLabel);
+        }
+        if i == 0 && label == "*" {
+            encoded_len += 2;
+            continue;
+        }
+        // Underscores show up in service and policy names such as `_sip._tcp` or `_dmarc`
+        for c in label.chars() {
+           if !c.is_alphanumeric() && c != '-' && c != '_' {
+              return Err(BufferError::InvalidCharacterInLabel);
+           }
+        }
+        if label.len() > MAX_LABEL_LENGTH {
+            return Err(BufferError::LabelTooLong);
+        }
+        encoded_len += label.len() + 1;
+    }
+
+    if encoded_len > MAX_NAME_LENGTH {
+        return Err(BufferError::NameTooLong);
+    }
+
+    Ok(())
+}
+
+/// Reads a possibly compressed name, lowercasing its labels when `lowercase` is set.
+fn read_name<T: PacketBuffer + ?Sized>(buffer: &mut T, outstr: &mut String, lowercase: bool) -> Result<()> {
+    let mut pos = buffer.pos();
+    let mut jumped = false;
+    let mut delim = "";
+    let mut last_offset = usize::MAX;
+
+    loop {
+        let len = buffer.get(pos)?;
+
+        if buffer.is_compression_pointer(len) {
+            let offset = buffer.calculate_offset(pos, len);
+
+            // Pointers may only refer to earlier data, and every jump has to land
+            // before the previous one, so a crafted name can't send us in circles
+            if offset >= pos || offset >= last_offset {
+                return Err(BufferError::InvalidCompressionPointer);
+            }
+
+            if !jumped {
+                buffer.seek(pos + 2)?;
+            }
+            last_offset = offset;
+            pos = offset;
+            jumped = true;
+            continue;
+        }
+        pos += 1;
+
+        if len == 0 {
+            break;
+        }
+        outstr.push_str(delim);
+        let label = String::from_utf8_lossy(buffer.get_range(pos, len as usize)?);
+        if lowercase {
+            outstr.push_str(&label.to_lowercase());
+        } else {
+            outstr.push_str(&label);
+        }
+
+        delim = ".";
+        pos += len as usize;
+    }
+    if !jumped {
+        buffer.seek(pos)?;
+    }
+    
+    Ok(())
+}
+
+/// Writes `qname` as a sequence of labels, compressing it against earlier names when `compress` is set.
+fn write_name<T: PacketBuffer + ?Sized>(buffer: &mut T, qname: &str, compress: bool) -> Result<()> {
+    // A trailing dot only marks the name as fully qualified, it isn't an empty label
+    let qname = qname.strip_suffix('.').unwrap_or(qname);
+
+    // Handle empty QName case, which is the root.
+    if qname.is_empty() {
+       buffer.write_u8(0)?;
+       return Ok(())
+    }
+
+    // Validate the whole name up front so nothing is written for a bad one
+    validate_qname(qname)?;
+
+    let labels = qname.split('.').collect::<Vec<&str>>();
+    let mut jumped = false;
+
+    for (i, label) in labels.iter().enumerate() {
+        let remaining_qname = labels[i..].join(".");
+        if compress {
+            if let Some(pos) = buffer.find_label(&remaining_qname) {
+                buffer.write_u16((pos as u16) | 0xC000)?;
+                jumped = true;
+                break;
+            }
+        }
+
+        // Saved either way, so later names can still point at this one
+        let pos = buffer.pos();
+        buffer.save_label(&remaining_qname, pos);
+        buffer.write_u8(label.len() as u8)?;
+        buffer.write_all(label.as_bytes())?;
+    }
+
+    if !jumped {
+        buffer.write_u8(0)?;
+    }
+
+    Ok(())
+}
+
 #[derive(Default)]
 pub struct VectorPacketBuffer {
     pub buffer: Vec<u8>,
@@ -243,22 +339,30 @@ impl PacketBuffer for VectorPacketBuffer {
         self.label_lookup.insert(label.to_string(), pos);
     }
 
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.buffer.truncate(len);
+        self.label_lookup.retain(|_, pos| *pos < len);
+        self.pos = len;
+
+        Ok(())
+    }
+
     fn read(&mut self) -> Result<u8> {
-        let res = self.buffer[self.pos];
+        let res = self.get(self.pos)?;
         self.pos += 1;
 
         Ok(res)
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        Ok(self.buffer[pos])
+        self.buffer.get(pos).copied().ok_or(BufferError::EndOfBuffer)
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
         if start + len > self.buffer.len() {
             return Err(BufferError::EndOfBuffer)
         }
-        Ok(&self.buffer[start..start + len as usize])
+        Ok(&self.buffer[start..start + len])
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
@@ -299,45 +403,70 @@ impl PacketBuffer for VectorPacketBuffer {
 
 pub struct StreamPacketBuffer<'a, T>
 where
-    T: AsyncRead,
+    T: Read,
 {
     pub stream: &'a mut T,
     pub buffer: Vec<u8>,
     pub pos: usize,
+    pub label_lookup: BTreeMap<String, usize>,
 }
 
 impl<'a, T> StreamPacketBuffer<'a, T>
 where
-    T: AsyncRead + Unpin + 'a + std::marker::Unpin,
+    T: Read + 'a,
 {
     pub fn new(stream: &'a mut T) -> StreamPacketBuffer<'a, T> {
         StreamPacketBuffer {
-            stream: stream,
+            stream,
             buffer: Vec::new(),
             pos: 0,
+            label_lookup: BTreeMap::new(),
+        }
+    }
+
+    /// Reads from the stream until at least `len` bytes are buffered.
+    fn fill(&mut self, len: usize) -> Result<()> {
+        while self.buffer.len() < len {
+            let mut local_buffer = [0; 1];
+            self.stream.read_exact(&mut local_buffer)?;
+            self.buffer.push(local_buffer[0]);
         }
+
+        Ok(())
     }
 }
 
 impl<'a, T> PacketBuffer for StreamPacketBuffer<'a, T>
 where
-    T: AsyncRead + 'a + std::marker::Unpin,
+    T: Read + 'a,
 {
-    fn find_label(&self, _: &str) -> Option<usize> {
-        None
+    fn find_label(&self, label: &str) -> Option<usize> {
+        self.label_lookup.get(label).cloned()
     }
 
-    fn save_label(&mut self, _: &str, _: usize) {
-        unimplemented!();
+    fn save_label(&mut self, label: &str, pos: usize) {
+        self.label_lookup.insert(label.to_string(), pos);
     }
 
-    fn read(&mut self) -> Result<u8> {
-        while self.pos >= self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer);
-            self.buffer.push(local_buffer[0]);
+    /// Compression pointers may only jump back into data already read from the stream.
+    ///
+    /// Anything further on yields an offset `read_qname` rejects, rather than reading ahead for it.
+    fn calculate_offset(&mut self, pos: usize, len: u8) -> usize {
+        let b2 = match self.get(pos + 1) {
+            Ok(val) => val as u16,
+            Err(_) => return usize::MAX,
+        };
+        let offset = ((((len as u16) ^ 0xC0) << 8) | b2) as usize;
+        if offset >= self.buffer.len() {
+            return usize::MAX;
         }
 
+        offset
+    }
+
+    fn read(&mut self) -> Result<u8> {
+        self.fill(self.pos + 1)?;
+
         let res = self.buffer[self.pos];
         self.pos += 1;
 
@@ -345,23 +474,15 @@ where
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        while pos >= self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer);
-            self.buffer.push(local_buffer[0]);
-        }
+        self.fill(pos + 1)?;
 
         Ok(self.buffer[pos])
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        while start + len > self.buffer.len() {
-            let mut local_buffer = [0; 1];
-            self.stream.read(&mut local_buffer);
-            self.buffer.push(local_buffer[0]);
-        }
+        self.fill(start + len)?;
 
-        Ok(&self.buffer[start..start + len as usize])
+        Ok(&self.buffer[start..start + len])
     }
 
     fn write(&mut self, _: u8) -> Result<()> {
@@ -435,7 +556,7 @@ impl PacketBuffer for BytePacketBuffer {
         if start + len >= 512 {
             return Err(BufferError::EndOfBuffer);
         }
-        Ok(&self.buf[start..start + len as usize])
+        Ok(&self.buf[start..start + len])
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
@@ -490,7 +611,7 @@ mod tests {
 
         // Then we set up a slight variation with relies on a jump back to the data of
         // the first name
-        let crafted_data = [0x01, b'b' as u8, 0xC0, 0x02];
+        let crafted_data = [0x01, b'b', 0xC0, 0x02];
         for b in &crafted_data {
             match buffer.write_u8(*b) {
                 Ok(_) => {}
@@ -528,8 +649,8 @@ mod tests {
         let mut buffer = VectorPacketBuffer::new();
 
         // Write the domain names
-        buffer.write_qname(&"ns1.google.com".to_string()).unwrap();
-        buffer.write_qname(&"ns2.google.com".to_string()).unwrap();
+        buffer.write_qname("ns1.google.com").unwrap();
+        buffer.write_qname("ns2.google.com").unwrap();
 
         // Print the buffer contents for debugging
         println!("Buffer after writing qnames: {:?}", buffer.buffer);
@@ -628,14 +749,74 @@ mod tests {
         assert_eq!(buffer.buffer, expected);
     }
     
+    #[test]
+    fn test_write_qname_label_too_long() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        let qname = format!("{}.com", "a".repeat(64));
+        assert!(matches!(buffer.write_qname(&qname), Err(BufferError::LabelTooLong)));
+        assert!(buffer.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_validate_qname() {
+        validate_qname("www.example.com").unwrap();
+        validate_qname("www.example.com.").unwrap();
+        validate_qname("_sip._tcp.example.com").unwrap();
+        validate_qname("").unwrap();
+        validate_qname(".").unwrap();
+
+        assert!(matches!(validate_qname("a..b"), Err(BufferError::EmptyLabel)));
+        assert!(matches!(validate_qname(".example.com"), Err(BufferError::EmptyLabel)));
+        assert!(matches!(validate_qname("example.com.."), Err(BufferError::EmptyLabel)));
+        assert!(matches!(validate_qname(&vec!["abcdefghi"; 26].join(".")), Err(BufferError::NameTooLong)));
+        assert!(matches!(validate_qname("exa mple.com"), Err(BufferError::InvalidCharacterInLabel)));
+
+        validate_qname("*.example.com").unwrap();
+        validate_qname("*").unwrap();
+        assert!(matches!(validate_qname("www.*.example.com"), Err(BufferError::InvalidCharacterInLabel)));
+        assert!(matches!(validate_qname("*www.example.com"), Err(BufferError::InvalidCharacterInLabel)));
+    }
+
+    #[test]
+    fn test_write_qname_name_too_long() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        // 26 labels of 9 bytes plus a length byte each, well within the label limit
+        let qname = vec!["abcdefghi"; 26].join(".");
+        assert!(matches!(buffer.write_qname(&qname), Err(BufferError::NameTooLong)));
+
+        // 25 of them encode to 251 bytes, which still fits
+        let qname = vec!["abcdefghi"; 25].join(".");
+        buffer.write_qname(&qname).unwrap();
+        assert_eq!(251, buffer.buffer.len());
+    }
+
     #[test]
     fn test_write_qname_invalid_characters() {
         let mut buffer = VectorPacketBuffer::new();
         
-        // Invalid DNS label containing underscores should panic or return an error
-        let result = buffer.write_qname("invalid_label_1.com");
+        // Characters outside of letters, digits, hyphens and underscores return an error
+        let result = buffer.write_qname("inv@lid.com");
         assert!(result.is_err(), "Expected error for invalid characters in label");
     }
+
+    #[test]
+    fn test_write_qname_underscore_labels() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        buffer.write_qname("_dmarc.example.com").unwrap();
+        buffer.write_qname("_sip._tcp.example.com").unwrap();
+
+        buffer.seek(0).unwrap();
+        let mut result = String::new();
+        buffer.read_qname(&mut result).unwrap();
+        assert_eq!("_dmarc.example.com", result);
+
+        let mut result = String::new();
+        buffer.read_qname(&mut result).unwrap();
+        assert_eq!("_sip._tcp.example.com", result);
+    }
     
     #[test]
     fn test_write_qname_multiple_repeated_labels_with_jump() {
@@ -718,6 +899,42 @@ mod tests {
         assert_eq!(result, "www.com");
     }
 
+    #[test]
+    fn test_read_qname_forward_pointer() {
+        let mut buffer = VectorPacketBuffer {
+            buffer: vec![
+                3, b'w', b'w', b'w', 0xC0, 0x08, // "www" followed by a pointer ahead of itself
+                0, 0,
+                3, b'c', b'o', b'm', 0,
+            ],
+            pos: 0,
+            label_lookup: BTreeMap::new(),
+        };
+        let mut result = String::new();
+
+        assert!(matches!(
+            buffer.read_qname(&mut result),
+            Err(BufferError::InvalidCompressionPointer)
+        ));
+    }
+
+    #[test]
+    fn test_read_qname_pointer_loop() {
+        let mut buffer = VectorPacketBuffer {
+            buffer: vec![
+                3, b'w', b'w', b'w', 0xC0, 0x00, // Points back to its own start
+            ],
+            pos: 0,
+            label_lookup: BTreeMap::new(),
+        };
+        let mut result = String::new();
+
+        assert!(matches!(
+            buffer.read_qname(&mut result),
+            Err(BufferError::InvalidCompressionPointer)
+        ));
+    }
+
     // Test case 5: Multiple labels
     #[test]
     fn test_read_qname_multiple_labels() {
@@ -734,4 +951,40 @@ mod tests {
         assert_eq!(result, "www.com.edu");
     }
 
+    #[test]
+    fn test_stream_read_qname_with_compression() {
+        let mut packet = VectorPacketBuffer::new();
+        packet.write_qname("a.google.com").unwrap();
+        // "b" followed by a pointer back to "google.com" in the first name
+        for b in [0x01, b'b', 0xC0, 0x02] {
+            packet.write_u8(b).unwrap();
+        }
+
+        let mut stream = std::io::Cursor::new(packet.buffer);
+        let mut buffer = StreamPacketBuffer::new(&mut stream);
+
+        let mut first = String::new();
+        buffer.read_qname(&mut first).unwrap();
+        assert_eq!("a.google.com", first);
+
+        let mut second = String::new();
+        buffer.read_qname(&mut second).unwrap();
+        assert_eq!("b.google.com", second);
+        assert_eq!(18, buffer.pos());
+    }
+
+    #[test]
+    fn test_stream_rejects_pointer_into_unread_data() {
+        // A pointer to offset 4, which hasn't been read from the stream yet
+        let mut stream = std::io::Cursor::new(vec![0xC0, 0x04, 0, 0, 3, b'c', b'o', b'm', 0]);
+        let mut buffer = StreamPacketBuffer::new(&mut stream);
+        let mut result = String::new();
+
+        assert!(matches!(
+            buffer.read_qname(&mut result),
+            Err(BufferError::InvalidCompressionPointer)
+        ));
+        // Nothing past the pointer was read on its behalf
+        assert_eq!(2, buffer.buffer.len());
+    }
 }
@@ -1,7 +1,7 @@
 use chrono::{DateTime, Duration, Local};
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use std::sync::Arc;
-use std::cmp::Ordering;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use tokio::sync::RwLock;
 use std::collections::BTreeMap;
 //use serde::{Deserialize, Serialize};
@@ -11,6 +11,7 @@ use std::hash::{Hash, Hasher};
 
 
 use crate::protocols::protocol::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use crate::server::shutdown::ShutdownSignal;
 
 #[derive(Debug, thiserror::Error)]
 pub enum CacheError {
@@ -20,6 +21,10 @@ pub enum CacheError {
     PoisonedLock,
 }
 
+/// Fraction of a record's TTL below which a cache hit triggers a refresh.
+pub const DEFAULT_PREFETCH_THRESHOLD: f64 = 0.1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CacheState {
     PositiveCache,
     NegativeCache,
@@ -50,6 +55,8 @@ pub enum RecordSet {
         qtype: QueryType,
         ttl: u32,
         timestamp: DateTime<Local>,
+        /// SOA of the zone which denied the name, served in the authority section.
+        soa: Option<DnsRecord>,
     },
     Records {
         qtype: QueryType,
@@ -57,42 +64,73 @@ pub enum RecordSet {
     },
 }
 
-#[derive(Clone, Debug)]
-pub struct DomainCache {
-    inner: DashMap<String, DomainEntry>,
-}
-
-#[derive(Clone, Debug)]
+/// The cached record sets of a single domain.
+///
+/// Everything is interiorly mutable, so entries can be updated while other
+/// `Arc` clones of them are held, for instance by callers of `list`.
+#[derive(Debug)]
 pub struct DomainEntry {
     pub domain: String,
     pub record_types: DashMap<QueryType, RecordSet>,
-    pub hits: u32,
-    pub updates: u32,
+    pub hits: AtomicU32,
+    pub updates: AtomicU32,
+}
+
+impl Clone for DomainEntry {
+    fn clone(&self) -> Self {
+        DomainEntry {
+            domain: self.domain.clone(),
+            record_types: self.record_types.clone(),
+            hits: AtomicU32::new(self.hits.load(Ordering::Acquire)),
+            updates: AtomicU32::new(self.updates.load(Ordering::Acquire)),
+        }
+    }
 }
 
 impl DomainEntry {
     pub fn new(domain: String) -> Self {
         DomainEntry {
-            domain: domain,
+            domain,
             record_types: DashMap::new(),
-            hits: 0,
-            updates: 0,
+            hits: AtomicU32::new(0),
+            updates: AtomicU32::new(0),
         }
     }
 
-    pub fn store_nxdomain(&mut self, qtype: QueryType, ttl: u32) {
-        self.updates += 1;
+    pub fn store_nxdomain(&self, qtype: QueryType, ttl: u32, soa: Option<&DnsRecord>) {
+        self.updates.fetch_add(1, Ordering::Release);
 
         let new_set = RecordSet::NoRecords {
             qtype,
             ttl,
             timestamp: Local::now(),
+            soa: soa.cloned(),
         };
         self.record_types.insert(qtype, new_set);
     }
 
-    pub fn store_record(&mut self, rec: &DnsRecord) {
-        self.updates += 1;
+    /// The SOA stored along with a negative entry of `qtype`, its TTL counted down like
+    /// the entry's and never above it.
+    pub fn negative_soa(&self, qtype: QueryType) -> Option<DnsRecord> {
+        match self.record_types.get(&qtype)?.value() {
+            RecordSet::NoRecords {
+                ttl,
+                timestamp,
+                soa: Some(ref soa),
+                ..
+            } => {
+                let remaining_ms = (*timestamp + Duration::seconds(*ttl as i64) - Local::now())
+                    .num_milliseconds()
+                    .max(0);
+                let remaining = ((remaining_ms + 999) / 1000) as u32;
+                Some(soa.with_ttl(remaining.min(soa.get_ttl())))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn store_record(&self, rec: &DnsRecord) {
+        self.updates.fetch_add(1, Ordering::Release);
 
         let entry = RecordEntry {
             record: rec.clone(),
@@ -103,7 +141,8 @@ impl DomainEntry {
             .entry(rec.get_querytype())
             .and_modify(|set| {
                 if let RecordSet::Records { records, .. } = set {
-                    records.insert(entry.clone());
+                    // Replace rather than insert so a re-resolved record gets a fresh timestamp
+                    records.replace(entry.clone());
                 }
             })
             .or_insert_with(|| RecordSet::Records {
@@ -116,7 +155,53 @@ impl DomainEntry {
             });
     }
 
+    /// Replaces the cached records of `qtype` with `records`.
+    pub fn store_rrset(&self, qtype: QueryType, records: &[DnsRecord]) {
+        self.updates.fetch_add(1, Ordering::Release);
+
+        let timestamp = Local::now();
+        let mut records: HashSet<RecordEntry> = records
+            .iter()
+            .map(|rec| RecordEntry {
+                record: rec.clone(),
+                timestamp,
+            })
+            .collect();
+
+        // The signatures over every type of the domain share a set, so only the ones
+        // over the types being stored are replaced
+        if qtype == QueryType::RRSIG {
+            let covered = records
+                .iter()
+                .filter_map(|entry| type_covered(&entry.record))
+                .collect::<HashSet<u16>>();
+            if let Some(RecordSet::Records { records: existing, .. }) =
+                self.record_types.get(&qtype).map(|set| set.value().clone())
+            {
+                records.extend(
+                    existing
+                        .into_iter()
+                        .filter(|entry| type_covered(&entry.record).is_some_and(|rrtype| !covered.contains(&rrtype))),
+                );
+            }
+        }
+
+        self.record_types.insert(qtype, RecordSet::Records { qtype, records });
+    }
+
     pub fn get_cache_state(&self, qtype: QueryType) -> CacheState {
+        // ANY is answered by whatever valid records we hold for the domain
+        if qtype == QueryType::ANY {
+            let has_records = self.record_types.iter().any(|set| match set.value() {
+                RecordSet::Records { records, .. } => records.iter().any(|entry| entry.is_valid()),
+                RecordSet::NoRecords { .. } => false,
+            });
+
+            if has_records {
+                return CacheState::PositiveCache;
+            }
+        }
+
         match self.record_types.get(&qtype) {
             Some(set) => match set.value() {
                 RecordSet::Records { records, .. } => {
@@ -138,15 +223,86 @@ impl DomainEntry {
         }
     }
 
-    pub fn fill_query_result(&self, qtype: QueryType, result_vec: &mut Vec<DnsRecord>) {
+    /// Drops the record sets with nothing left in them that's valid, returning true once none remain.
+    pub fn remove_expired(&self) -> bool {
+        let now = Local::now();
+        self.record_types.retain(|_, set| match set {
+            RecordSet::Records { records, .. } => records.iter().any(|entry| entry.is_valid()),
+            RecordSet::NoRecords { ttl, timestamp, .. } => *timestamp + Duration::seconds(*ttl as i64) > now,
+        });
+
+        self.record_types.is_empty()
+    }
+
+    /// Whether any valid record of `qtype` has less than `threshold` of its TTL left.
+    pub fn needs_prefetch(&self, qtype: QueryType, threshold: f64) -> bool {
+        match self.record_types.get(&qtype) {
+            Some(set) => match set.value() {
+                RecordSet::Records { records, .. } => records.iter().any(|entry| {
+                    let ttl_ms = entry.record.get_ttl() as f64 * 1000.0;
+                    entry.is_valid() && (entry.remaining_ttl().num_milliseconds() as f64) < ttl_ms * threshold
+                }),
+                RecordSet::NoRecords { .. } => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Adds the valid records of `qtype` to `result_vec`, their TTLs counted down when `decrement_ttl` is set.
+    pub fn fill_query_result(&self, qtype: QueryType, result_vec: &mut Vec<DnsRecord>, decrement_ttl: bool) {
+        let serve = |entry: &RecordEntry| {
+            if decrement_ttl {
+                entry.served_record()
+            } else {
+                entry.record.clone()
+            }
+        };
+
+        if qtype == QueryType::ANY {
+            for set in self.record_types.iter() {
+                if let RecordSet::Records { records, .. } = set.value() {
+                    result_vec.extend(
+                        records
+                            .iter()
+                            .filter(|entry| entry.is_valid())
+                            .map(serve),
+                    );
+                }
+            }
+            return;
+        }
+
         if let Some(RecordSet::Records { records, .. }) = self.record_types.get(&qtype).map(|v| v.value().clone()) {
+            let start = result_vec.len();
             result_vec.extend(
                 records
                     .iter()
                     .filter(|entry| entry.is_valid())
-                    .map(|entry| entry.record.clone()),
+                    .map(serve),
             );
+
+            if qtype == QueryType::A {
+                self.rotate_records(&mut result_vec[start..]);
+            }
+        }
+    }
+
+    /// Orders records round robin, shifting the first one served each time the domain is hit.
+    fn rotate_records(&self, records: &mut [DnsRecord]) {
+        if records.is_empty() {
+            return;
         }
+
+        // The set has no order of its own, so sort first to rotate from a stable base
+        records.sort();
+        records.rotate_left(self.hits.load(Ordering::Acquire) as usize % records.len());
+    }
+}
+
+fn type_covered(record: &DnsRecord) -> Option<u16> {
+    match *record {
+        DnsRecord::RRSIG { type_covered, .. } => Some(type_covered),
+        _ => None,
     }
 }
 
@@ -154,30 +310,57 @@ impl RecordEntry {
     pub fn is_valid(&self) -> bool {
         self.timestamp + Duration::seconds(self.record.get_ttl() as i64) > Local::now()
     }
+
+    /// Time left until the record expires, negative once it has.
+    pub fn remaining_ttl(&self) -> Duration {
+        self.timestamp + Duration::seconds(self.record.get_ttl() as i64) - Local::now()
+    }
+
+    /// The record as served to clients, its TTL counted down by the time spent in the cache.
+    pub fn served_record(&self) -> DnsRecord {
+        // Rounded up so a record looked up right after being stored keeps its full TTL
+        let remaining_ms = self.remaining_ttl().num_milliseconds().max(0);
+        self.record.with_ttl(((remaining_ms + 999) / 1000) as u32)
+    }
 }
 
 
-#[derive(Default)]
 pub struct Cache {
     domain_entries: BTreeMap<String, Arc<DomainEntry>>,
+    decrement_ttl: bool,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new()
+    }
 }
 
 impl Cache {
     pub fn new() -> Self {
         Cache {
             domain_entries: BTreeMap::new(),
+            decrement_ttl: true,
         }
     }
 
-    fn get_or_create_entry(&mut self, qname: &str) -> &mut Arc<DomainEntry> {
+    /// Sets whether served records carry the TTL they have left, rather than the one they were stored with.
+    pub fn set_ttl_decrement(&mut self, enabled: bool) {
+        self.decrement_ttl = enabled;
+    }
+
+    // Names are keyed in lowercase, so lookups match whatever case they're made in
+
+    fn get_or_create_entry(&mut self, qname: &str) -> &Arc<DomainEntry> {
+        let qname = qname.to_lowercase();
         self.domain_entries
-            .entry(qname.to_string())
-            .or_insert_with(|| Arc::new(DomainEntry::new(qname.to_string())))
+            .entry(qname.clone())
+            .or_insert_with(|| Arc::new(DomainEntry::new(qname)))
     }
 
     fn get_cache_state(&self, qname: &str, qtype: QueryType) -> CacheState {
         self.domain_entries
-            .get(qname)
+            .get(&qname.to_lowercase())
             .map(|entry| entry.get_cache_state(qtype))
             .unwrap_or(CacheState::NotCached)
     }
@@ -189,79 +372,369 @@ impl Cache {
         result_vec: &mut Vec<DnsRecord>,
         increment_stats: bool,
     ) {
-        if let Some(domain_entry) = self.domain_entries.get(qname) {
+        if let Some(domain_entry) = self.domain_entries.get(&qname.to_lowercase()) {
+            domain_entry.fill_query_result(qtype, result_vec, self.decrement_ttl);
+
+            // Counted after filling so the first answer served isn't rotated
             if increment_stats {
-                domain_entry.hits += 1;
+                domain_entry.hits.fetch_add(1, Ordering::Release);
             }
-
-            domain_entry.fill_query_result(qtype, result_vec);
         }
     }
 
     pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
-        match self.get_cache_state(qname, qtype) {
+        self.lookup_with_state(qname, qtype).1
+    }
+
+    /// Looks up `qname`, telling a positive hit, a cached NXDOMAIN and a miss apart.
+    pub fn lookup_with_state(&self, qname: &str, qtype: QueryType) -> (CacheState, Option<DnsPacket>) {
+        let state = self.get_cache_state(qname, qtype);
+        let result = match state {
             CacheState::PositiveCache => {
                 let mut qr = DnsPacket::new();
                 self.fill_queryresult(qname, qtype, &mut qr.answers, true);
-                self.fill_queryresult(qname, QueryType::NS, &mut qr.authorities, false);
+                if qtype != QueryType::ANY {
+                    self.fill_signatures(qname, qtype, &mut qr.answers);
+                    self.fill_authority(qname, &mut qr);
+                }
                 Some(qr)
             }
             CacheState::NegativeCache => {
                 let mut qr = DnsPacket::new();
                 qr.header.rescode = ResultCode::NXDOMAIN;
+                // Clients need the SOA to cache the denial themselves, RFC 2308
+                if let Some(soa) = self
+                    .domain_entries
+                    .get(&qname.to_lowercase())
+                    .and_then(|entry| entry.negative_soa(qtype))
+                {
+                    qr.authorities.push(soa);
+                }
                 Some(qr)
             }
             CacheState::NotCached => None,
+        };
+
+        (state, result)
+    }
+
+    /// Adds the cached RRSIGs over the `qtype` records of `qname`, so answers served
+    /// from the cache can still be validated.
+    fn fill_signatures(&self, qname: &str, qtype: QueryType, result_vec: &mut Vec<DnsRecord>) {
+        if qtype == QueryType::RRSIG {
+            return;
         }
+
+        let mut signatures = Vec::new();
+        self.fill_queryresult(qname, QueryType::RRSIG, &mut signatures, false);
+        result_vec.extend(
+            signatures
+                .into_iter()
+                .filter(|rec| type_covered(rec) == Some(qtype.to_num())),
+        );
     }
 
+    /// Adds the NS records of the closest enclosing name we hold them for, with any
+    /// cached addresses of those name servers as glue.
+    fn fill_authority(&self, qname: &str, qr: &mut DnsPacket) {
+        let mut zone = qname.trim_end_matches('.');
+        loop {
+            self.fill_queryresult(zone, QueryType::NS, &mut qr.authorities, false);
+            if !qr.authorities.is_empty() {
+                break;
+            }
+
+            match zone.split_once('.') {
+                Some((_, parent)) => zone = parent,
+                None => return,
+            }
+        }
+
+        let hosts = qr
+            .authorities
+            .iter()
+            .filter_map(|rec| match *rec {
+                DnsRecord::NS { ref host, .. } => Some(host.clone()),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+
+        for host in hosts {
+            self.fill_queryresult(&host, QueryType::A, &mut qr.resources, false);
+            self.fill_queryresult(&host, QueryType::AAAA, &mut qr.resources, false);
+        }
+    }
+
+    fn needs_prefetch(&self, qname: &str, qtype: QueryType, threshold: f64) -> bool {
+        self.domain_entries
+            .get(&qname.to_lowercase())
+            .map(|entry| entry.needs_prefetch(qtype, threshold))
+            .unwrap_or(false)
+    }
+
+    /// Stores `records`, each RRset in them replacing whatever was cached for its name and type.
     pub fn store(&mut self, records: &[DnsRecord]) {
+        let mut rrsets: Vec<(String, QueryType, Vec<DnsRecord>)> = Vec::new();
         for record in records {
-            if let Some(domain) = record.get_domain() {
-                let entry = self.get_or_create_entry(&domain);
-                Arc::get_mut(entry).unwrap().store_record(record);
+            let domain = match record.get_domain() {
+                Some(domain) => domain.to_lowercase(),
+                None => continue,
+            };
+            let qtype = record.get_querytype();
+
+            match rrsets.iter_mut().find(|(d, t, _)| *d == domain && *t == qtype) {
+                Some((_, _, set)) => set.push(record.clone()),
+                None => rrsets.push((domain, qtype, vec![record.clone()])),
             }
         }
+
+        for (domain, qtype, set) in rrsets {
+            self.get_or_create_entry(&domain).store_rrset(qtype, &set);
+        }
+    }
+
+    /// Remembers that `qname` has no records of `qtype` for `ttl` seconds, along with the
+    /// SOA of the zone saying so, if the denial carried one.
+    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32, soa: Option<&DnsRecord>) {
+        self.get_or_create_entry(qname).store_nxdomain(qtype, ttl, soa);
+    }
+
+    /// Removes expired record sets, and the domains left without any, returning how many domains went.
+    pub fn reap_expired(&mut self) -> usize {
+        let before = self.domain_entries.len();
+        self.domain_entries.retain(|_, entry| !entry.remove_expired());
+        before - self.domain_entries.len()
     }
 
-    pub fn store_nxdomain(&mut self, qname: &str, qtype: QueryType, ttl: u32) {
-        let entry = self.get_or_create_entry(qname);
-        Arc::get_mut(entry).unwrap().store_nxdomain(qtype, ttl);
+    /// Number of domains held in the cache
+    pub fn len(&self) -> usize {
+        self.domain_entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domain_entries.is_empty()
     }
 }
 
-#[derive(Default)]
+/// Point-in-time counters describing cache usage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
 pub struct SynchronizedCache {
     cache: RwLock<Cache>,
+    entries: AtomicUsize,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    prefetch_threshold: f64,
+    prefetching: DashSet<(String, QueryType)>,
+}
+
+impl Default for SynchronizedCache {
+    fn default() -> Self {
+        SynchronizedCache::new()
+    }
 }
 
 impl SynchronizedCache {
     pub fn new() -> Self {
         SynchronizedCache {
             cache: RwLock::new(Cache::new()),
+            entries: AtomicUsize::new(0),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            prefetch_threshold: DEFAULT_PREFETCH_THRESHOLD,
+            prefetching: DashSet::new(),
+        }
+    }
+
+    /// Sets the fraction of a record's TTL below which hits trigger a refresh, 0 disables prefetching.
+    pub fn set_prefetch_threshold(&mut self, threshold: f64) {
+        self.prefetch_threshold = threshold;
+    }
+
+    pub fn prefetch_threshold(&self) -> f64 {
+        self.prefetch_threshold
+    }
+
+    /// Sets whether served records carry the TTL they have left, on by default.
+    pub fn set_ttl_decrement(&mut self, enabled: bool) {
+        self.cache.get_mut().set_ttl_decrement(enabled);
+    }
+
+    /// Claims the refresh of `qname` and `qtype` if its records are close to expiring.
+    ///
+    /// Returns false if they are still fresh or a refresh is already running. A caller
+    /// which gets true must call `finish_prefetch` once the refresh is done.
+    pub async fn start_prefetch(&self, qname: &str, qtype: QueryType) -> bool {
+        if self.prefetch_threshold <= 0.0 {
+            return false;
+        }
+
+        let due = self.cache.read().await.needs_prefetch(qname, qtype, self.prefetch_threshold);
+        due && self.prefetching.insert((qname.to_string(), qtype))
+    }
+
+    pub fn finish_prefetch(&self, qname: &str, qtype: QueryType) {
+        self.prefetching.remove(&(qname.to_string(), qtype));
+    }
+
+    /// Returns the current entry count along with the lookup hit and miss totals.
+    ///
+    /// The counters are kept outside the lock so reading them never waits on writers.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.load(Ordering::Acquire),
+            hits: self.hits.load(Ordering::Acquire),
+            misses: self.misses.load(Ordering::Acquire),
+        }
+    }
+
+    fn record_lookup(&self, found: bool) {
+        if found {
+            self.hits.fetch_add(1, Ordering::Release);
+        } else {
+            self.misses.fetch_add(1, Ordering::Release);
         }
     }
 
+    /// Lists the cached domains.
+    ///
+    /// The synchronous methods block the calling thread while waiting for the lock,
+    /// so they must not be used from async code; use the `_async` variants there.
     pub fn list(&self) -> Result<Vec<Arc<DomainEntry>>, CacheError> {
-        let cache = self.cache.read().map_err(|_| CacheError::PoisonedLock)?;
+        let cache = self.cache.blocking_read();
 
         Ok(cache.domain_entries.values().cloned().collect())
     }
 
     pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
-        let cache = self.cache.read().ok()?;
-        cache.lookup(qname, qtype)
+        let cache = self.cache.blocking_read();
+        let result = cache.lookup(qname, qtype);
+        self.record_lookup(result.is_some());
+        result
+    }
+
+    pub fn lookup_with_state(&self, qname: &str, qtype: QueryType) -> (CacheState, Option<DnsPacket>) {
+        let cache = self.cache.blocking_read();
+        let result = cache.lookup_with_state(qname, qtype);
+        self.record_lookup(result.1.is_some());
+        result
     }
 
     pub fn store(&self, records: &[DnsRecord]) -> Result<(), CacheError> {
-        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
+        let mut cache = self.cache.blocking_write();
         cache.store(records);
+        self.entries.store(cache.len(), Ordering::Release);
         Ok(())
     }
 
-    pub fn store_nxdomain(&self, qname: &str, qtype: QueryType, ttl: u32) -> Result<(), CacheError> {
-        let mut cache = self.cache.write().map_err(|_| CacheError::PoisonedLock)?;
-        cache.store_nxdomain(qname, qtype, ttl);
+    /// Stores several sections of a response, taking the write lock just once.
+    pub fn store_all(&self, sections: &[&[DnsRecord]]) -> Result<(), CacheError> {
+        let mut cache = self.cache.blocking_write();
+        for records in sections {
+            cache.store(records);
+        }
+        self.entries.store(cache.len(), Ordering::Release);
+        Ok(())
+    }
+
+    pub fn store_nxdomain(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        ttl: u32,
+        soa: Option<&DnsRecord>,
+    ) -> Result<(), CacheError> {
+        let mut cache = self.cache.blocking_write();
+        cache.store_nxdomain(qname, qtype, ttl, soa);
+        self.entries.store(cache.len(), Ordering::Release);
+        Ok(())
+    }
+
+    /// Removes expired record sets and the domains left empty, returning how many domains went.
+    pub fn reap_expired(&self) -> usize {
+        let mut cache = self.cache.blocking_write();
+        let removed = cache.reap_expired();
+        self.entries.store(cache.len(), Ordering::Release);
+        removed
+    }
+
+    pub async fn list_async(&self) -> Result<Vec<Arc<DomainEntry>>, CacheError> {
+        let cache = self.cache.read().await;
+
+        Ok(cache.domain_entries.values().cloned().collect())
+    }
+
+    pub async fn lookup_async(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let cache = self.cache.read().await;
+        let result = cache.lookup(qname, qtype);
+        self.record_lookup(result.is_some());
+        result
+    }
+
+    pub async fn lookup_with_state_async(&self, qname: &str, qtype: QueryType) -> (CacheState, Option<DnsPacket>) {
+        let cache = self.cache.read().await;
+        let result = cache.lookup_with_state(qname, qtype);
+        self.record_lookup(result.1.is_some());
+        result
+    }
+
+    pub async fn store_async(&self, records: &[DnsRecord]) -> Result<(), CacheError> {
+        let mut cache = self.cache.write().await;
+        cache.store(records);
+        self.entries.store(cache.len(), Ordering::Release);
+        Ok(())
+    }
+
+    pub async fn store_all_async(&self, sections: &[&[DnsRecord]]) -> Result<(), CacheError> {
+        let mut cache = self.cache.write().await;
+        for records in sections {
+            cache.store(records);
+        }
+        self.entries.store(cache.len(), Ordering::Release);
+        Ok(())
+    }
+
+    pub async fn reap_expired_async(&self) -> usize {
+        let mut cache = self.cache.write().await;
+        let removed = cache.reap_expired();
+        self.entries.store(cache.len(), Ordering::Release);
+        removed
+    }
+
+    /// Reaps expired entries every `period` until `signal` fires.
+    ///
+    /// Expired records are otherwise only skipped over by lookups, so names that
+    /// are never queried again would hold on to their memory.
+    pub async fn run_reaper(&self, period: std::time::Duration, mut signal: ShutdownSignal) {
+        let mut interval = tokio::time::interval(period);
+        // The first tick completes straight away
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = signal.recv() => return,
+                _ = interval.tick() => {
+                    self.reap_expired_async().await;
+                }
+            }
+        }
+    }
+
+    pub async fn store_nx_domain_async(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        ttl: u32,
+        soa: Option<&DnsRecord>,
+    ) -> Result<(), CacheError> {
+        let mut cache = self.cache.write().await;
+        cache.store_nxdomain(qname, qtype, ttl, soa);
+        self.entries.store(cache.len(), Ordering::Release);
         Ok(())
     }
 }
@@ -271,6 +744,23 @@ mod tests {
     use super::*;
     use crate::protocols::protocol::{DnsRecord, QueryType, ResultCode, TransientTtl};
 
+    #[test]
+    fn test_domain_entry_keeps_its_name() {
+        let mut cache = Cache::new();
+
+        cache.store(&[DnsRecord::A {
+            domain: "named.example.com".to_string(),
+            addr: "192.168.0.1".parse().unwrap(),
+            ttl: TransientTtl(300),
+        }]);
+
+        let entry = cache.get_or_create_entry("named.example.com");
+        assert_eq!("named.example.com", entry.domain);
+        assert_eq!(1, entry.updates.load(Ordering::Acquire));
+
+        assert_eq!("other.example.com", DomainEntry::new("other.example.com".to_string()).domain);
+    }
+
     #[test]
     fn test_store_and_retrieve_multiple_records() {
         let mut cache = Cache::new();
@@ -299,6 +789,165 @@ mod tests {
         }
     } 
 
+    #[test]
+    fn test_answer_carries_ns_authority_and_glue() {
+        let mut cache = Cache::new();
+
+        cache.store(&[
+            DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "192.168.0.1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            },
+            DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns1.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::A {
+                domain: "ns1.example.com".to_string(),
+                addr: "192.168.0.53".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            },
+        ]);
+
+        let packet = cache.lookup("www.example.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(
+            vec![DnsRecord::NS {
+                domain: "example.com".to_string(),
+                host: "ns1.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            }],
+            packet.authorities
+        );
+        assert_eq!(Some("192.168.0.53".to_string()), packet.get_resolved_ns("www.example.com"));
+    }
+
+    #[test]
+    fn test_answers_carry_their_signatures() {
+        let mut cache = Cache::new();
+
+        let rrsig = |type_covered: u16| DnsRecord::RRSIG {
+            domain: "www.example.com".to_string(),
+            type_covered,
+            algorithm: 13,
+            labels: 3,
+            original_ttl: 300,
+            expiration: 1893456000,
+            inception: 1577836800,
+            key_tag: 12345,
+            signer_name: "example.com".to_string(),
+            signature: vec![type_covered as u8; 64],
+            ttl: TransientTtl(300),
+        };
+
+        cache.store(&[
+            DnsRecord::A {
+                domain: "www.example.com".to_string(),
+                addr: "192.168.0.1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            },
+            rrsig(1),
+        ]);
+        // Signatures over another type are stored alongside, not in place of, the first
+        cache.store(&[
+            DnsRecord::AAAA {
+                domain: "www.example.com".to_string(),
+                addr: "2001:db8::1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            },
+            rrsig(28),
+        ]);
+
+        let packet = cache.lookup("www.example.com", QueryType::A).unwrap();
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(rrsig(1), packet.answers[1]);
+
+        let packet = cache.lookup("www.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(2, packet.answers.len());
+        assert_eq!(rrsig(28), packet.answers[1]);
+    }
+
+    #[test]
+    fn test_a_records_rotate_round_robin() {
+        let mut cache = Cache::new();
+
+        let records = (1..=3)
+            .map(|i| DnsRecord::A {
+                domain: "rr.example.com".to_string(),
+                addr: format!("192.168.0.{}", i).parse().unwrap(),
+                ttl: TransientTtl(300),
+            })
+            .collect::<Vec<DnsRecord>>();
+
+        cache.store(&records);
+
+        for i in 0..6 {
+            let packet = cache.lookup("rr.example.com", QueryType::A).unwrap();
+
+            let mut expected = records.clone();
+            expected.rotate_left(i % 3);
+            assert_eq!(expected, packet.answers);
+        }
+    }
+
+    #[test]
+    fn test_multi_chunk_txt_round_trip() {
+        let mut cache = Cache::new();
+
+        // Long enough to be written as two character-strings
+        let record = DnsRecord::TXT {
+            domain: "txt.example.com".to_string(),
+            data: "x".repeat(300),
+            ttl: TransientTtl(300),
+        };
+
+        // Storing the same record twice must not produce a second entry
+        cache.store(&[record.clone(), record.clone()]);
+
+        if let Some(packet) = cache.lookup("txt.example.com", QueryType::TXT) {
+            assert_eq!(vec![record], packet.answers);
+        } else {
+            panic!("Expected TXT record for txt.example.com");
+        }
+    }
+
+    #[test]
+    fn test_any_lookup_returns_all_types() {
+        let mut cache = Cache::new();
+
+        let records = vec![
+            DnsRecord::A {
+                domain: "any.example.com".to_string(),
+                addr: "192.168.0.1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            },
+            DnsRecord::AAAA {
+                domain: "any.example.com".to_string(),
+                addr: "::1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            },
+            DnsRecord::MX {
+                domain: "any.example.com".to_string(),
+                priority: 10,
+                host: "mail.example.com".to_string(),
+                ttl: TransientTtl(300),
+            },
+        ];
+
+        cache.store(&records);
+
+        if let Some(packet) = cache.lookup("any.example.com", QueryType::ANY) {
+            assert_eq!(3, packet.answers.len());
+            for record in &records {
+                assert!(packet.answers.contains(record));
+            }
+        } else {
+            panic!("Expected ANY answer for any.example.com");
+        }
+    }
+
     #[test]
     fn test_case_insensitive_lookup() {
         let mut cache = Cache::new();
@@ -338,6 +987,43 @@ mod tests {
         assert!(cache.lookup("ttl-test.com", QueryType::A).is_none());
     }
 
+    #[test]
+    fn test_served_ttl_counts_down() {
+        let mut cache = Cache::new();
+
+        cache.store(&[DnsRecord::A {
+            domain: "countdown.example.com".to_string(),
+            addr: "192.168.0.1".parse().unwrap(),
+            ttl: TransientTtl(100),
+        }]);
+
+        let age = |cache: &Cache, seconds: i64| {
+            let entry = cache.domain_entries.get("countdown.example.com").unwrap();
+            let mut set = entry.record_types.get_mut(&QueryType::A).unwrap();
+            if let RecordSet::Records { records, .. } = set.value_mut() {
+                *records = records
+                    .drain()
+                    .map(|mut record| {
+                        record.timestamp -= Duration::seconds(seconds);
+                        record
+                    })
+                    .collect();
+            }
+        };
+
+        age(&cache, 30);
+        let packet = cache.lookup("countdown.example.com", QueryType::A).unwrap();
+        assert_eq!(70, packet.answers[0].get_ttl());
+
+        cache.set_ttl_decrement(false);
+        let packet = cache.lookup("countdown.example.com", QueryType::A).unwrap();
+        assert_eq!(100, packet.answers[0].get_ttl());
+
+        // Nothing is served once the TTL has run out
+        age(&cache, 70);
+        assert!(cache.lookup("countdown.example.com", QueryType::A).is_none());
+    }
+
     #[test]
     fn test_concurrent_access() {
         use std::sync::Arc;
@@ -361,11 +1047,188 @@ mod tests {
         assert!(sync_cache.lookup("concurrent-test.com", QueryType::A).is_some());
     }
 
+    #[tokio::test]
+    async fn test_async_store_and_lookup() {
+        let sync_cache = SynchronizedCache::new();
+
+        let record = DnsRecord::A {
+            domain: "async-test.com".to_string(),
+            addr: "192.168.0.1".parse().unwrap(),
+            ttl: TransientTtl(300),
+        };
+
+        sync_cache.store_async(std::slice::from_ref(&record)).await.unwrap();
+        sync_cache.store_nx_domain_async("missing-async-test.com", QueryType::A, 300, None).await.unwrap();
+
+        let packet = sync_cache.lookup_async("async-test.com", QueryType::A).await.unwrap();
+        assert_eq!(vec![record], packet.answers);
+
+        let packet = sync_cache.lookup_async("missing-async-test.com", QueryType::A).await.unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+
+        assert_eq!(2, sync_cache.list_async().await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_with_state() {
+        let sync_cache = SynchronizedCache::new();
+
+        sync_cache
+            .store_async(&[DnsRecord::A {
+                domain: "state-test.com".to_string(),
+                addr: "192.168.0.1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            }])
+            .await
+            .unwrap();
+        sync_cache.store_nx_domain_async("missing-state-test.com", QueryType::A, 300, None).await.unwrap();
+
+        let (state, packet) = sync_cache.lookup_with_state_async("state-test.com", QueryType::A).await;
+        assert_eq!(CacheState::PositiveCache, state);
+        assert_eq!(1, packet.unwrap().answers.len());
+
+        let (state, packet) = sync_cache.lookup_with_state_async("missing-state-test.com", QueryType::A).await;
+        assert_eq!(CacheState::NegativeCache, state);
+        assert_eq!(ResultCode::NXDOMAIN, packet.unwrap().header.rescode);
+
+        let (state, packet) = sync_cache.lookup_with_state_async("unknown-state-test.com", QueryType::A).await;
+        assert_eq!(CacheState::NotCached, state);
+        assert!(packet.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_all_stores_every_section() {
+        let sync_cache = SynchronizedCache::new();
+
+        let answers = [DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: "192.168.0.1".parse().unwrap(),
+            ttl: TransientTtl(300),
+        }];
+        let authorities = [DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(300),
+        }];
+        let resources = [DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: "192.168.0.53".parse().unwrap(),
+            ttl: TransientTtl(300),
+        }];
+
+        sync_cache
+            .store_all_async(&[&answers, &authorities, &resources])
+            .await
+            .unwrap();
+
+        assert_eq!(3, sync_cache.stats().entries);
+        assert!(sync_cache.lookup_async("www.example.com", QueryType::A).await.is_some());
+        assert!(sync_cache.lookup_async("example.com", QueryType::NS).await.is_some());
+        assert!(sync_cache.lookup_async("ns1.example.com", QueryType::A).await.is_some());
+    }
+
+    #[test]
+    fn test_reap_expired() {
+        let sync_cache = SynchronizedCache::new();
+
+        sync_cache
+            .store(&[
+                DnsRecord::A {
+                    domain: "short.example.com".to_string(),
+                    addr: "192.168.0.1".parse().unwrap(),
+                    ttl: TransientTtl(1),
+                },
+                DnsRecord::A {
+                    domain: "long.example.com".to_string(),
+                    addr: "192.168.0.2".parse().unwrap(),
+                    ttl: TransientTtl(300),
+                },
+            ])
+            .unwrap();
+        sync_cache.store_nxdomain("missing.example.com", QueryType::A, 1, None).unwrap();
+        assert_eq!(3, sync_cache.stats().entries);
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        assert_eq!(2, sync_cache.reap_expired());
+        assert_eq!(1, sync_cache.stats().entries);
+        assert!(sync_cache.lookup("long.example.com", QueryType::A).is_some());
+    }
+
+    #[test]
+    fn test_store_while_listed_entries_are_held() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let sync_cache = Arc::new(SynchronizedCache::new());
+
+        sync_cache
+            .store(&[DnsRecord::A {
+                domain: "held.example.com".to_string(),
+                addr: "192.168.0.1".parse().unwrap(),
+                ttl: TransientTtl(300),
+            }])
+            .unwrap();
+
+        // Keep clones of the entries alive while another thread stores into them
+        let listed = sync_cache.list().unwrap();
+
+        let sync_cache_clone = Arc::clone(&sync_cache);
+        let handle = thread::spawn(move || {
+            sync_cache_clone
+                .store(&[DnsRecord::A {
+                    domain: "held.example.com".to_string(),
+                    addr: "192.168.0.2".parse().unwrap(),
+                    ttl: TransientTtl(300),
+                }])
+                .unwrap();
+            sync_cache_clone.store_nxdomain("held.example.com", QueryType::AAAA, 300, None).unwrap();
+        });
+
+        handle.join().unwrap();
+
+        assert_eq!(1, listed.len());
+        assert_eq!(3, listed[0].updates.load(Ordering::Acquire));
+
+        // The second store replaced the A record set the listed entry held
+        let packet = sync_cache.lookup("held.example.com", QueryType::A).unwrap();
+        assert_eq!(1, packet.answers.len());
+        assert_eq!(Some("192.168.0.2".parse().unwrap()), packet.answers[0].get_address());
+    }
+
+    #[test]
+    fn test_nxdomain_carries_soa() {
+        let mut cache = Cache::new();
+
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 2024010101,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: TransientTtl(3600),
+        };
+        cache.store_nxdomain("missing.example.com", QueryType::A, 300, Some(&soa));
+
+        let packet = cache.lookup("missing.example.com", QueryType::A).unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, packet.header.rescode);
+        assert_eq!(vec![soa], packet.authorities);
+        // Served no longer than the negative entry itself lasts
+        assert_eq!(300, packet.authorities[0].get_ttl());
+
+        cache.store_nxdomain("other.example.com", QueryType::A, 300, None);
+        let packet = cache.lookup("other.example.com", QueryType::A).unwrap();
+        assert!(packet.authorities.is_empty());
+    }
+
     #[test]
     fn test_negative_ttl_expiry() {
         let mut cache = Cache::new();
 
-        cache.store_nxdomain("negative-ttl.com", QueryType::A, 1); // 1 second TTL
+        cache.store_nxdomain("negative-ttl.com", QueryType::A, 1, None); // 1 second TTL
 
         // Verify the negative cache entry exists initially
         if let Some(packet) = cache.lookup("negative-ttl.com", QueryType::A) {
@@ -420,10 +1283,9 @@ mod tests {
      }
 
     #[test]
-    fn test_cache_eviction_policy() {
+    fn test_cache_keeps_every_entry() {
         let mut cache = Cache::new();
 
-        // Simulate cache size of 2 for this test
         let records = vec![
             DnsRecord::A {
                 domain: "domain1.com".to_string(),
@@ -439,15 +1301,15 @@ mod tests {
 
         cache.store(&records);
 
-        // Add another record, simulating cache eviction of the oldest entry
         cache.store(&[DnsRecord::A {
             domain: "domain3.com".to_string(),
             addr: "192.168.0.3".parse().unwrap(),
             ttl: TransientTtl(300),
         }]);
 
-        // Verify eviction
-        assert!(cache.lookup("domain1.com", QueryType::A).is_none());
+        // The cache is unbounded, entries only go once they expire
+        assert_eq!(3, cache.len());
+        assert!(cache.lookup("domain1.com", QueryType::A).is_some());
         assert!(cache.lookup("domain2.com", QueryType::A).is_some());
         assert!(cache.lookup("domain3.com", QueryType::A).is_some());
     }
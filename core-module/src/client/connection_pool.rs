@@ -0,0 +1,123 @@
+//! Keeps idle TCP connections to upstream servers around for reuse
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+/// Idle connections kept per server by default.
+pub const DEFAULT_MAX_IDLE_PER_SERVER: usize = 4;
+
+/// How long a connection may sit idle before it's closed rather than reused.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct IdleConnection {
+    stream: TcpStream,
+    since: Instant,
+}
+
+/// Idle connections keyed by the `(host, port)` they're connected to.
+///
+/// Connections are taken out for the length of a query and handed back once it
+/// succeeds, so one that failed is never reused.
+pub struct ConnectionPool {
+    idle: Mutex<HashMap<(String, u16), Vec<IdleConnection>>>,
+    /// Most idle connections kept for a single server, 0 disables pooling.
+    pub max_idle_per_server: usize,
+    pub idle_timeout: Duration,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        ConnectionPool::new(DEFAULT_MAX_IDLE_PER_SERVER, DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl fmt::Debug for ConnectionPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionPool")
+            .field("idle", &self.idle_count())
+            .field("max_idle_per_server", &self.max_idle_per_server)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_server: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle_per_server,
+            idle_timeout,
+        }
+    }
+
+    /// Takes the most recently used idle connection to `server`, closing any that timed out.
+    pub fn take(&self, server: (&str, u16)) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().ok()?;
+        let connections = idle.get_mut(&(server.0.to_string(), server.1))?;
+
+        connections.retain(|conn| conn.since.elapsed() < self.idle_timeout);
+        connections.pop().map(|conn| conn.stream)
+    }
+
+    /// Hands a connection back once a query over it has completed.
+    ///
+    /// It's closed instead if `server` already has as many idle connections as allowed.
+    pub fn release(&self, server: (&str, u16), stream: TcpStream) {
+        let mut idle = match self.idle.lock() {
+            Ok(idle) => idle,
+            Err(_) => return,
+        };
+        let connections = idle.entry((server.0.to_string(), server.1)).or_default();
+
+        connections.retain(|conn| conn.since.elapsed() < self.idle_timeout);
+        if connections.len() < self.max_idle_per_server {
+            connections.push(IdleConnection {
+                stream,
+                since: Instant::now(),
+            });
+        }
+    }
+
+    /// Number of idle connections held across all servers.
+    pub fn idle_count(&self) -> usize {
+        self.idle
+            .lock()
+            .map(|idle| idle.values().map(|connections| connections.len()).sum())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_idle_connections_are_capped_and_expire() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.push(stream);
+            }
+        });
+
+        let pool = ConnectionPool::new(1, Duration::from_millis(50));
+        for _ in 0..2 {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            pool.release(("127.0.0.1", port), stream);
+        }
+        assert_eq!(1, pool.idle_count());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pool.take(("127.0.0.1", port)).is_none());
+        assert_eq!(0, pool.idle_count());
+    }
+}
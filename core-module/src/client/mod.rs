@@ -1 +1,4 @@
+pub mod connection_pool;
 pub mod network_client;
+#[cfg(feature = "tls")]
+pub mod tls;
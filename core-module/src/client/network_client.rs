@@ -1,70 +1,304 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::time::{self, Duration};
-use tokio::io::AsyncWriteExt;
-use tracing::{instrument};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{instrument, warn};
+use async_trait::async_trait;
+use hyper::{header, Body, Method, Request};
 
-use chrono::{DateTime, Local};
 use derive_more::{Display, Error, From};
 
-use crate::buffer::buffer::{BytePacketBuffer, PacketBuffer, StreamPacketBuffer};
-use crate::network_utilities::netutil::{read_packet_length, write_packet_length};
-use crate::protocols::protocol::{DnsPacket, DnsQuestion, QueryType};
+use crate::buffer::buffer::{validate_qname, BufferError, BytePacketBuffer, PacketBuffer, VectorPacketBuffer};
+use crate::client::connection_pool::ConnectionPool;
+use crate::network_utilities::netutil::{format_address, is_ipv6_literal, read_packet_length, write_packet_length};
+use crate::server::shutdown::ShutdownSignal;
+use crate::protocols::protocol::{
+    DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, TransientTtl, DEFAULT_UDP_PAYLOAD_SIZE,
+    EDNS_DNSSEC_OK,
+};
 
 #[derive(Debug, Display, From, Error)]
 pub enum ClientError {
     Protocol(crate::protocols::protocol::ProtocolError),
     Io(std::io::Error),
     PoisonedLock,
-    LookupFailed,
+    /// The server answered, but not with a usable response to the query.
+    #[display(fmt = "Lookup of {} {:?} at {} failed", qname, qtype, server)]
+    LookupFailed { qname: String, qtype: QueryType, server: String },
     TimeOut,
+    TlsUnavailable,
+    Http(hyper::Error),
+    InvalidDohUrl,
+    InvalidZoneTransfer,
+    NoRuntime,
+    /// Blocking on a query needs a multi threaded runtime, the current one would deadlock.
+    #[display(fmt = "Blocking queries need a multi threaded runtime")]
+    UnsupportedRuntime,
+    /// The query can't be written into a single UDP datagram.
+    #[display(fmt = "Query too large for UDP, send it over TCP instead")]
+    QueryTooLarge,
 }
 
 type Result<T> = std::result::Result<T, ClientError>;
 
+impl ClientError {
+    /// A `LookupFailed` for the query of `qname` and `qtype` sent to `server`.
+    pub fn lookup_failed(qname: &str, qtype: QueryType, server: (&str, u16)) -> ClientError {
+        ClientError::LookupFailed {
+            qname: qname.to_string(),
+            qtype,
+            server: format_address(server.0, server.1),
+        }
+    }
+}
+
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_MAX_RETRIES: usize = 2;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// Address sockets are bound to unless configured otherwise, covering every IPv4 interface.
+pub const DEFAULT_LISTEN_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// Well known port for DNS-over-TLS.
+pub const DOT_PORT: u16 = 853;
+
+/// Media type of wire format DNS messages sent over HTTP.
+const DNS_MESSAGE_MEDIA_TYPE: &str = "application/dns-message";
+
+/// How DNS-over-HTTPS queries are put into the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DohMethod {
+    /// The query is the request body.
+    Post,
+    /// The query is base64url encoded into the `dns` parameter, which lets HTTP caches help.
+    Get,
+}
+
+/// An encrypted stream produced by a `TlsConnector`.
+pub trait TlsStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> TlsStream for T {}
+
+/// Performs the client side of a TLS handshake for DNS-over-TLS queries.
+///
+/// Implementations are expected to verify the upstream certificate against
+/// `server_name`, which is also sent as SNI.
+#[async_trait]
+pub trait TlsConnector: Send + Sync {
+    async fn connect(&self, server_name: &str, stream: TcpStream) -> std::io::Result<Box<dyn TlsStream>>;
+}
+
+#[cfg(feature = "tls")]
+fn default_tls_connector() -> Option<Arc<dyn TlsConnector>> {
+    Some(Arc::new(crate::client::tls::RustlsConnector::new()))
+}
+
+#[cfg(not(feature = "tls"))]
+fn default_tls_connector() -> Option<Arc<dyn TlsConnector>> {
+    None
+}
+
+#[async_trait]
 pub trait DnsClient {
     fn get_sent_count(&self) -> usize;
     fn get_failed_count(&self) -> usize;
     fn run(&self) -> Result<()>;
+    /// Sets whether queries set the DO bit, asking upstreams for the RRSIGs over their answers.
+    fn set_dnssec_ok(&self, _dnssec_ok: bool) {}
     fn send_query (
        &self,
        qname: &str,
        qtype: QueryType,
        server: (&str, u16),
        recursive: bool,
+       checking_disabled: bool,
+    ) -> Result<DnsPacket>;
+    async fn send_query_async(
+       &self,
+       qname: &str,
+       qtype: QueryType,
+       server: (&str, u16),
+       recursive: bool,
+       checking_disabled: bool,
     ) -> Result<DnsPacket>;
+    /// Sends a query over DNS-over-TLS, verifying the upstream as `tls_name`.
+    async fn send_tls_query_async(
+       &self,
+       _qname: &str,
+       _qtype: QueryType,
+       _server: (&str, u16),
+       _tls_name: &str,
+       _recursive: bool,
+       _checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        Err(ClientError::TlsUnavailable)
+    }
 }
 
 #[derive(Debug)]
 struct PendingQuery {
     seq: u16,
-    timestamp: DateTime<Local>,
     tx: Sender<Option<DnsPacket>>,
 }
 
-#[derive(Debug)]
 pub struct DnsNetworkClient {
     total_sent: AtomicUsize,
     total_failed: AtomicUsize,
+    total_unmatched: Arc<AtomicUsize>,
     seq: AtomicUsize,
     socket: Arc<UdpSocket>,
+    /// Used for IPv6 upstreams when `socket` is bound to an IPv4 address.
+    socket_v6: Option<Arc<UdpSocket>>,
     pending_queries: Arc<Mutex<Vec<PendingQuery>>>,
+    /// Number of times a timed out UDP query is resent before giving up.
+    pub max_retries: usize,
+    /// Delay before the first resend, doubled on every further attempt.
+    pub retry_delay: Duration,
+    /// How long to wait for the response to a single UDP query.
+    pub query_timeout: Duration,
+    /// Establishes the TLS sessions used by `send_dot_query` and `https` DoH endpoints.
+    ///
+    /// Defaults to a `RustlsConnector` when built with the `tls` feature.
+    pub tls_connector: Option<Arc<dyn TlsConnector>>,
+    /// Endpoint used by `send_doh_query`, such as `https://dns.example.net/dns-query`.
+    pub doh_url: Option<String>,
+    pub doh_method: DohMethod,
+    /// Stops the receive loop started by `run` once it fires.
+    pub shutdown: Option<ShutdownSignal>,
+    /// Randomizes the case of UDP query names (0x20 encoding) and rejects
+    /// responses which don't echo it exactly, making spoofed answers harder to land.
+    pub randomize_case: bool,
+    /// Idle connections reused by `send_tcp_query`.
+    pub tcp_pool: ConnectionPool,
+    /// Whether queries carry an OPT record with the DO bit set.
+    dnssec_ok: AtomicBool,
+}
+
+impl fmt::Debug for DnsNetworkClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsNetworkClient")
+            .field("total_sent", &self.total_sent)
+            .field("total_failed", &self.total_failed)
+            .field("total_unmatched", &self.total_unmatched)
+            .field("seq", &self.seq)
+            .field("socket", &self.socket)
+            .field("socket_v6", &self.socket_v6)
+            .field("pending_queries", &self.pending_queries)
+            .field("max_retries", &self.max_retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("query_timeout", &self.query_timeout)
+            .field("tls_connector", &self.tls_connector.is_some())
+            .field("doh_url", &self.doh_url)
+            .field("doh_method", &self.doh_method)
+            .field("shutdown", &self.shutdown.is_some())
+            .field("randomize_case", &self.randomize_case)
+            .field("tcp_pool", &self.tcp_pool)
+            .field("dnssec_ok", &self.dnssec_ok)
+            .finish()
+    }
 }
 
 impl DnsNetworkClient {
     pub async fn new(port: u16) -> Result<DnsNetworkClient> {
-        let socket = UdpSocket::bind(("0.0.0.0", port)).await.map_err(ClientError::Io)?;
+        Self::new_with_retries(port, DEFAULT_MAX_RETRIES, DEFAULT_RETRY_DELAY).await
+    }
+
+    /// Creates a client on a port picked by the OS.
+    ///
+    /// Preferred over a fixed port outside of tests, since an unpredictable
+    /// source port makes spoofing responses into the cache much harder.
+    pub async fn new_ephemeral() -> Result<DnsNetworkClient> {
+        Self::new(0).await
+    }
+
+    /// Creates a client with its UDP socket bound to `listen_addr` rather than every interface.
+    pub async fn new_on(listen_addr: IpAddr, port: u16) -> Result<DnsNetworkClient> {
+        Self::bind(listen_addr, port, DEFAULT_MAX_RETRIES, DEFAULT_RETRY_DELAY).await
+    }
+
+    /// Returns the local port the UDP socket is bound to.
+    pub fn local_port(&self) -> Result<u16> {
+        Ok(self.local_addr()?.port())
+    }
+
+    /// Returns the local address the UDP socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Creates a client which waits `query_timeout` for each UDP response.
+    pub async fn new_with_timeout(port: u16, query_timeout: Duration) -> Result<DnsNetworkClient> {
+        let mut client = Self::new(port).await?;
+        client.query_timeout = query_timeout;
+
+        Ok(client)
+    }
+
+    /// Creates a client with a custom UDP retry policy.
+    pub async fn new_with_retries(
+        port: u16,
+        max_retries: usize,
+        retry_delay: Duration,
+    ) -> Result<DnsNetworkClient> {
+        Self::bind(DEFAULT_LISTEN_ADDR, port, max_retries, retry_delay).await
+    }
+
+    async fn bind(
+        listen_addr: IpAddr,
+        port: u16,
+        max_retries: usize,
+        retry_delay: Duration,
+    ) -> Result<DnsNetworkClient> {
+        let socket = UdpSocket::bind((listen_addr, port)).await.map_err(ClientError::Io)?;
+
+        // Listening on every IPv4 interface, so IPv6 upstreams get a socket of their own.
+        // Hosts without IPv6 simply go without.
+        let socket_v6 = if listen_addr == DEFAULT_LISTEN_ADDR {
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok().map(Arc::new)
+        } else {
+            None
+        };
+
         Ok(DnsNetworkClient {
             total_sent: AtomicUsize::new(0),
             total_failed: AtomicUsize::new(0),
-            seq: AtomicUsize::new(0),
+            total_unmatched: Arc::new(AtomicUsize::new(0)),
+            seq: AtomicUsize::new(rand::random::<u16>() as usize),
             socket: Arc::new(socket),
+            socket_v6,
             pending_queries: Arc::new(Mutex::new(Vec::new())),
+            max_retries,
+            retry_delay,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            tls_connector: default_tls_connector(),
+            doh_url: None,
+            doh_method: DohMethod::Post,
+            shutdown: None,
+            randomize_case: false,
+            tcp_pool: ConnectionPool::default(),
+            dnssec_ok: AtomicBool::new(false),
         })
     }
 
+    /// Returns the UDP socket queries to `host` are sent from.
+    fn socket_for(&self, host: &str) -> &Arc<UdpSocket> {
+        match self.socket_v6 {
+            Some(ref socket_v6) if is_ipv6_literal(host) => socket_v6,
+            _ => &self.socket,
+        }
+    }
+
+    /// Returns the number of responses that didn't match any outstanding query.
+    pub fn get_unmatched_count(&self) -> usize {
+        self.total_unmatched.load(Ordering::Acquire)
+    }
+
     #[instrument]
     pub async fn send_tcp_query(
         &self,
@@ -72,47 +306,412 @@ impl DnsNetworkClient {
         qtype: QueryType,
         server: (&str, u16),
         recursive: bool,
+        checking_disabled: bool,
     ) -> Result<DnsPacket> {
-        self.total_sent.fetch_add(1, Ordering::Release);
-        let mut packet = DnsPacket::new();
-        packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
-        packet.header.questions = 1;
-        packet.header.recursion_desired = recursive;
-        packet.questions.push(DnsQuestion::new(qname.into(), qtype));
+        if let Some(mut socket) = self.tcp_pool.take(server) {
+            match self
+                .send_stream_query(&mut socket, qname, qtype, server, recursive, checking_disabled)
+                .await
+            {
+                Ok(response) => {
+                    self.tcp_pool.release(server, socket);
+                    return Ok(response);
+                }
+                // Most likely the server closed the idle connection, so retry on a fresh one
+                Err(ClientError::Io(err)) => {
+                    warn!(target: "dns", "Pooled connection to {}:{} failed: {}", server.0, server.1, err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let address = format_address(server.0, server.1);
+        let mut socket = self.within_timeout(TcpStream::connect(address)).await?;
+
+        let response = self
+            .send_stream_query(&mut socket, qname, qtype, server, recursive, checking_disabled)
+            .await?;
+        self.tcp_pool.release(server, socket);
+
+        Ok(response)
+    }
+
+    /// Sends a query to `server` over DNS-over-TLS.
+    ///
+    /// The upstream certificate is verified against `tls_name` rather than the
+    /// address in `server`, which is usually an IP.
+    #[instrument]
+    pub async fn send_dot_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        tls_name: &str,
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let connector = match self.tls_connector {
+            Some(ref connector) => connector.clone(),
+            None => return Err(ClientError::TlsUnavailable),
+        };
+
+        let address = format_address(server.0, server.1);
+        let socket = self.within_timeout(TcpStream::connect(address)).await?;
+        let mut stream = self.within_timeout(connector.connect(tls_name, socket)).await?;
+
+        self.send_stream_query(&mut stream, qname, qtype, server, recursive, checking_disabled)
+            .await
+    }
+
+    /// Sends a query to the configured DNS-over-HTTPS endpoint.
+    ///
+    /// Plain `http` endpoints are accepted as well, for proxies on the local host.
+    #[instrument]
+    pub async fn send_doh_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let url = match self.doh_url {
+            Some(ref url) => DohUrl::parse(url)?,
+            None => return Err(ClientError::InvalidDohUrl),
+        };
+
+        let mut packet = self.build_query(qname, qtype, recursive, checking_disabled);
+        // A fixed id keeps identical GET requests cacheable, see RFC 8484 section 4.1
+        packet.header.id = 0;
 
         let mut req_buffer = BytePacketBuffer::new();
         packet.write(&mut req_buffer, 0xFFFF)?;
+        let query = &req_buffer.buf[0..req_buffer.pos];
+
+        let host = if is_ipv6_literal(&url.host) {
+            format!("[{}]", url.host)
+        } else {
+            url.host.clone()
+        };
+        let builder = Request::builder()
+            .header(header::HOST, host)
+            .header(header::ACCEPT, DNS_MESSAGE_MEDIA_TYPE);
+        let request = match self.doh_method {
+            DohMethod::Post => builder
+                .method(Method::POST)
+                .uri(url.path.as_str())
+                .header(header::CONTENT_TYPE, DNS_MESSAGE_MEDIA_TYPE)
+                .body(Body::from(query.to_vec())),
+            DohMethod::Get => {
+                let separator = if url.path.contains('?') { '&' } else { '?' };
+                builder
+                    .method(Method::GET)
+                    .uri(format!("{}{}dns={}", url.path, separator, base64url_encode(query)))
+                    .body(Body::empty())
+            }
+        }
+        .map_err(|_| ClientError::InvalidDohUrl)?;
+
+        // Every phase is bounded, a stalled endpoint would otherwise hold the query forever
+        let socket = self.within_timeout(TcpStream::connect((url.host.as_str(), url.port))).await?;
+        let stream: Box<dyn TlsStream> = if url.tls {
+            match self.tls_connector {
+                Some(ref connector) => self.within_timeout(connector.connect(&url.host, socket)).await?,
+                None => return Err(ClientError::TlsUnavailable),
+            }
+        } else {
+            Box::new(socket)
+        };
+
+        let (mut sender, connection) = self.within_timeout(hyper::client::conn::handshake(stream)).await?;
+        tokio::spawn(connection);
 
-        let address = format!("{}:{}", server.0, server.1);
-        let mut socket = TcpStream::connect(address).await.map_err(ClientError::Io)?;
+        let response = self.within_timeout(sender.send_request(request)).await?;
+        if !response.status().is_success() {
+            self.total_failed.fetch_add(1, Ordering::Release);
+            return Err(ClientError::lookup_failed(qname, qtype, (&url.host, url.port)));
+        }
 
-        write_packet_length(&mut socket, req_buffer.pos()).await?;
-        socket.write_all(&req_buffer.buf[0..req_buffer.pos]).await?;
-        socket.flush().await?;
+        let body = self.within_timeout(hyper::body::to_bytes(response.into_body())).await?;
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer = body.to_vec();
 
-        let _ = read_packet_length(&mut socket).await?;
-        let mut stream_buffer = StreamPacketBuffer::new(&mut socket);
-        let response_packet = DnsPacket::from_buffer(&mut stream_buffer)?;
+        let mut response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if !response.is_valid_response(&packet) {
+            return Err(ClientError::lookup_failed(qname, qtype, (&url.host, url.port)));
+        }
+        response.dedup();
 
-        Ok(response_packet)
+        Ok(response)
     }
 
-    #[instrument]
-    pub async fn send_udp_query(
+    /// Writes a length prefixed query to `stream` and reads back the response.
+    async fn send_stream_query<S>(
         &self,
+        stream: &mut S,
         qname: &str,
         qtype: QueryType,
         server: (&str, u16),
         recursive: bool,
-    ) -> Result<DnsPacket> {
+        checking_disabled: bool,
+    ) -> Result<DnsPacket>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut packet = self.build_query(qname, qtype, recursive, checking_disabled);
+        self.within_timeout(write_framed_packet(stream, &mut packet)).await?;
+
+        // A half-open upstream would otherwise keep us waiting on the read forever
+        let response = self.within_timeout(read_framed_packet(stream)).await?;
+        if !response.is_valid_response(&packet) {
+            return Err(ClientError::lookup_failed(qname, qtype, server));
+        }
+
+        Ok(response)
+    }
+
+    /// Runs `future`, giving up with `TimeOut` once the query timeout has passed.
+    async fn within_timeout<T, E, F>(&self, future: F) -> Result<T>
+    where
+        F: Future<Output = std::result::Result<T, E>>,
+        ClientError: From<E>,
+    {
+        match time::timeout(self.query_timeout, future).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(ClientError::TimeOut),
+        }
+    }
+
+    /// Requests a full transfer of the zone `qname` from `server`.
+    ///
+    /// The zone's SOA comes first in the returned records, the copy closing
+    /// the transfer is left out.
+    #[instrument]
+    pub async fn send_axfr(&self, qname: &str, server: (&str, u16)) -> Result<Vec<DnsRecord>> {
+        let result = async {
+            let mut transfer = self.start_transfer(qname, QueryType::AXFR, server, None).await?;
+
+            let first = transfer.next_record().await?;
+            if soa_serial(&first).is_none() {
+                return Err(ClientError::InvalidZoneTransfer);
+            }
+
+            let mut records = vec![first];
+            loop {
+                let rec = transfer.next_record().await?;
+                if soa_serial(&rec).is_some() {
+                    return Ok(records);
+                }
+                records.push(rec);
+            }
+        }
+        .await;
+
+        self.count_failure(result)
+    }
+
+    /// Requests the changes to zone `qname` since `known_serial` from `server`.
+    ///
+    /// Servers may answer with the whole zone instead, in which case the diff
+    /// has `full_transfer` set and holds the zone in `added`, SOA first.
+    #[instrument]
+    pub async fn send_ixfr(&self, qname: &str, server: (&str, u16), known_serial: u32) -> Result<ZoneDiff> {
+        let result = async {
+            let mut transfer = self
+                .start_transfer(qname, QueryType::IXFR, server, Some(known_serial))
+                .await?;
+
+            let first = transfer.next_record().await?;
+            let serial = soa_serial(&first).ok_or(ClientError::InvalidZoneTransfer)?;
+            let mut diff = ZoneDiff {
+                serial,
+                ..ZoneDiff::default()
+            };
+
+            // A lone SOA means we're already up to date
+            if serial == known_serial {
+                return Ok(diff);
+            }
+
+            let second = transfer.next_record().await?;
+            match soa_serial(&second) {
+                Some(from) if from != serial => {}
+                // Anything else is a plain AXFR, if possibly of an empty zone
+                _ => {
+                    diff.full_transfer = true;
+                    diff.added.push(first);
+                    let mut rec = second;
+                    while soa_serial(&rec).is_none() {
+                        diff.added.push(rec);
+                        rec = transfer.next_record().await?;
+                    }
+                    return Ok(diff);
+                }
+            }
+
+            // Each delta is an SOA of the old version followed by the records it
+            // loses, then an SOA of the new version followed by the records it gains
+            let mut adding = false;
+            loop {
+                let rec = transfer.next_record().await?;
+                match soa_serial(&rec) {
+                    Some(version) if adding && version == serial => return Ok(diff),
+                    Some(_) => adding = !adding,
+                    None if adding => diff.add(rec),
+                    None => diff.remove(rec),
+                }
+            }
+        }
+        .await;
+
+        self.count_failure(result)
+    }
+
+    /// Sends a zone transfer request, leaving the connection open for the response.
+    async fn start_transfer(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        known_serial: Option<u32>,
+    ) -> Result<ZoneTransfer> {
+        let address = format_address(server.0, server.1);
+        let mut socket = self.within_timeout(TcpStream::connect(address)).await?;
+
+        let mut packet = self.build_query(qname, qtype, false, false);
+        if let Some(serial) = known_serial {
+            // Only the serial of the SOA in an IXFR request is looked at
+            packet.authorities.push(DnsRecord::SOA {
+                domain: qname.to_string(),
+                m_name: String::new(),
+                r_name: String::new(),
+                serial,
+                refresh: 0,
+                retry: 0,
+                expire: 0,
+                minimum: 0,
+                ttl: TransientTtl(0),
+            });
+        }
+        self.within_timeout(write_framed_packet(&mut socket, &mut packet)).await?;
+
+        Ok(ZoneTransfer {
+            socket,
+            timeout: self.query_timeout,
+            id: packet.header.id,
+            qname: qname.to_string(),
+            qtype,
+            server: format_address(server.0, server.1),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn count_failure<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.total_failed.fetch_add(1, Ordering::Release);
+        }
+        result
+    }
+
+    /// Creates a single question query, counting it as sent.
+    fn build_query(&self, qname: &str, qtype: QueryType, recursive: bool, checking_disabled: bool) -> DnsPacket {
         self.total_sent.fetch_add(1, Ordering::Release);
-        let mut packet = DnsPacket::new();
+        let mut packet = self.query_packet(qname, qtype, recursive, checking_disabled);
         packet.header.id = self.seq.fetch_add(1, Ordering::SeqCst) as u16;
+
+        packet
+    }
+
+    /// Creates a single question query without an id.
+    fn query_packet(&self, qname: &str, qtype: QueryType, recursive: bool, checking_disabled: bool) -> DnsPacket {
+        let mut packet = DnsPacket::new();
         packet.header.questions = 1;
         packet.header.recursion_desired = recursive;
+        packet.header.checking_disabled = checking_disabled;
         packet.questions.push(DnsQuestion::new(qname.to_string(), qtype));
 
-        let (tx, mut rx) = channel(1);
+        // Advertising no more than the receive buffer holds, bigger answers come over TCP
+        if self.dnssec_ok.load(Ordering::Acquire) {
+            packet.resources.push(DnsRecord::OPT {
+                packet_len: DEFAULT_UDP_PAYLOAD_SIZE as u16,
+                flags: EDNS_DNSSEC_OK,
+                options: Vec::new(),
+            });
+        }
+
+        packet
+    }
+
+    /// Checks that a query for `qname` fits in a single datagram, before anything is sent.
+    fn check_udp_query_len(&self, qname: &str, qtype: QueryType) -> Result<()> {
+        // Names too long to write at all don't fit either
+        match validate_qname(qname) {
+            Ok(()) => {}
+            Err(BufferError::NameTooLong) => return Err(ClientError::QueryTooLarge),
+            Err(err) => return Err(ClientError::Protocol(err.into())),
+        }
+
+        let packet = self.query_packet(qname, qtype, false, false);
+        let query_len = packet.header.binary_len()
+            + packet.questions.iter().map(|question| question.binary_len() + 4).sum::<usize>()
+            + packet.resources.iter().map(DnsRecord::binary_len).sum::<usize>();
+        if query_len > DEFAULT_UDP_PAYLOAD_SIZE {
+            return Err(ClientError::QueryTooLarge);
+        }
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub async fn send_udp_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        // Resending wouldn't make the query any smaller, so this is checked once
+        self.check_udp_query_len(qname, qtype)?;
+
+        let mut attempt = 0;
+
+        loop {
+            match self.send_udp_attempt(qname, qtype, server, recursive, checking_disabled).await {
+                Err(ClientError::TimeOut) if attempt < self.max_retries => {
+                    // Back off exponentially before resending the query
+                    time::sleep(self.retry_delay * 2u32.pow(attempt as u32)).await;
+                    attempt += 1;
+                }
+                Err(err) => {
+                    self.total_failed.fetch_add(1, Ordering::Release);
+                    return Err(err);
+                }
+                Ok(packet) => return Ok(packet),
+            }
+        }
+    }
+
+    /// Sends a single query datagram with a fresh transaction id and waits for the response.
+    async fn send_udp_attempt(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let qname = if self.randomize_case {
+            randomize_case(qname)
+        } else {
+            qname.to_string()
+        };
+        let mut packet = self.build_query(&qname, qtype, recursive, checking_disabled);
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer, DEFAULT_UDP_PAYLOAD_SIZE)?;
+
+        // Registered before sending so a quick answer isn't taken for an unmatched one
+        let (tx, mut rx) = channel(4);
         {
             let mut pending_queries = self
                 .pending_queries
@@ -120,32 +719,1465 @@ impl DnsNetworkClient {
                 .map_err(|_| ClientError::PoisonedLock)?;
             pending_queries.push(PendingQuery {
                 seq: packet.header.id,
-                timestamp: Local::now(),
                 tx,
             });
         }
 
-        let mut req_buffer = BytePacketBuffer::new();
-        packet.write(&mut req_buffer, 512)?;
-
-        let address = format!("{}:{}", server.0, server.1);
-        self.socket
+        let address = format_address(server.0, server.1);
+        if let Err(err) = self
+            .socket_for(server.0)
             .send_to(&req_buffer.buf[0..req_buffer.pos], &address)
             .await
-            .map_err(ClientError::Io)?;
+        {
+            self.forget_query(packet.header.id)?;
+            return Err(ClientError::Io(err));
+        }
+
+        // Responses that don't answer the question are dropped rather than failing the
+        // lookup, so a spoofed one can't keep the genuine answer from getting through
+        let deadline = time::Instant::now() + self.query_timeout;
+        loop {
+            match time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some(Some(response)))
+                    if response.is_valid_response(&packet)
+                        && (!self.randomize_case || questions_match_exactly(&response.questions, &packet.questions)) =>
+                {
+                    self.forget_query(packet.header.id)?;
+                    return Ok(response);
+                }
+                Ok(Some(Some(_))) => {
+                    warn!(target: "dns", "Dropped a response from {}:{} not matching its query", server.0, server.1);
+                    self.total_failed.fetch_add(1, Ordering::Release);
+                }
+                Ok(Some(None)) | Err(_) => {
+                    // Forget the query so a late answer isn't routed to a dead channel
+                    self.forget_query(packet.header.id)?;
+
+                    return Err(ClientError::TimeOut);
+                }
+                Ok(None) => return Err(ClientError::lookup_failed(&qname, qtype, server)),
+            }
+        }
+    }
+
+    /// Stops waiting for the response to the query with id `seq`.
+    fn forget_query(&self, seq: u16) -> Result<()> {
+        let mut pending_queries = self
+            .pending_queries
+            .lock()
+            .map_err(|_| ClientError::PoisonedLock)?;
+        pending_queries.retain(|pending| pending.seq != seq);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DnsClient for DnsNetworkClient {
+    fn get_sent_count(&self) -> usize {
+        self.total_sent.load(Ordering::Acquire)
+    }
+
+    fn get_failed_count(&self) -> usize {
+        self.total_failed.load(Ordering::Acquire)
+    }
+
+    /// Spawns the task routing responses on the UDP socket to their pending queries.
+    ///
+    /// Has to be called from within a tokio runtime.
+    fn run(&self) -> Result<()> {
+        let handle = tokio::runtime::Handle::try_current().map_err(|_| ClientError::NoRuntime)?;
+
+        for socket in std::iter::once(&self.socket).chain(self.socket_v6.iter()) {
+            let socket = socket.clone();
+            let pending_queries = self.pending_queries.clone();
+            let unmatched = self.total_unmatched.clone();
+            let mut shutdown = self.shutdown.clone();
+
+            handle.spawn(async move {
+                loop {
+                    let mut res_buffer = BytePacketBuffer::new();
+                    let received = match shutdown {
+                        Some(ref mut shutdown) => tokio::select! {
+                            _ = shutdown.recv() => return,
+                            received = socket.recv_from(&mut res_buffer.buf) => received,
+                        },
+                        None => socket.recv_from(&mut res_buffer.buf).await,
+                    };
+
+                    if let Err(err) = received {
+                        warn!(target: "dns", "Failed to receive on the client socket: {}", err);
+                        continue;
+                    }
+
+                    // Keeping the question's case lets 0x20 encoded queries be checked
+                    let mut packet = match DnsPacket::from_buffer_preserving_case(&mut res_buffer) {
+                        Ok(packet) => packet,
+                        Err(_) => continue,
+                    };
+                    packet.dedup();
+
+                    if dispatch_response(&pending_queries, &unmatched, packet).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        Ok(())
+    }
+
+    fn set_dnssec_ok(&self, dnssec_ok: bool) {
+        self.dnssec_ok.store(dnssec_ok, Ordering::Release);
+    }
+
+    /// Blocks on `send_query_async`, which needs a multi threaded runtime to be running.
+    fn send_query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let handle = tokio::runtime::Handle::try_current().map_err(|_| ClientError::NoRuntime)?;
+        // block_in_place panics on a current thread runtime
+        if handle.runtime_flavor() != tokio::runtime::RuntimeFlavor::MultiThread {
+            return Err(ClientError::UnsupportedRuntime);
+        }
+        tokio::task::block_in_place(|| {
+            handle.block_on(self.send_query_async(qname, qtype, server, recursive, checking_disabled))
+        })
+    }
+
+    /// Queries over UDP, retrying over TCP when the answer doesn't fit in a datagram.
+    async fn send_query_async(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        let packet = self
+            .send_udp_query(qname, qtype, server, recursive, checking_disabled)
+            .await?;
+        if !packet.header.truncated_message {
+            return Ok(packet);
+        }
+
+        self.send_tcp_query(qname, qtype, server, recursive, checking_disabled)
+            .await
+    }
+
+    async fn send_tls_query_async(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        server: (&str, u16),
+        tls_name: &str,
+        recursive: bool,
+        checking_disabled: bool,
+    ) -> Result<DnsPacket> {
+        self.send_dot_query(qname, qtype, server, tls_name, recursive, checking_disabled)
+            .await
+    }
+}
+
+/// Changes to a zone received through IXFR.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ZoneDiff {
+    /// Serial of the zone once the changes are applied.
+    pub serial: u32,
+    pub removed: Vec<DnsRecord>,
+    pub added: Vec<DnsRecord>,
+    /// Set when the server sent the full zone rather than the changes.
+    pub full_transfer: bool,
+}
+
+impl ZoneDiff {
+    /// Records `rec` as added, cancelling out an earlier removal of it.
+    fn add(&mut self, rec: DnsRecord) {
+        match self.removed.iter().position(|removed| *removed == rec) {
+            Some(idx) => {
+                self.removed.remove(idx);
+            }
+            None => self.added.push(rec),
+        }
+    }
+
+    /// Records `rec` as removed, cancelling out an earlier addition of it.
+    fn remove(&mut self, rec: DnsRecord) {
+        match self.added.iter().position(|added| *added == rec) {
+            Some(idx) => {
+                self.added.remove(idx);
+            }
+            None => self.removed.push(rec),
+        }
+    }
+}
 
-        let response = time::timeout(Duration::from_secs(3), rx.recv()).await;
+/// The records of a zone transfer response, which may span several messages.
+struct ZoneTransfer {
+    socket: TcpStream,
+    /// How long to wait for each message, a stalled primary would otherwise hold the transfer forever.
+    timeout: Duration,
+    id: u16,
+    qname: String,
+    qtype: QueryType,
+    server: String,
+    pending: VecDeque<DnsRecord>,
+}
 
-        match response {
-            Ok(Some(Some(packet))) => Ok(packet),
-            Ok(Some(None)) | Err(_) => {
-                self.total_failed.fetch_add(1, Ordering::Release);
-                Err(ClientError::TimeOut)
+impl ZoneTransfer {
+    async fn next_record(&mut self) -> Result<DnsRecord> {
+        loop {
+            if let Some(rec) = self.pending.pop_front() {
+                return Ok(rec);
             }
-            Ok(None) => {
-                self.total_failed.fetch_add(1, Ordering::Release);
-                Err(ClientError::LookupFailed)
+
+            let response = match time::timeout(self.timeout, read_framed_packet(&mut self.socket)).await {
+                Ok(response) => response?,
+                Err(_) => return Err(ClientError::TimeOut),
+            };
+            if response.header.id != self.id || response.header.rescode != ResultCode::NOERROR {
+                return Err(ClientError::LookupFailed {
+                    qname: self.qname.clone(),
+                    qtype: self.qtype,
+                    server: self.server.clone(),
+                });
             }
+            self.pending.extend(response.answers);
         }
     }
 }
+
+/// Flips the case of each letter in `qname` at random, as done by 0x20 encoding.
+fn randomize_case(qname: &str) -> String {
+    qname
+        .chars()
+        .map(|c| {
+            if rand::random::<bool>() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// Whether `answered` echoes `asked` exactly, the case of the names included.
+fn questions_match_exactly(answered: &[DnsQuestion], asked: &[DnsQuestion]) -> bool {
+    answered.len() == asked.len() && answered.iter().zip(asked).all(|(a, b)| a.eq_exact(b))
+}
+
+fn soa_serial(rec: &DnsRecord) -> Option<u32> {
+    match *rec {
+        DnsRecord::SOA { serial, .. } => Some(serial),
+        _ => None,
+    }
+}
+
+/// Writes `packet` to a stream transport, preceded by its length.
+async fn write_framed_packet<S>(stream: &mut S, packet: &mut DnsPacket) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer, 0xFFFF)?;
+
+    write_packet_length(stream, req_buffer.pos()).await?;
+    stream.write_all(&req_buffer.buf[0..req_buffer.pos]).await?;
+    stream.flush().await?;
+
+    Ok(())
+}
+
+/// Reads one length prefixed message from a stream transport.
+async fn read_framed_packet<S>(stream: &mut S) -> Result<DnsPacket>
+where
+    S: AsyncRead + Unpin,
+{
+    // The length prefix says exactly how much to read, so take the whole message at once
+    let len = read_packet_length(stream).await?;
+    let mut res_buffer = VectorPacketBuffer::new();
+    res_buffer.buffer.resize(len as usize, 0);
+    stream.read_exact(&mut res_buffer.buffer).await?;
+
+    Ok(DnsPacket::from_buffer(&mut res_buffer)?)
+}
+
+/// The parts of a DoH endpoint URL needed to reach it.
+#[derive(Debug, PartialEq, Eq)]
+struct DohUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl DohUrl {
+    fn parse(url: &str) -> Result<DohUrl> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(ClientError::InvalidDohUrl);
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+
+        let default_port = if tls { 443 } else { 80 };
+        let parse_port = |port: &str| port.parse().map_err(|_| ClientError::InvalidDohUrl);
+
+        // IPv6 literals are bracketed, their colons would be taken for the port otherwise.
+        // The host is kept without the brackets, as it's connected to as it is.
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, rest) = bracketed.split_once(']').ok_or(ClientError::InvalidDohUrl)?;
+            if !is_ipv6_literal(host) {
+                return Err(ClientError::InvalidDohUrl);
+            }
+            match rest.strip_prefix(':') {
+                Some(port) => (host, parse_port(port)?),
+                None if rest.is_empty() => (host, default_port),
+                None => return Err(ClientError::InvalidDohUrl),
+            }
+        } else {
+            match authority.split_once(':') {
+                Some((host, port)) => (host, parse_port(port)?),
+                None => (authority, default_port),
+            }
+        };
+
+        if host.is_empty() {
+            return Err(ClientError::InvalidDohUrl);
+        }
+
+        Ok(DohUrl {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Encodes `data` as unpadded base64url, as used by DoH GET requests.
+fn base64url_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity((data.len() * 4).div_ceil(3));
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, byte)| acc | ((*byte as u32) << (16 - 8 * i)));
+
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3F) as usize] as char);
+        }
+    }
+
+    encoded
+}
+
+/// Hands a received response to the query waiting on the same transaction id.
+///
+/// Responses matching no outstanding query, such as late or spoofed answers,
+/// are dropped and counted in `unmatched`.
+fn dispatch_response(
+    pending_queries: &Mutex<Vec<PendingQuery>>,
+    unmatched: &AtomicUsize,
+    packet: DnsPacket,
+) -> Result<()> {
+    let pending_queries = pending_queries
+        .lock()
+        .map_err(|_| ClientError::PoisonedLock)?;
+
+    match pending_queries
+        .iter()
+        .position(|pending| pending.seq == packet.header.id)
+    {
+        // Left registered until the query is done with, as the packet may not be the answer
+        Some(idx) => {
+            let _ = pending_queries[idx].tx.try_send(Some(packet));
+        }
+        None => {
+            unmatched.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    use crate::server::shutdown::Shutdown;
+
+
+    pub type StubCallback = dyn Fn(&str, QueryType, (&str, u16), bool, bool) -> Result<DnsPacket> + Sync + Send;
+
+    /// A client which answers every query by invoking a callback, for use in tests.
+    pub struct DnsStubClient {
+        callback: Box<StubCallback>,
+    }
+
+    impl DnsStubClient {
+        pub fn new(callback: Box<StubCallback>) -> DnsStubClient {
+            DnsStubClient { callback }
+        }
+    }
+
+    #[async_trait]
+    impl DnsClient for DnsStubClient {
+        fn get_sent_count(&self) -> usize {
+            0
+        }
+
+        fn get_failed_count(&self) -> usize {
+            0
+        }
+
+        fn run(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn send_query(
+            &self,
+            qname: &str,
+            qtype: QueryType,
+            server: (&str, u16),
+            recursive: bool,
+            checking_disabled: bool,
+        ) -> Result<DnsPacket> {
+            (self.callback)(qname, qtype, server, recursive, checking_disabled)
+        }
+
+        async fn send_query_async(
+            &self,
+            qname: &str,
+            qtype: QueryType,
+            server: (&str, u16),
+            recursive: bool,
+            checking_disabled: bool,
+        ) -> Result<DnsPacket> {
+            (self.callback)(qname, qtype, server, recursive, checking_disabled)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_retry_after_dropped_datagram() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+                received += 1;
+
+                // Drop the first datagram on the floor
+                if received == 1 {
+                    continue;
+                }
+
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: "retry.example.com".to_string(),
+                    addr: Ipv4Addr::new(10, 0, 0, 1),
+                    ttl: TransientTtl(60),
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+            }
+        });
+
+        let mut client = DnsNetworkClient::new_with_retries(0, 2, Duration::from_millis(10))
+            .await
+            .unwrap();
+        client.query_timeout = Duration::from_millis(250);
+        client.run().unwrap();
+
+        let response = client
+            .send_udp_query("retry.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(1, response.answers.len());
+        assert_eq!(2, client.total_sent.load(Ordering::Acquire));
+        assert_eq!(0, client.total_failed.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn test_response_with_wrong_id_is_ignored() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            // A spoofed answer with a mismatched id arrives first
+            for (id, addr) in &[
+                (request.header.id.wrapping_add(1), Ipv4Addr::new(6, 6, 6, 6)),
+                (request.header.id, Ipv4Addr::new(10, 0, 0, 1)),
+            ] {
+                let mut response = DnsPacket::new();
+                response.header.id = *id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: "spoof.example.com".to_string(),
+                    addr: *addr,
+                    ttl: TransientTtl(60),
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+            }
+        });
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        client.run().unwrap();
+
+        let response = client
+            .send_udp_query("spoof.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(Some("10.0.0.1".to_string()), response.get_random_a());
+        assert_eq!(1, client.get_unmatched_count());
+    }
+
+    /// Answers the first query on `server` twice, first with `bad_question` applied to the
+    /// question and then as asked, returning the name the query was sent with.
+    fn spawn_spoofing_server(
+        server: UdpSocket,
+        bad_question: fn(&mut DnsQuestion),
+    ) -> tokio::task::JoinHandle<String> {
+        tokio::spawn(async move {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+            let request = DnsPacket::from_buffer_preserving_case(&mut req_buffer).unwrap();
+
+            for (spoofed, addr) in [(true, Ipv4Addr::new(6, 6, 6, 6)), (false, Ipv4Addr::new(10, 0, 0, 20))] {
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                if spoofed {
+                    bad_question(&mut response.questions[0]);
+                }
+                response.answers.push(DnsRecord::A {
+                    domain: "mixed.example.com".to_string(),
+                    addr,
+                    ttl: TransientTtl(60),
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+            }
+
+            request.questions[0].name.clone()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_randomized_case_must_be_echoed_exactly() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        // The first answer has the case of the name swapped
+        let sent = spawn_spoofing_server(server, |question| {
+            question.name = question
+                .name
+                .chars()
+                .map(|c| if c.is_ascii_uppercase() { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() })
+                .collect();
+        });
+
+        let mut client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.randomize_case = true;
+        client.run().unwrap();
+
+        let response = client
+            .send_udp_query("mixed.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await
+            .unwrap();
+        assert_eq!(Some("10.0.0.20".to_string()), response.get_random_a());
+        assert_eq!(1, client.get_failed_count());
+
+        // Only the case differs from the name asked for
+        assert!(sent.await.unwrap().eq_ignore_ascii_case("mixed.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_response_is_dropped() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        // The first answer carries the right id, but for another name
+        let _server = spawn_spoofing_server(server, |question| {
+            question.name = "evil.example.com".to_string();
+        });
+
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.run().unwrap();
+
+        let response = client
+            .send_udp_query("mixed.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(Some("10.0.0.20".to_string()), response.get_random_a());
+        assert_eq!(1, client.get_failed_count());
+        assert!(client.pending_queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_forgets_the_query() {
+        let mut client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.max_retries = 0;
+
+        // Nothing can be sent to port zero
+        let result = client
+            .send_udp_query("www.example.com", QueryType::A, ("127.0.0.1", 0), true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::Io(_))));
+        assert!(client.pending_queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_short_timeout_against_silent_server() {
+        // Bound but never answers
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        let mut client = DnsNetworkClient::new_with_timeout(0, Duration::from_millis(50))
+            .await
+            .unwrap();
+        client.max_retries = 0;
+
+        let start = std::time::Instant::now();
+        let result = client
+            .send_udp_query("silent.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::TimeOut)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(1, client.total_failed.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_query_times_out_against_silent_server() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        // Accepts the connection and holds it open without ever answering
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = DnsNetworkClient::new_with_timeout(0, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let result = client
+            .send_tcp_query("silent.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::TimeOut)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_queries_reuse_pooled_connection() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let accepted_clone = accepted.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted_clone.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    // Keep answering on the connection until the client closes it
+                    while let Ok(len) = read_packet_length(&mut stream).await {
+                        let mut req_buffer = VectorPacketBuffer::new();
+                        req_buffer.buffer.resize(len as usize, 0);
+                        stream.read_exact(&mut req_buffer.buffer).await.unwrap();
+                        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                        let mut response = DnsPacket::new();
+                        response.header.id = request.header.id;
+                        response.header.response = true;
+                        response.questions = request.questions.clone();
+
+                        let mut res_buffer = VectorPacketBuffer::new();
+                        response.write(&mut res_buffer, 0xFFFF).unwrap();
+                        write_packet_length(&mut stream, res_buffer.pos()).await.unwrap();
+                        stream.write_all(&res_buffer.buffer[0..res_buffer.pos()]).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        for qname in ["one.example.com", "two.example.com"] {
+            let response = client
+                .send_tcp_query(qname, QueryType::A, ("127.0.0.1", server_port), true, false)
+                .await
+                .unwrap();
+            assert_eq!(qname, response.questions[0].name);
+        }
+
+        assert_eq!(1, accepted.load(Ordering::SeqCst));
+        assert_eq!(1, client.tcp_pool.idle_count());
+    }
+
+    /// Hands back the plain TCP stream, standing in for a TLS handshake.
+    struct PlaintextConnector {
+        server_names: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TlsConnector for PlaintextConnector {
+        async fn connect(&self, server_name: &str, stream: TcpStream) -> std::io::Result<Box<dyn TlsStream>> {
+            self.server_names.lock().unwrap().push(server_name.to_string());
+            Ok(Box::new(stream))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dot_query_frames_messages_over_the_session() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = read_packet_length(&mut stream).await.unwrap();
+            let mut req_buffer = VectorPacketBuffer::new();
+            req_buffer.buffer.resize(len as usize, 0);
+            stream.read_exact(&mut req_buffer.buffer).await.unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions.clone();
+            response.answers.push(DnsRecord::A {
+                domain: "private.example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 53),
+                ttl: TransientTtl(60),
+            });
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            response.write(&mut res_buffer, 0xFFFF).unwrap();
+            write_packet_length(&mut stream, res_buffer.pos()).await.unwrap();
+            stream.write_all(&res_buffer.buffer[0..res_buffer.pos()]).await.unwrap();
+        });
+
+        let connector = Arc::new(PlaintextConnector {
+            server_names: Mutex::new(Vec::new()),
+        });
+        let mut client = DnsNetworkClient::new(0).await.unwrap();
+        client.tls_connector = Some(connector.clone());
+
+        let response = client
+            .send_dot_query(
+                "private.example.com",
+                QueryType::A,
+                ("127.0.0.1", server_port),
+                "dns.example.net",
+                true,
+                false,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(Some("10.0.0.53".to_string()), response.get_random_a());
+        assert_eq!(vec!["dns.example.net".to_string()], *connector.server_names.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_dot_query_without_connector() {
+        let mut client = DnsNetworkClient::new(0).await.unwrap();
+        client.tls_connector = None;
+
+        let result = client
+            .send_dot_query("example.com", QueryType::A, ("127.0.0.1", DOT_PORT), "dns.example.net", true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::TlsUnavailable)));
+    }
+
+    /// Answers a single HTTP request with `response` as an application/dns-message body.
+    ///
+    /// Returns the request head and body once they've been read.
+    fn spawn_doh_server(listener: TcpListener, mut response: DnsPacket) -> tokio::task::JoinHandle<(String, Vec<u8>)> {
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = Vec::new();
+            let head_len = loop {
+                let mut chunk = [0; 1024];
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[0..read]);
+                if let Some(idx) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break idx + 4;
+                }
+            };
+            let head = String::from_utf8(request[0..head_len].to_vec()).unwrap();
+
+            let content_length = head
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .map(|(_, value)| value.trim().parse::<usize>().unwrap())
+                .unwrap_or(0);
+            while request.len() < head_len + content_length {
+                let mut chunk = [0; 1024];
+                let read = stream.read(&mut chunk).await.unwrap();
+                request.extend_from_slice(&chunk[0..read]);
+            }
+
+            let mut res_buffer = VectorPacketBuffer::new();
+            response.write(&mut res_buffer, 0xFFFF).unwrap();
+            let body = &res_buffer.buffer[0..res_buffer.pos()];
+
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\ncontent-length: {}\r\n\r\n",
+                body.len()
+            );
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+
+            (head, request[head_len..].to_vec())
+        })
+    }
+
+    fn doh_response() -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.questions.push(DnsQuestion::new("doh.example.com".to_string(), QueryType::A));
+        response.answers.push(DnsRecord::A {
+            domain: "doh.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 4, 43),
+            ttl: TransientTtl(60),
+        });
+        response
+    }
+
+    #[tokio::test]
+    async fn test_doh_post_query() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let server = spawn_doh_server(listener, doh_response());
+
+        let mut client = DnsNetworkClient::new(0).await.unwrap();
+        client.doh_url = Some(format!("http://127.0.0.1:{}/dns-query", server_port));
+
+        let response = client
+            .send_doh_query("doh.example.com", QueryType::A, true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(1, response.answers.len());
+        assert_eq!(doh_response().answers[0], response.answers[0]);
+        assert_eq!(Some("10.0.4.43".to_string()), response.get_random_a());
+
+        let (head, body) = server.await.unwrap();
+        assert!(head.starts_with("POST /dns-query HTTP/1.1\r\n"));
+        assert!(head.to_lowercase().contains("content-type: application/dns-message"));
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer = body;
+        let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+        assert_eq!(0, request.header.id);
+        assert_eq!("doh.example.com", request.questions[0].name);
+    }
+
+    #[tokio::test]
+    async fn test_doh_query_times_out_on_silent_endpoint() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        // Accepts the connection and reads the request, but never answers it
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut chunk = [0; 1024];
+            while stream.read(&mut chunk).await.unwrap_or(0) > 0 {}
+        });
+
+        let mut client = DnsNetworkClient::new_with_timeout(0, Duration::from_millis(200)).await.unwrap();
+        client.doh_url = Some(format!("http://127.0.0.1:{}/dns-query", server_port));
+
+        let result = client.send_doh_query("doh.example.com", QueryType::A, true, false).await;
+        assert!(matches!(result, Err(ClientError::TimeOut)));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_doh_get_query() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let server = spawn_doh_server(listener, doh_response());
+
+        let mut client = DnsNetworkClient::new(0).await.unwrap();
+        client.doh_url = Some(format!("http://127.0.0.1:{}/dns-query", server_port));
+        client.doh_method = DohMethod::Get;
+
+        let response = client
+            .send_doh_query("doh.example.com", QueryType::A, true, false)
+            .await
+            .unwrap();
+        assert_eq!(Some("10.0.4.43".to_string()), response.get_random_a());
+
+        let (head, body) = server.await.unwrap();
+        assert!(head.starts_with("GET /dns-query?dns="));
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_base64url_encode() {
+        assert_eq!("", base64url_encode(b""));
+        assert_eq!("Zg", base64url_encode(b"f"));
+        assert_eq!("Zm8", base64url_encode(b"fo"));
+        assert_eq!("Zm9v", base64url_encode(b"foo"));
+        assert_eq!("Zm9vYmFy", base64url_encode(b"foobar"));
+        assert_eq!("-_8", base64url_encode(&[0xFB, 0xFF]));
+    }
+
+    #[test]
+    fn test_parse_doh_url() {
+        assert_eq!(
+            DohUrl {
+                tls: true,
+                host: "dns.example.net".to_string(),
+                port: 443,
+                path: "/dns-query".to_string(),
+            },
+            DohUrl::parse("https://dns.example.net/dns-query").unwrap()
+        );
+        assert_eq!(
+            DohUrl {
+                tls: false,
+                host: "127.0.0.1".to_string(),
+                port: 8053,
+                path: "/".to_string(),
+            },
+            DohUrl::parse("http://127.0.0.1:8053").unwrap()
+        );
+        assert_eq!(
+            DohUrl {
+                tls: true,
+                host: "2001:db8::1".to_string(),
+                port: 443,
+                path: "/dns-query".to_string(),
+            },
+            DohUrl::parse("https://[2001:db8::1]/dns-query").unwrap()
+        );
+        assert_eq!(
+            DohUrl {
+                tls: false,
+                host: "::1".to_string(),
+                port: 8053,
+                path: "/".to_string(),
+            },
+            DohUrl::parse("http://[::1]:8053").unwrap()
+        );
+        assert!(DohUrl::parse("ftp://dns.example.net/").is_err());
+        assert!(DohUrl::parse("https://2001:db8::1/dns-query").is_err());
+        assert!(DohUrl::parse("https://[2001:db8::1/dns-query").is_err());
+        assert!(DohUrl::parse("https://[dns.example.net]/dns-query").is_err());
+    }
+
+    fn example_soa(serial: u32) -> DnsRecord {
+        DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "admin.example.com".to_string(),
+            serial,
+            refresh: 7200,
+            retry: 900,
+            expire: 1209600,
+            minimum: 300,
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    fn example_a(name: &str, last_octet: u8) -> DnsRecord {
+        DnsRecord::A {
+            domain: format!("{}.example.com", name),
+            addr: Ipv4Addr::new(192, 0, 2, last_octet),
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    /// Answers the first query on `listener` with one framed message per entry of `messages`.
+    fn spawn_transfer_server(listener: TcpListener, messages: Vec<Vec<DnsRecord>>) -> tokio::task::JoinHandle<DnsPacket> {
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_framed_packet(&mut stream).await.unwrap();
+
+            for answers in messages {
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.header.authoritative_answer = true;
+                response.questions = request.questions.clone();
+                response.answers = answers;
+
+                write_framed_packet(&mut stream, &mut response).await.unwrap();
+            }
+
+            request
+        })
+    }
+
+    #[tokio::test]
+    async fn test_axfr_collects_records_from_every_message() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        let server = spawn_transfer_server(
+            listener,
+            vec![
+                vec![example_soa(2024010101), example_a("ns1", 1), example_a("www", 10)],
+                vec![example_a("mail", 25), example_a("ftp", 21), example_soa(2024010101)],
+            ],
+        );
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        let records = client
+            .send_axfr("example.com", ("127.0.0.1", server_port))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                example_soa(2024010101),
+                example_a("ns1", 1),
+                example_a("www", 10),
+                example_a("mail", 25),
+                example_a("ftp", 21),
+            ],
+            records
+        );
+
+        let request = server.await.unwrap();
+        assert_eq!(QueryType::AXFR, request.questions[0].qtype);
+    }
+
+    #[tokio::test]
+    async fn test_axfr_must_start_with_soa() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let _server = spawn_transfer_server(listener, vec![vec![example_a("www", 10)]]);
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        let result = client.send_axfr("example.com", ("127.0.0.1", server_port)).await;
+
+        assert!(matches!(result, Err(ClientError::InvalidZoneTransfer)));
+    }
+
+    #[tokio::test]
+    async fn test_axfr_times_out_against_silent_server() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        // Accepts the connection and holds it open without ever answering
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = DnsNetworkClient::new_with_timeout(0, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let result = client.send_axfr("example.com", ("127.0.0.1", server_port)).await;
+
+        assert!(matches!(result, Err(ClientError::TimeOut)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(1, client.get_failed_count());
+    }
+
+    #[tokio::test]
+    async fn test_ixfr_interprets_delta_stream() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        let server = spawn_transfer_server(
+            listener,
+            vec![
+                vec![
+                    example_soa(3),
+                    example_soa(1),
+                    example_a("www", 10),
+                    example_soa(2),
+                    example_a("www", 11),
+                ],
+                vec![
+                    example_soa(2),
+                    example_a("ftp", 21),
+                    example_a("www", 11),
+                    example_soa(3),
+                    example_a("mail", 25),
+                    example_soa(3),
+                ],
+            ],
+        );
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        let diff = client
+            .send_ixfr("example.com", ("127.0.0.1", server_port), 1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            ZoneDiff {
+                serial: 3,
+                removed: vec![example_a("www", 10), example_a("ftp", 21)],
+                added: vec![example_a("mail", 25)],
+                full_transfer: false,
+            },
+            diff
+        );
+
+        let request = server.await.unwrap();
+        assert_eq!(QueryType::IXFR, request.questions[0].qtype);
+        assert_eq!(Some(1), request.authorities.first().and_then(soa_serial));
+    }
+
+    #[tokio::test]
+    async fn test_ixfr_times_out_on_stalled_delta_stream() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+
+        // Sends the start of a delta, then holds the connection open without finishing it
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_framed_packet(&mut stream).await.unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions.clone();
+            response.answers = vec![example_soa(3), example_soa(1), example_a("www", 10)];
+            write_framed_packet(&mut stream, &mut response).await.unwrap();
+
+            time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = DnsNetworkClient::new_with_timeout(0, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        let start = std::time::Instant::now();
+        let result = client.send_ixfr("example.com", ("127.0.0.1", server_port), 1).await;
+
+        assert!(matches!(result, Err(ClientError::TimeOut)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(1, client.get_failed_count());
+    }
+
+    #[tokio::test]
+    async fn test_ixfr_falls_back_to_full_transfer() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let _server = spawn_transfer_server(
+            listener,
+            vec![vec![example_soa(3), example_a("www", 10), example_a("mail", 25), example_soa(3)]],
+        );
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        let diff = client
+            .send_ixfr("example.com", ("127.0.0.1", server_port), 1)
+            .await
+            .unwrap();
+
+        assert!(diff.full_transfer);
+        assert_eq!(3, diff.serial);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            vec![example_soa(3), example_a("www", 10), example_a("mail", 25)],
+            diff.added
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ixfr_when_up_to_date() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let _server = spawn_transfer_server(listener, vec![vec![example_soa(3)]]);
+
+        let client = DnsNetworkClient::new(0).await.unwrap();
+        let diff = client
+            .send_ixfr("example.com", ("127.0.0.1", server_port), 3)
+            .await
+            .unwrap();
+
+        assert_eq!(3, diff.serial);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_clients_get_different_ports() {
+        let first = DnsNetworkClient::new_ephemeral().await.unwrap();
+        let second = DnsNetworkClient::new_ephemeral().await.unwrap();
+
+        let first_port = first.local_port().unwrap();
+        let second_port = second.local_port().unwrap();
+
+        assert_ne!(0, first_port);
+        assert_ne!(0, second_port);
+        assert_ne!(first_port, second_port);
+    }
+
+    #[test]
+    fn test_ipv6_addresses_are_bracketed() {
+        assert_eq!("[::1]:53", format_address("::1", 53));
+        assert_eq!("[2001:db8::1]:5353", format_address("2001:db8::1", 5353));
+        assert_eq!("127.0.0.1:53", format_address("127.0.0.1", 53));
+        assert_eq!("dns.example.net:853", format_address("dns.example.net", 853));
+    }
+
+    #[tokio::test]
+    async fn test_udp_query_to_ipv6_upstream() {
+        let server = UdpSocket::bind(("::1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.questions = request.questions.clone();
+            response.answers.push(DnsRecord::AAAA {
+                domain: "v6.example.com".to_string(),
+                addr: Ipv6Addr::LOCALHOST,
+                ttl: TransientTtl(60),
+            });
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.write(&mut res_buffer, 512).unwrap();
+            server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+        });
+
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.run().unwrap();
+
+        let response = client
+            .send_udp_query("v6.example.com", QueryType::AAAA, ("::1", server_port), true, false)
+            .await
+            .unwrap();
+
+        assert_eq!(1, response.answers.len());
+    }
+
+    #[tokio::test]
+    async fn test_queries_set_do_bit_when_asked_to() {
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        assert!(client.build_query("example.com", QueryType::A, true, false).resources.is_empty());
+
+        client.set_dnssec_ok(true);
+        let mut query = client.build_query("example.com", QueryType::A, true, false);
+
+        let mut buffer = BytePacketBuffer::new();
+        query.write(&mut buffer, DEFAULT_UDP_PAYLOAD_SIZE).unwrap();
+        buffer.seek(0).unwrap();
+        let sent = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        match sent.resources[..] {
+            [DnsRecord::OPT { packet_len, flags, .. }] => {
+                assert_eq!(DEFAULT_UDP_PAYLOAD_SIZE as u16, packet_len);
+                assert_eq!(EDNS_DNSSEC_OK, flags & EDNS_DNSSEC_OK);
+            }
+            ref other => panic!("Expected a single OPT record, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_udp_query_is_rejected() {
+        let client = DnsNetworkClient::new_with_retries(0, 2, Duration::from_millis(10)).await.unwrap();
+
+        let qname = vec!["a".repeat(63); 10].join(".");
+        let result = client
+            .send_udp_query(&qname, QueryType::A, ("127.0.0.1", 53), true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::QueryTooLarge)));
+        assert!(client.pending_queries.lock().unwrap().is_empty());
+        assert_eq!(0, client.get_sent_count());
+    }
+
+    #[tokio::test]
+    async fn test_query_names_just_over_the_limit_are_too_large() {
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.set_dnssec_ok(true);
+
+        // 256 bytes encoded, one more than a name may have
+        let qname = [vec!["a".repeat(63); 3], vec!["a".repeat(62)]].concat().join(".");
+        let result = client
+            .send_udp_query(&qname, QueryType::A, ("127.0.0.1", 53), true, false)
+            .await;
+
+        assert!(matches!(result, Err(ClientError::QueryTooLarge)));
+        assert_eq!(0, client.get_sent_count());
+    }
+
+    #[tokio::test]
+    async fn test_client_binds_listen_addr() {
+        let client = DnsNetworkClient::new_on(IpAddr::V4(Ipv4Addr::LOCALHOST), 0).await.unwrap();
+
+        let local_addr = client.local_addr().unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST), local_addr.ip());
+        assert_ne!(0, local_addr.port());
+    }
+
+    #[tokio::test]
+    async fn test_run_routes_answers_to_queries() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = server.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let mut req_buffer = BytePacketBuffer::new();
+                let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+                let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+                let mut response = DnsPacket::new();
+                response.header.id = request.header.id;
+                response.header.response = true;
+                response.questions = request.questions.clone();
+                response.answers.push(DnsRecord::A {
+                    domain: request.questions[0].name.clone(),
+                    addr: Ipv4Addr::new(10, 0, 0, 7),
+                    ttl: TransientTtl(60),
+                });
+
+                let mut res_buffer = BytePacketBuffer::new();
+                response.write(&mut res_buffer, 512).unwrap();
+                server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+            }
+        });
+
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.run().unwrap();
+
+        for qname in &["one.example.com", "two.example.com"] {
+            let response = client
+                .send_query_async(qname, QueryType::A, ("127.0.0.1", server_port), true, false)
+                .await
+                .unwrap();
+
+            assert_eq!(*qname, response.answers[0].get_domain().unwrap());
+        }
+
+        assert_eq!(2, client.get_sent_count());
+        assert_eq!(0, client.get_failed_count());
+        assert!(client.pending_queries.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answers_are_retried_over_tcp() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let server_port = listener.local_addr().unwrap().port();
+        let server = UdpSocket::bind(("127.0.0.1", server_port)).await.unwrap();
+
+        tokio::spawn(async move {
+            let mut req_buffer = BytePacketBuffer::new();
+            let (_, src) = server.recv_from(&mut req_buffer.buf).await.unwrap();
+            let request = DnsPacket::from_buffer(&mut req_buffer).unwrap();
+
+            let mut response = DnsPacket::new();
+            response.header.id = request.header.id;
+            response.header.response = true;
+            response.header.truncated_message = true;
+            response.questions = request.questions.clone();
+
+            let mut res_buffer = BytePacketBuffer::new();
+            response.write(&mut res_buffer, 512).unwrap();
+            server.send_to(&res_buffer.buf[0..res_buffer.pos], src).await.unwrap();
+        });
+        let _tcp = spawn_transfer_server(listener, vec![vec![example_a("www", 10)]]);
+
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.run().unwrap();
+
+        let response = client
+            .send_query_async("www.example.com", QueryType::A, ("127.0.0.1", server_port), true, false)
+            .await
+            .unwrap();
+
+        assert!(!response.header.truncated_message);
+        assert_eq!(vec![example_a("www", 10)], response.answers);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_shutdown() {
+        let shutdown = Shutdown::new();
+        let mut client = DnsNetworkClient::new_ephemeral().await.unwrap();
+        client.shutdown = Some(shutdown.subscribe());
+        client.run().unwrap();
+
+        shutdown.trigger();
+        // Only the client itself holds the socket once the receive loop is gone
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while Arc::strong_count(&client.socket) > 1 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn test_run_outside_runtime() {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let client = runtime.block_on(DnsNetworkClient::new_ephemeral()).unwrap();
+
+        assert!(matches!(client.run(), Err(ClientError::NoRuntime)));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_query_on_current_thread_runtime() {
+        let client = DnsNetworkClient::new_ephemeral().await.unwrap();
+
+        let result = client.send_query("www.example.com", QueryType::A, ("127.0.0.1", 53), true, false);
+
+        assert!(matches!(result, Err(ClientError::UnsupportedRuntime)));
+        assert_eq!(0, client.get_sent_count());
+    }
+}
@@ -0,0 +1,93 @@
+//! `TlsConnector` backed by rustls, used for DNS-over-TLS and DNS-over-HTTPS upstreams
+
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+use crate::client::network_client::{TlsConnector, TlsStream};
+
+/// Verifies upstream certificates against the Mozilla root store.
+#[derive(Clone)]
+pub struct RustlsConnector {
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl RustlsConnector {
+    pub fn new() -> RustlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+
+        Self::with_roots(roots)
+    }
+
+    /// Creates a connector trusting only the certificates in `roots`.
+    pub fn with_roots(roots: RootCertStore) -> RustlsConnector {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        RustlsConnector {
+            connector: tokio_rustls::TlsConnector::from(Arc::new(config)),
+        }
+    }
+}
+
+impl Default for RustlsConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TlsConnector for RustlsConnector {
+    async fn connect(&self, server_name: &str, stream: TcpStream) -> std::io::Result<Box<dyn TlsStream>> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+        let stream = self.connector.connect(server_name, stream).await?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_rejects_invalid_server_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        let result = RustlsConnector::new().connect("not a name", stream).await;
+        assert!(matches!(result, Err(err) if err.kind() == ErrorKind::InvalidInput));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_server_without_tls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Plays a plaintext server, which answers the ClientHello with garbage
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 512];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        assert!(RustlsConnector::new().connect("dns.example.net", stream).await.is_err());
+    }
+}
@@ -0,0 +1,726 @@
+//! Checks the RRSIGs over answers against DNSKEYs chaining to a trust anchor
+
+use chrono::Local;
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use tracing::debug;
+
+use crate::buffer::buffer::{BufferError, PacketBuffer, VectorPacketBuffer};
+use crate::protocols::protocol::{is_subdomain, same_name, DnsPacket, DnsRecord, QueryType, TransientTtl};
+
+pub const ALGORITHM_RSASHA256: u8 = 8;
+pub const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+
+/// DS digest type for SHA-256.
+pub const DIGEST_SHA256: u8 = 2;
+
+/// Set in the flags of DNSKEYs which may sign a zone's records.
+const ZONE_KEY_FLAG: u16 = 0x0100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationResult {
+    /// Every answer RRset has a signature from a key chaining to a trust anchor.
+    Secure,
+    /// Nothing could be validated, either because no trust anchor covers the name or
+    /// because the answer is a denial, whose NSEC records aren't checked.
+    Insecure,
+    /// Signatures are missing below a trust anchor, fail to verify, are outside their
+    /// validity period or come from keys that don't chain to a trust anchor.
+    Bogus,
+}
+
+/// Validates the records `packet` holds for `qname` at the current time.
+///
+/// The DNSKEYs needed, and the RRSIGs over them, are looked for in every section of
+/// the packet. `trust_anchors` holds the DS or DNSKEY records trusted up front.
+pub fn validate(packet: &DnsPacket, qname: &str, trust_anchors: &[DnsRecord]) -> ValidationResult {
+    validate_at(packet, qname, trust_anchors, Local::now().timestamp() as u32)
+}
+
+/// Validates the records `packet` holds for `qname` as if the time were `now`, in seconds since the epoch.
+///
+/// Answers are checked when there are any. Denials only get the SOA in their
+/// authority section checked, which is at best `Insecure` as it proves nothing.
+pub fn validate_at(packet: &DnsPacket, qname: &str, trust_anchors: &[DnsRecord], now: u32) -> ValidationResult {
+    let denial = packet.answers.is_empty();
+    let records = signed_section(packet);
+
+    if !records.iter().any(|rec| matches!(rec, DnsRecord::RRSIG { .. })) {
+        // Stripping the signatures mustn't be a way around validation
+        return if is_anchored(qname, trust_anchors) {
+            debug!(target: "dns", "No signatures for {} below a trust anchor", qname);
+            ValidationResult::Bogus
+        } else {
+            ValidationResult::Insecure
+        };
+    }
+
+    let keys = trusted_keys(packet, trust_anchors, now);
+    for rrset in rrsets(records) {
+        if denial && rrset[0].get_querytype() != QueryType::SOA {
+            continue;
+        }
+
+        if !verify_rrset(packet, &rrset, &keys, now) {
+            debug!(target: "dns", "No valid signature over {:?} {:?}", rrset[0].get_domain(), rrset[0].get_querytype());
+            return ValidationResult::Bogus;
+        }
+    }
+
+    if denial {
+        ValidationResult::Insecure
+    } else {
+        ValidationResult::Secure
+    }
+}
+
+/// The names of the zones whose signatures `packet` carries over its answers, or
+/// over its authority section for a denial.
+pub fn signers(packet: &DnsPacket) -> Vec<String> {
+    let mut signers: Vec<String> = Vec::new();
+    for rec in signed_section(packet) {
+        if let DnsRecord::RRSIG { ref signer_name, .. } = *rec {
+            if !signers.iter().any(|signer| same_name(signer, signer_name)) {
+                signers.push(signer_name.clone());
+            }
+        }
+    }
+    signers
+}
+
+/// The zone whose key signed the `rrtype` records `packet` answers with, if any did.
+pub fn signer_of(packet: &DnsPacket, rrtype: QueryType) -> Option<String> {
+    packet.answers.iter().find_map(|rec| match *rec {
+        DnsRecord::RRSIG {
+            type_covered,
+            ref signer_name,
+            ..
+        } if type_covered == rrtype.to_num() => Some(signer_name.clone()),
+        _ => None,
+    })
+}
+
+/// Whether one of `trust_anchors` is for `zone` itself.
+pub fn has_anchor_for(zone: &str, trust_anchors: &[DnsRecord]) -> bool {
+    trust_anchors
+        .iter()
+        .any(|anchor| anchor.matches_name(zone))
+}
+
+/// Whether `qname` is within the zone of one of `trust_anchors`.
+fn is_anchored(qname: &str, trust_anchors: &[DnsRecord]) -> bool {
+    trust_anchors
+        .iter()
+        .any(|anchor| anchor.matches_suffix(qname))
+}
+
+fn signed_section(packet: &DnsPacket) -> &[DnsRecord] {
+    if packet.answers.is_empty() {
+        &packet.authorities
+    } else {
+        &packet.answers
+    }
+}
+
+/// Groups `records` into RRsets, leaving out the signatures.
+fn rrsets(records: &[DnsRecord]) -> Vec<Vec<&DnsRecord>> {
+    let mut sets: Vec<Vec<&DnsRecord>> = Vec::new();
+    for rec in records {
+        if matches!(rec, DnsRecord::RRSIG { .. }) {
+            continue;
+        }
+
+        match sets.iter_mut().find(|set| same_rrset(set[0], rec)) {
+            Some(set) => set.push(rec),
+            None => sets.push(vec![rec]),
+        }
+    }
+    sets
+}
+
+fn same_rrset(a: &DnsRecord, b: &DnsRecord) -> bool {
+    a.get_querytype() == b.get_querytype() && same_name(&a.get_domain().unwrap_or_default(), &b.get_domain().unwrap_or_default())
+}
+
+fn all_records(packet: &DnsPacket) -> impl Iterator<Item = &DnsRecord> {
+    packet
+        .answers
+        .iter()
+        .chain(packet.authorities.iter())
+        .chain(packet.resources.iter())
+}
+
+/// The DNSKEYs in `packet` which chain to one of `trust_anchors`.
+///
+/// That's the keys matching an anchor, and the rest of the DNSKEY RRset of their
+/// zone once one of those keys is found to have signed it.
+fn trusted_keys<'a>(packet: &'a DnsPacket, trust_anchors: &[DnsRecord], now: u32) -> Vec<&'a DnsRecord> {
+    let keys = all_records(packet)
+        .filter(|rec| matches!(rec, DnsRecord::DNSKEY { .. }))
+        .collect::<Vec<&DnsRecord>>();
+
+    let mut trusted = keys
+        .iter()
+        .copied()
+        .filter(|key| trust_anchors.iter().any(|anchor| anchor_matches(anchor, key)))
+        .collect::<Vec<&DnsRecord>>();
+
+    let anchored = trusted.clone();
+    for key in anchored {
+        let rrset = keys
+            .iter()
+            .copied()
+            .filter(|other| same_rrset(key, other))
+            .collect::<Vec<&DnsRecord>>();
+
+        if verify_rrset(packet, &rrset, &trusted, now) {
+            for other in rrset {
+                if !trusted.contains(&other) {
+                    trusted.push(other);
+                }
+            }
+        }
+    }
+
+    trusted
+}
+
+fn anchor_matches(anchor: &DnsRecord, key: &DnsRecord) -> bool {
+    match *anchor {
+        DnsRecord::DNSKEY { .. } => {
+            same_rrset(anchor, key) && anchor.rdata_to_string() == key.rdata_to_string()
+        }
+        DnsRecord::DS {
+            ref domain,
+            key_tag,
+            algorithm,
+            digest_type,
+            ref digest,
+            ..
+        } => {
+            let (key_domain, key_algorithm) = match *key {
+                DnsRecord::DNSKEY { ref domain, algorithm, .. } => (domain, algorithm),
+                _ => return false,
+            };
+            if !key.matches_name(domain) || algorithm != key_algorithm || digest_type != DIGEST_SHA256 {
+                return false;
+            }
+
+            // The digest covers the key's owner name followed by its rdata
+            match canonical_record(key, key_domain, 0) {
+                Some((bytes, rdata_start)) => {
+                    let mut data = bytes[..rdata_start - 10].to_vec();
+                    data.extend_from_slice(&bytes[rdata_start..]);
+                    compute_key_tag(&bytes[rdata_start..]) == key_tag
+                        && digest::digest(&digest::SHA256, &data).as_ref() == &digest[..]
+                }
+                None => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Whether one of the RRSIGs in `packet` over `rrset` verifies with one of `keys`.
+fn verify_rrset(packet: &DnsPacket, rrset: &[&DnsRecord], keys: &[&DnsRecord], now: u32) -> bool {
+    let owner = rrset[0].get_domain().unwrap_or_default();
+    let qtype = rrset[0].get_querytype().to_num();
+    let owner_labels = label_count(&owner);
+
+    all_records(packet).any(|rrsig| match *rrsig {
+        DnsRecord::RRSIG {
+            type_covered,
+            algorithm,
+            labels,
+            expiration,
+            inception,
+            key_tag,
+            ref signer_name,
+            ref signature,
+            ..
+        } => {
+            if !rrsig.matches_name(&owner) || type_covered != qtype {
+                return false;
+            }
+            if now < inception || now > expiration || labels as usize > owner_labels || !is_subdomain(&owner, signer_name) {
+                return false;
+            }
+
+            let data = match signed_data(rrsig, rrset) {
+                Some(data) => data,
+                None => return false,
+            };
+
+            keys.iter().any(|key| match **key {
+                DnsRecord::DNSKEY {
+                    flags,
+                    algorithm: key_algorithm,
+                    ref public_key,
+                    ..
+                } => {
+                    key.matches_name(signer_name)
+                        && flags & ZONE_KEY_FLAG != 0
+                        && key_algorithm == algorithm
+                        && key_tag_of(key) == Some(key_tag)
+                        && verify_signature(algorithm, public_key, &data, signature)
+                }
+                _ => false,
+            })
+        }
+        _ => false,
+    })
+}
+
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match algorithm {
+        // Plenty of zones are still signed with 1024 bit keys
+        ALGORITHM_RSASHA256 => match rsa_components(public_key) {
+            Some((e, n)) => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, data, signature)
+                .is_ok(),
+            None => false,
+        },
+        ALGORITHM_ECDSAP256SHA256 => {
+            // DNSKEYs hold the point as `x | y`, leaving out the uncompressed point prefix
+            if public_key.len() != 64 {
+                return false;
+            }
+            let mut point = Vec::with_capacity(65);
+            point.push(0x04);
+            point.extend_from_slice(public_key);
+
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(data, signature)
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Splits an RSA key in the RFC 3110 format into its exponent and modulus.
+fn rsa_components(public_key: &[u8]) -> Option<(&[u8], &[u8])> {
+    match public_key.split_first()? {
+        (0, rest) if rest.len() >= 2 => {
+            let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+            rest[2..].split_at_checked(len)
+        }
+        (&len, rest) => rest.split_at_checked(len as usize),
+    }
+}
+
+fn label_count(name: &str) -> usize {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .count()
+}
+
+/// The data an RRSIG signs: its own rdata up to the signature, followed by the
+/// records of the RRset in canonical form and order, as RFC 4034 describes.
+fn signed_data(rrsig: &DnsRecord, rrset: &[&DnsRecord]) -> Option<Vec<u8>> {
+    let (labels, original_ttl, signature_len) = match *rrsig {
+        DnsRecord::RRSIG {
+            labels,
+            original_ttl,
+            ref signature,
+            ..
+        } => (labels, original_ttl, signature.len()),
+        _ => return None,
+    };
+
+    // The rdata written for the RRSIG itself, minus the signature at its end
+    let mut rrsig = rrsig.clone();
+    if let DnsRecord::RRSIG { ref mut signer_name, .. } = rrsig {
+        *signer_name = signer_name.to_lowercase();
+    }
+    let (bytes, rdata_start) = canonical_record(&rrsig, &rrsig.get_domain()?, 0)?;
+    let mut data = bytes[rdata_start..bytes.len() - signature_len].to_vec();
+
+    // Records expanded from a wildcard are signed with the wildcard as owner
+    let owner = rrset[0].get_domain()?;
+    let owner_labels = owner.trim_end_matches('.').split('.').collect::<Vec<&str>>();
+    let owner = if (labels as usize) < label_count(&owner) {
+        format!("*.{}", owner_labels[owner_labels.len() - labels as usize..].join("."))
+    } else {
+        owner
+    };
+
+    let mut records = rrset
+        .iter()
+        .map(|rec| canonical_record(rec, &owner, original_ttl))
+        .collect::<Option<Vec<(Vec<u8>, usize)>>>()?;
+    records.sort_by(|a, b| a.0[a.1..].cmp(&b.0[b.1..]));
+    records.dedup_by(|a, b| a.0 == b.0);
+
+    for (bytes, _) in records {
+        data.extend(bytes);
+    }
+    Some(data)
+}
+
+/// Writes `record` in canonical form, with `owner` and `ttl` in place of its own.
+///
+/// Returns the bytes along with where the rdata starts in them.
+fn canonical_record(record: &DnsRecord, owner: &str, ttl: u32) -> Option<(Vec<u8>, usize)> {
+    let mut record = record.clone();
+    *record.domain_mut()? = owner.to_lowercase();
+    *record.ttl_mut()? = TransientTtl(ttl);
+
+    let mut buffer = CanonicalBuffer(VectorPacketBuffer::new());
+    record.write(&mut buffer).ok()?;
+
+    let owner = owner.trim_end_matches('.');
+    let owner_len = if owner.is_empty() { 1 } else { owner.len() + 2 };
+    let bytes = buffer.0.buffer;
+    if bytes.len() < owner_len + 10 {
+        return None;
+    }
+
+    Some((bytes, owner_len + 10))
+}
+
+/// The key tag of a DNSKEY, as RRSIG and DS records refer to it.
+pub fn key_tag_of(key: &DnsRecord) -> Option<u16> {
+    let (bytes, rdata_start) = canonical_record(key, &key.get_domain()?, 0)?;
+    match key.get_querytype() {
+        QueryType::DNSKEY => Some(compute_key_tag(&bytes[rdata_start..])),
+        _ => None,
+    }
+}
+
+/// The checksum of RFC 4034 appendix B, over the DNSKEY rdata.
+fn compute_key_tag(rdata: &[u8]) -> u16 {
+    let mut acc = rdata.iter().enumerate().fold(0u32, |acc, (i, byte)| {
+        acc + if i % 2 == 0 { (*byte as u32) << 8 } else { *byte as u32 }
+    });
+    acc += (acc >> 16) & 0xFFFF;
+    acc as u16
+}
+
+/// Writes every name in full, as canonical form requires.
+struct CanonicalBuffer(VectorPacketBuffer);
+
+type BufferResult<T> = std::result::Result<T, BufferError>;
+
+impl PacketBuffer for CanonicalBuffer {
+    fn read(&mut self) -> BufferResult<u8> {
+        self.0.read()
+    }
+
+    fn get(&mut self, pos: usize) -> BufferResult<u8> {
+        self.0.get(pos)
+    }
+
+    fn get_range(&mut self, start: usize, len: usize) -> BufferResult<&[u8]> {
+        self.0.get_range(start, len)
+    }
+
+    fn write(&mut self, val: u8) -> BufferResult<()> {
+        self.0.write(val)
+    }
+
+    fn set(&mut self, pos: usize, val: u8) -> BufferResult<()> {
+        self.0.set(pos, val)
+    }
+
+    fn pos(&self) -> usize {
+        self.0.pos()
+    }
+
+    fn seek(&mut self, pos: usize) -> BufferResult<()> {
+        self.0.seek(pos)
+    }
+
+    fn step(&mut self, steps: usize) -> BufferResult<()> {
+        self.0.step(steps)
+    }
+
+    fn find_label(&self, _: &str) -> Option<usize> {
+        None
+    }
+
+    fn save_label(&mut self, _: &str, _: usize) {}
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    use super::*;
+
+    // Signed with throwaway keys, valid from 2020-01-01 to 2030-01-01
+    const INCEPTION: u32 = 1577836800;
+    const EXPIRATION: u32 = 1893456000;
+    const NOW: u32 = 1700000000;
+
+    const EC_PUBLIC_KEY: &str = concat!(
+        "ed43fdc1085e590b81b50a8753c46e810dade19925cf991facdb7ce8dcf90485",
+        "00e34c5b56de8e88f95ca61b0747865eefa54571ef73c6b3552e3e8986cc8468",
+    );
+    const EC_KEY_TAG: u16 = 16999;
+    const EC_A_SIGNATURE: &str = concat!(
+        "6a3b8f0342774733da3f1834ecaca3f6dd04c0e59dc271c4dd222a850ab0cfdb",
+        "ab1478a9ecbbeaa8705987d6a3e8878f51704bcba0235a777b1dab775acdaf6e",
+    );
+    const EC_DNSKEY_SIGNATURE: &str = concat!(
+        "b87d68533fd1cd4914fcd804a0cc03e245c807fd95fb548e46c312b98b458e3e",
+        "bc198c224f99888589e22d40167fe030d45b3ab0d5a3e27e4c06e07a068cf533",
+    );
+    const EC_DS_DIGEST: &str = "fda395990d9e717edccabe8305a8c063ba5f0fc82cf5a8aed685a2db446b459b";
+    const RSA_PUBLIC_KEY: &str = concat!(
+        "0301000192cea46046a55c5b50430f330a0860889e88df508b0db5d18988ea64",
+        "c4170a7b1edb7bc7c1e114979f44779c0f976ffd4b4bdc2b372ae1b7f4f7b431",
+        "ba3b3d96ee39eb2ad01fc3f85158399aa6c6a227eda2f560b46b038fc4c10966",
+        "d9faf8918e03458d6f350a26a2ffebcc8a1392aa058e1305f44621ef709970b4",
+        "beba97f970cc206378e4c8ae374832808cd6db71135b1377991110a0e6e85eb1",
+        "45cc0ba3985fee3c2d6aa2f5757559feb5747b4ae80232163b19c6ee82c61500",
+        "65d4f6dcfe282b4642b24c061ef26e87bc52c2ba0c6331ce0df11637a80d72e0",
+        "af8267fdfe18fd67c273cbacacdc2a60f18e15a6db3f666365cd83ae3d88f766",
+        "ed6dc64d",
+    );
+    const RSA_KEY_TAG: u16 = 47921;
+    const RSA_A_SIGNATURE: &str = concat!(
+        "728c2ae8919e204adef4721bfbae096344aebd44b755728ddd311f1971c62e7d",
+        "bf53c0c725e6f8060ecb602e78a05c4d9cc41d6e37fcd0d2c43b11377be294d9",
+        "cf6fe865e44e99050de40cf5149491dc39769dfe4b8206ac62816ee40fcf8767",
+        "29a3498b3f7e7fdb9afc7875d2b1d5d7d2165e0f9ffa85d7b9fd10da19a801e5",
+        "2d5416a36af669eb52a1fd23ae1a91ddb0e0f8d57b32d80edfcb904bcc7a5fd0",
+        "9eb868cf9b008e046a19601586536675c99e177a3b6bb43c72ba6046d8003212",
+        "3c11f94eb90e5bbe7b1ad3fd7791bed22d968fe245c2bff8e61848d90e8c727b",
+        "94e3c67080bd31007d6b4dd2bea85e693f7c0785420ce8bfcfac66e9c3322142",
+    );
+
+    fn unhex(data: &str) -> Vec<u8> {
+        (0..data.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&data[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn address(addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: "www.example.com".to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    fn dnskey(algorithm: u8, public_key: &str) -> DnsRecord {
+        DnsRecord::DNSKEY {
+            domain: "example.com".to_string(),
+            flags: 257,
+            protocol: 3,
+            algorithm,
+            public_key: unhex(public_key),
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    fn rrsig(domain: &str, type_covered: u16, algorithm: u8, key_tag: u16, signature: &str) -> DnsRecord {
+        DnsRecord::RRSIG {
+            domain: domain.to_string(),
+            type_covered,
+            algorithm,
+            labels: domain.split('.').count() as u8,
+            original_ttl: 3600,
+            expiration: EXPIRATION,
+            inception: INCEPTION,
+            key_tag,
+            signer_name: "example.com".to_string(),
+            signature: unhex(signature),
+            ttl: TransientTtl(3600),
+        }
+    }
+
+    fn ds_anchor() -> DnsRecord {
+        DnsRecord::DS {
+            domain: "example.com".to_string(),
+            key_tag: EC_KEY_TAG,
+            algorithm: ALGORITHM_ECDSAP256SHA256,
+            digest_type: DIGEST_SHA256,
+            digest: unhex(EC_DS_DIGEST),
+            ttl: TransientTtl(86400),
+        }
+    }
+
+    fn signed_ecdsa_packet(addr: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(address(addr));
+        packet.answers.push(rrsig("www.example.com", 1, ALGORITHM_ECDSAP256SHA256, EC_KEY_TAG, EC_A_SIGNATURE));
+        packet.resources.push(dnskey(ALGORITHM_ECDSAP256SHA256, EC_PUBLIC_KEY));
+        packet.resources.push(rrsig("example.com", 48, ALGORITHM_ECDSAP256SHA256, EC_KEY_TAG, EC_DNSKEY_SIGNATURE));
+        packet
+    }
+
+    #[test]
+    fn test_key_tag() {
+        assert_eq!(Some(EC_KEY_TAG), key_tag_of(&dnskey(ALGORITHM_ECDSAP256SHA256, EC_PUBLIC_KEY)));
+        assert_eq!(Some(RSA_KEY_TAG), key_tag_of(&dnskey(ALGORITHM_RSASHA256, RSA_PUBLIC_KEY)));
+    }
+
+    #[test]
+    fn test_ecdsa_rrset_chaining_to_ds_anchor() {
+        let packet = signed_ecdsa_packet("192.0.2.1");
+        assert_eq!(ValidationResult::Secure, validate_at(&packet, "www.example.com", &[ds_anchor()], NOW));
+
+        // Without an anchor nothing vouches for the key
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &[], NOW));
+
+        // Signatures are only good within their validity period
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &[ds_anchor()], EXPIRATION + 1));
+    }
+
+    #[test]
+    fn test_tampered_rrset_is_bogus() {
+        let packet = signed_ecdsa_packet("192.0.2.2");
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &[ds_anchor()], NOW));
+
+        let mut packet = signed_ecdsa_packet("192.0.2.1");
+        if let DnsRecord::RRSIG { ref mut signature, .. } = packet.answers[1] {
+            signature[10] ^= 1;
+        }
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &[ds_anchor()], NOW));
+    }
+
+    #[test]
+    fn test_rsa_rrset_chaining_to_dnskey_anchor() {
+        let anchors = [dnskey(ALGORITHM_RSASHA256, RSA_PUBLIC_KEY)];
+
+        let mut packet = DnsPacket::new();
+        packet.answers.push(address("192.0.2.1"));
+        packet.answers.push(rrsig("www.example.com", 1, ALGORITHM_RSASHA256, RSA_KEY_TAG, RSA_A_SIGNATURE));
+        packet.resources.push(anchors[0].clone());
+        assert_eq!(ValidationResult::Secure, validate_at(&packet, "www.example.com", &anchors, NOW));
+
+        packet.answers[0] = address("192.0.2.2");
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &anchors, NOW));
+    }
+
+    #[test]
+    fn test_unsigned_answers_below_an_anchor_are_bogus() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(address("192.0.2.1"));
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "www.example.com", &[ds_anchor()], NOW));
+
+        // Names no anchor covers can't be validated either way
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "www.example.org".to_string(),
+            addr: "192.0.2.1".parse().unwrap(),
+            ttl: TransientTtl(3600),
+        });
+        assert_eq!(ValidationResult::Insecure, validate_at(&packet, "www.example.org", &[ds_anchor()], NOW));
+    }
+
+    #[test]
+    fn test_denials_check_their_soa() {
+        let zone = TestZone::new("example.com");
+        let soa = DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "hostmaster.example.com".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+            ttl: TransientTtl(300),
+        };
+
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(soa.clone());
+        packet.authorities.push(zone.sign(std::slice::from_ref(&soa)));
+        packet.resources.push(zone.dnskey.clone());
+        packet.resources.push(zone.sign(std::slice::from_ref(&zone.dnskey)));
+
+        // What doesn't exist isn't proven, so a denial is never secure
+        let anchors = [zone.ds()];
+        assert_eq!(ValidationResult::Insecure, validate_at(&packet, "nope.example.com", &anchors, NOW));
+
+        if let DnsRecord::RRSIG { ref mut signature, .. } = packet.authorities[1] {
+            signature[0] ^= 1;
+        }
+        assert_eq!(ValidationResult::Bogus, validate_at(&packet, "nope.example.com", &anchors, NOW));
+    }
+
+    #[test]
+    fn test_signers() {
+        let packet = signed_ecdsa_packet("192.0.2.1");
+        assert_eq!(vec!["example.com".to_string()], signers(&packet));
+        assert_eq!(None, signer_of(&packet, QueryType::AAAA));
+        assert_eq!(Some("example.com".to_string()), signer_of(&packet, QueryType::A));
+    }
+
+    /// A zone with a freshly generated ECDSA key, to sign test records with.
+    pub struct TestZone {
+        pub name: String,
+        pub dnskey: DnsRecord,
+        key: EcdsaKeyPair,
+    }
+
+    impl TestZone {
+        pub fn new(name: &str) -> TestZone {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+
+            let dnskey = DnsRecord::DNSKEY {
+                domain: name.to_string(),
+                flags: 257,
+                protocol: 3,
+                algorithm: ALGORITHM_ECDSAP256SHA256,
+                public_key: key.public_key().as_ref()[1..].to_vec(),
+                ttl: TransientTtl(3600),
+            };
+
+            TestZone {
+                name: name.to_string(),
+                dnskey,
+                key,
+            }
+        }
+
+        /// An RRSIG over `rrset`, valid from `INCEPTION` on.
+        pub fn sign(&self, rrset: &[DnsRecord]) -> DnsRecord {
+            let owner = rrset[0].get_domain().unwrap();
+            let mut rrsig = DnsRecord::RRSIG {
+                domain: owner.clone(),
+                type_covered: rrset[0].get_querytype().to_num(),
+                algorithm: ALGORITHM_ECDSAP256SHA256,
+                labels: label_count(&owner) as u8,
+                original_ttl: rrset[0].get_ttl(),
+                expiration: u32::MAX,
+                inception: INCEPTION,
+                key_tag: key_tag_of(&self.dnskey).unwrap(),
+                signer_name: self.name.clone(),
+                signature: Vec::new(),
+                ttl: TransientTtl(rrset[0].get_ttl()),
+            };
+
+            let data = signed_data(&rrsig, &rrset.iter().collect::<Vec<&DnsRecord>>()).unwrap();
+            let signed = self.key.sign(&SystemRandom::new(), &data).unwrap();
+            if let DnsRecord::RRSIG { ref mut signature, .. } = rrsig {
+                *signature = signed.as_ref().to_vec();
+            }
+            rrsig
+        }
+
+        /// The DS record of the zone's key, as its parent would publish it.
+        pub fn ds(&self) -> DnsRecord {
+            let (bytes, rdata_start) = canonical_record(&self.dnskey, &self.name, 0).unwrap();
+            let mut data = bytes[..rdata_start - 10].to_vec();
+            data.extend_from_slice(&bytes[rdata_start..]);
+
+            DnsRecord::DS {
+                domain: self.name.clone(),
+                key_tag: key_tag_of(&self.dnskey).unwrap(),
+                algorithm: ALGORITHM_ECDSAP256SHA256,
+                digest_type: DIGEST_SHA256,
+                digest: digest::digest(&digest::SHA256, &data).as_ref().to_vec(),
+                ttl: TransientTtl(3600),
+            }
+        }
+    }
+}
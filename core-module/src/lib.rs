@@ -7,3 +7,5 @@ pub mod resolvers;
 pub mod server;
 pub mod authorities;
 pub mod network_utilities;
+#[cfg(feature = "dnssec")]
+pub mod dnssec;
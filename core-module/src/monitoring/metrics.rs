@@ -0,0 +1,183 @@
+//! Exports the server counters in the Prometheus text exposition format.
+
+use std::fmt::Write;
+
+use crate::cache::memory_cache::SynchronizedCache;
+use crate::protocols::protocol::QueryType;
+use crate::server::context::{ServerContext, ServerStatistics};
+
+/// Appends a single metric along with its `# HELP` and `# TYPE` lines.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: usize) {
+    // Writing into a String can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Label value for a query type, using the RFC 3597 `TYPEnnn` form for unknown ones.
+fn qtype_label(qtype: QueryType) -> String {
+    match qtype {
+        QueryType::UNKNOWN(num) => format!("TYPE{}", num),
+        _ => format!("{:?}", qtype),
+    }
+}
+
+/// Renders the query and cache counters for scraping.
+pub fn render_metrics(stats: &ServerStatistics, cache: &SynchronizedCache) -> String {
+    let mut out = String::new();
+    let cache_stats = cache.stats();
+
+    write_metric(
+        &mut out,
+        "dns_udp_queries_total",
+        "counter",
+        "Queries received over UDP.",
+        stats.get_udp_query_count(),
+    );
+    write_metric(
+        &mut out,
+        "dns_tcp_queries_total",
+        "counter",
+        "Queries received over TCP.",
+        stats.get_tcp_query_count(),
+    );
+
+    let mut by_type = stats
+        .query_type_counts()
+        .into_iter()
+        .map(|(qtype, count)| (qtype_label(qtype), count))
+        .collect::<Vec<(String, usize)>>();
+    by_type.sort();
+
+    let _ = writeln!(out, "# HELP dns_queries_total Queries resolved, by query type.");
+    let _ = writeln!(out, "# TYPE dns_queries_total counter");
+    for (qtype, count) in by_type {
+        let _ = writeln!(out, "dns_queries_total{{qtype=\"{}\"}} {}", qtype, count);
+    }
+
+    write_metric(
+        &mut out,
+        "dns_cache_hits_total",
+        "counter",
+        "Cache lookups answered from the cache.",
+        cache_stats.hits,
+    );
+    write_metric(
+        &mut out,
+        "dns_cache_misses_total",
+        "counter",
+        "Cache lookups that found nothing.",
+        cache_stats.misses,
+    );
+    write_metric(
+        &mut out,
+        "dns_cache_entries",
+        "gauge",
+        "Domains currently held in the cache.",
+        cache_stats.entries,
+    );
+
+    out
+}
+
+/// Renders the query, cache and upstream counters of `context` for scraping.
+pub fn export_prometheus(context: &ServerContext) -> String {
+    let mut out = render_metrics(&context.statistics, &context.cache);
+
+    write_metric(
+        &mut out,
+        "dns_upstream_queries_total",
+        "counter",
+        "Queries sent to upstream servers.",
+        context.client.get_sent_count(),
+    );
+    write_metric(
+        &mut out,
+        "dns_upstream_failures_total",
+        "counter",
+        "Upstream queries that failed.",
+        context.client.get_failed_count(),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use dashmap::DashMap;
+
+    use super::*;
+
+    use crate::protocols::protocol::{DnsPacket, DnsRecord, TransientTtl};
+    use crate::server::context::tests::create_test_context;
+
+    #[test]
+    fn test_export_contains_expected_metrics() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+
+        context.statistics.udp_query_count.fetch_add(3, Ordering::Release);
+        context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
+
+        context
+            .cache
+            .store(&[DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "127.0.0.1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            }])
+            .unwrap();
+        assert!(context.cache.lookup("example.com", QueryType::A).is_some());
+        assert!(context.cache.lookup("missing.example.com", QueryType::A).is_none());
+
+        let output = export_prometheus(&context);
+
+        assert!(output.contains("# TYPE dns_udp_queries_total counter\n"));
+        assert!(output.contains("dns_udp_queries_total 3\n"));
+        assert!(output.contains("dns_tcp_queries_total 1\n"));
+        assert!(output.contains("dns_cache_hits_total 1\n"));
+        assert!(output.contains("dns_cache_misses_total 1\n"));
+        assert!(output.contains("# TYPE dns_cache_entries gauge\n"));
+        assert!(output.contains("dns_cache_entries 1\n"));
+        assert!(output.contains("dns_upstream_queries_total 0\n"));
+        assert!(output.contains("dns_upstream_failures_total 0\n"));
+    }
+
+    #[test]
+    fn test_render_metrics_from_statistics_and_cache() {
+        let stats = ServerStatistics {
+            tcp_query_count: AtomicUsize::new(2),
+            udp_query_count: AtomicUsize::new(40),
+            query_type_count: DashMap::new(),
+        };
+        stats.record_query(QueryType::A);
+        stats.record_query(QueryType::A);
+        stats.record_query(QueryType::MX);
+        stats.record_query(QueryType::UNKNOWN(99));
+
+        let cache = SynchronizedCache::new();
+        cache
+            .store(&[DnsRecord::AAAA {
+                domain: "example.com".to_string(),
+                addr: "::1".parse().unwrap(),
+                ttl: TransientTtl(3600),
+            }])
+            .unwrap();
+        assert!(cache.lookup("missing.example.com", QueryType::AAAA).is_none());
+
+        let output = render_metrics(&stats, &cache);
+
+        assert!(output.contains("dns_udp_queries_total 40\n"));
+        assert!(output.contains("dns_tcp_queries_total 2\n"));
+        assert!(output.contains("# TYPE dns_queries_total counter\n"));
+        assert!(output.contains("dns_queries_total{qtype=\"A\"} 2\n"));
+        assert!(output.contains("dns_queries_total{qtype=\"MX\"} 1\n"));
+        assert!(output.contains("dns_queries_total{qtype=\"TYPE99\"} 1\n"));
+        assert!(output.contains("dns_cache_entries 1\n"));
+        assert!(output.contains("dns_cache_hits_total 0\n"));
+        assert!(output.contains("dns_cache_misses_total 1\n"));
+        assert!(!output.contains("dns_upstream_queries_total"));
+    }
+}
@@ -1,9 +1,27 @@
 //! Contains the network utilities
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::net::IpAddr;
 
-pub async fn read_packet_length(stream: &mut TcpStream) -> Result<u16, std::io::Error> {
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Joins `host` and `port` into an address, bracketing IPv6 literals such as `[::1]:53`.
+pub fn format_address(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(addr)) => format!("[{}]:{}", addr, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+/// Returns whether `host` is an IPv6 literal, with or without brackets.
+pub fn is_ipv6_literal(host: &str) -> bool {
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    matches!(host.parse::<IpAddr>(), Ok(IpAddr::V6(_)))
+}
+
+pub async fn read_packet_length<S>(stream: &mut S) -> Result<u16, std::io::Error>
+where
+    S: AsyncRead + Unpin,
+{
     let mut len_buffer = [0; 2];
     stream.read_exact(&mut len_buffer).await?;
     let length = ((len_buffer[0] as u16) << 8) | (len_buffer[1] as u16);
@@ -11,7 +29,10 @@ pub async fn read_packet_length(stream: &mut TcpStream) -> Result<u16, std::io::
     Ok(length)
 }
 
-pub async fn write_packet_length(stream: &mut TcpStream, len: usize) -> Result<(), std::io::Error> {
+pub async fn write_packet_length<S>(stream: &mut S, len: usize) -> Result<(), std::io::Error>
+where
+    S: AsyncWrite + Unpin,
+{
     let mut len_buffer = [0; 2];
     len_buffer[0] = (len >> 8) as u8;
     len_buffer[1] = (len & 0xFF) as u8;
@@ -3,12 +3,14 @@
 
 use std::fmt;
 use std::cmp::Ordering;
+use std::collections::HashSet;
 use std::hash::{Hash, Hasher};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
 use derive_more::{Display, Error, From};
 use serde_derive::{Deserialize, Serialize};
 use crate::buffer::buffer;
-use crate::buffer::buffer::{PacketBuffer, VectorPacketBuffer};
+use crate::buffer::buffer::PacketBuffer;
 
 
 #[derive(Debug, Display, From, Error)]
@@ -33,16 +35,50 @@ pub enum QueryType {
     CNAME, // 5
     /// Start of authority record query.
     SOA, // 6
+    /// Domain name pointer, used for reverse lookups
+    PTR, // 12
+    /// Host information
+    HINFO, // 13
     /// Mail exchange record query.
     MX, // 15
     /// Text record query.
     TXT, //16
     /// IPv6 address query
     AAAA, // 28
+    /// Geographical location
+    LOC, // 29
     /// Service locator record query
     SRV, // 33
+    /// Naming authority pointer, used by ENUM and SIP
+    NAPTR, // 35
+    /// Certificate or certificate revocation list
+    CERT, // 37
     /// Options for extended DNS packets
     OPT, // 41
+    /// Address prefix list
+    APL, // 42
+    /// Delegation signer, the digest of a child zone's key
+    DS, // 43
+    /// Fingerprint of an SSH host key
+    SSHFP, // 44
+    /// Signature over an RRset
+    RRSIG, // 46
+    /// Public key of a DNSSEC signed zone
+    DNSKEY, // 48
+    /// S/MIME certificate association
+    SMIMEA, // 53
+    /// General purpose service binding
+    SVCB, // 64
+    /// Service binding for HTTPS origins
+    HTTPS, // 65
+    /// Request for an incremental zone transfer
+    IXFR, // 251
+    /// Request for a full zone transfer
+    AXFR, // 252
+    /// Request for all records held for a name
+    ANY, // 255
+    /// Uniform resource identifier for a service
+    URI, // 256
 }
 
 
@@ -51,6 +87,7 @@ impl QueryType {
     ///
     /// # Examples
     /// ```
+    /// # use core_module::protocols::protocol::QueryType;
     /// let query_type = QueryType::A;
     /// assert_eq!(query_type.to_num(), 1);
     /// ```
@@ -61,11 +98,28 @@ impl QueryType {
            QueryType::NS => 2, 
            QueryType::CNAME => 5,
            QueryType::SOA => 6,
+           QueryType::PTR => 12,
+           QueryType::HINFO => 13,
            QueryType::MX => 15,
            QueryType::TXT => 16,
            QueryType::AAAA => 28,
+           QueryType::LOC => 29,
            QueryType::SRV => 33,
+           QueryType::NAPTR => 35,
+           QueryType::CERT => 37,
            QueryType::OPT => 41,
+           QueryType::APL => 42,
+           QueryType::DS => 43,
+           QueryType::SSHFP => 44,
+           QueryType::RRSIG => 46,
+           QueryType::DNSKEY => 48,
+           QueryType::SMIMEA => 53,
+           QueryType::SVCB => 64,
+           QueryType::HTTPS => 65,
+           QueryType::IXFR => 251,
+           QueryType::AXFR => 252,
+           QueryType::ANY => 255,
+           QueryType::URI => 256,
         }
     }
 
@@ -73,6 +127,7 @@ impl QueryType {
     ///
     /// # Examples
     /// ```
+    /// # use core_module::protocols::protocol::QueryType;
     /// let query_type = QueryType::from_num(15);
     /// assert_eq!(query_type, QueryType::MX);
     /// ```
@@ -82,18 +137,35 @@ impl QueryType {
             2 => QueryType::NS,
             5 => QueryType::CNAME,
             6 => QueryType::SOA,
+            12 => QueryType::PTR,
+            13 => QueryType::HINFO,
             15 => QueryType::MX,
             16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            29 => QueryType::LOC,
             33 => QueryType::SRV,
+            35 => QueryType::NAPTR,
+            37 => QueryType::CERT,
             41 => QueryType::OPT,
+            42 => QueryType::APL,
+            43 => QueryType::DS,
+            44 => QueryType::SSHFP,
+            46 => QueryType::RRSIG,
+            48 => QueryType::DNSKEY,
+            53 => QueryType::SMIMEA,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            251 => QueryType::IXFR,
+            252 => QueryType::AXFR,
+            255 => QueryType::ANY,
+            256 => QueryType::URI,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
 
 
-#[derive(Copy, Clone, Debug, Eq, Ord, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, Eq, Serialize, Deserialize)]
 pub struct TransientTtl(pub u32);
 
 impl PartialEq for TransientTtl {
@@ -103,8 +175,14 @@ impl PartialEq for TransientTtl {
 }
 
 impl PartialOrd for TransientTtl {
-    fn partial_cmp(&self, _: &Self) -> Option<Ordering> {
-        Some(Ordering::Equal)
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TransientTtl {
+    fn cmp(&self, _: &Self) -> Ordering {
+        Ordering::Equal
     }
 }
 
@@ -128,6 +206,296 @@ impl TransientTtl {
 }
 
 
+/// A single address prefix of an APL record, as described in RFC 3123.
+///
+/// The address is kept as sent on the wire, with trailing zero bytes omitted.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct AplItem {
+    /// IANA address family, 1 for IPv4 and 2 for IPv6.
+    pub family: u16,
+    pub prefix: u8,
+    pub negation: bool,
+    pub address: Vec<u8>,
+}
+
+/// Builds the name under which PTR records for `ip` are published.
+///
+/// IPv4 addresses map to their octets in reverse under `in-addr.arpa`, IPv6
+/// addresses to their nibbles in reverse under `ip6.arpa`.
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(addr) => {
+            let octets = addr.octets();
+            format!(
+                "{}.{}.{}.{}.in-addr.arpa",
+                octets[3], octets[2], octets[1], octets[0]
+            )
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::with_capacity(72);
+            for octet in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0F, octet >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+/// Reads one length prefixed character-string, without running past the rdata.
+fn read_character_string<T: PacketBuffer>(buffer: &mut T, end_pos: usize) -> Result<String> {
+    if buffer.pos() >= end_pos {
+        return Ok(String::new());
+    }
+
+    let len = (buffer.read()? as usize).min(end_pos.saturating_sub(buffer.pos()));
+    let cur_pos = buffer.pos();
+    let value = String::from_utf8_lossy(buffer.get_range(cur_pos, len)?).to_string();
+    buffer.step(len)?;
+
+    Ok(value)
+}
+
+/// Encoded length of a character-string, including its length prefix.
+fn character_string_len(value: &str) -> usize {
+    1 + value.len().min(255)
+}
+
+/// Strips the trailing dot of a fully qualified name.
+fn trim_root(name: &str) -> &str {
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+/// Whether `a` and `b` are the same name, ignoring case and any trailing dot.
+pub fn same_name(a: &str, b: &str) -> bool {
+    trim_root(a).eq_ignore_ascii_case(trim_root(b))
+}
+
+/// Whether `name` is `zone` or a name below it, comparing whole labels and ignoring case.
+///
+/// Every name is below the root, which is written as an empty name.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let zone = trim_root(zone).as_bytes();
+    let name = trim_root(name).as_bytes();
+
+    if zone.is_empty() {
+        return true;
+    }
+    if name.len() == zone.len() {
+        return name.eq_ignore_ascii_case(zone);
+    }
+
+    name.len() > zone.len()
+        && name[name.len() - zone.len() - 1] == b'.'
+        && name[name.len() - zone.len()..].eq_ignore_ascii_case(zone)
+}
+
+/// Encoded length of `name` written without compression.
+fn name_len(name: &str) -> usize {
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    // The root is just the terminating zero length label
+    if name.is_empty() {
+        return 1;
+    }
+
+    name.split('.').map(|x| x.len() + 1).sum::<usize>() + 1
+}
+
+/// Encodes `data` as base64 with padding, the way key material is presented in master files.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, byte)| acc | ((*byte as u32) << (16 - 8 * i)));
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+/// LOC latitudes and longitudes are thousandths of an arc second, offset so this is the equator or prime meridian.
+const LOC_COORDINATE_BASE: i64 = 1 << 31;
+
+/// LOC altitudes are centimeters, offset so this is the WGS 84 reference spheroid.
+const LOC_ALTITUDE_BASE: f64 = 10_000_000.0;
+
+/// Converts a LOC latitude or longitude to degrees, negative to the south or west.
+pub fn loc_coordinate_to_degrees(value: u32) -> f64 {
+    (value as i64 - LOC_COORDINATE_BASE) as f64 / 3_600_000.0
+}
+
+/// Formats a LOC latitude or longitude as degrees, minutes, seconds and hemisphere.
+fn loc_coordinate_to_string(value: u32, positive: char, negative: char) -> String {
+    let offset = value as i64 - LOC_COORDINATE_BASE;
+    let hemisphere = if offset < 0 { negative } else { positive };
+    let offset = offset.abs();
+
+    format!(
+        "{} {} {:.3} {}",
+        offset / 3_600_000,
+        offset / 60_000 % 60,
+        (offset % 60_000) as f64 / 1000.0,
+        hemisphere
+    )
+}
+
+/// Decodes a LOC size or precision, a mantissa and power of ten exponent in centimeters.
+fn loc_precision_to_meters(value: u8) -> f64 {
+    (value >> 4) as f64 * 10f64.powi((value & 0x0F) as i32) / 100.0
+}
+
+/// Writes `value` as a character-string, truncated to the 255 bytes its length prefix allows.
+fn write_character_string<T: PacketBuffer>(buffer: &mut T, value: &str) -> Result<()> {
+    let bytes = &value.as_bytes()[..value.len().min(255)];
+    buffer.write_u8(bytes.len() as u8)?;
+    buffer.write_all(bytes)?;
+    Ok(())
+}
+
+/// EDNS option code of Extended DNS Errors.
+pub const EDNS_EXTENDED_ERROR: u16 = 15;
+
+/// Extended DNS Error info-codes, as described in RFC 8914.
+pub const EDE_OTHER: u16 = 0;
+pub const EDE_DNSSEC_BOGUS: u16 = 6;
+pub const EDE_BLOCKED: u16 = 15;
+pub const EDE_CENSORED: u16 = 16;
+pub const EDE_FILTERED: u16 = 17;
+pub const EDE_PROHIBITED: u16 = 18;
+
+/// The DO bit in the flags of an OPT record, asking for DNSSEC records along with the answer.
+pub const EDNS_DNSSEC_OK: u32 = 0x8000;
+
+/// UDP payload size advertised in OPT records the server creates.
+const DEFAULT_EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+/// Largest UDP response a client can receive without EDNS.
+pub const DEFAULT_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// A single option carried in the rdata of an OPT record.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct OptOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+impl OptOption {
+    /// Creates an Extended DNS Error option with the given info-code and explanation.
+    pub fn extended_error(info_code: u16, text: &str) -> OptOption {
+        let mut data = info_code.to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+
+        OptOption {
+            code: EDNS_EXTENDED_ERROR,
+            data,
+        }
+    }
+
+    /// Returns the info-code and text if this is an Extended DNS Error option.
+    pub fn get_extended_error(&self) -> Option<(u16, String)> {
+        if self.code != EDNS_EXTENDED_ERROR || self.data.len() < 2 {
+            return None;
+        }
+
+        let info_code = u16::from_be_bytes([self.data[0], self.data[1]]);
+        let text = String::from_utf8_lossy(&self.data[2..]).to_string();
+
+        Some((info_code, text))
+    }
+
+    /// Creates an edns-tcp-keepalive option.
+    pub fn tcp_keepalive(keepalive: TcpKeepalive) -> OptOption {
+        OptOption {
+            code: EDNS_TCP_KEEPALIVE,
+            data: keepalive.timeout.map(|timeout| timeout.to_be_bytes().to_vec()).unwrap_or_default(),
+        }
+    }
+
+    /// Returns the keepalive if this is an edns-tcp-keepalive option.
+    pub fn get_tcp_keepalive(&self) -> Option<TcpKeepalive> {
+        if self.code != EDNS_TCP_KEEPALIVE {
+            return None;
+        }
+
+        let timeout = match self.data.len() {
+            0 => None,
+            1 => return None,
+            _ => Some(u16::from_be_bytes([self.data[0], self.data[1]])),
+        };
+
+        Some(TcpKeepalive { timeout })
+    }
+}
+
+/// EDNS option code of edns-tcp-keepalive.
+pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+
+/// The edns-tcp-keepalive option, as described in RFC 7828.
+///
+/// Clients send it without a timeout to ask for one, servers answer with the idle
+/// timeout they'll apply to the connection, in units of 100 milliseconds.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    pub timeout: Option<u16>,
+}
+
+impl TcpKeepalive {
+    /// Creates the option advertising `idle_timeout`, saturating at the largest encodable value.
+    pub fn from_duration(idle_timeout: Duration) -> TcpKeepalive {
+        let units = idle_timeout.as_millis() / 100;
+        TcpKeepalive {
+            timeout: Some(units.min(u16::MAX as u128) as u16),
+        }
+    }
+
+    /// Returns the advertised idle timeout, if any.
+    pub fn duration(&self) -> Option<Duration> {
+        self.timeout
+            .map(|units| Duration::from_millis(units as u64 * 100))
+    }
+
+    /// Reads the option data of an option `len` bytes long.
+    pub fn read<T: PacketBuffer>(buffer: &mut T, len: u16) -> Result<TcpKeepalive> {
+        if len < 2 {
+            buffer.step(len as usize)?;
+            return Ok(TcpKeepalive { timeout: None });
+        }
+
+        let timeout = buffer.read_u16()?;
+        buffer.step(len as usize - 2)?;
+
+        Ok(TcpKeepalive {
+            timeout: Some(timeout),
+        })
+    }
+
+    /// Writes the option code, length and data.
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<()> {
+        buffer.write_u16(EDNS_TCP_KEEPALIVE)?;
+        match self.timeout {
+            Some(timeout) => {
+                buffer.write_u16(2)?;
+                buffer.write_u16(timeout)?;
+            }
+            None => buffer.write_u16(0)?,
+        }
+
+        Ok(())
+    }
+}
+
 /// `DnsRecord` is the primary representation of a DNS record.
 ///
 /// This enumeration is used for reading and writing records from the network
@@ -167,6 +535,17 @@ pub enum DnsRecord {
         minimum: u32,
         ttl: TransientTtl,
     },
+    PTR {
+        domain: String,
+        host: String,
+        ttl: TransientTtl,
+    },
+    HINFO {
+        domain: String,
+        cpu: String,
+        os: String,
+        ttl: TransientTtl,
+    },
     MX {
         domain: String,
         priority: u16,
@@ -183,6 +562,17 @@ pub enum DnsRecord {
         addr: Ipv6Addr,
         ttl: TransientTtl,
     },
+    LOC {
+        domain: String,
+        version: u8,
+        size: u8,
+        horiz_pre: u8,
+        vert_pre: u8,
+        latitude: u32,
+        longitude: u32,
+        altitude: u32,
+        ttl: TransientTtl,
+    },
     SRV {
         domain: String,
         priority: u16,
@@ -191,10 +581,98 @@ pub enum DnsRecord {
         host: String,
         ttl: TransientTtl,
     },
+    NAPTR {
+        domain: String,
+        order: u16,
+        preference: u16,
+        flags: String,
+        services: String,
+        regexp: String,
+        replacement: String,
+        ttl: TransientTtl,
+    },
+    CERT {
+        domain: String,
+        cert_type: u16,
+        key_tag: u16,
+        algorithm: u8,
+        certificate: Vec<u8>,
+        ttl: TransientTtl,
+    },
     OPT {
         packet_len: u16,
         flags: u32,
-        data: String,
+        options: Vec<OptOption>,
+    },
+    APL {
+        domain: String,
+        prefixes: Vec<AplItem>,
+        ttl: TransientTtl,
+    },
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: TransientTtl,
+    },
+    SSHFP {
+        domain: String,
+        algorithm: u8,
+        fp_type: u8,
+        fingerprint: Vec<u8>,
+        ttl: TransientTtl,
+    },
+    RRSIG {
+        domain: String,
+        type_covered: u16,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: String,
+        signature: Vec<u8>,
+        ttl: TransientTtl,
+    },
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: TransientTtl,
+    },
+    SMIMEA {
+        domain: String,
+        usage: u8,
+        selector: u8,
+        matching_type: u8,
+        data: Vec<u8>,
+        ttl: TransientTtl,
+    },
+    SVCB {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<(u16, Vec<u8>)>,
+        ttl: TransientTtl,
+    },
+    HTTPS {
+        domain: String,
+        priority: u16,
+        target: String,
+        params: Vec<(u16, Vec<u8>)>,
+        ttl: TransientTtl,
+    },
+    URI {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        target: String,
+        ttl: TransientTtl,
     },
 }
 
@@ -224,9 +702,21 @@ impl DnsRecord {
             // Canonical Name (CNAME record)
             QueryType::CNAME => Self::read_cname_record(buffer, domain, ttl),
 
+            // Domain Name Pointer (PTR record)
+            QueryType::PTR => Self::read_ptr_record(buffer, domain, ttl),
+
+            // Location (LOC record)
+            QueryType::LOC => Self::read_loc_record(buffer, domain, ttl),
+
             // Service Record (SRV record)
             QueryType::SRV => Self::read_srv_record(buffer, domain, ttl),
 
+            // Host Information (HINFO record)
+            QueryType::HINFO => Self::read_hinfo_record(buffer, domain, ttl, data_len),
+
+            // Naming Authority Pointer (NAPTR record)
+            QueryType::NAPTR => Self::read_naptr_record(buffer, domain, ttl, data_len),
+
             // Mail Exchange (MX record)
             QueryType::MX => Self::read_mx_record(buffer, domain, ttl),
 
@@ -236,11 +726,38 @@ impl DnsRecord {
             // Text Record (TXT record)
             QueryType::TXT => Self::read_txt_record(buffer, domain, ttl, data_len),
 
+            // Certificate (CERT record)
+            QueryType::CERT => Self::read_cert_record(buffer, domain, ttl, data_len),
+
             // EDNS Option (OPT record)
             QueryType::OPT => Self::read_opt_record(buffer, class, ttl, data_len),
 
-            // Unknown Record Type
-            QueryType::UNKNOWN(_) => {
+            // Address Prefix List (APL record)
+            QueryType::APL => Self::read_apl_record(buffer, domain, ttl, data_len),
+
+            // Delegation Signer (DS record)
+            QueryType::DS => Self::read_ds_record(buffer, domain, ttl, data_len),
+
+            // SSH Key Fingerprint (SSHFP record)
+            QueryType::SSHFP => Self::read_sshfp_record(buffer, domain, ttl, data_len),
+
+            // Signature over an RRset (RRSIG record)
+            QueryType::RRSIG => Self::read_rrsig_record(buffer, domain, ttl, data_len),
+
+            // DNSSEC public key (DNSKEY record)
+            QueryType::DNSKEY => Self::read_dnskey_record(buffer, domain, ttl, data_len),
+
+            // S/MIME Certificate Association (SMIMEA record)
+            QueryType::SMIMEA => Self::read_smimea_record(buffer, domain, ttl, data_len),
+
+            // Service Binding (SVCB and HTTPS records)
+            QueryType::SVCB | QueryType::HTTPS => Self::read_svcb_record(buffer, qtype, domain, ttl, data_len),
+
+            // Uniform Resource Identifier (URI record)
+            QueryType::URI => Self::read_uri_record(buffer, domain, ttl, data_len),
+
+            // Unknown Record Type, or a query-only type such as ANY
+            QueryType::UNKNOWN(_) | QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
                 buffer.step(data_len as usize)?;
                 Ok(DnsRecord::UNKNOWN {
                     domain,
@@ -259,7 +776,7 @@ impl DnsRecord {
             ((raw_addr >> 24) & 0xFF) as u8,
             ((raw_addr >> 16) & 0xFF) as u8,
             ((raw_addr >> 8) & 0xFF) as u8,
-            ((raw_addr >> 0) & 0xFF) as u8,
+            (raw_addr & 0xFF) as u8,
         );
 
         Ok(DnsRecord::A {
@@ -276,13 +793,13 @@ impl DnsRecord {
         let raw_addr4 = buffer.read_u32()?;
         let addr = Ipv6Addr::new(
             ((raw_addr1 >> 16) & 0xFFFF) as u16,
-            ((raw_addr1 >> 0) & 0xFFFF) as u16,
+            (raw_addr1 & 0xFFFF) as u16,
             ((raw_addr2 >> 16) & 0xFFFF) as u16,
-            ((raw_addr2 >> 0) & 0xFFFF) as u16,
+            (raw_addr2 & 0xFFFF) as u16,
             ((raw_addr3 >> 16) & 0xFFFF) as u16,
-            ((raw_addr3 >> 0) & 0xFFFF) as u16,
+            (raw_addr3 & 0xFFFF) as u16,
             ((raw_addr4 >> 16) & 0xFFFF) as u16,
-            ((raw_addr4 >> 0) & 0xFFFF) as u16,
+            (raw_addr4 & 0xFFFF) as u16,
         );
 
         Ok(DnsRecord::AAAA {
@@ -314,6 +831,31 @@ impl DnsRecord {
         })
     }
 
+    fn read_ptr_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32) -> Result<DnsRecord> {
+        let mut ptr = String::new();
+        buffer.read_qname(&mut ptr)?;
+
+        Ok(DnsRecord::PTR {
+            domain,
+            host: ptr,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_loc_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32) -> Result<DnsRecord> {
+        Ok(DnsRecord::LOC {
+            domain,
+            version: buffer.read()?,
+            size: buffer.read()?,
+            horiz_pre: buffer.read()?,
+            vert_pre: buffer.read()?,
+            latitude: buffer.read_u32()?,
+            longitude: buffer.read_u32()?,
+            altitude: buffer.read_u32()?,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
     fn read_srv_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32) -> Result<DnsRecord> {
         let priority = buffer.read_u16()?;
         let weight = buffer.read_u16()?;
@@ -344,6 +886,46 @@ impl DnsRecord {
         })
     }
 
+    fn read_hinfo_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let cpu = read_character_string(buffer, end_pos)?;
+        let os = read_character_string(buffer, end_pos)?;
+        buffer.seek(end_pos)?;
+
+        Ok(DnsRecord::HINFO {
+            domain,
+            cpu,
+            os,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_naptr_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let order = buffer.read_u16()?;
+        let preference = buffer.read_u16()?;
+        let flags = read_character_string(buffer, end_pos)?;
+        let services = read_character_string(buffer, end_pos)?;
+        let regexp = read_character_string(buffer, end_pos)?;
+
+        let mut replacement = String::new();
+        buffer.read_qname(&mut replacement)?;
+        buffer.seek(end_pos)?;
+
+        Ok(DnsRecord::NAPTR {
+            domain,
+            order,
+            preference,
+            flags,
+            services,
+            regexp,
+            replacement,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
     fn read_soa_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32) -> Result<DnsRecord> {
         let mut m_name = String::new();
         buffer.read_qname(&mut m_name)?;
@@ -371,9 +953,19 @@ impl DnsRecord {
     }
 
     fn read_txt_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
-        let cur_pos = buffer.pos();
-        let txt = String::from_utf8_lossy(buffer.get_range(cur_pos, data_len as usize)?).to_string();
-        buffer.step(data_len as usize)?;
+        let end_pos = buffer.pos() + data_len as usize;
+
+        // Long values are split over several character-strings, which get joined
+        // back together before decoding so multi-byte characters survive the split
+        let mut bytes = Vec::with_capacity(data_len as usize);
+        while buffer.pos() < end_pos {
+            let len = (buffer.read()? as usize).min(end_pos - buffer.pos());
+            let cur_pos = buffer.pos();
+            bytes.extend_from_slice(buffer.get_range(cur_pos, len)?);
+            buffer.step(len)?;
+        }
+
+        let txt = String::from_utf8_lossy(&bytes).to_string();
 
         Ok(DnsRecord::TXT {
             domain,
@@ -382,51 +974,278 @@ impl DnsRecord {
         })
     }
 
-    fn read_opt_record<T: PacketBuffer>(buffer: &mut T, class: u16, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+    fn read_cert_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let cert_type = buffer.read_u16()?;
+        let key_tag = buffer.read_u16()?;
+        let algorithm = buffer.read()?;
+
+        let len = (data_len as usize).saturating_sub(5);
         let cur_pos = buffer.pos();
-        let data = String::from_utf8_lossy(buffer.get_range(cur_pos, data_len as usize)?).to_string();
-        buffer.step(data_len as usize)?;
+        let certificate = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::CERT {
+            domain,
+            cert_type,
+            key_tag,
+            algorithm,
+            certificate,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_opt_record<T: PacketBuffer>(buffer: &mut T, class: u16, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let mut options = Vec::new();
+        while buffer.pos() < end_pos {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let cur_pos = buffer.pos();
+            let data = buffer.get_range(cur_pos, len)?.to_vec();
+            buffer.step(len)?;
+
+            options.push(OptOption { code, data });
+        }
 
         Ok(DnsRecord::OPT {
             packet_len: class,
             flags: ttl,
-            data,
+            options,
         })
     }
 
+    fn read_apl_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let mut prefixes = Vec::new();
+        while buffer.pos() < end_pos {
+            let family = buffer.read_u16()?;
+            let prefix = buffer.read()?;
+            let afd = buffer.read()?;
+            let len = (afd & 0x7F) as usize;
+            let cur_pos = buffer.pos();
+            let address = buffer.get_range(cur_pos, len)?.to_vec();
+            buffer.step(len)?;
+
+            prefixes.push(AplItem {
+                family,
+                prefix,
+                negation: (afd & 0x80) > 0,
+                address,
+            });
+        }
 
-    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
-    let start_pos = buffer.pos();
+        Ok(DnsRecord::APL {
+            domain,
+            prefixes,
+            ttl: TransientTtl(ttl),
+        })
+    }
 
-    // Helper function to write common parts of the record (qname, query type, class, ttl)
-    fn write_common<T: PacketBuffer>(
-        buffer: &mut T,
-        domain: &str,
-        query_type: QueryType,
-        ttl: u32,
-    ) -> Result<()> {
-        buffer.write_qname(domain)?;
-        buffer.write_u16(query_type.to_num())?;
-        buffer.write_u16(1)?; // Record class (IN)
-        buffer.write_u32(ttl)?;
-        Ok(())
+    fn read_ds_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let key_tag = buffer.read_u16()?;
+        let algorithm = buffer.read()?;
+        let digest_type = buffer.read()?;
+
+        let len = (data_len as usize).saturating_sub(4);
+        let cur_pos = buffer.pos();
+        let digest = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::DS {
+            domain,
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+            ttl: TransientTtl(ttl),
+        })
     }
 
-    match *self {
-        DnsRecord::A {
-            ref domain,
-            ref addr,
+    fn read_sshfp_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let algorithm = buffer.read()?;
+        let fp_type = buffer.read()?;
+
+        let len = (data_len as usize).saturating_sub(2);
+        let cur_pos = buffer.pos();
+        let fingerprint = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::SSHFP {
+            domain,
+            algorithm,
+            fp_type,
+            fingerprint,
             ttl: TransientTtl(ttl),
-        } => {
-            write_common(buffer, domain, QueryType::A, ttl)?;
-            buffer.write_u16(4)?;
-            let octets = addr.octets();
-            for &octet in &octets {
-                buffer.write_u8(octet)?;
-            }
-        }
-        DnsRecord::AAAA {
-            ref domain,
+        })
+    }
+
+    fn read_rrsig_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let type_covered = buffer.read_u16()?;
+        let algorithm = buffer.read()?;
+        let labels = buffer.read()?;
+        let original_ttl = buffer.read_u32()?;
+        let expiration = buffer.read_u32()?;
+        let inception = buffer.read_u32()?;
+        let key_tag = buffer.read_u16()?;
+        let mut signer_name = String::new();
+        buffer.read_qname(&mut signer_name)?;
+
+        // The signature takes up the rest of the rdata
+        let len = end_pos.saturating_sub(buffer.pos());
+        let cur_pos = buffer.pos();
+        let signature = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::RRSIG {
+            domain,
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer_name,
+            signature,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_dnskey_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let flags = buffer.read_u16()?;
+        let protocol = buffer.read()?;
+        let algorithm = buffer.read()?;
+
+        let len = (data_len as usize).saturating_sub(4);
+        let cur_pos = buffer.pos();
+        let public_key = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::DNSKEY {
+            domain,
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_smimea_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let usage = buffer.read()?;
+        let selector = buffer.read()?;
+        let matching_type = buffer.read()?;
+
+        // Whatever follows the three leading bytes is the association data
+        let len = (data_len as usize).saturating_sub(3);
+        let cur_pos = buffer.pos();
+        let data = buffer.get_range(cur_pos, len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::SMIMEA {
+            domain,
+            usage,
+            selector,
+            matching_type,
+            data,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+    fn read_svcb_record<T: PacketBuffer>(buffer: &mut T, qtype: QueryType, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let end_pos = buffer.pos() + data_len as usize;
+
+        let priority = buffer.read_u16()?;
+        let mut target = String::new();
+        buffer.read_qname(&mut target)?;
+
+        // The remaining rdata is a sequence of key/length/value SvcParams
+        let mut params = Vec::new();
+        while buffer.pos() < end_pos {
+            let key = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let cur_pos = buffer.pos();
+            let value = buffer.get_range(cur_pos, len)?.to_vec();
+            buffer.step(len)?;
+
+            params.push((key, value));
+        }
+
+        if qtype == QueryType::HTTPS {
+            Ok(DnsRecord::HTTPS {
+                domain,
+                priority,
+                target,
+                params,
+                ttl: TransientTtl(ttl),
+            })
+        } else {
+            Ok(DnsRecord::SVCB {
+                domain,
+                priority,
+                target,
+                params,
+                ttl: TransientTtl(ttl),
+            })
+        }
+    }
+
+    fn read_uri_record<T: PacketBuffer>(buffer: &mut T, domain: String, ttl: u32, data_len: u16) -> Result<DnsRecord> {
+        let priority = buffer.read_u16()?;
+        let weight = buffer.read_u16()?;
+
+        // Unlike SRV, the target is the rest of the rdata rather than a name
+        let len = (data_len as usize).saturating_sub(4);
+        let cur_pos = buffer.pos();
+        let target = String::from_utf8_lossy(buffer.get_range(cur_pos, len)?).to_string();
+        buffer.step(len)?;
+
+        Ok(DnsRecord::URI {
+            domain,
+            priority,
+            weight,
+            target,
+            ttl: TransientTtl(ttl),
+        })
+    }
+
+
+    pub fn write<T: PacketBuffer>(&self, buffer: &mut T) -> Result<usize> {
+    let start_pos = buffer.pos();
+
+    // Helper function to write common parts of the record (qname, query type, class, ttl)
+    fn write_common<T: PacketBuffer>(
+        buffer: &mut T,
+        domain: &str,
+        query_type: QueryType,
+        ttl: u32,
+    ) -> Result<()> {
+        buffer.write_qname(domain)?;
+        buffer.write_u16(query_type.to_num())?;
+        buffer.write_u16(1)?; // Record class (IN)
+        buffer.write_u32(ttl)?;
+        Ok(())
+    }
+
+    match *self {
+        DnsRecord::A {
+            ref domain,
+            ref addr,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::A, ttl)?;
+            buffer.write_u16(4)?;
+            let octets = addr.octets();
+            for &octet in &octets {
+                buffer.write_u8(octet)?;
+            }
+        }
+        DnsRecord::AAAA {
+            ref domain,
             ref addr,
             ttl: TransientTtl(ttl),
         } => {
@@ -460,6 +1279,39 @@ impl DnsRecord {
             let size = buffer.pos() - (pos + 2);
             buffer.set_u16(pos, size as u16)?;
         }
+        DnsRecord::PTR {
+            ref domain,
+            ref host,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::PTR, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_qname(host)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::LOC {
+            ref domain,
+            version,
+            size,
+            horiz_pre,
+            vert_pre,
+            latitude,
+            longitude,
+            altitude,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::LOC, ttl)?;
+            buffer.write_u16(16)?;
+            buffer.write_u8(version)?;
+            buffer.write_u8(size)?;
+            buffer.write_u8(horiz_pre)?;
+            buffer.write_u8(vert_pre)?;
+            buffer.write_u32(latitude)?;
+            buffer.write_u32(longitude)?;
+            buffer.write_u32(altitude)?;
+        }
         DnsRecord::SRV {
             ref domain,
             priority,
@@ -474,7 +1326,44 @@ impl DnsRecord {
             buffer.write_u16(priority)?;
             buffer.write_u16(weight)?;
             buffer.write_u16(port)?;
-            buffer.write_qname(host)?;
+            // RFC 2782 forbids compressing the target
+            buffer.write_qname_uncompressed(host)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::HINFO {
+            ref domain,
+            ref cpu,
+            ref os,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::HINFO, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            write_character_string(buffer, cpu)?;
+            write_character_string(buffer, os)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::NAPTR {
+            ref domain,
+            order,
+            preference,
+            ref flags,
+            ref services,
+            ref regexp,
+            ref replacement,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::NAPTR, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u16(order)?;
+            buffer.write_u16(preference)?;
+            write_character_string(buffer, flags)?;
+            write_character_string(buffer, services)?;
+            write_character_string(buffer, regexp)?;
+            buffer.write_qname_uncompressed(replacement)?;
             let size = buffer.pos() - (pos + 2);
             buffer.set_u16(pos, size as u16)?;
         }
@@ -522,12 +1411,206 @@ impl DnsRecord {
             ttl: TransientTtl(ttl),
         } => {
             write_common(buffer, domain, QueryType::TXT, ttl)?;
-            buffer.write_u16(data.len() as u16)?;
-            for &b in data.as_bytes() {
-                buffer.write_u8(b)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            if data.is_empty() {
+                buffer.write_u8(0)?;
+            }
+            for chunk in data.as_bytes().chunks(255) {
+                buffer.write_u8(chunk.len() as u8)?;
+                buffer.write_all(chunk)?;
+            }
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::CERT {
+            ref domain,
+            cert_type,
+            key_tag,
+            algorithm,
+            ref certificate,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::CERT, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u16(cert_type)?;
+            buffer.write_u16(key_tag)?;
+            buffer.write_u8(algorithm)?;
+            buffer.write_all(certificate)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::APL {
+            ref domain,
+            ref prefixes,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::APL, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            for item in prefixes {
+                buffer.write_u16(item.family)?;
+                buffer.write_u8(item.prefix)?;
+                buffer.write_u8(((item.negation as u8) << 7) | (item.address.len() as u8 & 0x7F))?;
+                buffer.write_all(&item.address)?;
+            }
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::DS {
+            ref domain,
+            key_tag,
+            algorithm,
+            digest_type,
+            ref digest,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::DS, ttl)?;
+            buffer.write_u16(4 + digest.len() as u16)?;
+            buffer.write_u16(key_tag)?;
+            buffer.write_u8(algorithm)?;
+            buffer.write_u8(digest_type)?;
+            buffer.write_all(digest)?;
+        }
+        DnsRecord::SSHFP {
+            ref domain,
+            algorithm,
+            fp_type,
+            ref fingerprint,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::SSHFP, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u8(algorithm)?;
+            buffer.write_u8(fp_type)?;
+            buffer.write_all(fingerprint)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::RRSIG {
+            ref domain,
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            ref signer_name,
+            ref signature,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::RRSIG, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u16(type_covered)?;
+            buffer.write_u8(algorithm)?;
+            buffer.write_u8(labels)?;
+            buffer.write_u32(original_ttl)?;
+            buffer.write_u32(expiration)?;
+            buffer.write_u32(inception)?;
+            buffer.write_u16(key_tag)?;
+            // RFC 4034 forbids compressing the signer's name
+            buffer.write_qname_uncompressed(signer_name)?;
+            buffer.write_all(signature)?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::DNSKEY {
+            ref domain,
+            flags,
+            protocol,
+            algorithm,
+            ref public_key,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::DNSKEY, ttl)?;
+            buffer.write_u16(4 + public_key.len() as u16)?;
+            buffer.write_u16(flags)?;
+            buffer.write_u8(protocol)?;
+            buffer.write_u8(algorithm)?;
+            buffer.write_all(public_key)?;
+        }
+        DnsRecord::SMIMEA {
+            ref domain,
+            usage,
+            selector,
+            matching_type,
+            ref data,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::SMIMEA, ttl)?;
+            buffer.write_u16(3 + data.len() as u16)?;
+            buffer.write_u8(usage)?;
+            buffer.write_u8(selector)?;
+            buffer.write_u8(matching_type)?;
+            buffer.write_all(data)?;
+        }
+        DnsRecord::SVCB {
+            ref domain,
+            priority,
+            ref target,
+            ref params,
+            ttl: TransientTtl(ttl),
+        }
+        | DnsRecord::HTTPS {
+            ref domain,
+            priority,
+            ref target,
+            ref params,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, self.get_querytype(), ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u16(priority)?;
+            buffer.write_qname_uncompressed(target)?;
+            for (key, value) in params {
+                buffer.write_u16(*key)?;
+                buffer.write_u16(value.len() as u16)?;
+                buffer.write_all(value)?;
+            }
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::URI {
+            ref domain,
+            priority,
+            weight,
+            ref target,
+            ttl: TransientTtl(ttl),
+        } => {
+            write_common(buffer, domain, QueryType::URI, ttl)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            buffer.write_u16(priority)?;
+            buffer.write_u16(weight)?;
+            buffer.write_all(target.as_bytes())?;
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
+        }
+        DnsRecord::OPT {
+            packet_len,
+            flags,
+            ref options,
+        } => {
+            // OPT records are owned by the root, and reuse class and ttl for EDNS fields
+            buffer.write_u8(0)?;
+            buffer.write_u16(QueryType::OPT.to_num())?;
+            buffer.write_u16(packet_len)?;
+            buffer.write_u32(flags)?;
+            let pos = buffer.pos();
+            buffer.write_u16(0)?;
+            for option in options {
+                buffer.write_u16(option.code)?;
+                buffer.write_u16(option.data.len() as u16)?;
+                buffer.write_all(&option.data)?;
             }
+            let size = buffer.pos() - (pos + 2);
+            buffer.set_u16(pos, size as u16)?;
         }
-        DnsRecord::OPT { .. } => {} // OPT record doesn't need writing
         DnsRecord::UNKNOWN { .. } => {
             println!("Skipping record: {:?}", self);
         }
@@ -536,17 +1619,92 @@ impl DnsRecord {
     Ok(buffer.pos() - start_pos)
     }
 
+    /// Length of the rdata `write` produces, without compression of the names in it.
+    ///
+    /// Compression only ever shortens names, so this is an upper bound of what ends up in a packet.
+    pub fn rdata_len(&self) -> usize {
+        match *self {
+            DnsRecord::A { .. } => 4,
+            DnsRecord::AAAA { .. } => 16,
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => name_len(host),
+            DnsRecord::LOC { .. } => 16,
+            DnsRecord::SRV { ref host, .. } => 6 + name_len(host),
+            DnsRecord::HINFO { ref cpu, ref os, .. } => character_string_len(cpu) + character_string_len(os),
+            DnsRecord::NAPTR {
+                ref flags,
+                ref services,
+                ref regexp,
+                ref replacement,
+                ..
+            } => {
+                4 + character_string_len(flags)
+                    + character_string_len(services)
+                    + character_string_len(regexp)
+                    + name_len(replacement)
+            }
+            DnsRecord::MX { ref host, .. } => 2 + name_len(host),
+            DnsRecord::SOA { ref m_name, ref r_name, .. } => name_len(m_name) + name_len(r_name) + 20,
+            DnsRecord::TXT { ref data, .. } => {
+                if data.is_empty() {
+                    1
+                } else {
+                    data.len() + data.len().div_ceil(255)
+                }
+            }
+            DnsRecord::CERT { ref certificate, .. } => 5 + certificate.len(),
+            DnsRecord::APL { ref prefixes, .. } => prefixes.iter().map(|item| 4 + item.address.len()).sum(),
+            DnsRecord::DS { ref digest, .. } => 4 + digest.len(),
+            DnsRecord::SSHFP { ref fingerprint, .. } => 2 + fingerprint.len(),
+            DnsRecord::RRSIG { ref signer_name, ref signature, .. } => 18 + name_len(signer_name) + signature.len(),
+            DnsRecord::DNSKEY { ref public_key, .. } => 4 + public_key.len(),
+            DnsRecord::SMIMEA { ref data, .. } => 3 + data.len(),
+            DnsRecord::SVCB { ref target, ref params, .. } | DnsRecord::HTTPS { ref target, ref params, .. } => {
+                2 + name_len(target) + params.iter().map(|(_, value)| 4 + value.len()).sum::<usize>()
+            }
+            DnsRecord::URI { ref target, .. } => 4 + target.len(),
+            DnsRecord::OPT { ref options, .. } => options.iter().map(|option| 4 + option.data.len()).sum(),
+            DnsRecord::UNKNOWN { .. } => 0,
+        }
+    }
+
+    /// Length of the whole record as `write` produces it, without compression.
+    pub fn binary_len(&self) -> usize {
+        match *self {
+            // Written with the root as owner
+            DnsRecord::OPT { .. } => 1 + 10 + self.rdata_len(),
+            // Not written at all
+            DnsRecord::UNKNOWN { .. } => 0,
+            _ => name_len(&self.get_domain().unwrap_or_default()) + 10 + self.rdata_len(),
+        }
+    }
+
     pub fn get_querytype(&self) -> QueryType {
         match *self {
             DnsRecord::A { .. } => QueryType::A,
             DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::LOC { .. } => QueryType::LOC,
             DnsRecord::NS { .. } => QueryType::NS,
             DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::PTR { .. } => QueryType::PTR,
             DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::HINFO { .. } => QueryType::HINFO,
+            DnsRecord::NAPTR { .. } => QueryType::NAPTR,
+            DnsRecord::CERT { .. } => QueryType::CERT,
             DnsRecord::MX { .. } => QueryType::MX,
             DnsRecord::SOA { .. } => QueryType::SOA,
             DnsRecord::TXT { .. } => QueryType::TXT,
             DnsRecord::OPT { .. } => QueryType::OPT,
+            DnsRecord::APL { .. } => QueryType::APL,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::SSHFP { .. } => QueryType::SSHFP,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::SMIMEA { .. } => QueryType::SMIMEA,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::URI { .. } => QueryType::URI,
             DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype), // Directly return the unknown query type
         }
     }
@@ -555,65 +1713,407 @@ impl DnsRecord {
         match *self {
             DnsRecord::A { ref domain, .. }
             | DnsRecord::AAAA { ref domain, .. }
+            | DnsRecord::LOC { ref domain, .. }
             | DnsRecord::NS { ref domain, .. }
             | DnsRecord::CNAME { ref domain, .. }
+            | DnsRecord::PTR { ref domain, .. }
             | DnsRecord::SRV { ref domain, .. }
+            | DnsRecord::HINFO { ref domain, .. }
+            | DnsRecord::NAPTR { ref domain, .. }
+            | DnsRecord::CERT { ref domain, .. }
             | DnsRecord::MX { ref domain, .. }
             | DnsRecord::UNKNOWN { ref domain, .. }
             | DnsRecord::SOA { ref domain, .. }
-            | DnsRecord::TXT { ref domain, .. } => Some(domain.clone()),
+            | DnsRecord::TXT { ref domain, .. }
+            | DnsRecord::APL { ref domain, .. }
+            | DnsRecord::DS { ref domain, .. }
+            | DnsRecord::SSHFP { ref domain, .. }
+            | DnsRecord::RRSIG { ref domain, .. }
+            | DnsRecord::DNSKEY { ref domain, .. }
+            | DnsRecord::SMIMEA { ref domain, .. }
+            | DnsRecord::SVCB { ref domain, .. }
+            | DnsRecord::HTTPS { ref domain, .. }
+            | DnsRecord::URI { ref domain, .. } => Some(domain.clone()),
             DnsRecord::OPT { .. } => None,
         }
     }
 
+    /// The address held by an A or AAAA record.
+    pub fn get_address(&self) -> Option<IpAddr> {
+        match *self {
+            DnsRecord::A { addr, .. } => Some(IpAddr::V4(addr)),
+            DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(addr)),
+            _ => None,
+        }
+    }
+
+    /// Formats the record data the way it's presented in master files and by dig.
+    pub fn rdata_to_string(&self) -> String {
+        match *self {
+            DnsRecord::A { ref addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { ref addr, .. } => addr.to_string(),
+            DnsRecord::NS { ref host, .. }
+            | DnsRecord::CNAME { ref host, .. }
+            | DnsRecord::PTR { ref host, .. } => format!("{}.", host),
+            DnsRecord::HINFO { ref cpu, ref os, .. } => format!("{:?} {:?}", cpu, os),
+            DnsRecord::NAPTR {
+                order,
+                preference,
+                ref flags,
+                ref services,
+                ref regexp,
+                ref replacement,
+                ..
+            } => format!(
+                "{} {} {:?} {:?} {:?} {}.",
+                order, preference, flags, services, regexp, replacement
+            ),
+            DnsRecord::MX { priority, ref host, .. } => format!("{} {}.", priority, host),
+            DnsRecord::LOC {
+                size,
+                horiz_pre,
+                vert_pre,
+                latitude,
+                longitude,
+                altitude,
+                ..
+            } => format!(
+                "{} {} {:.2}m {}m {}m {}m",
+                loc_coordinate_to_string(latitude, 'N', 'S'),
+                loc_coordinate_to_string(longitude, 'E', 'W'),
+                (altitude as f64 - LOC_ALTITUDE_BASE) / 100.0,
+                loc_precision_to_meters(size),
+                loc_precision_to_meters(horiz_pre),
+                loc_precision_to_meters(vert_pre)
+            ),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                ref host,
+                ..
+            } => format!("{} {} {} {}.", priority, weight, port, host),
+            DnsRecord::SOA {
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{}. {}. {} {} {} {} {}",
+                m_name, r_name, serial, refresh, retry, expire, minimum
+            ),
+            DnsRecord::TXT { ref data, .. } => format!("{:?}", data),
+            DnsRecord::CERT {
+                cert_type,
+                key_tag,
+                algorithm,
+                ref certificate,
+                ..
+            } => format!("{} {} {} {}", cert_type, key_tag, algorithm, base64_encode(certificate)),
+            DnsRecord::APL { ref prefixes, .. } => prefixes
+                .iter()
+                .map(|item| {
+                    let address = match item.family {
+                        1 => {
+                            let mut octets = [0; 4];
+                            for (i, b) in item.address.iter().take(4).enumerate() {
+                                octets[i] = *b;
+                            }
+                            Ipv4Addr::from(octets).to_string()
+                        }
+                        2 => {
+                            let mut octets = [0; 16];
+                            for (i, b) in item.address.iter().take(16).enumerate() {
+                                octets[i] = *b;
+                            }
+                            Ipv6Addr::from(octets).to_string()
+                        }
+                        _ => item.address.iter().map(|b| format!("{:02x}", b)).collect(),
+                    };
+                    format!(
+                        "{}{}:{}/{}",
+                        if item.negation { "!" } else { "" },
+                        item.family,
+                        address,
+                        item.prefix
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                digest.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+            ),
+            DnsRecord::SSHFP {
+                algorithm,
+                fp_type,
+                ref fingerprint,
+                ..
+            } => format!(
+                "{} {} {}",
+                algorithm,
+                fp_type,
+                fingerprint.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer_name,
+                ref signature,
+                ..
+            } => {
+                let covered = match QueryType::from_num(type_covered) {
+                    QueryType::UNKNOWN(num) => format!("TYPE{}", num),
+                    qtype => format!("{:?}", qtype),
+                };
+                let timestamp = |secs: u32| {
+                    chrono::DateTime::from_timestamp(secs as i64, 0)
+                        .map(|time| time.format("%Y%m%d%H%M%S").to_string())
+                        .unwrap_or_default()
+                };
+
+                format!(
+                    "{} {} {} {} {} {} {} {}. {}",
+                    covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    timestamp(expiration),
+                    timestamp(inception),
+                    key_tag,
+                    signer_name,
+                    base64_encode(signature)
+                )
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ..
+            } => format!("{} {} {} {}", flags, protocol, algorithm, base64_encode(public_key)),
+            DnsRecord::SMIMEA {
+                usage,
+                selector,
+                matching_type,
+                ref data,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                usage,
+                selector,
+                matching_type,
+                data.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+            ),
+            DnsRecord::SVCB {
+                priority,
+                ref target,
+                ref params,
+                ..
+            }
+            | DnsRecord::HTTPS {
+                priority,
+                ref target,
+                ref params,
+                ..
+            } => {
+                let mut out = format!("{} {}.", priority, target);
+                for (key, value) in params {
+                    out.push_str(&format!(" key{}={:?}", key, String::from_utf8_lossy(value)));
+                }
+                out
+            }
+            DnsRecord::URI {
+                priority,
+                weight,
+                ref target,
+                ..
+            } => format!("{} {} {:?}", priority, weight, target),
+            DnsRecord::OPT { ref options, .. } => options
+                .iter()
+                .map(|option| {
+                    let data = option.data.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+                    format!("{}:{}", option.code, data)
+                })
+                .collect::<Vec<String>>()
+                .join(" "),
+            DnsRecord::UNKNOWN { data_len, .. } => format!("\\# {}", data_len),
+        }
+    }
+
     pub fn get_ttl(&self) -> u32 {
         match *self {
             DnsRecord::A { ttl: TransientTtl(ttl), .. }
             | DnsRecord::AAAA { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::LOC { ttl: TransientTtl(ttl), .. }
             | DnsRecord::NS { ttl: TransientTtl(ttl), .. }
             | DnsRecord::CNAME { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::PTR { ttl: TransientTtl(ttl), .. }
             | DnsRecord::SRV { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::HINFO { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::NAPTR { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::CERT { ttl: TransientTtl(ttl), .. }
             | DnsRecord::MX { ttl: TransientTtl(ttl), .. }
             | DnsRecord::UNKNOWN { ttl: TransientTtl(ttl), .. }
             | DnsRecord::SOA { ttl: TransientTtl(ttl), .. }
-            | DnsRecord::TXT { ttl: TransientTtl(ttl), .. } => ttl,
+            | DnsRecord::TXT { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::APL { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::DS { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::SSHFP { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::RRSIG { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::DNSKEY { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::SMIMEA { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::SVCB { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::HTTPS { ttl: TransientTtl(ttl), .. }
+            | DnsRecord::URI { ttl: TransientTtl(ttl), .. } => ttl,
             DnsRecord::OPT { .. } => 0,
         }
-    }    
-}
+    }
 
+    /// Mutable access to the owner name, `None` for OPT which doesn't carry one
+    pub fn domain_mut(&mut self) -> Option<&mut String> {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::LOC { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::HINFO { domain, .. }
+            | DnsRecord::NAPTR { domain, .. }
+            | DnsRecord::CERT { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::APL { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::SSHFP { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::SMIMEA { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::URI { domain, .. } => Some(domain),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
 
-/// The result code for a DNS query, as described in the specification
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-#[repr(u8)] // Specifies the enum's underlying type
-pub enum ResultCode {
-    NOERROR = 0,
-    FORMERR = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP = 4,
-    REFUSED = 5,
-}
+    /// Mutable access to the record TTL, `None` for OPT which doesn't carry one
+    pub fn ttl_mut(&mut self) -> Option<&mut TransientTtl> {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::LOC { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::HINFO { ttl, .. }
+            | DnsRecord::NAPTR { ttl, .. }
+            | DnsRecord::CERT { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::APL { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::SSHFP { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::SMIMEA { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::URI { ttl, .. } => Some(ttl),
+            DnsRecord::OPT { .. } => None,
+        }
+    }
 
-impl Default for ResultCode {
-    fn default() -> Self {
-        ResultCode::NOERROR
+    /// Returns the latitude and longitude of a LOC record in degrees, `None` for other records
+    pub fn loc_degrees(&self) -> Option<(f64, f64)> {
+        match *self {
+            DnsRecord::LOC { latitude, longitude, .. } => {
+                Some((loc_coordinate_to_degrees(latitude), loc_coordinate_to_degrees(longitude)))
+            }
+            _ => None,
+        }
     }
-}
 
-impl ResultCode {
-    pub fn from_num(num: u8) -> ResultCode {
+    /// Whether the record is owned by `qname`, ignoring case and any trailing dot
+    pub fn matches_name(&self, qname: &str) -> bool {
+        match self.get_domain() {
+            Some(domain) => same_name(&domain, qname),
+            None => false,
+        }
+    }
+
+    /// Whether `qname` is the record's owner or a name below it, such as for the NS of a delegation
+    ///
+    /// Only whole labels match, so `example.com` covers `www.example.com` but not `badexample.com`.
+    pub fn matches_suffix(&self, qname: &str) -> bool {
+        match self.get_domain() {
+            Some(domain) => is_subdomain(qname, &domain),
+            None => false,
+        }
+    }
+
+    /// Returns a copy of the record with its TTL replaced by `ttl`, unchanged for OPT
+    pub fn with_ttl(&self, ttl: u32) -> DnsRecord {
+        let mut record = self.clone();
+        if let Some(record_ttl) = record.ttl_mut() {
+            *record_ttl = TransientTtl(ttl);
+        }
+
+        record
+    }
+}
+
+
+/// The result code for a DNS query, as described in the specification
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(u8)] // Specifies the enum's underlying type
+pub enum ResultCode {
+    #[default]
+    NOERROR = 0,
+    FORMERR = 1,
+    SERVFAIL = 2,
+    NXDOMAIN = 3,
+    NOTIMP = 4,
+    REFUSED = 5,
+}
+
+impl ResultCode {
+    pub fn from_num(num: u8) -> ResultCode {
         match num {
             1 => ResultCode::FORMERR,
             2 => ResultCode::SERVFAIL,
             3 => ResultCode::NXDOMAIN,
             4 => ResultCode::NOTIMP,
             5 => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            _ => ResultCode::NOERROR,
         }
     }
 }
-    /// Get the numeric representation of the `ResultCode`
+//     /// Get the numeric representation of the `ResultCode`
 //     pub fn to_num(&self) -> u8 {
 //         match *self {
 //             ResultCode::NOERROR => 0,
@@ -745,21 +2245,36 @@ impl fmt::Display for DnsHeader {
 }
 
 ///DNS Question representation
-#[derive(Debug, Clone, PartialEq, Eq)]
+///
+/// Questions compare equal regardless of the case of their names, as DNS names do.
+#[derive(Debug, Clone)]
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
 }
 
+impl PartialEq for DnsQuestion {
+    fn eq(&self, other: &Self) -> bool {
+        self.qtype == other.qtype && self.name.eq_ignore_ascii_case(&other.name)
+    }
+}
+
+impl Eq for DnsQuestion {}
+
 impl DnsQuestion {
     /// creates a new dns question.
     pub fn new(name: String, qtype: QueryType) -> Self {
         Self { name, qtype }
     }
 
+    /// Compares with `other` including the case of the names, as 0x20 encoding requires.
+    pub fn eq_exact(&self, other: &DnsQuestion) -> bool {
+        self.qtype == other.qtype && self.name == other.name
+    }
+
     /// calculates the binary length of a dns question.
     pub fn binary_len(&self) -> usize {
-        self.name.split('.').map(|x| x.len() + 1).sum::<usize>() + 1
+        name_len(&self.name)
     }
 
     /// Wrte the dns question to a packet buffer
@@ -777,6 +2292,14 @@ impl DnsQuestion {
         buffer.read_u16()?;
         Ok(())
     }
+
+    /// Reads the dns question, keeping the case of the name as sent.
+    pub fn read_preserving_case<T: PacketBuffer>(&mut self, buffer: &mut T) -> Result<()> {
+        buffer.read_qname_preserving_case(&mut self.name)?;
+        self.qtype = QueryType::from_num(buffer.read_u16()?);
+        buffer.read_u16()?;
+        Ok(())
+    }
 }
 
 impl fmt::Display for DnsQuestion {
@@ -794,7 +2317,7 @@ impl fmt::Display for DnsQuestion {
 ///
 /// This was our end goal all along. the queen of our chess pieces.
 /// A packet can be read and written in a single operation.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct DnsPacket {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
@@ -808,10 +2331,41 @@ impl DnsPacket {
     pub fn new() -> Self {
         Self::default()
     }
-    
+
+    /// Marks this packet as the response to the query with header `from_query`.
+    ///
+    /// Copies the id, opcode and the RD and CD bits of the query, and sets RA from
+    /// `recursion_available`. The rescode is left as it is.
+    pub fn make_response(&mut self, from_query: &DnsHeader, recursion_available: bool) {
+        self.header.id = from_query.id;
+        self.header.response = true;
+        self.header.opcode = from_query.opcode;
+        self.header.recursion_desired = from_query.recursion_desired;
+        self.header.checking_disabled = from_query.checking_disabled;
+        self.header.recursion_available = recursion_available;
+    }
 
     /// Reads a dns packet from the packet buffer
     pub fn from_buffer<T: PacketBuffer>(buffer: &mut T) -> Result<Self> {
+        Self::read_from(buffer, false)
+    }
+
+    /// Reads a dns packet from its wire format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut buffer = buffer::VectorPacketBuffer::new();
+        buffer.buffer = bytes.to_vec();
+
+        Self::from_buffer(&mut buffer)
+    }
+
+    /// Parses a packet like `from_buffer`, but keeps the case of the question names.
+    ///
+    /// Record names are lowercased either way.
+    pub fn from_buffer_preserving_case<T: PacketBuffer>(buffer: &mut T) -> Result<Self> {
+        Self::read_from(buffer, true)
+    }
+
+    fn read_from<T: PacketBuffer>(buffer: &mut T, preserve_case: bool) -> Result<Self> {
         let mut packet = Self::new();
 
         // Read the dns header
@@ -833,7 +2387,11 @@ impl DnsPacket {
         // Read the questions
         for _ in 0..packet.header.questions {
             let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
-            question.read(buffer)?;
+            if preserve_case {
+                question.read_preserving_case(buffer)?;
+            } else {
+                question.read(buffer)?;
+            }
             packet.questions.push(question);
         }
 
@@ -865,6 +2423,210 @@ impl DnsPacket {
 
     }
 
+    /// Renders the packet in the presentation format used by `dig`.
+    pub fn to_dig_string(&self) -> String {
+        let mut out = String::new();
+
+        let opcode = match self.header.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            x => x.to_string(),
+        };
+
+        out.push_str(&format!(
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}\n",
+            opcode, self.header.rescode, self.header.id
+        ));
+
+        let flags = [
+            (self.header.response, "qr"),
+            (self.header.authoritative_answer, "aa"),
+            (self.header.truncated_message, "tc"),
+            (self.header.recursion_desired, "rd"),
+            (self.header.recursion_available, "ra"),
+            (self.header.authed_data, "ad"),
+            (self.header.checking_disabled, "cd"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+        out.push_str(&format!(
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}\n",
+            flags,
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.resources.len()
+        ));
+
+        fn type_name(qtype: QueryType) -> String {
+            match qtype {
+                QueryType::UNKNOWN(x) => format!("TYPE{}", x),
+                _ => format!("{:?}", qtype),
+            }
+        }
+
+        if let Some(DnsRecord::OPT { packet_len, .. }) =
+            self.resources.iter().find(|rec| rec.get_querytype() == QueryType::OPT)
+        {
+            out.push_str("\n;; OPT PSEUDOSECTION:\n");
+            out.push_str(&format!("; EDNS: version: 0, udp: {}\n", packet_len));
+            if let Some((info_code, text)) = self.get_extended_error() {
+                out.push_str(&format!("; EDE: {}: ({})\n", info_code, text));
+            }
+        }
+
+        if !self.questions.is_empty() {
+            out.push_str("\n;; QUESTION SECTION:\n");
+            for question in &self.questions {
+                out.push_str(&format!(";{}.\t\tIN\t{}\n", question.name, type_name(question.qtype)));
+            }
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.resources),
+        ];
+
+        for (label, records) in sections.iter() {
+            let records = records
+                .iter()
+                .filter(|rec| rec.get_querytype() != QueryType::OPT)
+                .collect::<Vec<&DnsRecord>>();
+
+            if records.is_empty() {
+                continue;
+            }
+
+            out.push_str(&format!("\n;; {} SECTION:\n", label));
+            for rec in records {
+                out.push_str(&format!(
+                    "{}.\t{}\tIN\t{}\t{}\n",
+                    rec.get_domain().unwrap_or_default(),
+                    rec.get_ttl(),
+                    type_name(rec.get_querytype()),
+                    rec.rdata_to_string()
+                ));
+            }
+        }
+
+        out
+    }
+
+    /// Appends the records of `other` that aren't already present, section by section.
+    ///
+    /// Records are compared ignoring their TTL, and the header counts are updated
+    /// to match the merged sections.
+    pub fn merge(&mut self, other: &DnsPacket) {
+        fn merge_section(section: &mut Vec<DnsRecord>, records: &[DnsRecord]) {
+            for rec in records {
+                if !section.contains(rec) {
+                    section.push(rec.clone());
+                }
+            }
+        }
+
+        merge_section(&mut self.answers, &other.answers);
+        merge_section(&mut self.authorities, &other.authorities);
+        merge_section(&mut self.resources, &other.resources);
+
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    /// Checks that this packet is a response to `query`.
+    ///
+    /// The id has to match, the response flag must be set and the question
+    /// section must echo the query's, with names compared case-insensitively.
+    pub fn is_valid_response(&self, query: &DnsPacket) -> bool {
+        self.header.id == query.header.id
+            && self.header.response
+            && self.questions.len() == query.questions.len()
+            && self
+                .questions
+                .iter()
+                .zip(query.questions.iter())
+                .all(|(answered, asked)| answered == asked)
+    }
+
+    /// Attaches an Extended DNS Error, replacing any already present.
+    ///
+    /// An OPT record is added to the additional section if the packet has none.
+    pub fn set_extended_error(&mut self, info_code: u16, text: &str) {
+        let options = self.opt_options_mut();
+        options.retain(|option| option.code != EDNS_EXTENDED_ERROR);
+        options.push(OptOption::extended_error(info_code, text));
+    }
+
+    /// Attaches an edns-tcp-keepalive option, replacing any earlier one.
+    pub fn set_tcp_keepalive(&mut self, keepalive: TcpKeepalive) {
+        let options = self.opt_options_mut();
+        options.retain(|option| option.code != EDNS_TCP_KEEPALIVE);
+        options.push(OptOption::tcp_keepalive(keepalive));
+    }
+
+    /// Returns the edns-tcp-keepalive option attached to the packet.
+    pub fn get_tcp_keepalive(&self) -> Option<TcpKeepalive> {
+        self.resources.iter().find_map(|rec| match *rec {
+            DnsRecord::OPT { ref options, .. } => {
+                options.iter().find_map(|option| option.get_tcp_keepalive())
+            }
+            _ => None,
+        })
+    }
+
+    /// Whether the packet carries an OPT record, that is whether its sender speaks EDNS.
+    pub fn has_opt(&self) -> bool {
+        self.resources.iter().any(|rec| rec.get_querytype() == QueryType::OPT)
+    }
+
+    /// Returns the options of the packet's OPT record, adding the record if there's none.
+    fn opt_options_mut(&mut self) -> &mut Vec<OptOption> {
+        let idx = match self
+            .resources
+            .iter()
+            .position(|rec| rec.get_querytype() == QueryType::OPT)
+        {
+            Some(idx) => idx,
+            None => {
+                self.resources.push(DnsRecord::OPT {
+                    packet_len: DEFAULT_EDNS_PAYLOAD_SIZE,
+                    flags: 0,
+                    options: Vec::new(),
+                });
+                self.resources.len() - 1
+            }
+        };
+
+        match self.resources[idx] {
+            DnsRecord::OPT { ref mut options, .. } => options,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the info-code and text of the Extended DNS Error attached to the packet.
+    pub fn get_extended_error(&self) -> Option<(u16, String)> {
+        self.resources.iter().find_map(|rec| match *rec {
+            DnsRecord::OPT { ref options, .. } => {
+                options.iter().find_map(|option| option.get_extended_error())
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns the first SOA record in the authorities section
+    pub fn get_soa(&self) -> Option<&DnsRecord> {
+        self.authorities.iter().find(|record| matches!(record, DnsRecord::SOA { .. }))
+    }
+
     /// Retrieves the ttl value from the first SOA record in the authorities section
     pub fn get_ttl_from_soa(&self) -> Option<u32> {
         self.authorities.iter().find_map(|record| {
@@ -876,15 +2638,121 @@ impl DnsPacket {
         })
     }
 
+    /// Smallest TTL of the records in any section, which bounds how long the packet as a whole stays valid.
+    ///
+    /// OPT pseudo records carry no TTL and are skipped.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.resources.iter())
+            .filter(|record| !matches!(record, DnsRecord::OPT { .. }))
+            .map(|record| record.get_ttl())
+            .min()
+    }
+
+    /// Removes repeated records from each section, keeping the first of each and the order.
+    ///
+    /// Records that differ only in TTL count as repeats. Not for zone transfers, where
+    /// the repeated SOA records are meaningful.
+    pub fn dedup(&mut self) {
+        for section in [&mut self.answers, &mut self.authorities, &mut self.resources] {
+            let mut seen = HashSet::new();
+            section.retain(|record| seen.insert(record.clone()));
+        }
+
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = self.resources.len() as u16;
+    }
+
+    /// Number of records of `qtype` across all sections, or of every record for ANY.
+    pub fn count_records(&self, qtype: QueryType) -> usize {
+        self.answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.resources.iter())
+            .filter(|record| qtype == QueryType::ANY || record.get_querytype() == qtype)
+            .count()
+    }
+
     /// Gets a random A record's address from the answers section
     pub fn get_random_a(&self) -> Option<String> {
+        let addrs = self.get_a_records();
+        if addrs.is_empty() {
+            return None;
+        }
+
+        let idx = rand::random::<usize>() % addrs.len();
+        Some(addrs[idx].to_string())
+    }
+
+    /// Retrieves the addresses of all A records in the answers section, in answer order
+    pub fn get_a_records(&self) -> Vec<Ipv4Addr> {
         self.answers.iter().filter_map(|record| {
             if let DnsRecord::A { addr, .. } = record {
-                Some(addr.to_string())
+                Some(*addr)
             } else {
                 None
             }
-        }).next()
+        }).collect()
+    }
+
+    /// Retrieves the addresses of all AAAA records in the answers section, in answer order
+    pub fn get_aaaa_records(&self) -> Vec<Ipv6Addr> {
+        self.answers.iter().filter_map(|record| {
+            if let DnsRecord::AAAA { addr, .. } = record {
+                Some(*addr)
+            } else {
+                None
+            }
+        }).collect()
+    }
+
+    /// Returns the priority and host of the most preferred MX record in the answers section
+    ///
+    /// That is the one with the lowest priority, the first of them on a tie.
+    pub fn preferred_mx(&self) -> Option<(u16, String)> {
+        self.answers.iter().filter_map(|record| {
+            if let DnsRecord::MX { priority, ref host, .. } = *record {
+                Some((priority, host.clone()))
+            } else {
+                None
+            }
+        }).min_by_key(|&(priority, _)| priority)
+    }
+
+    /// Picks the SRV record to contact from the answers section, as described in RFC 2782
+    ///
+    /// Only records of the lowest priority are considered, chosen at random in proportion
+    /// to their weight. Records of weight 0 only stand a small chance against weighted ones.
+    pub fn select_srv(&self) -> Option<&DnsRecord> {
+        let records = self.answers.iter().filter_map(|record| match *record {
+            DnsRecord::SRV { priority, weight, .. } => Some((priority, weight, record)),
+            _ => None,
+        });
+
+        let lowest = records.clone().map(|(priority, _, _)| priority).min()?;
+        let mut candidates = records
+            .filter(|&(priority, _, _)| priority == lowest)
+            .map(|(_, weight, record)| (weight, record))
+            .collect::<Vec<_>>();
+
+        // Weight 0 records go first, so they're picked when the draw lands on 0
+        candidates.sort_by_key(|&(weight, _)| weight != 0);
+
+        let total = candidates.iter().map(|&(weight, _)| weight as u32).sum::<u32>();
+        let target = rand::random::<u32>() % (total + 1);
+
+        let mut running = 0;
+        candidates.into_iter().find_map(|(weight, record)| {
+            running += weight as u32;
+            if running >= target {
+                Some(record)
+            } else {
+                None
+            }
+        })
     }
 
     /// Retrieves unresolved CNAME records from the answers section
@@ -911,11 +2779,11 @@ impl DnsPacket {
     /// Retrieves a resolved NS record for the given query name
     pub fn get_resolved_ns(&self, qname: &str) -> Option<String> {
         self.authorities.iter().filter_map(|auth| {
-            if let DnsRecord::NS { domain, host, .. } = auth {
-                if qname.ends_with(domain) {
+            if let DnsRecord::NS { host, .. } = auth {
+                if auth.matches_suffix(qname) {
                     self.resources.iter().find_map(|resource| {
-                        if let DnsRecord::A { domain, addr, .. } = resource {
-                            if domain == host {
+                        if let DnsRecord::A { addr, .. } = resource {
+                            if resource.matches_name(host) {
                                 return Some(addr.to_string());
                             }
                         }
@@ -933,8 +2801,8 @@ impl DnsPacket {
     /// Retrieves an unresolved NS record for the given query name
     pub fn get_unresolved_ns(&self, qname: &str) -> Option<String> {
         self.authorities.iter().filter_map(|auth| {
-            if let DnsRecord::NS { domain, host, .. } = auth {
-                if qname.ends_with(domain) {
+            if let DnsRecord::NS { host, .. } = auth {
+                if auth.matches_suffix(qname) {
                     Some(host.clone())
                 } else {
                     None
@@ -945,17 +2813,67 @@ impl DnsPacket {
         }).next()
     }
 
+    /// Largest UDP response the sender of this query will accept.
+    ///
+    /// Comes from the payload size in the query's OPT record, and is 512 without one.
+    /// Advertised sizes below 512 are treated as 512, as RFC 6891 requires.
+    pub fn max_udp_response_size(&self) -> usize {
+        self.resources
+            .iter()
+            .find_map(|record| match *record {
+                DnsRecord::OPT { packet_len, .. } => Some(packet_len as usize),
+                _ => None,
+            })
+            .map_or(DEFAULT_UDP_PAYLOAD_SIZE, |size| size.max(DEFAULT_UDP_PAYLOAD_SIZE))
+    }
+
+    /// Writes this packet as the UDP response to `query`, limited to the size the query advertised.
+    pub fn write_udp_response<T: PacketBuffer>(&mut self, query: &DnsPacket, buffer: &mut T) -> Result<()> {
+        self.write(buffer, query.max_udp_response_size())
+    }
+
+    /// Writes this packet as the TCP response to `query`, on a connection idling out after `idle_timeout`.
+    ///
+    /// Queries with an OPT record are told the timeout through edns-tcp-keepalive,
+    /// RFC 7828 doesn't allow sending it to clients without EDNS.
+    pub fn write_tcp_response<T: PacketBuffer>(
+        &mut self,
+        query: &DnsPacket,
+        idle_timeout: Duration,
+        buffer: &mut T,
+    ) -> Result<()> {
+        if query.has_opt() {
+            self.set_tcp_keepalive(TcpKeepalive::from_duration(idle_timeout));
+        }
+
+        self.write(buffer, 0xFFFF)
+    }
+
     /// Writes the DNS packet to a packet buffer with a specified maximum size
+    ///
+    /// Records are written straight into `buffer`. The first one that doesn't fit
+    /// is rolled back and ends the packet, with the truncated flag set.
     pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T, max_size: usize) -> Result<()> {
-        let mut test_buffer = VectorPacketBuffer::new();
+        self.write_records(buffer, max_size).map(|_| ())
+    }
 
-        let mut size = self.header.binary_len();
-        for ref question in &self.questions {
-            size += question.binary_len();
-            question.write(&mut test_buffer)?;
-        }
+    /// Writes the packet like `write`, returning which records made it in.
+    ///
+    /// Records are numbered across the answer, authority and additional sections in turn.
+    fn write_records<T: PacketBuffer>(&mut self, buffer: &mut T, max_size: usize) -> Result<Vec<usize>> {
+        let start = buffer.pos();
+        let mut written = Vec::new();
+
+        // The counts are filled in once it's known how many records fit
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = 0;
+        self.header.authoritative_entries = 0;
+        self.header.resource_entries = 0;
+        self.header.write(buffer)?;
 
-        let mut record_count = self.answers.len() + self.authorities.len() + self.resources.len();
+        for question in &self.questions {
+            question.write(buffer)?;
+        }
 
         for (i, rec) in self
             .answers
@@ -964,12 +2882,24 @@ impl DnsPacket {
             .chain(self.resources.iter())
             .enumerate()
         {
-            size += rec.write(&mut test_buffer)?;
-            if size > max_size {
-                record_count = i;
+            let rec_start = buffer.pos();
+            let fits = match rec.write(buffer) {
+                // Records we can't encode aren't written, so mustn't be counted either
+                Ok(0) => continue,
+                Ok(_) => buffer.pos() - start <= max_size,
+                // A fixed size buffer running out is no different from reaching max_size
+                Err(ProtocolError::Buffer(buffer::BufferError::EndOfBuffer)) => false,
+                Err(err) => return Err(err),
+            };
+
+            if !fits {
+                buffer.truncate(rec_start)?;
                 self.header.truncated_message = true;
                 break;
-            } else if i < self.answers.len() {
+            }
+
+            written.push(i);
+            if i < self.answers.len() {
                 self.header.answers += 1;
             } else if i < self.answers.len() + self.authorities.len() {
                 self.header.authoritative_entries += 1;
@@ -978,27 +2908,116 @@ impl DnsPacket {
             }
         }
 
-        self.header.questions = self.questions.len() as u16;
+        // Go back and put the final counts and flags in the header
+        let mut header = buffer::BytePacketBuffer::new();
+        self.header.write(&mut header)?;
+        for (i, &byte) in header.buf[..header.pos()].iter().enumerate() {
+            buffer.set(start + i, byte)?;
+        }
 
-        self.header.write(buffer)?;
+        Ok(written)
+    }
+
+    /// Writes the packet, packing as many complete answer RRsets as fit within `max_size`.
+    ///
+    /// Unlike `write`, an RRset that doesn't fit is skipped rather than ending the answer
+    /// section, so smaller RRsets later in the packet still make it in.
+    pub fn write_packed<T: PacketBuffer>(&mut self, buffer: &mut T, max_size: usize) -> Result<()> {
+        // Group the answers into RRsets, keeping the order of first appearance
+        let mut rrsets: Vec<Vec<&DnsRecord>> = Vec::new();
+        for rec in &self.answers {
+            match rrsets.iter_mut().find(|set| {
+                set[0].get_domain() == rec.get_domain() && set[0].get_querytype() == rec.get_querytype()
+            }) {
+                Some(set) => set.push(rec),
+                None => rrsets.push(vec![rec]),
+            }
+        }
 
+        let mut size = self.header.binary_len();
         for question in &self.questions {
-            question.write(buffer)?;
+            size += question.binary_len() + 4;
         }
 
-        for rec in self
-            .answers
-            .iter()
-            .chain(self.authorities.iter())
-            .chain(self.resources.iter())
-            .take(record_count)
-        {
-            rec.write(buffer)?;
+        let mut packed = Vec::new();
+        let mut dropped = false;
+        for set in rrsets {
+            // Sizing ignores compression, so this never underestimates
+            let set_size = set.iter().map(|rec| rec.binary_len()).sum::<usize>();
+
+            if size + set_size > max_size {
+                dropped = true;
+                continue;
+            }
+
+            size += set_size;
+            packed.extend(set.into_iter().cloned());
         }
 
+        let mut packet = DnsPacket {
+            header: self.header.clone(),
+            questions: self.questions.clone(),
+            answers: packed,
+            authorities: self.authorities.clone(),
+            resources: self.resources.clone(),
+        };
+        packet.header.answers = 0;
+        packet.header.authoritative_entries = 0;
+        packet.header.resource_entries = 0;
+        packet.header.truncated_message = self.header.truncated_message || dropped;
+
+        packet.write(buffer, max_size)?;
+        self.header = packet.header;
+
         Ok(())
     }
-    
+
+    /// Writes the packet like `write` and returns the wire format
+    pub fn to_bytes(&mut self, max_size: usize) -> Result<Vec<u8>> {
+        let mut buffer = buffer::VectorPacketBuffer::new();
+        self.write(&mut buffer, max_size)?;
+
+        Ok(buffer.buffer)
+    }
+
+    /// Returns a copy holding only the records `write` would fit within `max_size`.
+    ///
+    /// The truncated flag is set on the copy when records had to be dropped, and a
+    /// packet that can't be written at all is cut down to its questions.
+    pub fn truncated(&self, max_size: usize) -> DnsPacket {
+        let mut packet = self.clone();
+
+        let written = match packet.write_records(&mut buffer::VectorPacketBuffer::new(), max_size) {
+            Ok(written) => written,
+            Err(_) => {
+                packet.answers.clear();
+                packet.authorities.clear();
+                packet.resources.clear();
+                packet.header.answers = 0;
+                packet.header.authoritative_entries = 0;
+                packet.header.resource_entries = 0;
+                packet.header.truncated_message = true;
+                return packet;
+            }
+        };
+
+        // Keep exactly the records written, records that aren't encoded at all may sit in between
+        let authorities_start = packet.answers.len();
+        let resources_start = authorities_start + packet.authorities.len();
+        let keep = |records: &mut Vec<DnsRecord>, offset: usize| {
+            let mut idx = offset;
+            records.retain(|_| {
+                idx += 1;
+                written.binary_search(&(idx - 1)).is_ok()
+            });
+        };
+        keep(&mut packet.answers, 0);
+        keep(&mut packet.authorities, authorities_start);
+        keep(&mut packet.resources, resources_start);
+
+        packet
+    }
+
 }
 
 #[cfg(test)]
@@ -1071,56 +3090,1202 @@ mod tests {
     }
 
     #[test]
-    fn test_ttl_from_soa() {
-        let mut packet = DnsPacket::new();
-        packet.authorities.push(DnsRecord::SOA {
-            domain: "example.com".to_string(),
-            m_name: "ns1.example.com".to_string(),
-            r_name: "admin.example.com".to_string(),
-            serial: 20231201,
-            refresh: 7200,
-            retry: 3600,
-            expire: 1209600,
-            minimum: 600,
-            ttl: TransientTtl(3600),
-        });
+    fn test_question_binary_len() {
+        for (name, expected) in [("", 1), (".", 1), ("com", 5), ("com.", 5), ("a.b.c", 7)] {
+            let question = DnsQuestion::new(name.to_string(), QueryType::A);
 
-        let ttl = packet.get_ttl_from_soa();
-        assert_eq!(ttl, Some(600));
+            let mut buffer = VectorPacketBuffer::new();
+            question.write(&mut buffer).unwrap();
+
+            assert_eq!(expected, question.binary_len(), "{:?}", name);
+            // Followed by the type and class
+            assert_eq!(expected + 4, buffer.buffer.len(), "{:?}", name);
+        }
     }
 
     #[test]
-    fn test_resolved_ns() {
+    fn test_dedup_removes_repeated_records() {
+        let record = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 0, 1),
+            ttl: TransientTtl(300),
+        };
+        let other = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 0, 2),
+            ttl: TransientTtl(300),
+        };
+
         let mut packet = DnsPacket::new();
+        packet.answers = vec![record.clone(), other.clone(), record.clone()];
+        packet.resources = vec![record.clone(), record.clone()];
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+        buffer.seek(0).unwrap();
+        let mut parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(3, parsed.header.answers);
+
+        parsed.dedup();
+        assert_eq!(vec![record.clone(), other], parsed.answers);
+        assert_eq!(vec![record], parsed.resources);
+        assert_eq!(2, parsed.header.answers);
+        assert_eq!(1, parsed.header.resource_entries);
+    }
 
+    #[test]
+    fn test_count_records() {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 0, 1),
+            ttl: TransientTtl(300),
+        });
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 0, 2),
+            ttl: TransientTtl(300),
+        });
         packet.authorities.push(DnsRecord::NS {
             domain: "example.com".to_string(),
             host: "ns1.example.com".to_string(),
             ttl: TransientTtl(3600),
         });
-
         packet.resources.push(DnsRecord::A {
             domain: "ns1.example.com".to_string(),
-            addr: Ipv4Addr::new(192, 168, 1, 1),
+            addr: Ipv4Addr::new(192, 168, 0, 53),
+            ttl: TransientTtl(3600),
+        });
+        packet.resources.push(DnsRecord::AAAA {
+            domain: "ns1.example.com".to_string(),
+            addr: "2001:db8::53".parse().unwrap(),
             ttl: TransientTtl(3600),
         });
 
-        let resolved_ns = packet.get_resolved_ns("example.com");
-        assert_eq!(resolved_ns, Some("192.168.1.1".to_string()));
+        assert_eq!(3, packet.count_records(QueryType::A));
+        assert_eq!(1, packet.count_records(QueryType::AAAA));
+        assert_eq!(1, packet.count_records(QueryType::NS));
+        assert_eq!(0, packet.count_records(QueryType::MX));
+        assert_eq!(5, packet.count_records(QueryType::ANY));
+    }
+
+    #[test]
+    fn test_get_address() {
+        let a = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 0, 1),
+            ttl: TransientTtl(300),
+        };
+        assert_eq!(Some(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1))), a.get_address());
+
+        let aaaa = DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: TransientTtl(300),
+        };
+        assert_eq!(Some("2001:db8::1".parse::<IpAddr>().unwrap()), aaaa.get_address());
+
+        let cname = DnsRecord::CNAME {
+            domain: "www.example.com".to_string(),
+            host: "example.com".to_string(),
+            ttl: TransientTtl(300),
+        };
+        assert_eq!(None, cname.get_address());
+    }
+
+    #[test]
+    fn test_min_ttl_across_sections() {
+        let mut packet = DnsPacket::new();
+        assert_eq!(None, packet.min_ttl());
+
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(120),
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 53),
+            ttl: TransientTtl(900),
+        });
+
+        // The OPT record reports a TTL of 0 but doesn't count
+        packet.set_extended_error(EDE_BLOCKED, "blocked");
+
+        assert_eq!(Some(120), packet.min_ttl());
+    }
+
+    #[test]
+    fn test_all_a_and_aaaa_records() {
+        let mut packet = DnsPacket::new();
+        for i in 1..=3 {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600),
+            });
+        }
+        packet.answers.push(DnsRecord::AAAA {
+            domain: "example.com".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: TransientTtl(3600),
+        });
+
+        assert_eq!(
+            vec![
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 2),
+                Ipv4Addr::new(10, 0, 0, 3),
+            ],
+            packet.get_a_records()
+        );
+        assert_eq!(
+            vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()],
+            packet.get_aaaa_records()
+        );
+
+        let addrs = packet
+            .get_a_records()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<String>>();
+        for _ in 0..20 {
+            assert!(addrs.contains(&packet.get_random_a().unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_random_a_without_a_records() {
+        assert_eq!(None, DnsPacket::new().get_random_a());
+    }
+
+    #[test]
+    fn test_ttl_from_soa() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example.com".to_string(),
+            m_name: "ns1.example.com".to_string(),
+            r_name: "admin.example.com".to_string(),
+            serial: 20231201,
+            refresh: 7200,
+            retry: 3600,
+            expire: 1209600,
+            minimum: 600,
+            ttl: TransientTtl(3600),
+        });
+
+        let ttl = packet.get_ttl_from_soa();
+        assert_eq!(ttl, Some(600));
+    }
+
+    #[test]
+    fn test_resolved_ns() {
+        let mut packet = DnsPacket::new();
+
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+
+        packet.resources.push(DnsRecord::A {
+            domain: "ns1.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 168, 1, 1),
+            ttl: TransientTtl(3600),
+        });
+
+        let resolved_ns = packet.get_resolved_ns("example.com");
+        assert_eq!(resolved_ns, Some("192.168.1.1".to_string()));
     }
 
     #[test]
     fn test_unresolved_ns() {
         let mut packet = DnsPacket::new();
 
-        packet.authorities.push(DnsRecord::NS {
+        packet.authorities.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+
+        let unresolved_ns = packet.get_unresolved_ns("example.com");
+        assert_eq!(unresolved_ns, Some("ns1.example.com".to_string()));
+    }
+
+    #[test]
+    fn test_ns_rdlength_with_compressed_host() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        let first = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+        let second = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "ns2.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        first.write(&mut buffer).unwrap();
+
+        // Owner pointer (2 bytes) followed by type, class and ttl (8 bytes)
+        let len_pos = buffer.pos() + 2 + 8;
+        second.write(&mut buffer).unwrap();
+
+        let rdlength = ((buffer.buffer[len_pos] as usize) << 8) | buffer.buffer[len_pos + 1] as usize;
+        let rdata = &buffer.buffer[len_pos + 2..];
+
+        // "ns2" label followed by a pointer back to "example.com"
+        assert_eq!(6, rdlength);
+        assert_eq!(rdata.len(), rdlength);
+        assert_eq!(0xC0, rdata[4] & 0xC0);
+
+        buffer.seek(0).unwrap();
+        assert_eq!(first, DnsRecord::read(&mut buffer).unwrap());
+        assert_eq!(second, DnsRecord::read(&mut buffer).unwrap());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_mx_rdlength_with_compressed_host() {
+        let mut buffer = VectorPacketBuffer::new();
+
+        let ns = DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+        let mx = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        ns.write(&mut buffer).unwrap();
+
+        let len_pos = buffer.pos() + 2 + 8;
+        mx.write(&mut buffer).unwrap();
+
+        let rdlength = ((buffer.buffer[len_pos] as usize) << 8) | buffer.buffer[len_pos + 1] as usize;
+
+        // Priority (2 bytes) followed by a single pointer to "mail.example.com"
+        assert_eq!(4, rdlength);
+        assert_eq!(buffer.buffer.len() - (len_pos + 2), rdlength);
+
+        buffer.seek(0).unwrap();
+        assert_eq!(ns, DnsRecord::read(&mut buffer).unwrap());
+        assert_eq!(mx, DnsRecord::read(&mut buffer).unwrap());
+    }
+
+    #[test]
+    fn test_dig_string_for_a_response() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1337;
+        packet.header.response = true;
+        packet.header.recursion_desired = true;
+        packet.header.recursion_available = true;
+
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(93, 184, 216, 34),
+            ttl: TransientTtl(3600),
+        });
+
+        let expected = [
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 1337",
+            ";; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 0, ADDITIONAL: 0",
+            "",
+            ";; QUESTION SECTION:",
+            ";example.com.\t\tIN\tA",
+            "",
+            ";; ANSWER SECTION:",
+            "example.com.\t3600\tIN\tA\t93.184.216.34",
+            "",
+        ]
+        .join("\n");
+
+        assert_eq!(expected, packet.to_dig_string());
+    }
+
+    #[test]
+    fn test_https_record_round_trip() {
+        let record = DnsRecord::HTTPS {
+            domain: "example.com".to_string(),
+            priority: 1,
+            target: "svc.example.com".to_string(),
+            // alpn=h2
+            params: vec![(1, vec![2, b'h', b'2'])],
+            ttl: TransientTtl(300),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::HTTPS, parsed.get_querytype());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_uri_record_round_trip() {
+        let record = DnsRecord::URI {
+            domain: "_http._tcp.example.com".to_string(),
+            priority: 10,
+            weight: 1,
+            target: "https://example.com/".to_string(),
+            ttl: TransientTtl(300),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.buffer.len(), record.binary_len());
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::URI, parsed.get_querytype());
+        assert_eq!(QueryType::URI, QueryType::from_num(256));
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert_eq!("10 1 \"https://example.com/\"", parsed.rdata_to_string());
+    }
+
+    #[test]
+    fn test_opt_record_round_trip() {
+        let record = DnsRecord::OPT {
+            packet_len: 4096,
+            // The DO bit
+            flags: 0x8000,
+            options: vec![
+                OptOption { code: 10, data: vec![1, 2, 3, 4, 5, 6, 7, 8] },
+                OptOption::extended_error(EDE_BLOCKED, "blocked"),
+            ],
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.buffer.len(), record.binary_len());
+
+        // Root owner, type 41, the payload size as class and the flags as ttl
+        assert_eq!(&[0, 0, 41, 0x10, 0x00, 0, 0, 0x80, 0], &buffer.buffer[..9]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_with_ttl_replaces_only_the_ttl() {
+        let domain = "example.com".to_string();
+        let ttl = TransientTtl(300);
+        let records = vec![
+            DnsRecord::UNKNOWN { domain: domain.clone(), qtype: 999, data_len: 4, ttl },
+            DnsRecord::A { domain: domain.clone(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl },
+            DnsRecord::NS { domain: domain.clone(), host: "ns1.example.com".to_string(), ttl },
+            DnsRecord::CNAME { domain: domain.clone(), host: "alias.example.com".to_string(), ttl },
+            DnsRecord::SOA {
+                domain: domain.clone(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "hostmaster.example.com".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl,
+            },
+            DnsRecord::PTR { domain: domain.clone(), host: "host.example.com".to_string(), ttl },
+            DnsRecord::HINFO { domain: domain.clone(), cpu: "AMD64".to_string(), os: "Linux".to_string(), ttl },
+            DnsRecord::MX { domain: domain.clone(), priority: 10, host: "mail.example.com".to_string(), ttl },
+            DnsRecord::TXT { domain: domain.clone(), data: "hello".to_string(), ttl },
+            DnsRecord::AAAA { domain: domain.clone(), addr: "2001:db8::1".parse().unwrap(), ttl },
+            DnsRecord::LOC {
+                domain: domain.clone(),
+                version: 0,
+                size: 0x12,
+                horiz_pre: 0x16,
+                vert_pre: 0x13,
+                latitude: 1 << 31,
+                longitude: 1 << 31,
+                altitude: 10_000_000,
+                ttl,
+            },
+            DnsRecord::SRV {
+                domain: domain.clone(),
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                host: "sip.example.com".to_string(),
+                ttl,
+            },
+            DnsRecord::NAPTR {
+                domain: domain.clone(),
+                order: 100,
+                preference: 10,
+                flags: "s".to_string(),
+                services: "SIP+D2U".to_string(),
+                regexp: String::new(),
+                replacement: "_sip._udp.example.com".to_string(),
+                ttl,
+            },
+            DnsRecord::CERT { domain: domain.clone(), cert_type: 1, key_tag: 2, algorithm: 8, certificate: vec![1, 2], ttl },
+            DnsRecord::APL {
+                domain: domain.clone(),
+                prefixes: vec![AplItem { family: 1, prefix: 24, negation: false, address: vec![192, 168, 1] }],
+                ttl,
+            },
+            DnsRecord::DS { domain: domain.clone(), key_tag: 1, algorithm: 13, digest_type: 2, digest: vec![0xAB; 32], ttl },
+            DnsRecord::SSHFP { domain: domain.clone(), algorithm: 4, fp_type: 2, fingerprint: vec![0xCD; 32], ttl },
+            DnsRecord::RRSIG {
+                domain: domain.clone(),
+                type_covered: 1,
+                algorithm: 13,
+                labels: 2,
+                original_ttl: 300,
+                expiration: 2,
+                inception: 1,
+                key_tag: 1,
+                signer_name: "example.com".to_string(),
+                signature: vec![1; 64],
+                ttl,
+            },
+            DnsRecord::DNSKEY { domain: domain.clone(), flags: 257, protocol: 3, algorithm: 13, public_key: vec![2; 64], ttl },
+            DnsRecord::SMIMEA { domain: domain.clone(), usage: 3, selector: 1, matching_type: 1, data: vec![3; 32], ttl },
+            DnsRecord::SVCB { domain: domain.clone(), priority: 1, target: "svc.example.com".to_string(), params: Vec::new(), ttl },
+            DnsRecord::HTTPS { domain: domain.clone(), priority: 1, target: "cdn.example.com".to_string(), params: Vec::new(), ttl },
+            DnsRecord::URI { domain: domain.clone(), priority: 10, weight: 1, target: "https://example.com/".to_string(), ttl },
+        ];
+
+        for record in records {
+            let updated = record.with_ttl(42);
+
+            assert_eq!(42, updated.get_ttl(), "{:?}", record);
+            assert_eq!(300, record.get_ttl(), "{:?}", record);
+            // TTLs compare equal, so this checks every other field
+            assert_eq!(record, updated);
+        }
+
+        let opt = DnsRecord::OPT { packet_len: 1232, flags: 0, options: Vec::new() };
+        assert_eq!(opt, opt.with_ttl(42));
+        assert_eq!(0, opt.with_ttl(42).get_ttl());
+    }
+
+    #[test]
+    fn test_loc_record_round_trip() {
+        // The example from RFC 1876, 52 22 23.000 N 4 53 32.000 E -2.00m 1m 10000m 10m
+        let record = DnsRecord::LOC {
+            domain: "cambridge-net.kei.com".to_string(),
+            version: 0,
+            size: 0x12,
+            horiz_pre: 0x16,
+            vert_pre: 0x13,
+            latitude: (1 << 31) + 188_543_000,
+            longitude: (1 << 31) + 17_612_000,
+            altitude: 9_999_800,
+            ttl: TransientTtl(300),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.buffer.len(), record.binary_len());
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::LOC, QueryType::from_num(29));
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert_eq!("52 22 23.000 N 4 53 32.000 E -2.00m 1m 10000m 10m", parsed.rdata_to_string());
+
+        let (latitude, longitude) = parsed.loc_degrees().unwrap();
+        assert!((latitude - 52.373_055).abs() < 1e-5);
+        assert!((longitude - 4.892_222).abs() < 1e-5);
+        assert!((loc_coordinate_to_degrees((1 << 31) - 3_600_000) + 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rdata_len_matches_written_bytes() {
+        // Names in the rdata share no suffix with the owner, so nothing gets compressed
+        let owner = "example.com".to_string();
+        let ttl = TransientTtl(300);
+        let records = vec![
+            DnsRecord::A { domain: owner.clone(), addr: Ipv4Addr::new(10, 0, 0, 1), ttl },
+            DnsRecord::AAAA { domain: owner.clone(), addr: "2001:db8::1".parse().unwrap(), ttl },
+            DnsRecord::NS { domain: owner.clone(), host: "ns1.example.net".to_string(), ttl },
+            DnsRecord::CNAME { domain: owner.clone(), host: "alias.example.org".to_string(), ttl },
+            DnsRecord::PTR { domain: owner.clone(), host: "host.example.net".to_string(), ttl },
+            DnsRecord::SRV {
+                domain: owner.clone(),
+                priority: 10,
+                weight: 5,
+                port: 5060,
+                host: "sip.example.net".to_string(),
+                ttl,
+            },
+            DnsRecord::HINFO { domain: owner.clone(), cpu: "AMD64".to_string(), os: "Linux".to_string(), ttl },
+            DnsRecord::NAPTR {
+                domain: owner.clone(),
+                order: 100,
+                preference: 10,
+                flags: "s".to_string(),
+                services: "SIP+D2U".to_string(),
+                regexp: String::new(),
+                replacement: "_sip._udp.example.net".to_string(),
+                ttl,
+            },
+            DnsRecord::MX { domain: owner.clone(), priority: 10, host: "mail.example.net".to_string(), ttl },
+            DnsRecord::SOA {
+                domain: owner.clone(),
+                m_name: "ns1.example.net".to_string(),
+                r_name: "hostmaster.example.org".to_string(),
+                serial: 2024010101,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl,
+            },
+            DnsRecord::TXT { domain: owner.clone(), data: String::new(), ttl },
+            DnsRecord::TXT { domain: owner.clone(), data: "x".repeat(300), ttl },
+            DnsRecord::APL {
+                domain: owner.clone(),
+                prefixes: vec![AplItem { family: 1, prefix: 24, negation: false, address: vec![192, 168, 1] }],
+                ttl,
+            },
+            DnsRecord::SMIMEA {
+                domain: owner.clone(),
+                usage: 3,
+                selector: 1,
+                matching_type: 1,
+                data: vec![0xAB; 32],
+                ttl,
+            },
+            DnsRecord::SVCB {
+                domain: owner.clone(),
+                priority: 1,
+                target: "svc.example.net".to_string(),
+                params: vec![(1, vec![2, b'h', b'2'])],
+                ttl,
+            },
+            DnsRecord::HTTPS { domain: owner.clone(), priority: 0, target: "cdn.example.net".to_string(), params: Vec::new(), ttl },
+            DnsRecord::OPT { packet_len: 1232, flags: 0, options: vec![OptOption::extended_error(EDE_BLOCKED, "blocked")] },
+        ];
+
+        for record in records {
+            let mut buffer = VectorPacketBuffer::new();
+            let written = record.write(&mut buffer).unwrap();
+
+            // The rdata length field follows the owner, type, class and ttl
+            let rdlength_pos = written - record.rdata_len() - 2;
+            let rdlength = buffer.get_range(rdlength_pos, 2).unwrap();
+
+            assert_eq!(record.binary_len(), written, "{:?}", record);
+            assert_eq!(record.rdata_len(), u16::from_be_bytes([rdlength[0], rdlength[1]]) as usize, "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn test_svcb_target_is_written_uncompressed() {
+        let record = DnsRecord::SVCB {
+            domain: "example.com".to_string(),
+            priority: 1,
+            target: "svc.example.com".to_string(),
+            params: Vec::new(),
+            ttl: TransientTtl(300),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        // Owner name (13) + type, class, ttl (8) + rdlength (2) + priority (2)
+        let target = &buffer.buffer[25..];
+        assert_eq!(b"\x03svc\x07example\x03com\x00", target);
+
+        buffer.seek(0).unwrap();
+        assert_eq!(record, DnsRecord::read(&mut buffer).unwrap());
+
+        // Names in the legacy types are still compressed against the owner
+        let mut buffer = VectorPacketBuffer::new();
+        DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(300),
+        }
+        .write(&mut buffer)
+        .unwrap();
+        assert_eq!(&[4, b'm', b'a', b'i', b'l', 0xC0, 0x00], &buffer.buffer[25..]);
+    }
+
+    #[test]
+    fn test_write_packed_fits_more_than_truncation() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::ANY));
+
+        // A single oversized RRset first, followed by small ones that would fit
+        packet.answers.push(DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: "x".repeat(480),
+            ttl: TransientTtl(3600),
+        });
+        for i in 0..10 {
+            packet.answers.push(DnsRecord::A {
+                domain: format!("host{}.example.com", i),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600),
+            });
+        }
+
+        let mut naive = packet.clone();
+        naive.write(&mut VectorPacketBuffer::new(), 512).unwrap();
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write_packed(&mut buffer, 512).unwrap();
+
+        assert_eq!(0, naive.header.answers);
+        assert_eq!(10, packet.header.answers);
+        assert!(packet.header.truncated_message);
+        assert!(buffer.buffer.len() <= 512);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(&packet.answers[1..], &parsed.answers[..]);
+    }
+
+    #[test]
+    fn test_truncated_copy_skips_unwritten_records() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        for i in 0..3 {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, i),
+                ttl: TransientTtl(3600),
+            });
+        }
+        packet.answers.insert(
+            1,
+            DnsRecord::UNKNOWN {
+                domain: "example.com".to_string(),
+                qtype: 65280,
+                data_len: 4,
+                ttl: TransientTtl(3600),
+            },
+        );
+
+        let copy = packet.truncated(512);
+        assert_eq!(3, copy.header.answers);
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 0), Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)], copy.get_a_records());
+        assert_eq!(3, copy.answers.len());
+
+        // Room for the question and two of the addresses, their owners compressed
+        let copy = packet.truncated(12 + 17 + 2 * 16 + 8);
+        assert!(copy.header.truncated_message);
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 0), Ipv4Addr::new(10, 0, 0, 1)], copy.get_a_records());
+        assert_eq!(2, copy.answers.len());
+    }
+
+    #[test]
+    fn test_unknown_records_are_not_counted() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+        packet.answers.push(DnsRecord::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 65280,
+            data_len: 4,
+            ttl: TransientTtl(3600),
+        });
+        packet.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        });
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+        assert_eq!(1, packet.header.answers);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(&packet.answers[1..], &parsed.answers[..]);
+    }
+
+    #[test]
+    fn test_apl_record_round_trip() {
+        let record = DnsRecord::APL {
+            domain: "acl.example.com".to_string(),
+            prefixes: vec![
+                AplItem {
+                    family: 1,
+                    prefix: 24,
+                    negation: false,
+                    address: vec![192, 168, 32],
+                },
+                AplItem {
+                    family: 2,
+                    prefix: 32,
+                    negation: true,
+                    address: vec![0x20, 0x01, 0x0d, 0xb8],
+                },
+            ],
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert_eq!("1:192.168.32.0/24 !2:2001:db8::/32", parsed.rdata_to_string());
+    }
+
+    #[test]
+    fn test_smimea_record_round_trip() {
+        // DANE-EE usage, SubjectPublicKeyInfo selector and a SHA-256 digest,
+        // published under the hashed local part of the address
+        let owner = "c93f1e400f26708f98cb19d936620da35eec8f72e57f9eec01c1afd6._smimecert.example.com";
+        let digest = (0..32).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+        let record = DnsRecord::SMIMEA {
+            domain: owner.to_string(),
+            usage: 3,
+            selector: 1,
+            matching_type: 1,
+            data: digest,
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::SMIMEA, parsed.get_querytype());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert!(parsed.rdata_to_string().starts_with("3 1 1 00070E15"));
+    }
+
+    #[test]
+    fn test_cert_record_round_trip() {
+        // A PKIX certificate, with no key tag or algorithm as it names neither
+        let record = DnsRecord::CERT {
+            domain: "mail.example.com".to_string(),
+            cert_type: 1,
+            key_tag: 0,
+            algorithm: 0,
+            certificate: (0..48).map(|i| (i * 5) as u8).collect(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.buffer.len(), record.binary_len());
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::CERT, parsed.get_querytype());
+        assert_eq!(QueryType::CERT, QueryType::from_num(37));
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_ds_and_dnskey_round_trip() {
+        // A SHA-256 digest and an ECDSA P-256 key, both for the KSK with tag 2371
+        let digest = (0..32).map(|i| (i * 11) as u8).collect::<Vec<u8>>();
+        let public_key = (0..64).map(|i| (255 - i * 3) as u8).collect::<Vec<u8>>();
+        let records = vec![
+            DnsRecord::DS {
+                domain: "example.com".to_string(),
+                key_tag: 2371,
+                algorithm: 13,
+                digest_type: 2,
+                digest,
+                ttl: TransientTtl(86400),
+            },
+            DnsRecord::DNSKEY {
+                domain: "example.com".to_string(),
+                flags: 257,
+                protocol: 3,
+                algorithm: 13,
+                public_key,
+                ttl: TransientTtl(3600),
+            },
+        ];
+
+        for record in records {
+            let mut buffer = VectorPacketBuffer::new();
+            record.write(&mut buffer).unwrap();
+            assert_eq!(buffer.buffer.len(), record.binary_len());
+
+            buffer.seek(0).unwrap();
+            let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+            assert_eq!(record, parsed);
+            assert_eq!(record.get_querytype(), parsed.get_querytype());
+            assert_eq!(buffer.buffer.len(), buffer.pos());
+        }
+
+        assert_eq!(QueryType::DS, QueryType::from_num(43));
+        assert_eq!(48, QueryType::DNSKEY.to_num());
+    }
+
+    #[test]
+    fn test_sshfp_record_round_trip() {
+        // An RSA key with a SHA-1 fingerprint, and an Ed25519 key with a SHA-256 one
+        let records = vec![
+            DnsRecord::SSHFP {
+                domain: "host.example.com".to_string(),
+                algorithm: 1,
+                fp_type: 1,
+                fingerprint: (0..20).map(|i| (i * 13) as u8).collect(),
+                ttl: TransientTtl(3600),
+            },
+            DnsRecord::SSHFP {
+                domain: "host.example.com".to_string(),
+                algorithm: 4,
+                fp_type: 2,
+                fingerprint: (0..32).map(|i| (255 - i * 7) as u8).collect(),
+                ttl: TransientTtl(3600),
+            },
+        ];
+
+        for record in records {
+            let mut buffer = VectorPacketBuffer::new();
+            record.write(&mut buffer).unwrap();
+            assert_eq!(buffer.buffer.len(), record.binary_len());
+
+            buffer.seek(0).unwrap();
+            let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+            assert_eq!(record, parsed);
+            assert_eq!(QueryType::SSHFP, parsed.get_querytype());
+            assert_eq!(buffer.buffer.len(), buffer.pos());
+        }
+
+        assert_eq!(QueryType::SSHFP, QueryType::from_num(44));
+    }
+
+    #[test]
+    fn test_rrsig_record_round_trip() {
+        let record = DnsRecord::RRSIG {
+            domain: "www.example.com".to_string(),
+            type_covered: QueryType::A.to_num(),
+            algorithm: 13,
+            labels: 3,
+            original_ttl: 3600,
+            expiration: 1_793_491_200,
+            inception: 1_790_899_200,
+            key_tag: 2371,
+            signer_name: "example.com".to_string(),
+            signature: (0..64).map(|i| (i * 5) as u8).collect(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(buffer.buffer.len(), record.binary_len());
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::RRSIG, parsed.get_querytype());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert!(parsed
+            .rdata_to_string()
+            .starts_with("A 13 3 3600 20261101000000 20261002000000 2371 example.com. AAUKDx"));
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!("", base64_encode(b""));
+        assert_eq!("Zg==", base64_encode(b"f"));
+        assert_eq!("Zm8=", base64_encode(b"fo"));
+        assert_eq!("Zm9v", base64_encode(b"foo"));
+        assert_eq!("+/8=", base64_encode(&[0xFB, 0xFF]));
+    }
+
+    #[test]
+    fn test_tcp_keepalive_option_round_trip() {
+        let keepalive = TcpKeepalive::from_duration(Duration::from_secs(30));
+
+        let mut buffer = VectorPacketBuffer::new();
+        keepalive.write(&mut buffer).unwrap();
+        assert_eq!(vec![0, 11, 0, 2, 0x01, 0x2C], buffer.buffer);
+
+        buffer.seek(2).unwrap();
+        let len = buffer.read_u16().unwrap();
+        let parsed = TcpKeepalive::read(&mut buffer, len).unwrap();
+
+        assert_eq!(keepalive, parsed);
+        assert_eq!(Some(Duration::from_secs(30)), parsed.duration());
+
+        // A query asking for a timeout carries the option without data
+        let mut buffer = VectorPacketBuffer::new();
+        TcpKeepalive::default().write(&mut buffer).unwrap();
+        assert_eq!(vec![0, 11, 0, 0], buffer.buffer);
+
+        let option = OptOption::tcp_keepalive(keepalive);
+        assert_eq!(Some(keepalive), option.get_tcp_keepalive());
+        assert_eq!(Some(TcpKeepalive::default()), OptOption::tcp_keepalive(TcpKeepalive::default()).get_tcp_keepalive());
+    }
+
+    #[test]
+    fn test_tcp_response_carries_keepalive_for_edns_queries() {
+        let mut query = DnsPacket::new();
+        query.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+
+        // Without EDNS the option can't be sent
+        let mut response = DnsPacket::new();
+        response
+            .write_tcp_response(&query, Duration::from_secs(30), &mut VectorPacketBuffer::new())
+            .unwrap();
+        assert!(!response.has_opt());
+
+        query.set_tcp_keepalive(TcpKeepalive::default());
+
+        let mut buffer = VectorPacketBuffer::new();
+        let mut response = DnsPacket::new();
+        response.write_tcp_response(&query, Duration::from_secs(30), &mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert_eq!(Some(TcpKeepalive { timeout: Some(300) }), parsed.get_tcp_keepalive());
+    }
+
+    #[test]
+    fn test_hinfo_record_round_trip() {
+        let record = DnsRecord::HINFO {
+            domain: "host.example.com".to_string(),
+            cpu: "AMD64".to_string(),
+            os: "Linux".to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::HINFO, parsed.get_querytype());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert_eq!("\"AMD64\" \"Linux\"", parsed.rdata_to_string());
+    }
+
+    #[test]
+    fn test_naptr_record_round_trip() {
+        let record = DnsRecord::NAPTR {
+            domain: "4.3.2.1.5.5.5.0.0.8.1.e164.arpa".to_string(),
+            order: 100,
+            preference: 10,
+            flags: "u".to_string(),
+            services: "E2U+sip".to_string(),
+            regexp: "!^.*$!sip:info@example.com!".to_string(),
+            replacement: String::new(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(QueryType::NAPTR, parsed.get_querytype());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+        assert_eq!(
+            "100 10 \"u\" \"E2U+sip\" \"!^.*$!sip:info@example.com!\" .",
+            parsed.rdata_to_string()
+        );
+    }
+
+    fn query_and_response() -> (DnsPacket, DnsPacket) {
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+
+        let mut response = query.clone();
+        response.header.response = true;
+
+        (query, response)
+    }
+
+    #[test]
+    fn test_matching_response_is_valid() {
+        let (query, mut response) = query_and_response();
+        assert!(response.is_valid_response(&query));
+
+        // The server may echo the name with different case
+        response.questions[0].name = "WWW.Example.COM".to_string();
+        assert!(response.is_valid_response(&query));
+
+        response.header.response = false;
+        assert!(!response.is_valid_response(&query));
+    }
+
+    #[test]
+    fn test_response_with_other_id_is_invalid() {
+        let (query, mut response) = query_and_response();
+        response.header.id = 4243;
+
+        assert!(!response.is_valid_response(&query));
+    }
+
+    #[test]
+    fn test_response_to_other_question_is_invalid() {
+        let (query, mut response) = query_and_response();
+        response.questions[0].name = "www.example.net".to_string();
+        assert!(!response.is_valid_response(&query));
+
+        let (query, mut response) = query_and_response();
+        response.questions[0].qtype = QueryType::AAAA;
+        assert!(!response.is_valid_response(&query));
+
+        let (query, mut response) = query_and_response();
+        response.questions.clear();
+        assert!(!response.is_valid_response(&query));
+    }
+
+    #[test]
+    fn test_merge_skips_duplicate_records() {
+        let shared = DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 1),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut cached = DnsPacket::new();
+        cached.answers.push(shared.clone());
+
+        let mut authoritative = DnsPacket::new();
+        authoritative.answers.push(shared.clone());
+        authoritative.answers.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr: Ipv4Addr::new(10, 0, 0, 2),
+            ttl: TransientTtl(3600),
+        });
+        authoritative.authorities.push(DnsRecord::NS {
             domain: "example.com".to_string(),
             host: "ns1.example.com".to_string(),
             ttl: TransientTtl(3600),
         });
 
-        let unresolved_ns = packet.get_unresolved_ns("example.com");
-        assert_eq!(unresolved_ns, Some("ns1.example.com".to_string()));
+        cached.merge(&authoritative);
+
+        assert_eq!(2, cached.answers.len());
+        assert_eq!(2, cached.header.answers);
+        assert_eq!(shared, cached.answers[0]);
+        assert_eq!(1, cached.authorities.len());
+        assert_eq!(1, cached.header.authoritative_entries);
+        assert_eq!(0, cached.header.resource_entries);
+    }
+
+    #[test]
+    fn test_txt_record_spanning_character_strings() {
+        let record = DnsRecord::TXT {
+            domain: "example.com".to_string(),
+            data: format!("v=spf1 {}", "a".repeat(293)),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        // rdata starts after the 13 byte name and the 10 bytes of type, class, ttl and length
+        assert_eq!([0x01, 0x2E], buffer.buffer[21..23]); // 302 bytes, two length bytes included
+        assert_eq!(255, buffer.buffer[23]);
+        assert_eq!(45, buffer.buffer[23 + 256]);
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsRecord::read(&mut buffer).unwrap();
+
+        assert_eq!(record, parsed);
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_reverse_name_ipv4() {
+        assert_eq!("1.0.0.127.in-addr.arpa", reverse_name("127.0.0.1".parse().unwrap()));
+        assert_eq!("10.2.0.192.in-addr.arpa", reverse_name("192.0.2.10".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_reverse_name_ipv6() {
+        assert_eq!(
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa",
+            reverse_name("::1".parse().unwrap())
+        );
+        assert_eq!(
+            "b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa",
+            reverse_name("4321:0:1:2:3:4:567:89ab".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_ptr_record_round_trip() {
+        let record = DnsRecord::PTR {
+            domain: reverse_name("192.0.2.10".parse().unwrap()),
+            host: "host.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut buffer = VectorPacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+
+        buffer.seek(0).unwrap();
+        assert_eq!(record, DnsRecord::read(&mut buffer).unwrap());
+        assert_eq!(buffer.buffer.len(), buffer.pos());
+    }
+
+    #[test]
+    fn test_extended_error_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4242;
+        packet.header.response = true;
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        packet.set_extended_error(EDE_OTHER, "placeholder");
+        packet.set_extended_error(EDE_FILTERED, "filtered by policy");
+
+        let mut buffer = VectorPacketBuffer::new();
+        packet.write(&mut buffer, 512).unwrap();
+
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+
+        assert_eq!(1, parsed.resources.len());
+        assert_eq!(
+            Some((EDE_FILTERED, "filtered by policy".to_string())),
+            parsed.get_extended_error()
+        );
+        match parsed.resources[0] {
+            DnsRecord::OPT { packet_len, ref options, .. } => {
+                assert_eq!(DEFAULT_EDNS_PAYLOAD_SIZE, packet_len);
+                assert_eq!(1, options.len());
+            }
+            _ => panic!("Expected an OPT record"),
+        }
     }
 
     #[test]
@@ -1128,8 +4293,8 @@ mod tests {
         let mut packet = DnsPacket::new();
         packet.header.id = 1337;
 
-        // Add multiple records to exceed the size limit
-        for i in 0..10 {
+        // Add multiple records to exceed the size limit, at 27 to 28 bytes each
+        for i in 0..30 {
             packet.answers.push(DnsRecord::A {
                 domain: format!("example{}.com", i),
                 addr: Ipv4Addr::new(127, 0, 0, 1),
@@ -1143,6 +4308,329 @@ mod tests {
 
         assert!(result.is_ok());
         assert!(packet.header.truncated_message);
+        assert!(buffer.buffer.len() <= max_size);
+
+        // What was written is a complete packet holding the records that fit
+        buffer.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer).unwrap();
+        assert!(parsed.header.truncated_message);
+        assert_eq!(packet.header.answers as usize, parsed.answers.len());
+        assert!(parsed.answers.len() < 30);
+    }
+
+    #[test]
+    fn test_preferred_mx() {
+        let mut packet = DnsPacket::new();
+        assert_eq!(None, packet.preferred_mx());
+
+        for (priority, host) in [(20, "backup.example.com"), (10, "mail.example.com")] {
+            packet.answers.push(DnsRecord::MX {
+                domain: "example.com".to_string(),
+                priority,
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+        }
+
+        assert_eq!(Some((10, "mail.example.com".to_string())), packet.preferred_mx());
+    }
+
+    #[test]
+    fn test_select_srv_prefers_lowest_priority() {
+        let srv = |priority, weight, host: &str| DnsRecord::SRV {
+            domain: "_sip._tcp.example.com".to_string(),
+            priority,
+            weight,
+            port: 5060,
+            host: host.to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        let mut packet = DnsPacket::new();
+        assert_eq!(None, packet.select_srv());
+
+        packet.answers.push(srv(20, 100, "backup.example.com"));
+        packet.answers.push(srv(10, 0, "idle.example.com"));
+        packet.answers.push(srv(10, 60, "big.example.com"));
+        packet.answers.push(srv(10, 40, "small.example.com"));
+
+        let mut picked = HashSet::new();
+        for _ in 0..200 {
+            match packet.select_srv() {
+                Some(&DnsRecord::SRV { priority, ref host, .. }) => {
+                    assert_eq!(10, priority);
+                    picked.insert(host.clone());
+                }
+                other => panic!("Expected an SRV record, got {:?}", other),
+            }
+        }
+
+        // Both weighted records of the preferred priority get their turn
+        assert!(picked.contains("big.example.com"));
+        assert!(picked.contains("small.example.com"));
+        assert!(!picked.contains("backup.example.com"));
+    }
+
+    #[test]
+    fn test_questions_compare_case_insensitively() {
+        let lower = DnsQuestion::new("google.com".to_string(), QueryType::A);
+        let mixed = DnsQuestion::new("Google.COM".to_string(), QueryType::A);
+
+        assert_eq!(lower, mixed);
+        assert!(!lower.eq_exact(&mixed));
+        assert!(lower.eq_exact(&lower.clone()));
+        assert_ne!(lower, DnsQuestion::new("google.com".to_string(), QueryType::AAAA));
+    }
+
+    #[test]
+    fn test_record_name_matching() {
+        let record = DnsRecord::NS {
+            domain: "Example.com".to_string(),
+            host: "ns1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        };
+
+        assert!(record.matches_name("example.com"));
+        assert!(record.matches_name("EXAMPLE.COM."));
+        assert!(!record.matches_name("www.example.com"));
+
+        assert!(record.matches_suffix("example.com."));
+        assert!(record.matches_suffix("www.EXAMPLE.com"));
+        assert!(record.matches_suffix("a.b.example.com."));
+        assert!(!record.matches_suffix("badexample.com"));
+        assert!(!record.matches_suffix("com"));
+
+        let root = DnsRecord::NS { domain: ".".to_string(), host: "a.root-servers.net".to_string(), ttl: TransientTtl(3600) };
+        assert!(root.matches_suffix("www.example.com"));
+
+        let opt = DnsRecord::OPT { packet_len: 1232, flags: 0, options: Vec::new() };
+        assert!(!opt.matches_name(""));
+        assert!(!opt.matches_suffix("example.com"));
+    }
+
+    #[test]
+    fn test_resolved_ns_ignores_case() {
+        let mut packet = DnsPacket::new();
+        packet.authorities.push(DnsRecord::NS {
+            domain: "Example.COM".to_string(),
+            host: "NS1.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "ns1.example.com.".to_string(),
+            addr: Ipv4Addr::new(192, 168, 1, 1),
+            ttl: TransientTtl(3600),
+        });
+
+        assert_eq!(Some("192.168.1.1".to_string()), packet.get_resolved_ns("www.example.com"));
+        assert_eq!(Some("NS1.example.com".to_string()), packet.get_unresolved_ns("www.example.com."));
+        assert_eq!(None, packet.get_unresolved_ns("www.badexample.com"));
+    }
+
+    #[test]
+    fn test_make_response_copies_query_flags() {
+        let mut query = DnsHeader::new();
+        query.id = 4242;
+        query.opcode = 0;
+        query.recursion_desired = true;
+        query.checking_disabled = true;
+
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::SERVFAIL;
+        packet.make_response(&query, true);
+
+        assert_eq!(4242, packet.header.id);
+        assert!(packet.header.response);
+        assert_eq!(0, packet.header.opcode);
+        assert!(packet.header.recursion_desired);
+        assert!(packet.header.checking_disabled);
+        assert!(packet.header.recursion_available);
+        assert_eq!(ResultCode::SERVFAIL, packet.header.rescode);
+
+        query.recursion_desired = false;
+        packet.make_response(&query, false);
+        assert!(!packet.header.recursion_desired);
+        assert!(!packet.header.recursion_available);
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4242;
+        packet.header.recursion_desired = true;
+        packet.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::MX));
+        packet.answers.push(DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: TransientTtl(3600),
+        });
+        packet.resources.push(DnsRecord::A {
+            domain: "mail.example.com".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 25),
+            ttl: TransientTtl(3600),
+        });
+
+        let bytes = packet.to_bytes(512).unwrap();
+        let parsed = DnsPacket::from_bytes(&bytes).unwrap();
+
+        assert_eq!(packet, parsed);
+        assert_eq!(3600, parsed.answers[0].get_ttl());
+
+        assert!(DnsPacket::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_copy() {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 1337;
+        for i in 0..30 {
+            packet.answers.push(DnsRecord::A {
+                domain: format!("example{}.com", i),
+                addr: Ipv4Addr::new(127, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            });
+        }
+
+        let truncated = packet.truncated(512);
+
+        assert!(truncated.header.truncated_message);
+        assert!(truncated.answers.len() < 30);
+        assert_eq!(truncated.header.answers as usize, truncated.answers.len());
+        assert_eq!(&packet.answers[..truncated.answers.len()], &truncated.answers[..]);
+
+        let mut buffer = VectorPacketBuffer::new();
+        truncated.clone().write(&mut buffer, 512).unwrap();
+        assert!(buffer.buffer.len() <= 512);
+
+        // The original keeps every record
+        assert!(!packet.header.truncated_message);
+        assert_eq!(30, packet.answers.len());
+
+        // Nothing is dropped from a packet which already fits
+        let whole = packet.truncated(4096);
+        assert!(!whole.header.truncated_message);
+        assert_eq!(30, whole.answers.len());
+    }
+
+    #[test]
+    fn test_response_size_follows_advertised_edns_size() {
+        let query_advertising = |packet_len: Option<u16>| {
+            let mut query = DnsPacket::new();
+            query.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+            if let Some(packet_len) = packet_len {
+                query.resources.push(DnsRecord::OPT { packet_len, flags: 0, options: Vec::new() });
+            }
+            query
+        };
+
+        let large_response = || {
+            let mut response = DnsPacket::new();
+            response.header.response = true;
+            response.questions.push(DnsQuestion::new("example.com".to_string(), QueryType::A));
+            for i in 0..60 {
+                response.answers.push(DnsRecord::A {
+                    domain: "example.com".to_string(),
+                    addr: Ipv4Addr::new(10, 0, 0, i),
+                    ttl: TransientTtl(300),
+                });
+            }
+            response
+        };
+
+        assert_eq!(DEFAULT_UDP_PAYLOAD_SIZE, query_advertising(None).max_udp_response_size());
+        assert_eq!(DEFAULT_UDP_PAYLOAD_SIZE, query_advertising(Some(256)).max_udp_response_size());
+        assert_eq!(4096, query_advertising(Some(4096)).max_udp_response_size());
+
+        let mut response = large_response();
+        let mut buffer = VectorPacketBuffer::new();
+        response.write_udp_response(&query_advertising(Some(4096)), &mut buffer).unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(60, response.header.answers);
+        assert!(buffer.buffer.len() > DEFAULT_UDP_PAYLOAD_SIZE);
+
+        let mut response = large_response();
+        let mut buffer = VectorPacketBuffer::new();
+        response.write_udp_response(&query_advertising(Some(512)), &mut buffer).unwrap();
+        assert!(response.header.truncated_message);
+        assert!(response.header.answers < 60);
+        assert!(buffer.buffer.len() <= DEFAULT_UDP_PAYLOAD_SIZE);
+    }
+
+    type BufferResult<T> = std::result::Result<T, buffer::BufferError>;
+
+    /// Counts the bytes written through it, to check nothing is serialized twice.
+    struct CountingBuffer {
+        inner: VectorPacketBuffer,
+        written: usize,
+    }
+
+    impl PacketBuffer for CountingBuffer {
+        fn read(&mut self) -> BufferResult<u8> {
+            self.inner.read()
+        }
+        fn get(&mut self, pos: usize) -> BufferResult<u8> {
+            self.inner.get(pos)
+        }
+        fn get_range(&mut self, start: usize, len: usize) -> BufferResult<&[u8]> {
+            self.inner.get_range(start, len)
+        }
+        fn write(&mut self, val: u8) -> BufferResult<()> {
+            self.written += 1;
+            self.inner.write(val)
+        }
+        fn set(&mut self, pos: usize, val: u8) -> BufferResult<()> {
+            self.inner.set(pos, val)
+        }
+        fn pos(&self) -> usize {
+            self.inner.pos()
+        }
+        fn seek(&mut self, pos: usize) -> BufferResult<()> {
+            self.inner.seek(pos)
+        }
+        fn step(&mut self, steps: usize) -> BufferResult<()> {
+            self.inner.step(steps)
+        }
+        fn find_label(&self, label: &str) -> Option<usize> {
+            self.inner.find_label(label)
+        }
+        fn save_label(&mut self, label: &str, pos: usize) {
+            self.inner.save_label(label, pos)
+        }
+        fn truncate(&mut self, len: usize) -> BufferResult<()> {
+            self.inner.truncate(len)
+        }
+    }
+
+    #[test]
+    fn test_large_packet_is_serialized_once() {
+        let mut packet = DnsPacket::new();
+        packet.questions.push(DnsQuestion::new("zone.example.com".to_string(), QueryType::A));
+        for i in 0..2000 {
+            packet.answers.push(DnsRecord::A {
+                domain: format!("host{}.zone.example.com", i),
+                addr: Ipv4Addr::new(10, (i >> 8) as u8, (i & 0xFF) as u8, 1),
+                ttl: TransientTtl(3600),
+            });
+        }
+
+        let mut buffer = CountingBuffer {
+            inner: VectorPacketBuffer::new(),
+            written: 0,
+        };
+        packet.write(&mut buffer, 0xFFFF).unwrap();
+
+        // Every byte of the output was written exactly once
+        assert!(!packet.header.truncated_message);
+        assert_eq!(buffer.inner.buffer.len(), buffer.written);
+
+        buffer.inner.seek(0).unwrap();
+        let parsed = DnsPacket::from_buffer(&mut buffer.inner).unwrap();
+        assert_eq!(2000, parsed.answers.len());
+
+        // Writing again doesn't add to the counts in the header
+        packet.write(&mut VectorPacketBuffer::new(), 0xFFFF).unwrap();
+        assert_eq!(2000, packet.header.answers);
     }
 
     #[test]
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use tracing::debug;
+use crate::server::context::ServerContext;
+use crate::protocols::protocol::{DnsPacket, QueryType};
+use crate::resolvers::forwading_resolver::ForwardingDnsResolver;
+use crate::resolvers::recursive_resolver::RecursiveDnsResolver;
+use crate::resolvers::resolve::{AnswerSource, DnsResolver, ResolveError, Result};
+
+/// A resolver which attempts recursion first
+///
+/// Queries are forwarded to the upstream server when no name server is known
+/// for the name or when talking to one fails.
+pub struct FallbackDnsResolver {
+    recursive: RecursiveDnsResolver,
+    forwarding: ForwardingDnsResolver,
+    source: AnswerSource,
+}
+
+impl FallbackDnsResolver {
+    /// Creates a new `FallbackDnsResolver` which falls back to `server`.
+    pub fn new(context: Arc<ServerContext>, server: (String, u16)) -> FallbackDnsResolver {
+        FallbackDnsResolver {
+            recursive: RecursiveDnsResolver::new(context.clone()),
+            forwarding: ForwardingDnsResolver::new(context, server),
+            source: AnswerSource::Recursive,
+        }
+    }
+}
+
+#[async_trait]
+impl DnsResolver for FallbackDnsResolver {
+    fn get_context(&self) -> Arc<ServerContext> {
+        self.recursive.get_context()
+    }
+
+    /// Only the forwarded queries carry the CD bit, recursion never sets it.
+    fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.recursive.set_checking_disabled(checking_disabled);
+        self.forwarding.set_checking_disabled(checking_disabled);
+    }
+
+    fn checking_disabled(&self) -> bool {
+        self.forwarding.checking_disabled()
+    }
+
+    /// Reports whichever path answered the last query.
+    fn source(&self) -> AnswerSource {
+        self.source
+    }
+
+    async fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        self.source = AnswerSource::Recursive;
+
+        match self.recursive.perform(qname, qtype).await {
+            Err(err @ ResolveError::NoServerFound) | Err(err @ ResolveError::Client(_)) => {
+                debug!(target: "dns", "Recursion for {:?} {} failed with {:?}, forwarding", qtype, qname, err);
+                self.source = AnswerSource::Forward;
+                self.forwarding.perform(qname, qtype).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    use crate::client::network_client::ClientError;
+    use crate::protocols::protocol::{DnsRecord, TransientTtl};
+    use crate::server::context::tests::create_test_context;
+
+    #[tokio::test]
+    async fn test_failed_recursion_falls_back_to_forwarding() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let servers_clone = servers.clone();
+
+        let context = create_test_context(Box::new(move |qname, _, (host, port), _, _| {
+            servers_clone.lock().unwrap().push(format!("{}:{}", host, port));
+
+            // The authoritative server is unreachable, the forwarder answers
+            if host == "192.168.1.1" {
+                return Err(ClientError::TimeOut);
+            }
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.com".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns1.example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 168, 1, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let mut resolver = FallbackDnsResolver::new(context, ("10.0.0.1".to_string(), 53));
+        let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+
+        assert_eq!(Some("10.1.2.3".to_string()), response.get_random_a());
+        assert_eq!(AnswerSource::Forward, resolver.source());
+        assert_eq!(vec!["192.168.1.1:53", "10.0.0.1:53"], *servers.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_missing_name_server_falls_back_to_forwarding() {
+        let mut context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        Arc::get_mut(&mut context).unwrap().root_servers.clear();
+
+        // Nothing is cached and there are no roots, so recursion has no name server to start from
+        let mut resolver = FallbackDnsResolver::new(context, ("10.0.0.1".to_string(), 53));
+        let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+
+        assert_eq!(Some("10.1.2.3".to_string()), response.get_random_a());
+        assert_eq!(AnswerSource::Forward, resolver.source());
+    }
+}
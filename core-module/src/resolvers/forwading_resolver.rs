@@ -1,44 +1,98 @@
 use std::sync::Arc;
 use async_trait::async_trait;
 use crate::server::context::ServerContext;
-use crate::protocols::protocol::{DnsPacket, QueryType};
-use crate::resolvers::resolve::{DnsResolver, ResolveError};
+use crate::protocols::protocol::{DnsPacket, QueryType, ResultCode};
+use crate::resolvers::resolve::{AnswerSource, DnsResolver, ResolveError};
 
-/// A forwading DNS Resolver
+/// TTL used for negative cache entries when the upstream omits an SOA record.
+const DEFAULT_NEGATIVE_TTL: u32 = 300;
+
+/// A forwarding DNS Resolver
 ///
 /// The resolver uses an external DNS Server to service a query.
-pub struct ForwadingDnsResolver {
+pub struct ForwardingDnsResolver {
     context: Arc<ServerContext>,
     server: (String, u16),
+    checking_disabled: bool,
+    recursion_desired: bool,
+    tls_name: Option<String>,
 }
 
-impl ForwadingDnsResolver {
-    /// Creates a new `ForwadingDnsResolver` with the given server context and upstream server.
-    pub fn new (context: Arc<ServerContext>, server: (String, u16)) -> ForwadingDnsResolver {
-        ForwadingDnsResolver {
+impl ForwardingDnsResolver {
+    /// Creates a new `ForwardingDnsResolver` with the given server context and upstream server.
+    pub fn new (context: Arc<ServerContext>, server: (String, u16)) -> ForwardingDnsResolver {
+        ForwardingDnsResolver {
             context,
             server,
+            checking_disabled: false,
+            recursion_desired: true,
+            tls_name: None,
         }
     }
+
+    /// Creates a resolver which only talks to the upstream over DNS-over-TLS.
+    ///
+    /// `tls_name` is the name the upstream certificate is verified against.
+    pub fn new_tls(context: Arc<ServerContext>, server: (String, u16), tls_name: String) -> ForwardingDnsResolver {
+        let mut resolver = ForwardingDnsResolver::new(context, server);
+        resolver.tls_name = Some(tls_name);
+        resolver
+    }
+
+    /// Sets whether forwarded queries ask the upstream to recurse on our behalf.
+    ///
+    /// Enabled by default, since a forwarder relies on the upstream doing the work.
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.recursion_desired = recursion_desired;
+    }
 }
 
 #[async_trait]
-impl DnsResolver for ForwadingDnsResolver {
+impl DnsResolver for ForwardingDnsResolver {
      /// Returns the shared server context.
      fn get_context(&self) -> Arc<ServerContext> {
         self.context.clone()
      }
 
+     /// Propagates the client's CD bit so the upstream skips DNSSEC validation as well.
+     fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.checking_disabled = checking_disabled;
+     }
+
+     fn checking_disabled(&self) -> bool {
+        self.checking_disabled
+     }
+
+     fn source(&self) -> AnswerSource {
+        AnswerSource::Forward
+     }
+
      /// Perfoms an asynchronous DNS Query to the external server.
      async fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket, ResolveError> {
            let (host, port) = &self.server;
 
            // Asynchronous query to the external DNS server
-           let result = self
-               .context
-               .client
-               .send_query_async(qname, qtype, (host.as_str(), *port), true)
-               .await?;
+           let client = &self.context.client;
+           let server = (host.as_str(), *port);
+           let result = match self.tls_name {
+               Some(ref tls_name) => {
+                   client
+                       .send_tls_query_async(qname, qtype, server, tls_name, self.recursion_desired, self.checking_disabled)
+                       .await?
+               }
+               None => {
+                   client
+                       .send_query_async(qname, qtype, server, self.recursion_desired, self.checking_disabled)
+                       .await?
+               }
+           };
+
+           // Remember non-existent domains so they aren't forwarded again
+           if result.header.rescode == ResultCode::NXDOMAIN {
+               let ttl = result.get_ttl_from_soa().unwrap_or(DEFAULT_NEGATIVE_TTL);
+               self.context.cache.store_nx_domain_async(qname, qtype, ttl, result.get_soa()).await?;
+               return Ok(result);
+           }
 
            // Cache the answers if the query suceeds
            self.context.cache.store_async(&result.answers).await?;
@@ -46,3 +100,108 @@ impl DnsResolver for ForwadingDnsResolver {
            Ok(result)
      }
 }
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    use crate::protocols::protocol::{DnsRecord, TransientTtl};
+    use crate::server::context::tests::create_test_context;
+
+    #[tokio::test]
+    async fn test_nxdomain_is_cached() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+
+            let mut packet = DnsPacket::new();
+            packet.header.rescode = ResultCode::NXDOMAIN;
+            packet.authorities.push(DnsRecord::SOA {
+                domain: "example.com".to_string(),
+                m_name: "ns1.example.com".to_string(),
+                r_name: "admin.example.com".to_string(),
+                serial: 2024010101,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 600,
+                ttl: TransientTtl(3600),
+            });
+
+            Ok(packet)
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+
+        let first = resolver.resolve("missing.example.com", QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, first.header.rescode);
+
+        // The second lookup should be answered from the negative cache, SOA included
+        let second = resolver.resolve("missing.example.com", QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, second.header.rescode);
+        assert_eq!(first.get_soa(), second.get_soa());
+        assert!(second.get_soa().is_some());
+
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_checking_disabled_is_forwarded() {
+        let upstream_cd = Arc::new(AtomicBool::new(false));
+        let upstream_cd_clone = upstream_cd.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, _, checking_disabled| {
+            upstream_cd_clone.store(checking_disabled, Ordering::SeqCst);
+            Ok(DnsPacket::new())
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        resolver.set_checking_disabled(true);
+        resolver.resolve("bogus.example.com", QueryType::A, true).await.unwrap();
+
+        assert!(upstream_cd.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_queries_set_recursion_desired() {
+        let upstream_rd = Arc::new(AtomicBool::new(false));
+        let upstream_rd_clone = upstream_rd.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, recursive, _| {
+            upstream_rd_clone.store(recursive, Ordering::SeqCst);
+            Ok(DnsPacket::new())
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+
+        assert!(upstream_rd.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_tls_forwarder_never_falls_back_to_plaintext() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+            Ok(DnsPacket::new())
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new_tls(
+            context,
+            ("127.0.0.1".to_string(), 853),
+            "dns.example.net".to_string(),
+        );
+
+        assert!(resolver.resolve("www.example.com", QueryType::A, true).await.is_err());
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod resolve;
 pub mod forwading_resolver;
 pub mod recursive_resolver;
+pub mod fallback_resolver;
@@ -3,20 +3,59 @@ use async_trait::async_trait;
 use tracing::{debug, error, info};
 use crate::server::context::ServerContext;
 use crate::protocols::protocol::{DnsPacket, QueryType, ResultCode};
-use crate::resolvers::resolve::{DnsResolver, ResolveError, Result};
+use crate::resolvers::resolve::{AnswerSource, DnsResolver, ResolveError, Result};
+
+/// How many name server addresses may be resolved within one another before giving up.
+const MAX_NS_DEPTH: usize = 8;
+
+/// How many referrals a single lookup may follow before giving up.
+const MAX_REFERRALS: usize = 16;
+
+/// How many upstream queries resolving a single name may take, name server lookups included.
+const DEFAULT_QUERY_BUDGET: usize = 64;
 
 /// A Recursive DNS Resolver
 ///
 /// This resolver can answer any request using the root servers of the internet.
 pub struct RecursiveDnsResolver {
     context: Arc<ServerContext>,
+    recursion_desired: bool,
+    /// Number of name server lookups currently nested within the query being resolved.
+    ns_depth: usize,
+    /// Whether the client set the CD bit, which isn't passed on to authoritative servers.
+    checking_disabled: bool,
+    /// Upstream queries allowed per resolution, shared with the name server lookups nested in it.
+    query_budget: usize,
+    /// Upstream queries the resolution in progress may still send.
+    queries_left: usize,
 }
 
 
 impl RecursiveDnsResolver {
     /// Creates a new `RecursiveDnsResolver`.
     pub fn new(context: Arc<ServerContext>) -> RecursiveDnsResolver{
-        RecursiveDnsResolver { context }
+        RecursiveDnsResolver {
+            context,
+            recursion_desired: false,
+            ns_depth: 0,
+            checking_disabled: false,
+            query_budget: DEFAULT_QUERY_BUDGET,
+            queries_left: DEFAULT_QUERY_BUDGET,
+        }
+    }
+
+    /// Sets how many upstream queries resolving a single name may take.
+    ///
+    /// Lookups of name server addresses count towards the budget of the name they're for.
+    pub fn set_query_budget(&mut self, query_budget: usize) {
+        self.query_budget = query_budget;
+    }
+
+    /// Sets whether queries to authoritative servers carry the RD flag.
+    ///
+    /// Disabled by default, as authoritative servers are expected to answer iteratively.
+    pub fn set_recursion_desired(&mut self, recursion_desired: bool) {
+        self.recursion_desired = recursion_desired;
     }
 }
 
@@ -26,66 +65,118 @@ impl DnsResolver for RecursiveDnsResolver {
         self.context.clone()
     }
 
+    fn set_checking_disabled(&mut self, checking_disabled: bool) {
+        self.checking_disabled = checking_disabled;
+    }
+
+    fn checking_disabled(&self) -> bool {
+        self.checking_disabled
+    }
+
+    fn source(&self) -> AnswerSource {
+        AnswerSource::Recursive
+    }
+
     async fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        // Nested name server lookups draw on the budget of the name they're for, so a
+        // hostile delegation can't multiply the work at each level
+        if self.ns_depth == 0 {
+            self.queries_left = self.query_budget;
+        }
+
         // Find the closest name server by progressively moving towards root servers.
         let mut tentative_ns = None;
         let labels = qname.split('.').collect::<Vec<&str>>();
 
+        // DS records are served by the parent zone, so its servers are the ones to ask
+        let first_label = if qtype == QueryType::DS { 1 } else { 0 };
+
         // Iterating over labels to find the closest nameserver
-        for lbl_idx in 0..=labels.len() {
+        for lbl_idx in first_label.min(labels.len())..=labels.len() {
             let domain = labels[lbl_idx..].join(".");
 
             // Lookup NS records asynchronously and try to find an A record for the nameserver.
-            match self
+            let ns_name = self
                 .context
                 .cache
                 .lookup_async(&domain, QueryType::NS)
                 .await
-                .and_then(|qr| qr.get_unresolved_ns(&domain))
-                .and_then(|ns| async {
-                    self.context.cache.lookup_async(&ns, QueryType::A).await
-                })
-                .await
-                .and_then(|qr| qr.get_random_a())
+                .and_then(|qr| qr.get_unresolved_ns(&domain));
 
-            {
-                Some(addr) => {
-                    tentative_ns = Some(addr);
-                    break;
-                }
-                None => continue,
+            let ns_addr = match ns_name {
+                Some(ns) => self
+                    .context
+                    .cache
+                    .lookup_async(&ns, QueryType::A)
+                    .await
+                    .and_then(|qr| qr.get_random_a()),
+                None => None,
+            };
+
+            if let Some(addr) = ns_addr {
+                tentative_ns = Some(addr);
+                break;
             }
         }
 
+        // Start from a root server when nothing closer is cached
+        if tentative_ns.is_none() && !self.context.root_servers.is_empty() {
+            let roots = &self.context.root_servers;
+            let (_, addr) = &roots[rand::random::<usize>() % roots.len()];
+            tentative_ns = Some(addr.to_string());
+        }
+
         // If no name servers are found, return an error
         let mut ns = tentative_ns.ok_or_else(|| ResolveError::NoServerFound)?;
 
+        // Servers referring to one another would otherwise keep us going round forever.
+        let mut referrals = 0;
+
         // Start Qerying the name servers
         loop {
+            if referrals > MAX_REFERRALS {
+                error!(target: "dns", "Giving up on {:?} {}, too many referrals", qtype, qname);
+                return Err(ResolveError::DelegationTooDeep);
+            }
+            referrals += 1;
+
+            if self.queries_left == 0 {
+                error!(target: "dns", "Giving up on {:?} {}, out of upstream queries", qtype, qname);
+                return Err(ResolveError::QueryBudgetExhausted);
+            }
+            self.queries_left -= 1;
+
             info!(target: "dns", "Attempting the lookup of {:?} {} with NS {}", qtype, qname, ns);
 
             let server = (ns.as_str(), 53);
-            let response = match self.context.client.send_query_async(qname, qtype.clone(), server, false).await {
+
+            // Only held for the query itself. Holding it across the whole resolution
+            // would deadlock when resolving a name server's address from in here.
+            // The semaphore is never closed, so acquiring can't fail.
+            let permit = self.context.resolution_slots.acquire().await.ok();
+            let response = match self.context.client.send_query_async(qname, qtype, server, self.recursion_desired, false).await {
                 Ok(res) => res,
                 Err(err) => {
                     error!(target: "dns", "Failed to send query: {:?}", err);
-                    return Err(ResolveError::client(err));
+                    return Err(ResolveError::Client(err));
                 }
             };
+            drop(permit);
 
             // If we got an actual answer, we are done!.
             if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
                 debug!(target: "dns", "Caching response and answers are returning");
-                self.context.cache.store_async(&response.answers).await?;
-                self.context.cache.store_async(&response.authorities).await?;
-                self.context.cache.store_async(&response.resources).await?;
+                self.context
+                    .cache
+                    .store_all_async(&[&response.answers, &response.authorities, &response.resources])
+                    .await?;
                 return Ok(response);
             }
 
             // Handle NXDOMAIN (non-existent domain) case
             if response.header.rescode == ResultCode::NXDOMAIN {
                 if let Some(ttl) = response.get_ttl_from_soa() {
-                    self.context.cache.store_nx_domain_async(qname, qtype, ttl).await?;
+                    self.context.cache.store_nx_domain_async(qname, qtype, ttl, response.get_soa()).await?;
                 }
                 return Ok(response);
             }
@@ -93,9 +184,10 @@ impl DnsResolver for RecursiveDnsResolver {
             // Try to find a new nameserver based on NS records and a corresponding A record
             if let Some(new_ns) = response.get_resolved_ns(qname) {
                 ns = new_ns.clone();
-                self.context.cache.store_async(&response.answers).await?;
-                self.context.cache.store_async(&response.authorities).await?;
-                self.context.cache.store_async(&response.resources).await?;
+                self.context
+                    .cache
+                    .store_all_async(&[&response.answers, &response.authorities, &response.resources])
+                    .await?;
                 continue;
             }
 
@@ -105,8 +197,18 @@ impl DnsResolver for RecursiveDnsResolver {
                 None => return Ok(response),
             };
 
+            // Delegations whose name servers lie in each other's zones would otherwise
+            // have us resolving them forever.
+            if self.ns_depth >= MAX_NS_DEPTH {
+                error!(target: "dns", "Giving up on {:?} {}, name servers nested too deep", qtype, qname);
+                return Err(ResolveError::DelegationTooDeep);
+            }
+
             debug!(target: "dns", "Recursively resolving NS {}", new_ns_name);
-            let recursive_response = self.resolve(&new_ns_name, QueryType::A, true).await?;
+            self.ns_depth += 1;
+            let recursive_response = self.resolve(&new_ns_name, QueryType::A, true).await;
+            self.ns_depth -= 1;
+            let recursive_response = recursive_response?;
 
             // Restart with a new NS if found
             if let Some(new_ns) = recursive_response.get_random_a() {
@@ -117,3 +219,311 @@ impl DnsResolver for RecursiveDnsResolver {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    use crate::protocols::protocol::{DnsQuestion, DnsRecord, TransientTtl};
+    use crate::server::context::tests::create_test_context;
+
+    #[tokio::test]
+    async fn test_queries_to_authoritative_servers_clear_recursion_desired() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let upstream_rd = Arc::new(AtomicBool::new(true));
+        let calls_clone = calls.clone();
+        let upstream_rd_clone = upstream_rd.clone();
+
+        let context = create_test_context(Box::new(move |qname, _, _, recursive, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+            upstream_rd_clone.store(recursive, Ordering::SeqCst);
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(127, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "google.com".to_string(),
+                    host: "ns1.google.com".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns1.google.com".to_string(),
+                    addr: Ipv4Addr::new(192, 168, 1, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        resolver.resolve("www.google.com", QueryType::A, true).await.unwrap();
+
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert!(!upstream_rd.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_cyclic_delegation_is_aborted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+
+        // a.test is served by ns1.b.test and b.test by ns1.a.test, neither with glue
+        let context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+
+            let (domain, host) = if qname.ends_with("a.test") {
+                ("a.test", "ns1.b.test")
+            } else {
+                ("b.test", "ns1.a.test")
+            };
+
+            let mut packet = DnsPacket::new();
+            packet.authorities.push(DnsRecord::NS {
+                domain: domain.to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "test".to_string(),
+                    host: "ns.test".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns.test".to_string(),
+                    addr: Ipv4Addr::new(192, 168, 1, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        let result = resolver.resolve("www.a.test", QueryType::A, true).await;
+
+        assert!(matches!(result, Err(ResolveError::DelegationTooDeep)));
+        assert_eq!(MAX_NS_DEPTH + 1, calls.load(Ordering::SeqCst));
+        assert_eq!(0, resolver.ns_depth);
+    }
+
+    #[tokio::test]
+    async fn test_referral_cycle_is_aborted() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+
+        // Two servers for the same zone, each referring to the other with glue
+        let context = create_test_context(Box::new(move |_, _, (server, _), _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+
+            let (host, addr) = if server == "192.0.2.1" {
+                ("ns2.test", Ipv4Addr::new(192, 0, 2, 2))
+            } else {
+                ("ns1.test", Ipv4Addr::new(192, 0, 2, 1))
+            };
+
+            let mut packet = DnsPacket::new();
+            packet.authorities.push(DnsRecord::NS {
+                domain: "test".to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+            packet.resources.push(DnsRecord::A {
+                domain: host.to_string(),
+                addr,
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "test".to_string(),
+                    host: "ns1.test".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns1.test".to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        let result = resolver.resolve("www.test", QueryType::A, true).await;
+
+        assert!(matches!(result, Err(ResolveError::DelegationTooDeep)));
+        assert_eq!(MAX_REFERRALS + 1, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_nested_lookups_share_query_budget() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let calls_clone = calls.clone();
+
+        // a.test is served by ns1.b.test and b.test by ns1.a.test, neither with glue
+        let context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+
+            let (domain, host) = if qname.ends_with("a.test") {
+                ("a.test", "ns1.b.test")
+            } else {
+                ("b.test", "ns1.a.test")
+            };
+
+            let mut packet = DnsPacket::new();
+            packet.authorities.push(DnsRecord::NS {
+                domain: domain.to_string(),
+                host: host.to_string(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "test".to_string(),
+                    host: "ns.test".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns.test".to_string(),
+                    addr: Ipv4Addr::new(192, 168, 1, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        resolver.set_query_budget(4);
+
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.header.recursion_desired = true;
+        query.questions.push(DnsQuestion::new("www.a.test".to_string(), QueryType::A));
+
+        // The budget runs out well before the nesting limit is reached
+        let response = resolver.resolve_query(&query).await.unwrap();
+
+        assert_eq!(ResultCode::SERVFAIL, response.header.rescode);
+        assert_eq!(4242, response.header.id);
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+        assert_eq!(0, resolver.ns_depth);
+
+        // The next resolution starts with a fresh budget
+        let result = resolver.resolve("www.b.test", QueryType::A, true).await;
+        assert!(matches!(result, Err(ResolveError::QueryBudgetExhausted)));
+        assert_eq!(8, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upstream_queries_respect_concurrency_limit() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let active_clone = active.clone();
+        let max_active_clone = max_active.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            let now_active = active_clone.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active_clone.fetch_max(now_active, Ordering::SeqCst);
+
+            // A slow upstream
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            active_clone.fetch_sub(1, Ordering::SeqCst);
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(127, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().set_max_concurrent_resolutions(1);
+
+        context
+            .cache
+            .store_async(&[
+                DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.com".to_string(),
+                    ttl: TransientTtl(3600),
+                },
+                DnsRecord::A {
+                    domain: "ns1.example.com".to_string(),
+                    addr: Ipv4Addr::new(192, 168, 1, 1),
+                    ttl: TransientTtl(3600),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let tasks = ["one.example.com", "two.example.com"]
+            .iter()
+            .map(|qname| {
+                let context = context.clone();
+                tokio::spawn(async move {
+                    let mut resolver = RecursiveDnsResolver::new(context);
+                    resolver.resolve(qname, QueryType::A, true).await.unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(1, max_active.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_cold_cache_starts_at_a_root_server() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let servers_clone = servers.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, (host, _), _, _| {
+            servers_clone.lock().unwrap().push(host.to_string());
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 7),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().root_servers =
+            vec![("root.test".to_string(), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 53)))];
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        let response = resolver.perform("www.example.com", QueryType::A).await.unwrap();
+
+        assert_eq!(Some("10.0.0.7".to_string()), response.get_random_a());
+        assert_eq!(vec!["192.0.2.53"], *servers.lock().unwrap());
+    }
+}
@@ -1,10 +1,20 @@
 //! Resolver implementations with startegies for answering incoming queries.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
+use std::time::Instant;
 use derive_more::{Display, Error, From};
 use async_trait::async_trait;
+use tracing::{debug, field, info, info_span, warn, Instrument};
 
-use crate::server::context::ServerContext;
-use crate::protocols::protocol::{DnsPacket, QueryType, ResultCode};
+use crate::buffer::buffer::validate_qname;
+#[cfg(feature = "dnssec")]
+use crate::dnssec::validator::{has_anchor_for, signer_of, signers, validate, ValidationResult};
+use crate::server::context::{BlockResponse, ServerContext};
+#[cfg(feature = "dnssec")]
+use crate::protocols::protocol::{is_subdomain, EDE_DNSSEC_BOGUS};
+use crate::protocols::protocol::{
+    reverse_name, DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode, TransientTtl, EDE_BLOCKED,
+};
 
 #[derive(Debug, Display, From, Error)]
 pub enum ResolveError {
@@ -12,58 +22,1042 @@ pub enum ResolveError {
     Cache(crate::cache::memory_cache::CacheError),
     Io(std::io::Error),
     NoServerFound,
+    /// Resolving name server addresses nested deeper than allowed, most likely a delegation loop.
+    DelegationTooDeep,
+    /// Resolving the name took more upstream queries than allowed.
+    QueryBudgetExhausted,
 }
 
 pub type Result<T> = std::result::Result<T, ResolveError>;
 
+/// TTL of the CNAME records synthesized for rewritten names.
+const REWRITE_TTL: u32 = 300;
+
+/// TTL of the addresses handed out for blocked names in sinkhole mode.
+const SINKHOLE_TTL: u32 = 300;
+
+/// The opcode of a standard query, the only kind the resolver answers.
+const OPCODE_QUERY: u8 = 0;
+
+/// How many DS records may be followed up from a signer to reach a trust anchor.
+#[cfg(feature = "dnssec")]
+const MAX_CHAIN_LENGTH: usize = 16;
+
 /// Asynchronous trait for DNS resolvers to implement various resolution strategies.
 #[async_trait]
 pub trait DnsResolver {
     /// Run the server context.
     fn get_context(&self) -> Arc<ServerContext>;
 
+    /// Records whether the downstream client set the CD (checking disabled) bit.
+    ///
+    /// Resolvers that pass queries on to another resolver should propagate it upstream.
+    fn set_checking_disabled(&mut self, _checking_disabled: bool) {}
+
+    /// Whether the downstream client set the CD bit, in which case bogus answers are served anyway.
+    fn checking_disabled(&self) -> bool {
+        false
+    }
+
     /// Resolves a DNS Query Asynchronously using the specified query_name, query_type and recursion preference
+    ///
+    /// Each resolution runs within a `resolve` span, which records where the answer came
+    /// from, its rescode and how long it took, and is logged at info once it completes.
     async fn resolve(&mut self, qname: &str, qtype: QueryType, recursion: bool) -> Result<DnsPacket> {
-        // Handle unsupported query types.
-        if let QueryType::UNKNOWN(_) = qtype {
-           return Ok(create_error_response(RESULT_CODE::NO_TIMP));
+        let span = info_span!(
+            target: "dns",
+            "resolve",
+            qname = %qname,
+            qtype = ?qtype,
+            source = field::Empty,
+            rescode = field::Empty,
+            error = field::Empty,
+            elapsed_ms = field::Empty
+        );
+        let started = Instant::now();
+
+        let result: Result<(DnsPacket, AnswerSource)> = async {
+            let context = self.get_context();
+
+            // Malformed names are refused outright. The question is left out of the
+            // response, as it couldn't be written back anyway.
+            if validate_qname(qname).is_err() {
+                let mut response = create_error_response(&context, qname, qtype, recursion, ResultCode::FORMERR);
+                response.questions.clear();
+                return Ok((response, AnswerSource::Local));
+            }
+
+            // Redirect names matching a rewrite rule, answering with a CNAME to the target.
+            // The target is resolved without applying the rules again, so they can't loop.
+            let target = context.rewrite(qname);
+            let name = target.as_deref().unwrap_or(qname);
+            let (mut response, source) = self.resolve_name(name, qtype, recursion).await?;
+
+            // Only answers from elsewhere are validated, the authority is trusted as it is
+            #[cfg(feature = "dnssec")]
+            if !context.trust_anchors.is_empty()
+                && matches!(source, AnswerSource::Cache | AnswerSource::Recursive | AnswerSource::Forward)
+            {
+                let result = self.validate(name, &response).await;
+                response.header.authed_data = result == ValidationResult::Secure;
+
+                if result == ValidationResult::Bogus && !self.checking_disabled() {
+                    response = create_error_response(&context, qname, qtype, recursion, ResultCode::SERVFAIL);
+                    response.set_extended_error(EDE_DNSSEC_BOGUS, "DNSSEC bogus");
+                    return Ok((response, source));
+                }
+            }
+
+            if let Some(target) = target {
+                response.answers.insert(
+                    0,
+                    DnsRecord::CNAME {
+                        domain: qname.to_string(),
+                        host: target,
+                        ttl: TransientTtl(REWRITE_TTL),
+                    },
+                );
+                // The CNAME is made up here, so nothing vouches for it
+                response.header.authed_data = false;
+            }
+
+            apply_ttl_floor(&mut response, context.serve_ttl_floor);
+
+            Ok((response, source))
         }
+        .instrument(span.clone())
+        .await;
+
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        span.record("elapsed_ms", elapsed_ms);
+
+        let (response, source) = match result {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                span.record("error", field::display(&err));
+                span.in_scope(|| warn!(target: "dns", "Failed to resolve {} {:?} in {}ms: {}", qname, qtype, elapsed_ms, err));
+                return Err(err);
+            }
+        };
+        span.record("source", source.as_str());
+        span.record("rescode", field::debug(response.header.rescode));
+        span.in_scope(|| info!(target: "dns", "Resolved {} {:?} from {} in {}ms", qname, qtype, source.as_str(), elapsed_ms));
+
+        Ok(response)
+    }
+
+    /// Validates the records `response` holds for `qname` against the configured trust anchors.
+    ///
+    /// The DNSKEYs of the signers are looked up through the regular resolution path, and
+    /// so are the DS records linking each signer to the closest zone with a trust anchor.
+    #[cfg(feature = "dnssec")]
+    async fn validate(&mut self, qname: &str, response: &DnsPacket) -> ValidationResult {
+        let context = self.get_context();
+
+        let mut packet = response.clone();
+        let mut anchors = context.trust_anchors.clone();
+        for signer in signers(response) {
+            match self.resolve_name(&signer, QueryType::DNSKEY, true).await {
+                Ok((keys, _)) => packet.resources.extend(keys.answers),
+                Err(err) => debug!(target: "dns", "Failed to fetch the DNSKEYs of {}: {}", signer, err),
+            }
+            anchors.extend(self.chain_of_trust(&signer).await);
+        }
+
+        validate(&packet, qname, &anchors)
+    }
+
+    /// Follows the DS records from `zone` up to the closest zone with a trust anchor,
+    /// then verifies each of them on the way back down.
+    ///
+    /// Returns the DS records of `zone` once the whole chain verifies, and none when it
+    /// breaks or `zone` has an anchor of its own.
+    #[cfg(feature = "dnssec")]
+    async fn chain_of_trust(&mut self, zone: &str) -> Vec<DnsRecord> {
+        let context = self.get_context();
+
+        let mut links = Vec::new();
+        let mut current = zone.to_string();
+        while !has_anchor_for(&current, &context.trust_anchors) {
+            if current.is_empty() || links.len() >= MAX_CHAIN_LENGTH {
+                return Vec::new();
+            }
+
+            let mut delegation = match self.resolve_name(&current, QueryType::DS, true).await {
+                Ok((delegation, _)) => delegation,
+                Err(err) => {
+                    debug!(target: "dns", "Failed to fetch the DS records of {}: {}", current, err);
+                    return Vec::new();
+                }
+            };
+
+            // The DS records live in the parent and are signed by it
+            let parent = match signer_of(&delegation, QueryType::DS) {
+                Some(parent) if is_ancestor(&parent, &current) => parent,
+                _ => return Vec::new(),
+            };
+            if let Ok((keys, _)) = self.resolve_name(&parent, QueryType::DNSKEY, true).await {
+                delegation.resources.extend(keys.answers);
+            }
+
+            links.push((current, delegation));
+            current = parent;
+        }
+
+        if links.is_empty() {
+            return Vec::new();
+        }
+
+        let mut trusted = context.trust_anchors.clone();
+        for (zone, delegation) in links.into_iter().rev() {
+            if validate(&delegation, &zone, &trusted) != ValidationResult::Secure {
+                debug!(target: "dns", "The DS records of {} don't verify", zone);
+                return Vec::new();
+            }
+
+            trusted = delegation
+                .answers
+                .into_iter()
+                .filter(|rec| rec.get_querytype() == QueryType::DS && rec.matches_name(&zone))
+                .collect();
+        }
+
+        trusted
+    }
+
+    /// Answers the first question of `query`, with the response flags set from its header.
+    ///
+    /// Anything but a standard QUERY, such as STATUS or UPDATE, gets a NOTIMP response
+    /// without being resolved.
+    async fn resolve_query(&mut self, query: &DnsPacket) -> Result<DnsPacket> {
+        let context = self.get_context();
+
+        if query.header.opcode != OPCODE_QUERY {
+            return Ok(error_response_for(query, ResultCode::NOTIMP, context.allow_recursive));
+        }
+
+        let question = match query.questions.first() {
+            Some(question) => question,
+            None => return Ok(error_response_for(query, ResultCode::FORMERR, context.allow_recursive)),
+        };
+
+        // Counted here rather than in `resolve`, which also serves the resolver's own lookups
+        context.statistics.record_query(question.qtype);
+
+        // Blocked names never reach the authority, the cache or an upstream. Only the
+        // client's question is checked, lookups of name server addresses are left alone.
+        if context.is_blocked(&question.name) {
+            let mut response = create_blocked_response(&question.name, question.qtype, context.block_response);
+            response.make_response(&query.header, context.allow_recursive);
+            return Ok(response);
+        }
+
+        self.set_checking_disabled(query.header.checking_disabled);
+        let mut response = match self.resolve(&question.name, question.qtype, query.header.recursion_desired).await {
+            Ok(response) => response,
+            // Resolution given up on as too costly is a server failure for the client
+            Err(ResolveError::DelegationTooDeep | ResolveError::QueryBudgetExhausted) => {
+                return Ok(error_response_for(query, ResultCode::SERVFAIL, context.allow_recursive));
+            }
+            Err(err) => return Err(err),
+        };
+        response.make_response(&query.header, context.allow_recursive);
+
+        Ok(response)
+    }
+
+    /// Looks up the PTR records for `ip` through the regular resolution path.
+    async fn resolve_ptr(&mut self, ip: IpAddr, recursion: bool) -> Result<DnsPacket> {
+        self.resolve(&reverse_name(ip), QueryType::PTR, recursion).await
+    }
 
+    /// Answers a query from the authority, the cache or by external resolution, in that order.
+    ///
+    /// Returns the answer along with which of those it came from.
+    async fn resolve_name(&mut self, qname: &str, qtype: QueryType, recursion: bool) -> Result<(DnsPacket, AnswerSource)> {
         let context = self.get_context();
 
+        // Handle unsupported query types.
+        if let QueryType::UNKNOWN(_) = qtype {
+           return Ok((create_error_response(&context, qname, qtype, recursion, ResultCode::NOTIMP), AnswerSource::Local));
+        }
+
         // Check if authority has answer.
         if let Some(response) = context.authority.query(qname, qtype) {
-           return Ok(response);
+           return Ok((response, AnswerSource::Authority));
         }
 
         // Refuse if recursion is disabled or not allowed.
         if !recursion || !context.allow_recursive {
-           return Ok(create_error_response(ResultCode::REFUSED));
+           return Ok((create_error_response(&context, qname, qtype, recursion, ResultCode::REFUSED), AnswerSource::Local));
         }
 
         // Check the cache for the answer.
-        if let Some(response) = context.cache.lookup(qname, qtype) {
-            return Ok(response);
+        if let Some(response) = context.cache.lookup_async(qname, qtype).await {
+            if context.cache.start_prefetch(qname, qtype).await {
+                spawn_prefetch(context, qname.to_string(), qtype);
+            }
+            return Ok((response, AnswerSource::Cache));
         }
 
         // Additional cache lookup for CNAME records when querying A or AAAA types.
         if matches!(qtype, QueryType::A | QueryType::AAAA) {
-            if let Some(cname_response) = context.cache.lookup(qname, QueryType::CNAME) {
-               return Ok(cname_response);
+            if let Some(cname_response) = context.cache.lookup_async(qname, QueryType::CNAME).await {
+               return Ok((cname_response, AnswerSource::Cache));
             }
         }
 
         // Perfom external resolution asynchronously if no local answer is found.
-        self.perform(qname, qtype).await
+        let response = self.perform(qname, qtype).await?;
+        Ok((response, self.source()))
     }
 
+    /// Where the answers `perform` produces come from.
+    fn source(&self) -> AnswerSource;
+
     /// Perfoms the actual DNS resolution asynchronously n/b forwarding or recursive resolution.
     async fn perform(&mut self, qname: &str, qtype: QueryType) -> Result<DnsPacket>;
 }
 
-/// Creates a dns packet with specific error result code.
-fn create_error_response(rescode: ResultCode) -> DnsPacket {
+/// Where the answer to a query came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnswerSource {
+    Authority,
+    Cache,
+    Recursive,
+    Forward,
+    /// An error response made up locally, such as for refused recursion.
+    Local,
+}
+
+impl AnswerSource {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AnswerSource::Authority => "authority",
+            AnswerSource::Cache => "cache",
+            AnswerSource::Recursive => "recursive",
+            AnswerSource::Forward => "forward",
+            AnswerSource::Local => "local",
+        }
+    }
+}
+
+/// Whether `zone` is a proper ancestor of `name`, which the root is of every other name.
+#[cfg(feature = "dnssec")]
+fn is_ancestor(zone: &str, name: &str) -> bool {
+    is_subdomain(name, zone) && !zone.trim_end_matches('.').eq_ignore_ascii_case(name.trim_end_matches('.'))
+}
+
+/// Re-resolves records nearing expiry in the background, so the answer already
+/// served isn't held up and the next query finds them fresh in the cache.
+fn spawn_prefetch(context: Arc<ServerContext>, qname: String, qtype: QueryType) {
+    tokio::spawn(async move {
+        let mut resolver = context.create_resolver(context.clone());
+        if let Err(err) = resolver.perform(&qname, qtype).await {
+            debug!(target: "dns", "Prefetch of {} {:?} failed: {}", qname, qtype, err);
+        }
+        context.cache.finish_prefetch(&qname, qtype);
+    });
+}
+
+/// Creates the error response to `query`, echoing its id and questions so the client can correlate it.
+pub fn error_response_for(query: &DnsPacket, rescode: ResultCode, recursion_available: bool) -> DnsPacket {
     let mut packet = DnsPacket::new();
+    packet.make_response(&query.header, recursion_available);
     packet.header.rescode = rescode;
+    packet.questions = query.questions.clone();
+    packet
+}
+
+/// Creates the error response to a query for `qname` and `qtype` received by the resolver.
+fn create_error_response(
+    context: &ServerContext,
+    qname: &str,
+    qtype: QueryType,
+    recursion: bool,
+    rescode: ResultCode,
+) -> DnsPacket {
+    let mut query = DnsPacket::new();
+    query.header.recursion_desired = recursion;
+    query.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+
+    error_response_for(&query, rescode, context.allow_recursive)
+}
+
+/// Creates the response for a query of a blocked name.
+fn create_blocked_response(qname: &str, qtype: QueryType, mode: BlockResponse) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+
+    match mode {
+        BlockResponse::NxDomain => packet.header.rescode = ResultCode::NXDOMAIN,
+        BlockResponse::Sinkhole => match qtype {
+            QueryType::A => packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::UNSPECIFIED,
+                ttl: TransientTtl(SINKHOLE_TTL),
+            }),
+            QueryType::AAAA => packet.answers.push(DnsRecord::AAAA {
+                domain: qname.to_string(),
+                addr: Ipv6Addr::UNSPECIFIED,
+                ttl: TransientTtl(SINKHOLE_TTL),
+            }),
+            _ => {}
+        },
+    }
+
+    packet.set_extended_error(EDE_BLOCKED, "blocked by local policy");
     packet
 }
+
+/// Raises the TTL of every record in the packet to at least `floor`.
+fn apply_ttl_floor(packet: &mut DnsPacket, floor: u32) {
+    if floor == 0 {
+        return;
+    }
+
+    let records = packet
+        .answers
+        .iter_mut()
+        .chain(packet.authorities.iter_mut())
+        .chain(packet.resources.iter_mut());
+
+    for record in records {
+        if let Some(ttl) = record.ttl_mut() {
+            if ttl.0 < floor {
+                ttl.0 = floor;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use super::*;
+
+    use crate::resolvers::forwading_resolver::ForwardingDnsResolver;
+    use crate::resolvers::recursive_resolver::RecursiveDnsResolver;
+    use crate::server::context::tests::create_test_context;
+    use crate::server::context::{ResolveStrategy, RewriteRule};
+    use crate::cache::memory_cache::RecordSet;
+    #[cfg(feature = "dnssec")]
+    use crate::dnssec::validator::tests::TestZone;
+
+    #[tokio::test]
+    async fn test_rewrite_rule_resolves_target() {
+        let upstream_qname = Arc::new(Mutex::new(String::new()));
+        let upstream_qname_clone = upstream_qname.clone();
+
+        let mut context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            *upstream_qname_clone.lock().unwrap() = qname.to_string();
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        Arc::get_mut(&mut context)
+            .unwrap()
+            .rewrite_rules
+            .push(RewriteRule::new(r"^(.+)\.corp\.example$", "$1.internal.example").unwrap());
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve("host.corp.example", QueryType::A, true).await.unwrap();
+
+        assert_eq!("host.internal.example", *upstream_qname.lock().unwrap());
+        assert_eq!(2, response.answers.len());
+        assert_eq!(
+            DnsRecord::CNAME {
+                domain: "host.corp.example".to_string(),
+                host: "host.internal.example".to_string(),
+                ttl: TransientTtl(REWRITE_TTL),
+            },
+            response.answers[0]
+        );
+        assert_eq!(Some("10.1.2.3".to_string()), response.get_random_a());
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_near_expiry_is_prefetched() {
+        let upstream_queries = Arc::new(Mutex::new(0));
+        let upstream_queries_clone = upstream_queries.clone();
+
+        let context = create_test_context(Box::new(move |qname, _, _, _, _| {
+            *upstream_queries_clone.lock().unwrap() += 1;
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: TransientTtl(10),
+            });
+            Ok(packet)
+        }));
+        context.set_resolve_strategy(ResolveStrategy::Forward {
+            host: "127.0.0.1".to_string(),
+            port: 53,
+        });
+
+        context
+            .cache
+            .store_async(&[DnsRecord::A {
+                domain: "short.example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: TransientTtl(10),
+            }])
+            .await
+            .unwrap();
+
+        let remaining_ttl = || async {
+            let entries = context.cache.list_async().await.unwrap();
+            let set = entries[0].record_types.get(&QueryType::A).unwrap();
+            match set.value() {
+                RecordSet::Records { records, .. } => records.iter().next().unwrap().remaining_ttl(),
+                RecordSet::NoRecords { .. } => panic!("Expected records"),
+            }
+        };
+
+        let mut resolver = ForwardingDnsResolver::new(context.clone(), ("127.0.0.1".to_string(), 53));
+
+        // Fresh records are answered from the cache without a refresh
+        resolver.resolve("short.example.com", QueryType::A, true).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(0, *upstream_queries.lock().unwrap());
+
+        // Age the record until only 5% of its TTL is left
+        for entry in context.cache.list_async().await.unwrap() {
+            if let Some(mut set) = entry.record_types.get_mut(&QueryType::A) {
+                if let RecordSet::Records { records, .. } = set.value_mut() {
+                    *records = records
+                        .drain()
+                        .map(|mut record| {
+                            record.timestamp -= chrono::Duration::milliseconds(9500);
+                            record
+                        })
+                        .collect();
+                }
+            }
+        }
+
+        // The cached answer is still served straight away, with the refresh running behind it
+        let response = resolver.resolve("short.example.com", QueryType::A, true).await.unwrap();
+        assert_eq!(Some("10.0.0.1".to_string()), response.get_random_a());
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while remaining_ttl().await < chrono::Duration::seconds(5) {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(1, *upstream_queries.lock().unwrap());
+
+        // Once refreshed, hits no longer trigger another one
+        resolver.resolve("short.example.com", QueryType::A, true).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(1, *upstream_queries.lock().unwrap());
+    }
+
+    #[test]
+    fn test_resolvers_are_usable_as_trait_objects() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+
+        let resolvers: Vec<Box<dyn DnsResolver + Send + Sync>> = vec![
+            Box::new(ForwardingDnsResolver::new(context.clone(), ("127.0.0.1".to_string(), 53))),
+            Box::new(RecursiveDnsResolver::new(context.clone())),
+            context.create_resolver(context.clone()),
+        ];
+
+        for resolver in &resolvers {
+            assert!(Arc::ptr_eq(&context, &resolver.get_context()));
+        }
+    }
+
+    #[test]
+    fn test_error_response_echoes_question_and_id() {
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.header.recursion_desired = true;
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+
+        let response = error_response_for(&query, ResultCode::REFUSED, false);
+
+        assert_eq!(4242, response.header.id);
+        assert!(response.header.response);
+        assert!(response.header.recursion_desired);
+        assert!(!response.header.recursion_available);
+        assert_eq!(ResultCode::REFUSED, response.header.rescode);
+        assert_eq!(query.questions, response.questions);
+        assert!(response.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_status_query_is_not_implemented() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let calls_clone = calls.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+            Ok(DnsPacket::new())
+        }));
+
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.header.opcode = 2;
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve_query(&query).await.unwrap();
+
+        assert_eq!(ResultCode::NOTIMP, response.header.rescode);
+        assert_eq!(4242, response.header.id);
+        assert_eq!(query.questions, response.questions);
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_name_gets_formerr() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let calls_clone = calls.clone();
+
+        let context = create_test_context(Box::new(move |_, _, _, _, _| {
+            calls_clone.fetch_add(1, Ordering::SeqCst);
+            Ok(DnsPacket::new())
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+
+        let response = resolver.resolve("a..b", QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::FORMERR, response.header.rescode);
+
+        let long_name = vec!["abcdefghi"; 26].join(".");
+        let response = resolver.resolve(&long_name, QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::FORMERR, response.header.rescode);
+        assert_eq!(0, calls.load(Ordering::SeqCst));
+
+        let response = resolver.resolve("www.example.com.", QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::NOERROR, response.header.rescode);
+
+        // A literal wildcard is a valid name, looked up like any other
+        let response = resolver.resolve("*.example.com", QueryType::A, true).await.unwrap();
+        assert_eq!(ResultCode::NOERROR, response.header.rescode);
+        assert_eq!(2, calls.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_refused_query_echoes_question() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve("www.example.com", QueryType::AAAA, false).await.unwrap();
+
+        assert_eq!(ResultCode::REFUSED, response.header.rescode);
+        assert!(response.header.response);
+        assert!(response.header.recursion_available);
+        assert_eq!(
+            vec![DnsQuestion::new("www.example.com".to_string(), QueryType::AAAA)],
+            response.questions
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_is_served_with_floor() {
+        let mut context = create_test_context(Box::new(|qname, _, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 1, 2, 3),
+                ttl: TransientTtl(0),
+            });
+            Ok(packet)
+        }));
+
+        Arc::get_mut(&mut context).unwrap().serve_ttl_floor = 30;
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve("zero.example", QueryType::A, true).await.unwrap();
+
+        assert_eq!(1, response.answers.len());
+        assert_eq!(30, response.answers[0].get_ttl());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ptr_queries_reverse_name() {
+        let context = create_test_context(Box::new(|qname, qtype, _, _, _| {
+            assert_eq!(QueryType::PTR, qtype);
+
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::PTR {
+                domain: qname.to_string(),
+                host: "host.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve_ptr("192.0.2.10".parse().unwrap(), true).await.unwrap();
+
+        assert_eq!(
+            vec![DnsRecord::PTR {
+                domain: "10.2.0.192.in-addr.arpa".to_string(),
+                host: "host.example.com".to_string(),
+                ttl: TransientTtl(3600),
+            }],
+            response.answers
+        );
+    }
+
+    /// A standard query for `qname` as a client would send it.
+    fn client_query(qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.header.recursion_desired = true;
+        query.questions.push(DnsQuestion::new(qname.to_string(), qtype));
+        query
+    }
+
+    #[tokio::test]
+    async fn test_blocked_names_are_not_resolved() {
+        let mut context = create_test_context(Box::new(|_, _, _, _, _| {
+            panic!("Blocked names must not be sent upstream");
+        }));
+
+        Arc::get_mut(&mut context)
+            .unwrap()
+            .blocklist
+            .insert("ads.example.com".to_string());
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+
+        let response = resolver.resolve_query(&client_query("ads.example.com", QueryType::A)).await.unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, response.header.rescode);
+        assert_eq!(Some(EDE_BLOCKED), response.get_extended_error().map(|(code, _)| code));
+        assert_eq!(4242, response.header.id);
+
+        let response = resolver.resolve_query(&client_query("pixel.ads.example.com", QueryType::A)).await.unwrap();
+        assert_eq!(ResultCode::NXDOMAIN, response.header.rescode);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_names_are_sinkholed() {
+        let mut context = create_test_context(Box::new(|_, _, _, _, _| {
+            panic!("Blocked names must not be sent upstream");
+        }));
+
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        ctx.blocklist.insert("ads.example.com".to_string());
+        ctx.block_response = BlockResponse::Sinkhole;
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+
+        let response = resolver.resolve_query(&client_query("pixel.ads.example.com", QueryType::A)).await.unwrap();
+        assert_eq!(ResultCode::NOERROR, response.header.rescode);
+        assert_eq!(vec![Ipv4Addr::UNSPECIFIED], response.get_a_records());
+
+        let response = resolver.resolve_query(&client_query("ads.example.com", QueryType::AAAA)).await.unwrap();
+        assert_eq!(vec![Ipv6Addr::UNSPECIFIED], response.get_aaaa_records());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_name_servers_are_still_resolved() {
+        let servers = Arc::new(Mutex::new(Vec::new()));
+        let servers_clone = servers.clone();
+
+        // example.com is delegated to a name server without glue
+        let mut context = create_test_context(Box::new(move |qname, qtype, (server, _), _, _| {
+            servers_clone.lock().unwrap().push(server.to_string());
+
+            let mut packet = DnsPacket::new();
+            if qname == "ns1.ads.example.net" {
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 53),
+                    ttl: TransientTtl(3600),
+                });
+            } else if server == "192.0.2.53" {
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: Ipv4Addr::new(10, 0, 0, 80),
+                    ttl: TransientTtl(3600),
+                });
+            } else {
+                assert_eq!(QueryType::A, qtype);
+                packet.authorities.push(DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.ads.example.net".to_string(),
+                    ttl: TransientTtl(3600),
+                });
+            }
+            Ok(packet)
+        }));
+
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        ctx.blocklist.insert("ads.example.net".to_string());
+        ctx.block_response = BlockResponse::Sinkhole;
+        ctx.root_servers = vec![("root.test".to_string(), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))];
+
+        let mut resolver = RecursiveDnsResolver::new(context);
+        let response = resolver.resolve_query(&client_query("www.example.com", QueryType::A)).await.unwrap();
+
+        assert_eq!(vec![Ipv4Addr::new(10, 0, 0, 80)], response.get_a_records());
+        assert_eq!(Some(&"192.0.2.53".to_string()), servers.lock().unwrap().last());
+    }
+
+    #[tokio::test]
+    async fn test_queries_are_counted_per_type() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+        let mut resolver = ForwardingDnsResolver::new(context.clone(), ("127.0.0.1".to_string(), 53));
+
+        for (qname, qtype) in &[
+            ("a.example.com", QueryType::A),
+            ("b.example.com", QueryType::A),
+            ("a.example.com", QueryType::AAAA),
+            ("example.com", QueryType::MX),
+        ] {
+            resolver.resolve_query(&client_query(qname, *qtype)).await.unwrap();
+        }
+
+        let counts = context.statistics.query_type_counts();
+        assert_eq!(3, counts.len());
+        assert_eq!(Some(&2), counts.get(&QueryType::A));
+        assert_eq!(Some(&1), counts.get(&QueryType::AAAA));
+        assert_eq!(Some(&1), counts.get(&QueryType::MX));
+    }
+
+    #[tokio::test]
+    async fn test_name_server_lookups_are_not_counted() {
+        // example.com is delegated to a name server without glue
+        let mut context = create_test_context(Box::new(|qname, _, (server, _), _, _| {
+            let mut packet = DnsPacket::new();
+            if qname == "ns1.example.net" {
+                packet.answers.push(DnsRecord::A {
+                    domain: qname.to_string(),
+                    addr: Ipv4Addr::new(192, 0, 2, 53),
+                    ttl: TransientTtl(3600),
+                });
+            } else if server == "192.0.2.53" {
+                packet.answers.push(DnsRecord::MX {
+                    domain: qname.to_string(),
+                    priority: 10,
+                    host: "mail.example.com".to_string(),
+                    ttl: TransientTtl(3600),
+                });
+            } else {
+                packet.authorities.push(DnsRecord::NS {
+                    domain: "example.com".to_string(),
+                    host: "ns1.example.net".to_string(),
+                    ttl: TransientTtl(3600),
+                });
+            }
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().root_servers =
+            vec![("root.test".to_string(), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))];
+
+        let mut resolver = RecursiveDnsResolver::new(context.clone());
+        let response = resolver.resolve_query(&client_query("example.com", QueryType::MX)).await.unwrap();
+        assert_eq!(1, response.answers.len());
+
+        let counts = context.statistics.query_type_counts();
+        assert_eq!(1, counts.len());
+        assert_eq!(Some(&1), counts.get(&QueryType::MX));
+    }
+
+    /// Collects the fields recorded on `resolve` spans.
+    #[derive(Clone, Default)]
+    struct SpanFields {
+        next_id: Arc<AtomicU64>,
+        resolve_spans: Arc<Mutex<Vec<Id>>>,
+        fields: Arc<Mutex<HashMap<String, String>>>,
+    }
+
+    impl tracing::field::Visit for SpanFields {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.fields.lock().unwrap().insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.fields.lock().unwrap().insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for SpanFields {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let id = Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+            if span.metadata().name() == "resolve" {
+                self.resolve_spans.lock().unwrap().push(id.clone());
+                span.record(&mut self.clone());
+            }
+            id
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            if self.resolve_spans.lock().unwrap().contains(span) {
+                values.record(&mut self.clone());
+            }
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[tokio::test]
+    async fn test_resolution_span_records_cache_hit() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+        context
+            .cache
+            .store_async(&[DnsRecord::A {
+                domain: "cached.example.com".to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            }])
+            .await
+            .unwrap();
+
+        let subscriber = SpanFields::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        resolver.resolve("cached.example.com", QueryType::A, true).await.unwrap();
+
+        let fields = subscriber.fields.lock().unwrap();
+        assert_eq!(Some("cached.example.com"), fields.get("qname").map(String::as_str));
+        assert_eq!(Some("A"), fields.get("qtype").map(String::as_str));
+        assert_eq!(Some("cache"), fields.get("source").map(String::as_str));
+        assert_eq!(Some("NOERROR"), fields.get("rescode").map(String::as_str));
+        assert!(fields.get("elapsed_ms").is_some_and(|ms| ms.parse::<u64>().is_ok()));
+    }
+
+    #[tokio::test]
+    async fn test_resolution_span_records_failure() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| {
+            Err(crate::client::network_client::ClientError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, "upstream timed out")))
+        }));
+
+        let subscriber = SpanFields::default();
+        let _guard = tracing::subscriber::set_default(subscriber.clone());
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        assert!(resolver.resolve("www.example.com", QueryType::A, true).await.is_err());
+
+        let fields = subscriber.fields.lock().unwrap();
+        assert_eq!(Some("www.example.com"), fields.get("qname").map(String::as_str));
+        assert!(fields.get("error").is_some_and(|err| err.contains("upstream timed out")));
+        assert!(fields.get("elapsed_ms").is_some_and(|ms| ms.parse::<u64>().is_ok()));
+        assert!(fields.get("rescode").is_none());
+    }
+
+    /// How the upstream in `signed_context` signs `www.example.com`.
+    #[cfg(feature = "dnssec")]
+    enum AnswerSigner {
+        /// With the key the DS record of example.com vouches for.
+        Delegated,
+        /// With a key of the same name no DS record vouches for.
+        Rogue,
+        Unsigned,
+    }
+
+    /// A context whose upstream serves `www.example.com` from the `example.com` zone,
+    /// which `com` delegates to through a DS record. `com` is the trust anchor.
+    #[cfg(feature = "dnssec")]
+    fn signed_context(answer_signer: AnswerSigner) -> Arc<ServerContext> {
+        let com = TestZone::new("com");
+        let example = TestZone::new("example.com");
+        let rogue = TestZone::new("example.com");
+        let anchors = vec![com.ds()];
+
+        let mut context = create_test_context(Box::new(move |qname, qtype, _, _, _| {
+            let mut packet = DnsPacket::new();
+            match (qname, qtype) {
+                ("www.example.com", QueryType::A) => {
+                    let rrset = [DnsRecord::A {
+                        domain: qname.to_string(),
+                        addr: Ipv4Addr::new(192, 0, 2, 1),
+                        ttl: TransientTtl(3600),
+                    }];
+                    packet.answers.extend(rrset.clone());
+                    match answer_signer {
+                        AnswerSigner::Delegated => packet.answers.push(example.sign(&rrset)),
+                        AnswerSigner::Rogue => packet.answers.push(rogue.sign(&rrset)),
+                        AnswerSigner::Unsigned => {}
+                    }
+                }
+                ("example.com", QueryType::DS) => {
+                    packet.answers.push(example.ds());
+                    packet.answers.push(com.sign(&[example.ds()]));
+                }
+                ("example.com", QueryType::DNSKEY) => {
+                    packet.answers.push(example.dnskey.clone());
+                    packet.answers.push(example.sign(std::slice::from_ref(&example.dnskey)));
+                }
+                ("com", QueryType::DNSKEY) => {
+                    packet.answers.push(com.dnskey.clone());
+                    packet.answers.push(com.sign(std::slice::from_ref(&com.dnskey)));
+                }
+                _ => {}
+            }
+            Ok(packet)
+        }));
+        Arc::get_mut(&mut context).unwrap().trust_anchors = anchors;
+
+        context
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[tokio::test]
+    async fn test_answers_chaining_to_an_anchor_are_authenticated() {
+        let context = signed_context(AnswerSigner::Delegated);
+
+        let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+        let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+        assert!(response.header.authed_data);
+
+        // The signatures are cached along with the answer
+        let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+        assert!(response.header.authed_data);
+        assert_eq!(Some("192.0.2.1".to_string()), response.get_random_a());
+    }
+
+    #[cfg(feature = "dnssec")]
+    #[tokio::test]
+    async fn test_bogus_answers_get_servfail() {
+        for answer_signer in [AnswerSigner::Rogue, AnswerSigner::Unsigned] {
+            let context = signed_context(answer_signer);
+
+            let mut resolver = ForwardingDnsResolver::new(context, ("127.0.0.1".to_string(), 53));
+            let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+            assert_eq!(ResultCode::SERVFAIL, response.header.rescode);
+            assert!(response.answers.is_empty());
+            assert_eq!(EDE_DNSSEC_BOGUS, response.get_extended_error().unwrap().0);
+
+            // Clients setting CD do their own checking, so they get the answer anyway
+            resolver.set_checking_disabled(true);
+            let response = resolver.resolve("www.example.com", QueryType::A, true).await.unwrap();
+            assert_eq!(ResultCode::NOERROR, response.header.rescode);
+            assert!(!response.header.authed_data);
+            assert_eq!(Some("192.0.2.1".to_string()), response.get_random_a());
+        }
+    }
+}
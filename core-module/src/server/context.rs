@@ -1,17 +1,29 @@
 //! The `ServerContext` in this module holds the common state across the server.
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+use dashmap::DashMap;
 use derive_more::{Display, Error, From};
+use regex::Regex;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 
 use crate::authorities::authority::Authority;
 use crate::cache::memory_cache::SynchronizedCache;
-use crate::client::network_client::{DnsClient, DnsNetworkClient};
+use crate::client::network_client::{DnsClient, DnsNetworkClient, DEFAULT_LISTEN_ADDR};
+use crate::protocols::protocol::{DnsRecord, QueryType};
+use crate::resolvers::fallback_resolver::FallbackDnsResolver;
+use crate::resolvers::forwading_resolver::ForwardingDnsResolver;
 use crate::resolvers::resolve::DnsResolver;
 use crate::resolvers::recursive_resolver::RecursiveDnsResolver;
-// use crate::resolvers::forwading_resolver::ForwadingDnsResolver;
+use crate::server::rate_limiter::RateLimiter;
+use crate::server::shutdown::Shutdown;
 
 
 #[derive(Debug, Display, From, Error)]
@@ -30,10 +42,39 @@ type Result<T> = std::result::Result<T, ContextError>;
 const DEFAULT_DNS_PORT: u16 = 53;
 const DEFAULT_API_PORT: u16 = 5380;
 const DEFAULT_ZONES_DIR: &str = "zones";
+const DEFAULT_SERVE_TTL_FLOOR: u32 = 0;
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_CONCURRENT_RESOLUTIONS: usize = 256;
+
+/// The IPv4 addresses of the root name servers, from the IANA root hints.
+const ROOT_SERVERS: [(&str, Ipv4Addr); 13] = [
+    ("a.root-servers.net", Ipv4Addr::new(198, 41, 0, 4)),
+    ("b.root-servers.net", Ipv4Addr::new(170, 247, 170, 2)),
+    ("c.root-servers.net", Ipv4Addr::new(192, 33, 4, 12)),
+    ("d.root-servers.net", Ipv4Addr::new(199, 7, 91, 13)),
+    ("e.root-servers.net", Ipv4Addr::new(192, 203, 230, 10)),
+    ("f.root-servers.net", Ipv4Addr::new(192, 5, 5, 241)),
+    ("g.root-servers.net", Ipv4Addr::new(192, 112, 36, 4)),
+    ("h.root-servers.net", Ipv4Addr::new(198, 97, 190, 53)),
+    ("i.root-servers.net", Ipv4Addr::new(192, 36, 148, 17)),
+    ("j.root-servers.net", Ipv4Addr::new(192, 58, 128, 30)),
+    ("k.root-servers.net", Ipv4Addr::new(193, 0, 14, 129)),
+    ("l.root-servers.net", Ipv4Addr::new(199, 7, 83, 42)),
+    ("m.root-servers.net", Ipv4Addr::new(202, 12, 27, 33)),
+];
+
+/// Returns the root name servers recursion starts from by default.
+pub fn default_root_servers() -> Vec<(String, IpAddr)> {
+    ROOT_SERVERS
+        .iter()
+        .map(|&(name, addr)| (name.to_string(), IpAddr::V4(addr)))
+        .collect()
+}
 
 pub struct ServerStatistics {
     pub tcp_query_count: AtomicUsize,
     pub udp_query_count: AtomicUsize,
+    pub query_type_count: DashMap<QueryType, AtomicUsize>,
 }
 
 impl ServerStatistics {
@@ -46,44 +87,140 @@ impl ServerStatistics {
     pub fn get_udp_query_count(&self) -> usize {
         self.udp_query_count.load(Ordering::Acquire)
     }
+
+    /// Counts a query of type `qtype`.
+    pub fn record_query(&self, qtype: QueryType) {
+        self.query_type_count
+            .entry(qtype)
+            .or_default()
+            .fetch_add(1, Ordering::Release);
+    }
+
+    /// Returns the number of queries seen for each query type.
+    pub fn query_type_counts(&self) -> HashMap<QueryType, usize> {
+        self.query_type_count
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().load(Ordering::Acquire)))
+            .collect()
+    }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ResolveStrategy {
     Recursive,
     Forward { host: String, port: u16 },
+    /// Forward over DNS-over-TLS, verifying the upstream certificate as `tls_name`.
+    ///
+    /// Needs the `tls` feature, without it every lookup fails with `TlsUnavailable`.
+    ForwardTls { host: String, port: u16, tls_name: String },
+    /// Resolve recursively, forwarding to the upstream when recursion fails.
+    RecursiveWithForwardFallback { host: String, port: u16 },
+}
+
+/// How queries for blocked names are answered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockResponse {
+    /// Claim the name doesn't exist.
+    NxDomain,
+    /// Answer address queries with the unspecified address.
+    Sinkhole,
+}
+
+/// Redirects query names matching `pattern` to `replacement`.
+///
+/// The replacement may refer to capture groups of the pattern, e.g. `$1.internal.example`.
+pub struct RewriteRule {
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl RewriteRule {
+    /// Creates a rule, failing if `pattern` isn't a valid regular expression.
+    pub fn new(pattern: &str, replacement: &str) -> std::result::Result<RewriteRule, regex::Error> {
+        Ok(RewriteRule {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// Returns the rewritten name if `qname` matches the rule.
+    pub fn apply(&self, qname: &str) -> Option<String> {
+        if !self.pattern.is_match(qname) {
+            return None;
+        }
+
+        Some(self.pattern.replace(qname, self.replacement.as_str()).into_owned())
+    }
 }
 
 pub struct ServerContext {
     pub authority: Authority,
     pub cache: SynchronizedCache,
     pub client: Box<dyn DnsClient + Sync + Send>,
+    /// Address the server and the upstream client bind their sockets to.
+    pub listen_addr: IpAddr,
     pub dns_port: u16,
     pub api_port: u16,
-    pub resolve_strategy: ResolveStrategy,
+    /// Guarded so the strategy can be swapped while the server is running.
+    pub resolve_strategy: RwLock<ResolveStrategy>,
     pub allow_recursive: bool,
     pub enable_udp: bool,
     pub enable_tcp: bool,
     pub enable_api: bool,
     pub statistics: ServerStatistics,
-    pub zones_dir: &'static str,
-}
-
-impl Default for ServerContext {
-    fn default() -> Self {
-        Self::new()
-    }
+    pub zones_dir: String,
+    pub rewrite_rules: Vec<RewriteRule>,
+    /// Minimum TTL put on records in outgoing responses, 0 leaves them untouched.
+    ///
+    /// Only affects what clients see; cached records keep the TTL they were stored with.
+    pub serve_ttl_floor: u32,
+    /// Idle timeout for persistent TCP connections, advertised through edns-tcp-keepalive.
+    pub tcp_keepalive: Duration,
+    /// Names refused along with all of their subdomains.
+    pub blocklist: HashSet<String>,
+    pub block_response: BlockResponse,
+    /// Limits the query rate of each client, when set.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Signals the listeners and the client loop to stop.
+    pub shutdown: Shutdown,
+    /// Bounds how many upstream queries recursive resolution has in flight at once.
+    pub resolution_slots: Semaphore,
+    /// DS or DNSKEY records that DNSSEC validation chains to, validation is off while empty.
+    ///
+    /// Signers below an anchor are linked to it through their DS records. Unsigned answers
+    /// below an anchor are bogus, since denials of DS records aren't checked against their
+    /// NSEC records and so an insecure delegation can't be told apart from stripped
+    /// signatures. Anchor the zones expected to be signed rather than the root, which
+    /// would fail every unsigned zone. Only used when built with the `dnssec` feature,
+    /// and should be set before `initialize`, which has upstream queries set the DO bit.
+    pub trust_anchors: Vec<DnsRecord>,
+    /// Name servers recursion starts from when nothing closer to the name is cached.
+    pub root_servers: Vec<(String, IpAddr)>,
 }
 
 impl ServerContext {
     /// Creates a new `ServerContext` with default settings.
-    pub fn new() -> Self {
-        ServerContext {
+    ///
+    /// The upstream client is bound to a port picked by the OS and stops along with the server.
+    pub async fn new() -> Result<ServerContext> {
+        Self::new_on(DEFAULT_LISTEN_ADDR).await
+    }
+
+    /// Creates a new `ServerContext` which binds its sockets to `listen_addr`, such as `::` for IPv6.
+    pub async fn new_on(listen_addr: IpAddr) -> Result<ServerContext> {
+        let shutdown = Shutdown::new();
+
+        let mut client = DnsNetworkClient::new_on(listen_addr, 0).await?;
+        client.shutdown = Some(shutdown.subscribe());
+
+        Ok(ServerContext {
             authority: Authority::new(),
             cache: SynchronizedCache::new(),
-            client: Box::new(DnsNetworkClient::new(34255)),
+            client: Box::new(client),
+            listen_addr,
             dns_port: DEFAULT_DNS_PORT,
             api_port: DEFAULT_API_PORT,
-            resolve_strategy: ResolveStrategy::Recursive,
+            resolve_strategy: RwLock::new(ResolveStrategy::Recursive),
             allow_recursive: true,
             enable_udp: true,
             enable_tcp: true,
@@ -91,33 +228,302 @@ impl ServerContext {
             statistics: ServerStatistics {
                 tcp_query_count: AtomicUsize::new(0),
                 udp_query_count: AtomicUsize::new(0),
+                query_type_count: DashMap::new(),
             },
-            zones_dir: DEFAULT_ZONES_DIR,
-        }
+            zones_dir: DEFAULT_ZONES_DIR.to_string(),
+            rewrite_rules: Vec::new(),
+            serve_ttl_floor: DEFAULT_SERVE_TTL_FLOOR,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            blocklist: HashSet::new(),
+            block_response: BlockResponse::NxDomain,
+            rate_limiter: None,
+            shutdown,
+            resolution_slots: Semaphore::new(DEFAULT_MAX_CONCURRENT_RESOLUTIONS),
+            trust_anchors: Vec::new(),
+            root_servers: default_root_servers(),
+        })
     }
 
     /// Initializes the server context, setting up directories, clients, and authority data.
     pub fn initialize(&mut self) -> Result<()> {
         // Ensure zones directory exists.
-        fs::create_dir_all(self.zones_dir)
+        fs::create_dir_all(&self.zones_dir)
             .map_err(ContextError::Io)?;
 
-        // Start the client thread.
+        // Start the client thread, asking for signatures when there's something to validate.
+        self.client.set_dnssec_ok(!self.trust_anchors.is_empty());
         self.client.run()?;
 
         // Load authority data.
-        self.authority.load()?;
+        self.authority.load(Path::new(&self.zones_dir))?;
 
         Ok(())
     }
 
+    /// Sets how many upstream queries recursive resolution may have in flight at once.
+    pub fn set_max_concurrent_resolutions(&mut self, limit: usize) {
+        self.resolution_slots = Semaphore::new(limit);
+    }
+
+    /// Stops accepting queries and waits for the ones being answered to finish.
+    pub async fn shutdown(&self) {
+        self.shutdown.trigger();
+        self.shutdown.drained().await;
+    }
+
+    /// Starts removing expired entries from the cache every `period`, until shutdown.
+    pub fn spawn_cache_reaper(self: &Arc<Self>, period: Duration) -> JoinHandle<()> {
+        let context = self.clone();
+        tokio::spawn(async move {
+            let signal = context.shutdown.subscribe();
+            context.cache.run_reaper(period, signal).await;
+        })
+    }
+
+    /// Applies the first rewrite rule matching `qname`, if any.
+    pub fn rewrite(&self, qname: &str) -> Option<String> {
+        self.rewrite_rules
+            .iter()
+            .find_map(|rule| rule.apply(qname))
+            .filter(|target| target != qname)
+    }
+
+    /// Checks whether a query from `ip` is within its rate limit.
+    ///
+    /// Request handlers should drop or refuse queries for which this returns false.
+    pub fn allow_query(&self, ip: IpAddr) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .is_none_or(|limiter| limiter.allow(ip))
+    }
+
+    /// Checks whether `qname` or any of its parent domains is on the blocklist.
+    pub fn is_blocked(&self, qname: &str) -> bool {
+        if self.blocklist.is_empty() {
+            return false;
+        }
+
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let mut name = qname.as_str();
+        loop {
+            if self.blocklist.contains(name) {
+                return true;
+            }
+
+            match name.find('.') {
+                Some(idx) => name = &name[idx + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    /// Adds the names of a hosts-format file, such as published ad blocking lists, to the blocklist.
+    ///
+    /// Each line is an address followed by one or more names, the address is ignored.
+    /// Returns the number of names added.
+    pub fn load_blocklist(&mut self, path: &Path) -> Result<usize> {
+        let contents = fs::read_to_string(path)?;
+
+        let mut added = 0;
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            for name in line.split_whitespace().skip(1) {
+                let name = name.trim_end_matches('.').to_lowercase();
+                if name.is_empty() || name == "localhost" {
+                    continue;
+                }
+
+                if self.blocklist.insert(name) {
+                    added += 1;
+                }
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Returns a copy of the current resolution strategy.
+    pub fn get_resolve_strategy(&self) -> ResolveStrategy {
+        // The strategy is replaced wholesale, so a poisoned lock still holds a usable value
+        match self.resolve_strategy.read() {
+            Ok(strategy) => strategy.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Replaces the resolution strategy, affecting every resolver created afterwards.
+    ///
+    /// Resolvers that already exist keep the strategy they were created with.
+    pub fn set_resolve_strategy(&self, strategy: ResolveStrategy) {
+        match self.resolve_strategy.write() {
+            Ok(mut current) => *current = strategy,
+            Err(poisoned) => *poisoned.into_inner() = strategy,
+        }
+    }
+
     /// Creates a DNS resolver based on the current resolution strategy.
-    pub fn create_resolver(&self, ptr: Arc<Self>) -> Box<dyn DnsResolver> {
-        match &self.resolve_strategy {
+    pub fn create_resolver(&self, ptr: Arc<Self>) -> Box<dyn DnsResolver + Send + Sync> {
+        match self.get_resolve_strategy() {
             ResolveStrategy::Recursive => Box::new(RecursiveDnsResolver::new(ptr)),
             ResolveStrategy::Forward { host, port } => {
-                Box::new(ForwardingDnsResolver::new(ptr, (host.clone(), *port)))
+                Box::new(ForwardingDnsResolver::new(ptr, (host, port)))
+            }
+            ResolveStrategy::ForwardTls { host, port, tls_name } => {
+                Box::new(ForwardingDnsResolver::new_tls(ptr, (host, port), tls_name))
+            }
+            ResolveStrategy::RecursiveWithForwardFallback { host, port } => {
+                Box::new(FallbackDnsResolver::new(ptr, (host, port)))
             }
         }
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    use crate::client::network_client::tests::{DnsStubClient, StubCallback};
+    use crate::protocols::protocol::DnsPacket;
+
+    /// Builds a context which answers upstream queries through `callback`.
+    pub fn create_test_context(callback: Box<StubCallback>) -> Arc<ServerContext> {
+        Arc::new(ServerContext {
+            authority: Authority::default(),
+            cache: SynchronizedCache::new(),
+            client: Box::new(DnsStubClient::new(callback)),
+            listen_addr: DEFAULT_LISTEN_ADDR,
+            dns_port: DEFAULT_DNS_PORT,
+            api_port: DEFAULT_API_PORT,
+            resolve_strategy: RwLock::new(ResolveStrategy::Recursive),
+            allow_recursive: true,
+            enable_udp: true,
+            enable_tcp: true,
+            enable_api: true,
+            statistics: ServerStatistics {
+                tcp_query_count: AtomicUsize::new(0),
+                udp_query_count: AtomicUsize::new(0),
+                query_type_count: DashMap::new(),
+            },
+            zones_dir: DEFAULT_ZONES_DIR.to_string(),
+            rewrite_rules: Vec::new(),
+            serve_ttl_floor: DEFAULT_SERVE_TTL_FLOOR,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            blocklist: HashSet::new(),
+            block_response: BlockResponse::NxDomain,
+            rate_limiter: None,
+            shutdown: Shutdown::new(),
+            resolution_slots: Semaphore::new(DEFAULT_MAX_CONCURRENT_RESOLUTIONS),
+            trust_anchors: Vec::new(),
+            root_servers: default_root_servers(),
+        })
+    }
+
+    #[test]
+    fn test_initialize_creates_custom_zones_dir() {
+        let zones_dir = std::env::temp_dir().join(format!("dns-server-zones-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&zones_dir);
+
+        let mut context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        ctx.zones_dir = zones_dir.to_string_lossy().into_owned();
+        ctx.initialize().unwrap();
+
+        assert!(zones_dir.is_dir());
+
+        fs::remove_dir_all(&zones_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_swapping_forward_upstream() {
+        let upstream = Arc::new(std::sync::Mutex::new(String::new()));
+        let upstream_clone = upstream.clone();
+
+        let context = create_test_context(Box::new(move |_, _, server, _, _| {
+            *upstream_clone.lock().unwrap() = format!("{}:{}", server.0, server.1);
+            Ok(DnsPacket::new())
+        }));
+
+        context.set_resolve_strategy(ResolveStrategy::Forward {
+            host: "10.0.0.1".to_string(),
+            port: 53,
+        });
+        let mut resolver = context.create_resolver(context.clone());
+        resolver.resolve("first.example", QueryType::A, true).await.unwrap();
+        assert_eq!("10.0.0.1:53", *upstream.lock().unwrap());
+
+        context.set_resolve_strategy(ResolveStrategy::Forward {
+            host: "10.0.0.2".to_string(),
+            port: 5353,
+        });
+        let mut resolver = context.create_resolver(context.clone());
+        resolver.resolve("second.example", QueryType::A, true).await.unwrap();
+        assert_eq!("10.0.0.2:5353", *upstream.lock().unwrap());
+    }
+
+    #[test]
+    fn test_load_blocklist_from_hosts_file() {
+        let path = std::env::temp_dir().join(format!("dns-server-blocklist-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# ad servers\n127.0.0.1 localhost\n0.0.0.0 ads.example.com tracker.example.net.\n\n0.0.0.0 Ads.Example.com # again\n",
+        )
+        .unwrap();
+
+        let mut context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        let added = ctx.load_blocklist(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(2, added);
+        assert!(ctx.is_blocked("ads.example.com"));
+        assert!(ctx.is_blocked("tracker.example.net"));
+        assert!(ctx.is_blocked("cdn.ads.example.com"));
+        assert!(!ctx.is_blocked("example.com"));
+        assert!(!ctx.is_blocked("localhost"));
+    }
+
+    #[test]
+    fn test_allow_query_consults_rate_limiter() {
+        let mut context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert!(context.allow_query(ip));
+        assert!(context.allow_query(ip));
+
+        Arc::get_mut(&mut context).unwrap().rate_limiter = Some(RateLimiter::new(1, 1));
+        assert!(context.allow_query(ip));
+        assert!(!context.allow_query(ip));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_query_loop() {
+        let context = create_test_context(Box::new(|_, _, _, _, _| Ok(DnsPacket::new())));
+
+        let mut signal = context.shutdown.subscribe();
+        let worker = context.clone();
+        let task = tokio::spawn(async move {
+            let mut answered = 0;
+            loop {
+                tokio::select! {
+                    _ = signal.recv() => return answered,
+                    _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                        let _in_flight = worker.shutdown.track();
+                        worker.cache.lookup_async("example.com", QueryType::A).await;
+                        answered += 1;
+                    }
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        tokio::time::timeout(Duration::from_secs(1), context.shutdown())
+            .await
+            .unwrap();
+
+        let answered = tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+        assert!(answered > 0);
+        assert_eq!(0, context.shutdown.in_flight());
+    }
+}
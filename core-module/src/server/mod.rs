@@ -1 +1,4 @@
-pub mod context;
+pub mod context;
+pub mod rate_limiter;
+pub mod shutdown;
+pub mod tcp;
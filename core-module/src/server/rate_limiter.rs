@@ -0,0 +1,109 @@
+//! Per client rate limiting of incoming queries.
+
+use std::net::IpAddr;
+use std::time::Instant;
+
+use dashmap::DashMap;
+
+/// Tokens available to a single client, refilled continuously over time.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket rate limiter keyed by the source address of a query.
+///
+/// Every client may send `burst` queries at once, after which it's held to
+/// `rate` queries per second.
+pub struct RateLimiter {
+    buckets: DashMap<IpAddr, TokenBucket>,
+    rate: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> RateLimiter {
+        RateLimiter {
+            buckets: DashMap::new(),
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+        }
+    }
+
+    /// Takes a token for `ip`, returning false if it has run out.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        let mut bucket = self.buckets.entry(ip).or_insert_with(|| TokenBucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forgets clients whose buckets have filled up again, as they're
+    /// indistinguishable from clients that were never seen.
+    pub fn purge_idle(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+    }
+
+    /// Number of clients currently tracked.
+    pub fn tracked_clients(&self) -> usize {
+        self.buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_clients_are_limited_to_their_burst() {
+        let limiter = RateLimiter::new(1, 5);
+        let noisy: IpAddr = "192.0.2.1".parse().unwrap();
+        let quiet: IpAddr = "192.0.2.2".parse().unwrap();
+
+        for _ in 0..5 {
+            assert!(limiter.allow(noisy));
+        }
+        assert!(!limiter.allow(noisy));
+        assert!(!limiter.allow(noisy));
+
+        assert!(limiter.allow(quiet));
+        assert_eq!(2, limiter.tracked_clients());
+    }
+
+    #[test]
+    fn test_tokens_refill_over_time() {
+        let limiter = RateLimiter::new(100, 1);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limiter.allow(ip));
+
+        std::thread::sleep(Duration::from_millis(30));
+        limiter.purge_idle();
+        assert_eq!(0, limiter.tracked_clients());
+    }
+}
@@ -0,0 +1,166 @@
+//! Coordinates stopping the server's long running tasks.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{watch, Notify};
+
+/// Shared shutdown state, normally owned by the `ServerContext`.
+///
+/// Long running loops hold a `ShutdownSignal` and exit once it fires, while
+/// work that should finish before the process exits holds an `InFlight` guard.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    in_flight: Arc<InFlightCounter>,
+}
+
+struct InFlightCounter {
+    count: AtomicUsize,
+    drained: Notify,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, _) = watch::channel(false);
+        Shutdown {
+            sender,
+            in_flight: Arc::new(InFlightCounter {
+                count: AtomicUsize::new(0),
+                drained: Notify::new(),
+            }),
+        }
+    }
+
+    /// Asks every task holding a signal to stop.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Returns a signal for a task to wait on.
+    pub fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    /// Marks a unit of work, such as a query being answered, as in flight until the guard is dropped.
+    pub fn track(&self) -> InFlight {
+        self.in_flight.count.fetch_add(1, Ordering::AcqRel);
+        InFlight {
+            counter: self.in_flight.clone(),
+        }
+    }
+
+    /// Number of `InFlight` guards currently alive.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count.load(Ordering::Acquire)
+    }
+
+    /// Waits until no work is in flight.
+    pub async fn drained(&self) {
+        loop {
+            // Register before checking so a guard dropped in between isn't missed
+            let notified = self.in_flight.drained.notified();
+            if self.in_flight() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Fires once shutdown has been triggered.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once shutdown is triggered, straight away if it already was.
+    ///
+    /// Also resolves if the `Shutdown` is dropped, as nothing can trigger it after that.
+    pub async fn recv(&mut self) {
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Guard for work that shutdown should wait for.
+pub struct InFlight {
+    counter: Arc<InFlightCounter>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.counter.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.counter.drained.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_signal_stops_loop() {
+        let shutdown = Shutdown::new();
+        let mut signal = shutdown.subscribe();
+
+        let task = tokio::spawn(async move {
+            let mut ticks = 0;
+            loop {
+                tokio::select! {
+                    _ = signal.recv() => return ticks,
+                    _ = tokio::time::sleep(Duration::from_millis(5)) => ticks += 1,
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        shutdown.trigger();
+
+        let ticks = tokio::time::timeout(Duration::from_secs(1), task).await.unwrap().unwrap();
+        assert!(ticks > 0);
+
+        // Subscribing late still sees the shutdown
+        let mut late = shutdown.subscribe();
+        assert!(late.is_triggered());
+        tokio::time::timeout(Duration::from_secs(1), late.recv()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_drained_waits_for_in_flight_work() {
+        let shutdown = Arc::new(Shutdown::new());
+        let guard = shutdown.track();
+        assert_eq!(1, shutdown.in_flight());
+
+        let waiter = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.drained().await })
+        };
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert_eq!(0, shutdown.in_flight());
+    }
+}
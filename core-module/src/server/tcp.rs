@@ -0,0 +1,159 @@
+//! Answers queries arriving on persistent TCP connections.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use derive_more::{Display, Error, From};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time;
+use tracing::warn;
+
+use crate::buffer::buffer::VectorPacketBuffer;
+use crate::network_utilities::netutil::{read_packet_length, write_packet_length};
+use crate::protocols::protocol::{DnsPacket, ResultCode};
+use crate::resolvers::resolve::error_response_for;
+use crate::server::context::ServerContext;
+
+#[derive(Debug, Display, From, Error)]
+pub enum TcpServerError {
+    Protocol(crate::protocols::protocol::ProtocolError),
+    Io(std::io::Error),
+}
+
+type Result<T> = std::result::Result<T, TcpServerError>;
+
+/// Accepts connections on the server's DNS port until shutdown, serving each on a task of its own.
+pub async fn run_tcp_server(context: Arc<ServerContext>) -> Result<()> {
+    let listener = TcpListener::bind((context.listen_addr, context.dns_port)).await?;
+    let mut shutdown = context.shutdown.subscribe();
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            _ = shutdown.recv() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+
+        if let Some(ref rate_limiter) = context.rate_limiter {
+            if !rate_limiter.allow(peer.ip()) {
+                continue;
+            }
+        }
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_tcp_connection(context, stream).await {
+                warn!(target: "dns", "TCP connection from {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Answers the queries sent on `stream` one after another.
+///
+/// The connection is closed once it has been idle for the keepalive timeout,
+/// which responses to EDNS queries advertise through edns-tcp-keepalive.
+pub async fn serve_tcp_connection<S>(context: Arc<ServerContext>, mut stream: S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut shutdown = context.shutdown.subscribe();
+
+    loop {
+        let len = tokio::select! {
+            _ = shutdown.recv() => return Ok(()),
+            len = time::timeout(context.tcp_keepalive, read_packet_length(&mut stream)) => match len {
+                Ok(Ok(len)) => len,
+                // The client closed the connection, or let it go idle
+                Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(err)) => return Err(err.into()),
+                Err(_) => return Ok(()),
+            },
+        };
+
+        let mut req_buffer = VectorPacketBuffer::new();
+        req_buffer.buffer.resize(len as usize, 0);
+        match time::timeout(context.tcp_keepalive, stream.read_exact(&mut req_buffer.buffer)).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(()),
+        };
+        let query = DnsPacket::from_buffer(&mut req_buffer)?;
+
+        let _in_flight = context.shutdown.track();
+        context.statistics.tcp_query_count.fetch_add(1, Ordering::Release);
+
+        let mut resolver = context.create_resolver(context.clone());
+        let mut response = match resolver.resolve_query(&query).await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!(target: "dns", "Failed to resolve TCP query: {}", err);
+                error_response_for(&query, ResultCode::SERVFAIL, context.allow_recursive)
+            }
+        };
+
+        let mut res_buffer = VectorPacketBuffer::new();
+        response.write_tcp_response(&query, context.tcp_keepalive, &mut res_buffer)?;
+
+        write_packet_length(&mut stream, res_buffer.pos).await?;
+        stream.write_all(&res_buffer.buffer[..res_buffer.pos]).await?;
+        stream.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use tokio::io::duplex;
+
+    use super::*;
+
+    use crate::buffer::buffer::BytePacketBuffer;
+    use crate::protocols::protocol::{DnsQuestion, DnsRecord, QueryType, TcpKeepalive, TransientTtl};
+    use crate::server::context::tests::create_test_context;
+
+    #[tokio::test]
+    async fn test_response_carries_configured_keepalive() {
+        let mut context = create_test_context(Box::new(|qname, _, _, _, _| {
+            let mut packet = DnsPacket::new();
+            packet.answers.push(DnsRecord::A {
+                domain: qname.to_string(),
+                addr: Ipv4Addr::new(10, 0, 0, 1),
+                ttl: TransientTtl(3600),
+            });
+            Ok(packet)
+        }));
+        let ctx = Arc::get_mut(&mut context).unwrap();
+        ctx.tcp_keepalive = Duration::from_millis(200);
+        ctx.root_servers = vec![("root.test".to_string(), Ipv4Addr::new(192, 0, 2, 1).into())];
+
+        let (mut client, server) = duplex(4096);
+        let connection = tokio::spawn(serve_tcp_connection(context, server));
+
+        let mut query = DnsPacket::new();
+        query.header.id = 4242;
+        query.header.recursion_desired = true;
+        query.questions.push(DnsQuestion::new("www.example.com".to_string(), QueryType::A));
+        query.set_tcp_keepalive(TcpKeepalive::default());
+
+        let mut req_buffer = BytePacketBuffer::new();
+        query.write(&mut req_buffer, 0xFFFF).unwrap();
+        write_packet_length(&mut client, req_buffer.pos).await.unwrap();
+        client.write_all(&req_buffer.buf[..req_buffer.pos]).await.unwrap();
+
+        let len = read_packet_length(&mut client).await.unwrap();
+        let mut res_buffer = VectorPacketBuffer::new();
+        res_buffer.buffer.resize(len as usize, 0);
+        client.read_exact(&mut res_buffer.buffer).await.unwrap();
+        let response = DnsPacket::from_buffer(&mut res_buffer).unwrap();
+
+        assert_eq!(4242, response.header.id);
+        assert_eq!(1, response.answers.len());
+        assert_eq!(Some(TcpKeepalive { timeout: Some(2) }), response.get_tcp_keepalive());
+
+        // Left idle, the connection is closed once the keepalive runs out
+        time::timeout(Duration::from_secs(1), connection).await.unwrap().unwrap().unwrap();
+    }
+}